@@ -0,0 +1,185 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::model::*;
+
+pub const ACHIEVEMENTS_PATH: &str = "achievements.txt"; // 解除した実績の保存先
+
+// 実績を解除する条件
+#[derive(Debug, Clone, Copy)]
+pub enum Condition {
+    Depth(i32),        // 深さがこの値以上になった
+    EraseGroup(i32),   // この個数以上つながったブロックを一度に消した
+    ClearWithAir(i32), // 残りエアがこの割合（%）以上のままクリアした
+    Combo(u32),        // コンボがこの回数以上続いた
+}
+
+impl Condition {
+    fn is_met(self, game: &Game) -> bool {
+        match self {
+            Condition::Depth(depth) => game.get_depth() >= depth,
+            Condition::EraseGroup(size) => game
+                .events
+                .iter()
+                .any(|&e| matches!(e, GameEvent::Erased(n) if n >= size)),
+            Condition::ClearWithAir(percent) => {
+                game.is_clear && game.player.air * 100 >= AIR_MAX * percent
+            }
+            Condition::Combo(combo) => game.combo >= combo,
+        }
+    }
+}
+
+pub struct Achievement {
+    pub id: &'static str, // 保存するときの名前。変えると解除済みでなくなるので変えない
+    pub title: &'static str, // 解除したときに表示する文
+    pub condition: Condition,
+}
+
+// 実績の一覧。増やすときはここに足すだけでよい
+pub static ACHIEVEMENTS: [Achievement; 8] = [
+    Achievement {
+        id: "depth_50",
+        title: "REACHED 50M",
+        condition: Condition::Depth(50),
+    },
+    Achievement {
+        id: "depth_100",
+        title: "REACHED 100M",
+        condition: Condition::Depth(100),
+    },
+    Achievement {
+        id: "depth_300",
+        title: "REACHED 300M",
+        condition: Condition::Depth(300),
+    },
+    Achievement {
+        id: "erase_6",
+        title: "ERASED A 6-BLOCK GROUP",
+        condition: Condition::EraseGroup(6),
+    },
+    Achievement {
+        id: "erase_10",
+        title: "ERASED A 10-BLOCK GROUP",
+        condition: Condition::EraseGroup(10),
+    },
+    Achievement {
+        id: "clear_air_80",
+        title: "CLEARED WITH 4/5 AIR",
+        condition: Condition::ClearWithAir(80),
+    },
+    Achievement {
+        id: "combo_3",
+        title: "3 COMBO",
+        condition: Condition::Combo(3),
+    },
+    Achievement {
+        id: "combo_5",
+        title: "5 COMBO",
+        condition: Condition::Combo(5),
+    },
+];
+
+// 解除した実績。ファイルには解除した実績のidを1行に1つ書く
+#[derive(Debug, Clone, Default)]
+pub struct Achievements {
+    unlocked: Vec<String>,
+}
+
+impl Achievements {
+    // ファイルがなければ何も解除していない。知らないidも覚えておき、保存したときに消さない
+    pub fn load(path: &Path) -> Achievements {
+        let Ok(text) = fs::read_to_string(path) else {
+            return Achievements::default();
+        };
+        Achievements {
+            unlocked: text
+                .lines()
+                .map(|line| line.trim().to_string())
+                .filter(|line| !line.is_empty())
+                .collect(),
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        fs::write(path, self.unlocked.join("\n") + "\n")
+    }
+
+    pub fn is_unlocked(&self, id: &str) -> bool {
+        self.unlocked.iter().any(|u| u == id)
+    }
+
+    // updateの直後（描画側がeventsを取り出す前）に呼び、新しく解除した実績を返す
+    pub fn check(&mut self, game: &Game) -> Vec<&'static Achievement> {
+        let mut newly = Vec::new();
+        for achievement in &ACHIEVEMENTS {
+            if !self.is_unlocked(achievement.id) && achievement.condition.is_met(game) {
+                self.unlocked.push(achievement.id.to_string());
+                newly.push(achievement);
+            }
+        }
+        newly
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ids(unlocked: &[&Achievement]) -> Vec<&'static str> {
+        unlocked.iter().map(|a| a.id).collect()
+    }
+
+    #[test]
+    fn achievements_unlock_only_once() {
+        let mut achievements = Achievements::default();
+        let mut game = Game::new_seeded(1);
+        assert!(achievements.check(&game).is_empty());
+
+        game.depth = 120;
+        assert_eq!(
+            ids(&achievements.check(&game)),
+            vec!["depth_50", "depth_100"]
+        );
+        assert!(achievements.check(&game).is_empty());
+        assert!(achievements.is_unlocked("depth_100"));
+        assert!(!achievements.is_unlocked("depth_300"));
+    }
+
+    #[test]
+    fn erase_combo_and_clear_conditions_read_the_game() {
+        let mut achievements = Achievements::default();
+        let mut game = Game::new_seeded(1);
+        game.events = vec![GameEvent::Erased(4), GameEvent::Erased(7)];
+        game.combo = 3;
+        assert_eq!(ids(&achievements.check(&game)), vec!["erase_6", "combo_3"]);
+
+        game.events.clear();
+        game.is_clear = true;
+        game.player.air = AIR_MAX * 79 / 100;
+        assert!(achievements.check(&game).is_empty());
+        game.player.air = AIR_MAX * 4 / 5;
+        assert_eq!(ids(&achievements.check(&game)), vec!["clear_air_80"]);
+    }
+
+    #[test]
+    fn saved_achievements_load_back_with_unknown_ids() {
+        let path = std::env::temp_dir().join(format!(
+            "rust-driller-achievements-{}.txt",
+            std::process::id()
+        ));
+        fs::write(&path, "depth_50\nfrom_a_newer_version\n").unwrap();
+        let mut achievements = Achievements::load(&path);
+        let mut game = Game::new_seeded(1);
+        game.depth = 100;
+        assert_eq!(ids(&achievements.check(&game)), vec!["depth_100"]);
+        achievements.save(&path).unwrap();
+
+        let loaded = Achievements::load(&path);
+        for id in ["depth_50", "depth_100", "from_a_newer_version"] {
+            assert!(loaded.is_unlocked(id), "{}", id);
+        }
+        fs::remove_file(&path).unwrap();
+    }
+}
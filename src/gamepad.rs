@@ -0,0 +1,302 @@
+use sdl2::controller::{Axis, Button, GameController};
+use sdl2::event::Event;
+use sdl2::keyboard::{Keycode, Mod};
+use sdl2::{GameControllerSubsystem, Sdl};
+
+use crate::direction_keycode;
+use crate::model::*;
+use crate::settings::*;
+
+// 左スティックをこれより小さく倒しても動かない（軸の値は-32768〜32767）
+const STICK_DEADZONE: i32 = 16000;
+
+// ゲームパッドの入力を、キーボードのキーを押した・離したイベントに読み替える
+// 十字キーと左スティックは今の割り当ての方向キー、Aボタンはスペースキーになるので、
+// 押したままの扱いや1フレームに1つだけコマンドを出す決まりはキーボードと同じになる
+pub struct Gamepad {
+    subsystem: Option<GameControllerSubsystem>, // 初期化できなければNone（キーボードだけで遊べる）
+    controllers: Vec<GameController>, // つながっているゲームパッド（開いている間だけイベントが届く）
+    stick_x: i32,
+    stick_y: i32,
+    stick: Option<(Command, Keycode)>, // 左スティックを倒している方向（大きく倒している軸の方だけ）と、押したことにしたキー
+    buttons: Vec<(Button, Keycode)>,   // 押しているボタンと、押したことにしたキー
+}
+
+impl Gamepad {
+    // つながっているゲームパッドは、SDLが最初に送ってくるControllerDeviceAddedで開く
+    pub fn new(sdl_context: &Sdl) -> Self {
+        let subsystem = match sdl_context.game_controller() {
+            Ok(subsystem) => Some(subsystem),
+            Err(e) => {
+                log_warn!("gamepad disabled: {}", e);
+                None
+            }
+        };
+        Gamepad {
+            subsystem,
+            controllers: Vec::new(),
+            stick_x: 0,
+            stick_y: 0,
+            stick: None,
+            buttons: Vec::new(),
+        }
+    }
+
+    // ゲームパッドのイベントはキーボードのイベントに読み替え、それ以外はそのまま返す
+    // つないだ・抜いたイベントや、倒している方向が変わらないスティックの動きは何も返さない
+    // 離すときは押したときのキーを離す（押している間にF8で割り当てが変わっても、押したままのキーが残らないように）
+    pub fn translate(&mut self, event: Event, scheme: ControlScheme) -> Vec<Event> {
+        match event {
+            Event::ControllerDeviceAdded { which, .. } => {
+                self.open(which);
+                Vec::new()
+            }
+            Event::ControllerDeviceRemoved { which, .. } => {
+                self.controllers.retain(|c| c.instance_id() != which);
+                log_info!("gamepad disconnected");
+                // スティックを倒したまま抜かれたら離したことにする
+                self.stick_x = 0;
+                self.stick_y = 0;
+                self.update_stick(scheme)
+            }
+            Event::ControllerButtonDown { button, .. } => {
+                let Some(code) = button_keycode(scheme, button) else {
+                    return Vec::new();
+                };
+                self.buttons.retain(|&(b, _)| b != button);
+                self.buttons.push((button, code));
+                vec![key_event(code, true)]
+            }
+            Event::ControllerButtonUp { button, .. } => {
+                let Some(i) = self.buttons.iter().position(|&(b, _)| b == button) else {
+                    return Vec::new();
+                };
+                let (_, code) = self.buttons.remove(i);
+                vec![key_event(code, false)]
+            }
+            Event::ControllerAxisMotion { axis, value, .. } => {
+                match axis {
+                    Axis::LeftX => self.stick_x = value as i32,
+                    Axis::LeftY => self.stick_y = value as i32,
+                    _ => return Vec::new(),
+                }
+                self.update_stick(scheme)
+            }
+            event => vec![event],
+        }
+    }
+
+    fn open(&mut self, joystick_index: u32) {
+        let Some(subsystem) = &self.subsystem else {
+            return;
+        };
+        match subsystem.open(joystick_index) {
+            Ok(controller) => {
+                log_info!("gamepad connected: {}", controller.name());
+                self.controllers.push(controller);
+            }
+            Err(e) => log_warn!("cannot open gamepad {}: {}", joystick_index, e),
+        }
+    }
+
+    // スティックの倒し方から方向を1つに決め直し、変わっていれば前の方向キーを離して新しい方向キーを押す
+    // 縦横両方に倒していたら、大きく倒している軸の方だけにする
+    fn update_stick(&mut self, scheme: ControlScheme) -> Vec<Event> {
+        let (x, y) = (self.stick_x, self.stick_y);
+        let stick = if x.abs() < STICK_DEADZONE && y.abs() < STICK_DEADZONE {
+            None
+        } else if x.abs() >= y.abs() {
+            Some(if x < 0 { Command::Left } else { Command::Right })
+        } else {
+            Some(if y < 0 { Command::Up } else { Command::Down })
+        };
+        if stick == self.stick.map(|(command, _)| command) {
+            return Vec::new();
+        }
+        let mut events = Vec::new();
+        if let Some((_, code)) = self.stick {
+            events.push(key_event(code, false));
+        }
+        self.stick = stick.and_then(|c| direction_keycode(scheme, c).map(|code| (c, code)));
+        if let Some((_, code)) = self.stick {
+            events.push(key_event(code, true));
+        }
+        events
+    }
+}
+
+// ボタンに対応するキー。十字キーは今の割り当てで同じ方向になるキーにする
+fn button_keycode(scheme: ControlScheme, button: Button) -> Option<Keycode> {
+    match button {
+        Button::DPadLeft => direction_keycode(scheme, Command::Left),
+        Button::DPadRight => direction_keycode(scheme, Command::Right),
+        Button::DPadUp => direction_keycode(scheme, Command::Up),
+        Button::DPadDown => direction_keycode(scheme, Command::Down),
+        Button::A => Some(Keycode::Space),
+        _ => None,
+    }
+}
+
+fn key_event(code: Keycode, down: bool) -> Event {
+    if down {
+        Event::KeyDown {
+            timestamp: 0,
+            window_id: 0,
+            keycode: Some(code),
+            scancode: None,
+            keymod: Mod::NOMOD,
+            repeat: false,
+        }
+    } else {
+        Event::KeyUp {
+            timestamp: 0,
+            window_id: 0,
+            keycode: Some(code),
+            scancode: None,
+            keymod: Mod::NOMOD,
+            repeat: false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_gamepad() -> Gamepad {
+        Gamepad {
+            subsystem: None,
+            controllers: Vec::new(),
+            stick_x: 0,
+            stick_y: 0,
+            stick: None,
+            buttons: Vec::new(),
+        }
+    }
+
+    // 読み替えたキーのイベント（キー、押したか）
+    fn keys(events: Vec<Event>) -> Vec<(Keycode, bool)> {
+        events
+            .into_iter()
+            .map(|event| match event {
+                Event::KeyDown {
+                    keycode: Some(code),
+                    ..
+                } => (code, true),
+                Event::KeyUp {
+                    keycode: Some(code),
+                    ..
+                } => (code, false),
+                event => panic!("not a key event: {:?}", event),
+            })
+            .collect()
+    }
+
+    fn stick(gamepad: &mut Gamepad, axis: Axis, value: i16) -> Vec<(Keycode, bool)> {
+        let event = Event::ControllerAxisMotion {
+            timestamp: 0,
+            which: 0,
+            axis,
+            value,
+        };
+        keys(gamepad.translate(event, ControlScheme::Arrows))
+    }
+
+    fn button(
+        gamepad: &mut Gamepad,
+        button: Button,
+        down: bool,
+        scheme: ControlScheme,
+    ) -> Vec<(Keycode, bool)> {
+        let event = if down {
+            Event::ControllerButtonDown {
+                timestamp: 0,
+                which: 0,
+                button,
+            }
+        } else {
+            Event::ControllerButtonUp {
+                timestamp: 0,
+                which: 0,
+                button,
+            }
+        };
+        keys(gamepad.translate(event, scheme))
+    }
+
+    #[test]
+    fn small_stick_motion_stays_inside_the_deadzone() {
+        let mut gamepad = test_gamepad();
+        let inside = (STICK_DEADZONE - 1) as i16;
+        assert!(stick(&mut gamepad, Axis::LeftX, inside).is_empty());
+        assert!(stick(&mut gamepad, Axis::LeftY, -inside).is_empty());
+        assert_eq!(
+            stick(&mut gamepad, Axis::LeftX, STICK_DEADZONE as i16),
+            [(Keycode::Right, true)]
+        );
+        // 倒す量が変わっても方向が同じなら何も出さない
+        assert!(stick(&mut gamepad, Axis::LeftX, i16::MAX).is_empty());
+        assert_eq!(
+            stick(&mut gamepad, Axis::LeftX, 0),
+            [(Keycode::Right, false)]
+        );
+    }
+
+    #[test]
+    fn stick_points_along_the_axis_pushed_further() {
+        let mut gamepad = test_gamepad();
+        assert_eq!(
+            stick(&mut gamepad, Axis::LeftX, -20000),
+            [(Keycode::Left, true)]
+        );
+        // 縦に大きく倒すと、横を離して縦を押す
+        assert_eq!(
+            stick(&mut gamepad, Axis::LeftY, 25000),
+            [(Keycode::Left, false), (Keycode::Down, true)]
+        );
+        // 同じだけ倒していれば横
+        assert_eq!(
+            stick(&mut gamepad, Axis::LeftX, -25000),
+            [(Keycode::Down, false), (Keycode::Left, true)]
+        );
+        assert_eq!(
+            stick(&mut gamepad, Axis::LeftY, -30000),
+            [(Keycode::Left, false), (Keycode::Up, true)]
+        );
+    }
+
+    #[test]
+    fn keys_pressed_before_a_scheme_switch_are_released_as_pressed() {
+        let mut gamepad = test_gamepad();
+        assert_eq!(
+            stick(&mut gamepad, Axis::LeftX, -20000),
+            [(Keycode::Left, true)]
+        );
+        assert_eq!(
+            button(&mut gamepad, Button::DPadDown, true, ControlScheme::Arrows),
+            [(Keycode::Down, true)]
+        );
+        // F8でWASDに変えたあとも、離すのは押したときの矢印キー
+        let event = Event::ControllerAxisMotion {
+            timestamp: 0,
+            which: 0,
+            axis: Axis::LeftX,
+            value: 0,
+        };
+        assert_eq!(
+            keys(gamepad.translate(event, ControlScheme::Wasd)),
+            [(Keycode::Left, false)]
+        );
+        assert_eq!(
+            button(&mut gamepad, Button::DPadDown, false, ControlScheme::Wasd),
+            [(Keycode::Down, false)]
+        );
+        // 新しく押せば新しい割り当てのキー
+        assert_eq!(
+            button(&mut gamepad, Button::DPadDown, true, ControlScheme::Wasd),
+            [(Keycode::S, true)]
+        );
+        // 押していないボタンを離しても何も出さない
+        assert!(button(&mut gamepad, Button::DPadUp, false, ControlScheme::Wasd).is_empty());
+    }
+}
@@ -0,0 +1,61 @@
+use std::sync::atomic::{AtomicU8, Ordering};
+
+// ログの重要度。設定したレベル以下のものだけを出す
+#[derive(Debug, Clone, Copy, Eq, PartialEq, PartialOrd, Ord)]
+pub enum Level {
+    Off,
+    Warn,  // 続けられるが何かがうまくいかなかった（標準エラーに出す）
+    Info,  // シードや保存したファイルなど、あとで再現や確認に使う情報
+    Debug, // デバッグ中にだけ見たい詳しい情報
+}
+
+impl Level {
+    pub const ALL: [Level; 4] = [Level::Off, Level::Warn, Level::Info, Level::Debug];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Level::Off => "off",
+            Level::Warn => "warn",
+            Level::Info => "info",
+            Level::Debug => "debug",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Level> {
+        Level::ALL.into_iter().find(|level| level.name() == name)
+    }
+}
+
+static LEVEL: AtomicU8 = AtomicU8::new(Level::Info as u8);
+
+pub fn set_level(level: Level) {
+    LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+pub fn enabled(level: Level) -> bool {
+    level != Level::Off && level as u8 <= LEVEL.load(Ordering::Relaxed)
+}
+
+macro_rules! log_warn {
+    ($($arg:tt)*) => {
+        if $crate::log::enabled($crate::log::Level::Warn) {
+            eprintln!("warning: {}", format!($($arg)*));
+        }
+    };
+}
+
+macro_rules! log_info {
+    ($($arg:tt)*) => {
+        if $crate::log::enabled($crate::log::Level::Info) {
+            println!($($arg)*);
+        }
+    };
+}
+
+macro_rules! log_debug {
+    ($($arg:tt)*) => {
+        if $crate::log::enabled($crate::log::Level::Debug) {
+            println!($($arg)*);
+        }
+    };
+}
@@ -1,23 +1,81 @@
-use sdl2::event::Event;
+use sdl2::event::{Event, WindowEvent};
 use sdl2::gfx::primitives::DrawRenderer;
-use sdl2::keyboard::Keycode;
+use sdl2::image::SaveSurface;
+use sdl2::keyboard::{Keycode, Mod};
 use sdl2::mixer;
-use sdl2::pixels::Color;
+use sdl2::pixels::{Color, PixelFormatEnum};
 use sdl2::rect::Rect;
 use sdl2::render::{BlendMode, Canvas, Texture, TextureCreator};
+use sdl2::surface::Surface;
 use sdl2::ttf::Sdl2TtfContext;
 use sdl2::video::{Window, WindowContext};
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
+use std::env;
 use std::fs;
-use std::time::{Duration, SystemTime};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+#[macro_use]
+mod log;
+mod achievements;
+mod gamepad;
 mod model;
+mod palette;
+mod ratings;
+mod replay;
+mod savegame;
+mod scores;
+mod script;
+mod settings;
+use crate::achievements::*;
+use crate::gamepad::*;
 use crate::model::*;
+use crate::palette::*;
+use crate::ratings::*;
+use crate::replay::*;
+use crate::savegame::*;
+use crate::scores::*;
+use crate::script::*;
+use crate::settings::*;
 
 pub const CELL_SIZE: i32 = 40;
 pub const INFO_WIDTH: i32 = 100;
-pub const INFO_X: i32 = CELL_SIZE * CELLS_X_LEN;
-pub const SCREEN_WIDTH: i32 = CELL_SIZE * CELLS_X_LEN + INFO_WIDTH;
 pub const SCREEN_HEIGHT: i32 = CELL_SIZE * 12;
+pub const FRAME_TIME_SAMPLES: usize = 30; // FPS計測の移動平均に使うフレーム数
+pub const RECOIL_FRAMES: i32 = 4; // 入力を受け付けられなかったときにプレイヤーがのけぞるフレーム数
+pub const RECOIL_PX: i32 = 4; // のけぞりの最大幅
+pub const INTRO_FRAMES: i32 = FPS; // 開始時にカメラが地表からプレイヤーまで移動するフレーム数
+pub const CAMERA_STIFFNESS: f32 = 0.12; // カメラの慣性：目標位置へ引き戻す強さ
+pub const CAMERA_DAMPING: f32 = 0.4; // カメラの慣性：速度の減衰率
+pub const CAMERA_LOOK_FALLING: f32 = 2.0; // 落ちている間にカメラを下へずらす行数（下がよく見える）
+pub const CAMERA_LOOK_STANDING: f32 = -2.0; // 立ち止まっている間のずらし（負なら上へずらし、上がよく見える）
+pub const CAMERA_LOOK_EASE: f32 = 0.1; // カメラのずらしを1フレームで目標に近づける割合
+pub const DEPTH_RULER_INTERVAL: i32 = 10; // 深さ目盛りの間隔（マス）
+pub const LANDING_FRAMES: i32 = FPS / 3; // ブロックが着地したときの光と土煙が消えるまでのフレーム数
+pub const TOAST_FRAMES: i32 = FPS * 3; // 実績を解除したお知らせを1つ表示しておくフレーム数
+pub const DIG_QUEUE_LEN: i32 = 5; // 情報パネルに並べる、プレイヤーの真下のセルの数
+pub const DIG_QUEUE_CELL: i32 = 16; // そのセル1つの大きさ（px）
+pub const HUD_PADDING: i32 = 5; // 情報パネルの縁と要素の間（px）
+pub const HUD_GAP: i32 = 10; // 情報パネルに積む要素どうしの間（px）
+pub const AIR_GAUGE_RADIUS: i32 = 30; // 情報パネルのエアゲージの半径（px）
+pub const HUD_HIGH_SCORES: usize = 3; // 情報パネルに出す深さの記録の数
+pub const MINIMAP_ROWS: i32 = 30; // 縮図に出す、プレイヤーより下の行数（1行を1pxの高さで描く）
+pub const VANISH_FRAMES: i32 = FPS / 4; // 掘ったブロックが縮んで消え、破片が飛び散るまでのフレーム数
+pub const DEATH_CAM_FRAMES: usize = FPS as usize * 3; // ゲームオーバーの直前をふり返るために覚えておくフレーム数
+pub const DEATH_CAM_SLOWDOWN: usize = 2; // ふり返りで1フレームを何フレームかけて見せるか
+pub const HOLD_DIG_FRAMES: i32 = FPS / 4; // 長押しで掘るとき、何回も掘るブロックを削る間隔
+pub const AUTO_REPEAT_DELAY: i32 = FPS / 3; // 方向キーを押したままにしてから、くり返し始めるまでのフレーム数
+pub const AUTO_REPEAT_FRAMES: i32 = FPS / 10; // くり返し始めてからの、方向を出す間隔
+pub const SHAKE_PX: f32 = 3.0; // 落ちる直前のブロックが横に揺れる幅（px）
+pub const SHAKE_PERIOD_MIN: i32 = 6; // ブロックが1往復揺れるフレーム数の最小
+pub const SHAKE_PERIOD_VARIANTS: i32 = 5; // 1往復のフレーム数はSHAKE_PERIOD_MINからこの数だけばらつく
+pub const SCORE_POPUP_FRAMES: i32 = FPS; // かたまりを消したときの「+点」が浮かんで消えるまでのフレーム数
+pub const LOGIC_STEP: Duration = Duration::from_nanos(1_000_000_000 / FPS as u64); // ゲームを1フレーム進める間隔（実時間）
+pub const MAX_STEPS_PER_FRAME: u32 = 5; // 描画1回の間にゲームを進める最大の回数
+pub const RENDER_STEP: Duration = Duration::from_nanos(1_000_000_000 / 60); // 描画する間隔の目安（ゲームを進める間隔とは別）
+pub const FONT_NAME: &str = "boxfont2.ttf"; // 文字を書くときのフォント
+pub const FONT_SIZES: [u16; 4] = [16, 24, 32, 48]; // フォントを読み込んでおく大きさ（pt）
+pub const FONT_SIZE: u16 = 32; // ふだんの文字の大きさ（pt）
+pub const TITLE_FONT_SIZE: u16 = 48; // CLEAR!!など大きく出す見出しの文字の大きさ（pt）
 
 struct Image<'a> {
     texture: Texture<'a>,
@@ -29,12 +87,11 @@ struct Image<'a> {
 impl<'a> Image<'a> {
     fn new(texture: Texture<'a>) -> Self {
         let q = texture.query();
-        let image = Image {
+        Image {
             texture,
             w: q.width,
             h: q.height,
-        };
-        image
+        }
     }
 }
 
@@ -42,14 +99,757 @@ struct Resources<'a> {
     images: HashMap<String, Image<'a>>,
     chunks: HashMap<String, sdl2::mixer::Chunk>,
     fonts: HashMap<String, sdl2::ttf::Font<'a, 'a>>,
+    audio_enabled: bool, // falseなら音を鳴らさない（オーディオデバイスがない環境など）
+}
+
+impl<'a> Resources<'a> {
+    // 指定した大きさのフォント。読み込んでいない大きさなら、いちばん近い大きさのものを返す
+    fn font(&self, size: u16) -> &sdl2::ttf::Font<'a, 'a> {
+        let size = FONT_SIZES
+            .into_iter()
+            .min_by_key(|&s| s.abs_diff(size))
+            .unwrap();
+        &self.fonts[&font_key(FONT_NAME, size)]
+    }
+}
+
+// fontsのキー。同じフォントを大きさごとに別々に読み込んでおく
+fn font_key(name: &str, size: u16) -> String {
+    format!("{}@{}", name, size)
+}
+
+#[derive(Clone, Copy)]
+enum NumberAlign {
+    Left,
+    Right, // 指定したx座標を右端として描く
+}
+
+// render_numberで数字画像をどう並べるか
+#[derive(Clone, Copy)]
+struct NumberStyle {
+    digit_width: i32, // 数字画像の1文字分の幅（px）
+    spacing: i32,     // 文字間の余白（px、拡大前）
+    scale: f32,
+    align: NumberAlign,
+}
+
+impl Default for NumberStyle {
+    // numbers.bmpに合わせた既定値
+    fn default() -> Self {
+        NumberStyle {
+            digit_width: 8,
+            spacing: 0,
+            scale: 1.0,
+            align: NumberAlign::Left,
+        }
+    }
+}
+
+// 表示中の画面
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+enum Scene {
+    Title,
+    Playing,
+}
+
+// ゲームオーバー時のメニュー項目
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+enum GameOverOption {
+    Retry,   // 同じシードでやり直す
+    NewGame, // 新しいシードで始める
+    Title,   // タイトル画面に戻る
+}
+
+impl GameOverOption {
+    const ALL: [GameOverOption; 3] = [
+        GameOverOption::Retry,
+        GameOverOption::NewGame,
+        GameOverOption::Title,
+    ];
+
+    fn label(self) -> &'static str {
+        match self {
+            GameOverOption::Retry => "RETRY",
+            GameOverOption::NewGame => "NEW GAME",
+            GameOverOption::Title => "TITLE",
+        }
+    }
+}
+
+// チュートリアルの段階。表示中の操作をすると次の段階に進む
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+enum TutorialStep {
+    DigDown,
+    DigSideways,
+    GrabAir,
+    DigClear,
+    Done,
+}
+
+impl TutorialStep {
+    fn prompt(self) -> &'static [&'static str] {
+        match self {
+            TutorialStep::DigDown => &["DIG DOWN", "WITH DOWN KEY"],
+            TutorialStep::DigSideways => &["DIG SIDEWAYS", "LEFT / RIGHT"],
+            TutorialStep::GrabAir => &["GRAB THE AIR", "BEFORE IT", "RUNS OUT"],
+            TutorialStep::DigClear => &["DIG THE CLEAR", "BLOCK TO WIN"],
+            TutorialStep::Done => &[],
+        }
+    }
+
+    // モデルから届いた出来事で次の段階に進める
+    fn advance(self, event: GameEvent) -> TutorialStep {
+        match (self, event) {
+            (TutorialStep::DigDown, GameEvent::Dug(Direction::Down)) => TutorialStep::DigSideways,
+            (TutorialStep::DigSideways, GameEvent::Dug(Direction::Left | Direction::Right)) => {
+                TutorialStep::GrabAir
+            }
+            (TutorialStep::GrabAir, GameEvent::AirCollected) => TutorialStep::DigClear,
+            _ => self,
+        }
+    }
+}
+
+// キーボードの入力状態。OSのキーリピートには頼らず、KeyDown/KeyUpで押しているキーを覚えておき、
+// 押していなかったキーが押されたときだけ反応する（1回押せば1回だけ動く）
+struct InputState {
+    held: Vec<Keycode>,    // 押したままのキー（押した順）
+    command: Command,      // 前回取り出してから新しく押された方向（複数なら最後に押したもの）
+    scheme: ControlScheme, // 方向キーの割り当て
+}
+
+impl InputState {
+    fn new(scheme: ControlScheme) -> Self {
+        InputState {
+            held: Vec::new(),
+            command: Command::None,
+            scheme,
+        }
+    }
+
+    // キーが押された。押したままのキーのリピートならfalseを返す
+    fn key_down(&mut self, code: Keycode) -> bool {
+        if self.held.contains(&code) {
+            return false;
+        }
+        self.held.push(code);
+        if let Some(command) = direction_command(self.scheme, code) {
+            self.command = command;
+        }
+        true
+    }
+
+    fn key_up(&mut self, code: Keycode) {
+        self.held.retain(|&c| c != code);
+    }
+
+    // 方向キーの割り当てを変える。押したままのキーはそのまま、新しい割り当てで読み直す
+    fn set_scheme(&mut self, scheme: ControlScheme) {
+        self.scheme = scheme;
+    }
+
+    // ウィンドウがフォーカスを失うとKeyUpが届かないことがあるので、すべて離したことにする
+    fn release_all(&mut self) {
+        self.held.clear();
+    }
+
+    // 方向キー以外で出すコマンド（崩落技など）
+    fn press(&mut self, command: Command) {
+        self.command = command;
+    }
+
+    // このフレームにupdateへ渡すコマンドを取り出す
+    fn take_command(&mut self) -> Command {
+        std::mem::replace(&mut self.command, Command::None)
+    }
+
+    // 押したままの方向キーのうち最後に押したものの方向
+    fn held_direction(&self) -> Option<Command> {
+        self.held
+            .iter()
+            .rev()
+            .find_map(|&code| direction_command(self.scheme, code))
+    }
+
+    // 方向キーを押したままか
+    fn is_held(&self, command: Command) -> bool {
+        self.held
+            .iter()
+            .any(|&code| direction_command(self.scheme, code) == Some(command))
+    }
+
+    // このフレームに新しく押したキーがなく、下を押したままならSoftDropにする
+    // 速く落ちるのは落下中だけなので、立っているときに出しても何も起きない（掘り続けたりはしない）
+    fn with_soft_drop(&self, command: Command) -> Command {
+        if command == Command::None && self.is_held(Command::Down) {
+            Command::SoftDrop
+        } else {
+            command
+        }
+    }
+
+    // 押したキーが今の割り当てでどの方向か
+    fn command_of(&self, code: Keycode) -> Option<Command> {
+        direction_command(self.scheme, code)
+    }
+}
+
+// 方向キーの割り当て（左、右、下、上の順）
+fn direction_keys(scheme: ControlScheme) -> (Keycode, Keycode, Keycode, Keycode) {
+    match scheme {
+        ControlScheme::Arrows => (Keycode::Left, Keycode::Right, Keycode::Down, Keycode::Up),
+        ControlScheme::Wasd => (Keycode::A, Keycode::D, Keycode::S, Keycode::W),
+        ControlScheme::Ijkl => (Keycode::J, Keycode::L, Keycode::K, Keycode::I),
+        ControlScheme::MirroredArrows => {
+            (Keycode::Right, Keycode::Left, Keycode::Down, Keycode::Up)
+        }
+    }
+}
+
+// 今の割り当てでその方向になるキー（ゲームパッドの方向をキーに読み替えるのに使う）
+fn direction_keycode(scheme: ControlScheme, command: Command) -> Option<Keycode> {
+    let (left, right, down, up) = direction_keys(scheme);
+    match command {
+        Command::Left => Some(left),
+        Command::Right => Some(right),
+        Command::Down => Some(down),
+        Command::Up => Some(up),
+        _ => None,
+    }
+}
+
+fn direction_command(scheme: ControlScheme, code: Keycode) -> Option<Command> {
+    let (left, right, down, up) = direction_keys(scheme);
+    if code == left {
+        Some(Command::Left)
+    } else if code == right {
+        Some(Command::Right)
+    } else if code == down {
+        Some(Command::Down)
+    } else if code == up {
+        Some(Command::Up)
+    } else {
+        None
+    }
+}
+
+// モデルには持たせない、描画やUIのための状態
+struct UiState {
+    scene: Scene,
+    frame_times: VecDeque<Duration>, // 直近フレームの処理時間（スリープを除く）
+    mouse_x: i32,
+    mouse_y: i32,
+    recoil_frames: i32, // のけぞりアニメーションの残りフレーム数
+    recoil_direction: Direction,
+    intro_frames: i32,   // 開始演出の残りフレーム数。0になるまで入力を受け付けない
+    camera_spring: bool, // カメラに慣性をつけ、大きく落ちたときに少し行き過ぎてから戻るようにする
+    camera_pos: f32,     // 慣性つきカメラの位置（px）
+    camera_velocity: f32,
+    camera_deadzone: i32, // プレイヤーが画面の中心からこの行数までずれてもカメラを動かさない（0なら常に追う）
+    camera_row: i32,      // 不感帯を考えたカメラの目標位置（行）
+    camera_look: f32, // 動きの先を見せるためのカメラのずらし（行）。camera_look_targetへ少しずつ近づける
+    show_air_number: bool, // エアゲージの下にエアの残量を数字でも表示する
+    game_over_cursor: usize, // ゲームオーバーメニューで選んでいる項目（GameOverOption::ALLの添字）
+    tutorial: Option<TutorialStep>, // チュートリアル中なら今の段階
+    show_grounded: bool, // 接地していないセルを赤く塗って、接地判定の結果を見えるようにする
+    show_planner: bool, // 落ちているブロックが着地したときに消えるかたまりを、着地する位置に表示する
+    show_landing: bool, // 落ちてきたブロックが着地したセルを少しの間光らせ、土煙を出す
+    show_air_path: bool, // 一番少ないエアでたどり着けるエアカプセルまでの道のりを点線で表示する
+    show_dig_queue: bool, // 情報パネルにプレイヤーの真下のセルを上から順に並べる
+    show_minimap: bool, // 情報パネルにプレイヤーより下の行の縮図を出す
+    controls: ControlScheme, // 方向キーの割り当て（タイトル画面に表示する）
+    hud: Vec<HudElement>, // 情報パネルに上から並べる要素
+    high_scores: Vec<ScoreEntry>, // 保存してある記録（深い順）
+    toasts: VecDeque<&'static str>, // まだ表示し終わっていない、解除した実績の文（先頭を表示中）
+    toast_frames: i32,  // 先頭のお知らせを表示してからのフレーム数
+    new_best: bool,     // 今クリアしたステージで最高評価を更新したか（クリア画面に表示する）
+    landing_frames: Vec<i32>, // セルごとの着地演出の残りフレーム数（cell_indexの順）
+    // 掘って消えたブロックの演出（セル、色、残りフレーム数）。モデルではもう何もないセルになっている
+    vanishing: Vec<(Point, BlockColor, i32)>,
+    score_popups: Vec<(Point, u32, i32)>, // かたまりを消してもらった点の表示（セル、点、残りフレーム数）
+    game_over_frames: i32,                // ゲームオーバーになってからのフレーム数
+    game_over_color: Color, // ゲームオーバーでプレイフィールドにかける色（アルファは最終的な不透明度）
+    palette: Palette,       // セルを描く色（palette.tomlで置き換えられる）
+    game_over_fade_frames: i32, // game_over_colorの不透明度になるまでのフレーム数
+    focus_paused: bool,     // ウィンドウがフォーカスを失ったので一時停止している
+    dig_mode: DigMode,      // 何回も掘るブロック（既定では茶色）の掘り方
+    hold_dig_frames: i32, // 長押しで掘るとき、何回も掘るブロックに向けて押したままにしているフレーム数
+    auto_repeat: bool,    // 方向キーを押したままなら、同じ方向をくり返し出す
+    repeat_command: Command, // くり返している方向（押したままの方向キーのうち最後に押したもの）
+    repeat_frames: i32, // repeat_commandを押したままにしているフレーム数（くり返し始めたあとは間隔を数える）
+    repeat_blocked: bool, // くり返した方向に進めも掘れもしなかったので、押し直すまでくり返さない
+    history: VecDeque<Game>, // 直近DEATH_CAM_FRAMESフレームのシミュレーション状態（古い順）
+    death_cam: Option<usize>, // ゲームオーバーの直前をふり返っている間は、始めてからのフレーム数
+    // 描画の補間用に覚えておく、直前のシミュレーション状態
+    prev_player_pos: Option<(i32, i32)>, // プレイヤーのワールド座標（px）
+    prev_fall_offsets: Vec<i32>,         // セルごとの落下中のずれ（px、cell_indexの順）
+}
+
+impl UiState {
+    fn new() -> Self {
+        UiState {
+            scene: Scene::Playing,
+            frame_times: VecDeque::with_capacity(FRAME_TIME_SAMPLES),
+            mouse_x: -1,
+            mouse_y: -1,
+            recoil_frames: 0,
+            recoil_direction: Direction::Left,
+            intro_frames: INTRO_FRAMES,
+            camera_spring: false,
+            camera_pos: 0.0,
+            camera_velocity: 0.0,
+            camera_deadzone: 0,
+            camera_row: 0,
+            camera_look: CAMERA_LOOK_STANDING,
+            show_air_number: false,
+            game_over_cursor: 0,
+            tutorial: None,
+            show_grounded: false,
+            show_planner: false,
+            show_landing: true,
+            show_air_path: false,
+            show_dig_queue: false,
+            show_minimap: false,
+            controls: ControlScheme::Arrows,
+            hud: HudElement::ALL.to_vec(),
+            high_scores: Vec::new(),
+            toasts: VecDeque::new(),
+            toast_frames: 0,
+            new_best: false,
+            landing_frames: Vec::new(),
+            vanishing: Vec::new(),
+            score_popups: Vec::new(),
+            game_over_frames: 0,
+            game_over_color: Color::RGBA(255, 0, 0, 128),
+            palette: Palette::default(),
+            game_over_fade_frames: 0,
+            focus_paused: false,
+            dig_mode: DigMode::Instant,
+            hold_dig_frames: 0,
+            auto_repeat: false,
+            repeat_command: Command::None,
+            repeat_frames: 0,
+            repeat_blocked: false,
+            history: VecDeque::with_capacity(DEATH_CAM_FRAMES),
+            death_cam: None,
+            prev_player_pos: None,
+            prev_fall_offsets: Vec::new(),
+        }
+    }
+
+    // シミュレーションを1フレーム進める直前に呼び、補間の始点となる状態を覚えておく
+    fn snapshot_for_interpolation(&mut self, game: &Game) {
+        self.prev_player_pos = Some(player_world_pos(game));
+        self.prev_fall_offsets.resize(cell_count(game), 0);
+        for y in CELLS_Y_MIN..=game.config.cells_y_max() {
+            for x in CELLS_X_MIN..=game.config.cells_x_max() {
+                let offset = fall_offset_y(game, game.cell(Point::new(x, y)));
+                self.prev_fall_offsets[cell_index(game, x, y)] = offset;
+            }
+        }
+    }
+
+    // 直前の状態から現在の状態までalphaの割合だけ進めたプレイヤーの位置
+    // 1マスより大きく動いていたら（ステージの切り替えなど）補間せずに現在の位置にする
+    fn interpolated_player_pos(&self, game: &Game, alpha: f32) -> (i32, i32) {
+        let (x, y) = player_world_pos(game);
+        match self.prev_player_pos {
+            Some((px, py)) if (x - px).abs() <= CELL_SIZE && (y - py).abs() <= CELL_SIZE => {
+                (lerp(px, x, alpha), lerp(py, y, alpha))
+            }
+            _ => (x, y),
+        }
+    }
+
+    // 直前の状態から現在の状態までalphaの割合だけ進めた、落下中のセルのずれ
+    // ずれが減っていたら（1マス下に移った、または接地した）補間せずに現在の値にする
+    fn interpolated_fall_offset(
+        &self,
+        game: &Game,
+        x: i32,
+        y: i32,
+        current: i32,
+        alpha: f32,
+    ) -> i32 {
+        if !(CELLS_Y_MIN..=game.config.cells_y_max()).contains(&y) {
+            return current;
+        }
+        let Some(&prev) = self.prev_fall_offsets.get(cell_index(game, x, y)) else {
+            return current;
+        };
+        if prev > current {
+            return current;
+        }
+        lerp(prev, current, alpha)
+    }
+
+    // シミュレーションを1フレーム進めた直後に呼び、着地演出を進める
+    // このフレームに落下して接地したブロックは、今着地したところなので演出を始める
+    fn update_landing(&mut self, game: &Game) {
+        self.landing_frames.resize(cell_count(game), 0);
+        for y in CELLS_Y_MIN..=game.config.cells_y_max() {
+            for x in CELLS_X_MIN..=game.config.cells_x_max() {
+                let i = cell_index(game, x, y);
+                let cell = game.cell(Point::new(x, y));
+                if cell.cell_type == CellType::Block && cell.fell && cell.grounded {
+                    self.landing_frames[i] = LANDING_FRAMES;
+                } else if self.landing_frames[i] > 0 {
+                    self.landing_frames[i] -= 1;
+                }
+            }
+        }
+    }
+
+    // 着地演出の残りフレーム数。世界の外の行は0
+    fn landing_frames_at(&self, game: &Game, x: i32, y: i32) -> i32 {
+        if !(CELLS_Y_MIN..=game.config.cells_y_max()).contains(&y) {
+            return 0;
+        }
+        self.landing_frames
+            .get(cell_index(game, x, y))
+            .copied()
+            .unwrap_or(0)
+    }
+
+    // シミュレーションを1フレーム進めた直後に呼び、ふり返り用に状態を覚えておく
+    fn record_history(&mut self, game: &Game) {
+        if self.history.len() == DEATH_CAM_FRAMES {
+            self.history.pop_front();
+        }
+        self.history.push_back(game.clone());
+    }
+
+    // ふり返りで今見せる状態。ふり返っていなければNone
+    fn death_cam_game(&self) -> Option<&Game> {
+        self.death_cam
+            .and_then(|frames| self.history.get(frames / DEATH_CAM_SLOWDOWN))
+    }
+
+    // ふり返りを1フレーム進める。覚えていた状態を見せ終わったらやめる
+    fn advance_death_cam(&mut self) {
+        if let Some(frames) = self.death_cam {
+            let frames = frames + 1;
+            self.death_cam = if frames / DEATH_CAM_SLOWDOWN < self.history.len() {
+                Some(frames)
+            } else {
+                None
+            };
+        }
+    }
+
+    // ゲームオーバーになってからのフレーム数を数える。遊んでいる間とふり返っている間は0に戻す
+    fn update_game_over(&mut self, game: &Game) {
+        if game.is_over && self.death_cam.is_none() {
+            self.game_over_frames += 1;
+        } else {
+            self.game_over_frames = 0;
+        }
+    }
+
+    // 今のフレームでプレイフィールドにかける色。不透明度をフェードの進み具合に合わせる
+    fn game_over_overlay(&self) -> Color {
+        let max = self.game_over_color.a as i32;
+        let a = if self.game_over_fade_frames <= 0 {
+            max
+        } else {
+            max * self.game_over_frames.min(self.game_over_fade_frames) / self.game_over_fade_frames
+        };
+        Color::RGBA(
+            self.game_over_color.r,
+            self.game_over_color.g,
+            self.game_over_color.b,
+            a as u8,
+        )
+    }
+
+    // ステージを始めるときの状態にする
+    fn start_stage(&mut self, game: &Game) {
+        self.scene = Scene::Playing;
+        self.landing_frames.fill(0);
+        self.vanishing.clear();
+        self.score_popups.clear();
+        self.history.clear();
+        self.death_cam = None;
+        self.camera_look = CAMERA_LOOK_STANDING;
+        // カメラが地表から動かないなら、開始演出は入力を待たせるだけなのでしない
+        self.intro_frames = if self.intro_target_px(game) > 0 {
+            INTRO_FRAMES
+        } else {
+            0
+        };
+        self.game_over_cursor = 0;
+    }
+
+    // 長押しで掘るとき、何回も掘るブロックに向けて方向キーを押したままならHOLD_DIG_FRAMESごとにその方向を出す
+    // 新しく押したキーはそのまま出す（押した瞬間にも1回削る）。1回に削る量は押したときと同じ
+    fn hold_dig_command(&mut self, command: Command, input: &InputState, game: &Game) -> Command {
+        let held = input.held_direction().filter(|&held| {
+            self.dig_mode == DigMode::Hold
+                && command == Command::None
+                && game.is_tough_block_toward(Direction::from_command(held))
+        });
+        let Some(held) = held else {
+            self.hold_dig_frames = 0;
+            return command;
+        };
+        self.hold_dig_frames += 1;
+        if self.hold_dig_frames < HOLD_DIG_FRAMES {
+            return Command::None;
+        }
+        self.hold_dig_frames = 0;
+        held
+    }
+
+    // 方向キーを押したままなら、AUTO_REPEAT_DELAYフレームたってからAUTO_REPEAT_FRAMESごとにその方向を出す
+    // 押したままのキーが複数なら最後に押したもの。全部離していればcommandのまま（ふつうはNone）
+    // 出すのはプレイヤーが立っているときだけ（歩いている・落ちている途中に出しても入力が無視されるだけなので）
+    // 何回も掘るブロックに向けてはくり返さない（押すたびに1回削るか、dig_modeがholdならhold_dig_commandが削る）
+    fn auto_repeat_command(
+        &mut self,
+        command: Command,
+        input: &InputState,
+        game: &Game,
+    ) -> Command {
+        let held = input.held_direction().filter(|&held| {
+            self.auto_repeat && !game.is_tough_block_toward(Direction::from_command(held))
+        });
+        let Some(held) = held else {
+            self.repeat_command = Command::None;
+            self.repeat_frames = 0;
+            return command;
+        };
+        // 新しく押したときや、押したままの方向が変わったときは数え直す
+        if command != Command::None || held != self.repeat_command {
+            self.repeat_command = held;
+            self.repeat_frames = 0;
+            self.repeat_blocked = false;
+            return command;
+        }
+        self.repeat_frames += 1;
+        if self.repeat_blocked
+            || self.repeat_frames < AUTO_REPEAT_DELAY
+            || game.player.state != PlayerState::Standing
+        {
+            return Command::None;
+        }
+        self.repeat_frames = AUTO_REPEAT_DELAY - AUTO_REPEAT_FRAMES;
+        held
+    }
+
+    // ゲームオーバーメニューのカーソルを動かす（端では止まる）
+    fn move_game_over_cursor(&mut self, delta: i32) {
+        let last = GameOverOption::ALL.len() as i32 - 1;
+        self.game_over_cursor = clamp(0, self.game_over_cursor as i32 + delta, last) as usize;
+    }
+
+    fn record_frame_time(&mut self, elapsed: Duration) {
+        self.frame_times.push_back(elapsed);
+        while self.frame_times.len() > FRAME_TIME_SAMPLES {
+            self.frame_times.pop_front();
+        }
+    }
+
+    // モデルから届いた出来事を演出に反映する
+    fn handle_events(&mut self, game: &mut Game) {
+        if self.recoil_frames > 0 {
+            self.recoil_frames -= 1;
+        }
+        for v in self.vanishing.iter_mut() {
+            v.2 -= 1;
+        }
+        self.vanishing.retain(|&(_, _, frames)| frames > 0);
+        for popup in self.score_popups.iter_mut() {
+            popup.2 -= 1;
+        }
+        self.score_popups.retain(|&(_, _, frames)| frames > 0);
+        for event in game.events.drain(..) {
+            if let Some(step) = self.tutorial {
+                self.tutorial = Some(step.advance(event));
+            }
+            match event {
+                GameEvent::Blocked(direction) => {
+                    if self.repeat_command != Command::None
+                        && Direction::from_command(self.repeat_command) == direction
+                    {
+                        self.repeat_blocked = true;
+                    }
+                    self.recoil_frames = RECOIL_FRAMES;
+                    self.recoil_direction = direction;
+                }
+                GameEvent::Vanished(p, color) => self.vanishing.push((p, color, VANISH_FRAMES)),
+                GameEvent::Scored(p, points) => {
+                    self.score_popups.push((p, points, SCORE_POPUP_FRAMES))
+                }
+                GameEvent::Dug(_) | GameEvent::AirCollected | GameEvent::Erased(_) => {}
+            }
+        }
+        if self.tutorial == Some(TutorialStep::DigClear) && game.is_clear {
+            self.tutorial = Some(TutorialStep::Done);
+        }
+    }
+
+    // お知らせを1つずつ順に表示する。表示し終わったら次へ
+    fn update_toasts(&mut self) {
+        if self.toasts.is_empty() {
+            return;
+        }
+        self.toast_frames += 1;
+        if self.toast_frames >= TOAST_FRAMES {
+            self.toasts.pop_front();
+            self.toast_frames = 0;
+        }
+    }
+
+    // のけぞりによるプレイヤーの描画位置のずれ（押した方向にぶつかって少し戻る）
+    fn recoil_offset(&self) -> (i32, i32) {
+        if self.recoil_frames <= 0 {
+            return (0, 0);
+        }
+        let d = RECOIL_PX * self.recoil_frames / RECOIL_FRAMES;
+        match self.recoil_direction {
+            Direction::Left => (-d, 0),
+            Direction::Right => (d, 0),
+            Direction::Up => (0, -d),
+            Direction::Down => (0, d),
+        }
+    }
+
+    // 画面上の座標(sx, sy)にあるセル。情報パネルの上や世界の外ならNone
+    fn screen_to_cell(&self, game: &Game, sx: i32, sy: i32) -> Option<Point> {
+        if !(0..info_x(&game.config)).contains(&sx) || !(0..SCREEN_HEIGHT).contains(&sy) {
+            return None;
+        }
+        let cell_y = (sy + self.camera_px(game)).div_euclid(CELL_SIZE);
+        if !(CELLS_Y_MIN..=game.config.cells_y_max()).contains(&cell_y)
+            || sx / CELL_SIZE > game.config.cells_x_max()
+        {
+            return None;
+        }
+        Some(Point::new(sx / CELL_SIZE, cell_y))
+    }
+
+    // セルの左上の画面上の座標（画面の外になることもある）
+    fn cell_to_screen(&self, game: &Game, p: Point) -> (i32, i32) {
+        (CELL_SIZE * p.x, CELL_SIZE * p.y - self.camera_px(game))
+    }
+
+    // 描画に使うカメラ位置（px）。動きの先を見せるずらしを足し、盤面より上は映さない
+    // 開始演出中は地表（y=0）からモデルのカメラ位置までなめらかに移動する
+    // 慣性が有効なら、行き過ぎて戻る途中の位置になる
+    fn camera_px(&self, game: &Game) -> i32 {
+        let look = (self.camera_look * CELL_SIZE as f32).round() as i32;
+        if self.intro_frames > 0 {
+            let target = self.intro_target_px(game);
+            let t = 1.0 - self.intro_frames as f32 / INTRO_FRAMES as f32;
+            let eased = t * t * (3.0 - 2.0 * t);
+            return (target as f32 * eased) as i32;
+        }
+        let follow = if self.camera_spring {
+            self.camera_pos.round() as i32
+        } else {
+            self.camera_row * CELL_SIZE
+        };
+        (follow + look).max(0)
+    }
+
+    // 開始演出でカメラが地表から移っていく先（px）
+    fn intro_target_px(&self, game: &Game) -> i32 {
+        let look = (self.camera_look * CELL_SIZE as f32).round() as i32;
+        (game.camera_y * CELL_SIZE + look).max(0)
+    }
+
+    // カメラの目標位置を不感帯の外に出た分だけ動かし、慣性つきカメラをそこに向けて1フレーム分動かす
+    // 開始演出中はモデルのカメラ位置（プレイヤーが中心に来る位置）にそろえておく
+    fn update_camera(&mut self, game: &Game) {
+        self.camera_look += (camera_look_target(game) - self.camera_look) * CAMERA_LOOK_EASE;
+        self.camera_row = if self.intro_frames > 0 {
+            game.camera_y
+        } else {
+            camera_target(self.camera_row, game.camera_y, self.camera_deadzone)
+        };
+        if !self.camera_spring || self.intro_frames > 0 {
+            // 慣性を切り替えたときに位置が飛ばないよう、追いかけている位置に合わせておく
+            self.camera_pos = (self.camera_row * CELL_SIZE) as f32;
+            self.camera_velocity = 0.0;
+            return;
+        }
+        let target = (self.camera_row * CELL_SIZE) as f32;
+        self.camera_velocity = self.camera_velocity * (1.0 - CAMERA_DAMPING)
+            + (target - self.camera_pos) * CAMERA_STIFFNESS;
+        self.camera_pos += self.camera_velocity;
+        // 十分近づいたら目標にぴったり合わせ、ずれが残らないようにする
+        if (target - self.camera_pos).abs() < 0.5 && self.camera_velocity.abs() < 0.5 {
+            self.camera_pos = target;
+            self.camera_velocity = 0.0;
+        }
+    }
+
+    // 1フレームの平均処理時間（ミリ秒）
+    fn average_frame_ms(&self) -> f32 {
+        if self.frame_times.is_empty() {
+            return 0.0;
+        }
+        let total: f32 = self
+            .frame_times
+            .iter()
+            .map(|d| d.as_secs_f32() * 1000.0)
+            .sum();
+        total / self.frame_times.len() as f32
+    }
+
+    // 実測FPS（処理が間に合っていればフレーム間隔までスリープするので、その分も含める）
+    fn measured_fps(&self) -> f32 {
+        if self.frame_times.is_empty() {
+            return 0.0;
+        }
+        let frame_ms = 1000.0 / FPS as f32;
+        let total: f32 = self
+            .frame_times
+            .iter()
+            .map(|d| (d.as_secs_f32() * 1000.0).max(frame_ms))
+            .sum();
+        1000.0 / (total / self.frame_times.len() as f32)
+    }
 }
 
 pub fn main() -> Result<(), String> {
+    // --log-levelがなければ、リプレイの検証やシミュレーションでは結果以外を出さないように警告だけにする
+    let verify_path = arg_value("--verify-replay")?;
+    let simulate_frames = arg_value("--simulate")?;
+    let log_level = match arg_value("--log-level")? {
+        Some(name) => log::Level::from_name(&name).ok_or_else(|| {
+            format!(
+                "--log-level must be one of off, warn, info, debug: {}",
+                name
+            )
+        })?,
+        None if verify_path.is_some() || simulate_frames.is_some() => log::Level::Warn,
+        None => log::Level::Info,
+    };
+    log::set_level(log_level);
+
+    // リプレイの検証とシミュレーションはSDLを使わずに行う
+    if let Some(path) = verify_path {
+        return verify_replay(Path::new(&path));
+    }
+    if let Some(frames) = simulate_frames {
+        let frames = frames
+            .parse()
+            .map_err(|_| format!("--simulate must be a number of frames: {}", frames))?;
+        return simulate(frames);
+    }
+
+    let resources_dir = resources_dir()?;
+
     let sdl_context = sdl2::init()?;
 
     let video_subsystem = sdl_context.video()?;
     let window = video_subsystem
-        .window("rust-driller", SCREEN_WIDTH as u32, SCREEN_HEIGHT as u32)
+        .window(
+            "rust-driller",
+            screen_width(&GameConfig::default()) as u32,
+            SCREEN_HEIGHT as u32,
+        )
         .position_centered()
         .opengl()
         .build()
@@ -57,7 +857,13 @@ pub fn main() -> Result<(), String> {
 
     sdl_context.mouse().show_cursor(false);
 
-    init_mixer();
+    let audio_enabled = match init_mixer() {
+        Ok(()) => true,
+        Err(e) => {
+            log_warn!("audio disabled: {}", e);
+            false
+        }
+    };
 
     let ttf_context = sdl2::ttf::init().map_err(|e| e.to_string())?;
 
@@ -65,96 +871,658 @@ pub fn main() -> Result<(), String> {
     canvas.set_blend_mode(BlendMode::Blend);
 
     let texture_creator = canvas.texture_creator();
-    let mut resources = load_resources(&texture_creator, &mut canvas, &ttf_context);
+    let mut resources = load_resources(
+        &texture_creator,
+        &mut canvas,
+        &ttf_context,
+        &resources_dir,
+        audio_enabled,
+    );
 
     let mut event_pump = sdl_context.event_pump()?;
+    let mut gamepad = Gamepad::new(&sdl_context);
 
-    let mut game = Game::new();
+    let mut settings = Settings::load(Path::new(SETTINGS_PATH));
+    let mut config = GameConfig::with_color_count(settings.color_count);
+    config.dig_air_cost = settings.dig_air_cost;
+    config.walk_air_cost = settings.walk_air_cost;
+    config.air_brown_clearance = settings.air_brown_clearance.max(0);
+    config.air_band_rows = settings.air_band_rows.max(0);
+    config.air_band_min = settings.air_band_min.max(0);
+    config.air_carry = settings.air_carry;
+    config.shake_warning = settings.shake_warning;
+    config.par_seconds = settings.par_seconds;
+    config.surface_return = settings.surface_return;
+    config.block_push = settings.block_push;
+    config.dig_assist = settings.dig_assist;
+    config.rigid_fall = settings.rigid_fall;
+    config.clear_requires_key = settings.clear_requires_key;
+    config.last_breath = settings.last_breath;
+    config.grounding_through_air = settings.grounding_through_air;
+    config.fall_pace = settings.fall_pace.clone();
+    config.width = settings.width;
+    config.up_space_height = settings.up_space_height;
+    config.normal_blocks_height = settings.normal_blocks_height;
+    config.clear_blocks_height = settings.clear_blocks_height;
+    config.wrap_horizontal = settings.wrap_horizontal;
+    config.enemies = settings.enemies;
+    config.dig_hits = settings.dig_hits.clone();
+    config.sandbox = has_flag("--sandbox");
+    let mut ui = UiState::new();
+    let [r, g, b] = settings.game_over_color;
+    ui.game_over_color = Color::RGBA(r, g, b, settings.game_over_alpha);
+    ui.palette = Palette::load(Path::new(PALETTE_PATH));
+    ui.game_over_fade_frames = settings.game_over_fade_frames;
+    ui.controls = settings.controls;
+    ui.camera_deadzone = settings.camera_deadzone.max(0);
+    ui.hud = settings.hud.clone();
+    ui.high_scores = load_high_scores();
+    ui.dig_mode = settings.dig_mode;
+    ui.auto_repeat = settings.auto_repeat;
+    ui.camera_spring = settings.camera_spring;
+    ui.show_air_number = settings.show_air_number;
+    ui.show_planner = settings.show_planner;
+    ui.show_landing = settings.show_landing;
+    ui.show_air_path = settings.show_air_path;
+    ui.show_dig_queue = settings.show_dig_queue;
+    ui.show_grounded = settings.show_grounded;
+    ui.show_minimap = settings.show_minimap;
+    let mut script = match arg_value("--script")? {
+        Some(path) => Some(Script::load(Path::new(&path))?),
+        None => None,
+    };
+    // 初めて遊ぶときはチュートリアルから始める（スクリプトを動かすときとサンドボックスは除く）
+    let mut game = if let Some(seed) = script.as_ref().and_then(|s| s.seed) {
+        Game::from_seed(seed, config.clone())
+    } else if settings.tutorial_done || script.is_some() || config.sandbox {
+        Game::new_with_config(config.clone())
+    } else {
+        ui.tutorial = Some(TutorialStep::DigDown);
+        tutorial_game(&settings)
+    };
+    ui.start_stage(&game);
+    let mut input = InputState::new(settings.controls);
+    let mut achievements = Achievements::load(Path::new(ACHIEVEMENTS_PATH));
+    let mut ratings = Ratings::load(Path::new(RATINGS_PATH));
+    // 次に描画した画面を保存する先。--screenshotなら最初の画面を保存して終了する
+    let mut screenshot = arg_value("--screenshot")?.map(PathBuf::from);
+    let screenshot_and_quit = screenshot.is_some();
+    // 掘ったブロック数の色ごとの合計（終わったステージの分）。--dig-statsなら終了時にCSVに書き出す
+    let dig_stats_path = arg_value("--dig-stats")?.map(PathBuf::from);
+    let mut dug_totals = [0; BLOCK_COLORS];
+    let mut replay: Option<Replay> = None; // 遊んでいるステージのリプレイ（記録中でなければNone）
 
-    println!("Keys:");
-    println!("    Left  : Move player or dig left");
-    println!("    Right : Move player or dig right");
-    println!("    Down  : Dig down");
-    println!("    Up    : Dig up");
-    println!("    Space : Restart when game over");
+    log_info!("Keys:");
+    log_info!("    Left  : Move player or dig left");
+    log_info!("    Right : Move player or dig right");
+    log_info!("    Down  : Dig down (or let go of the wall in surface return mode); hold while falling to fall 1.5 times as fast");
+    log_info!("    Up    : Dig up (or climb in surface return mode)");
+    log_info!("    Space : Start, skip the replay or choose in the game over menu, or collapse the column below (when charged)");
+    log_info!("    F2    : Toggle camera momentum");
+    log_info!("    F3    : Toggle air readout");
+    log_info!("    F4    : Toggle grounded overlay");
+    log_info!("    F5    : Toggle erase planner");
+    log_info!("    F6    : Toggle landing effect");
+    log_info!("    F7    : Toggle path to the nearest air");
+    log_info!("    F8    : Switch controls (arrows, WASD, IJKL, mirrored arrows)");
+    log_info!("    F9    : Toggle dig queue");
+    log_info!("    F10   : Toggle minimap");
+    log_info!("    F11   : Save the game (Shift+F11 to load it)");
+    log_info!("    F12   : Save a screenshot");
+    log_info!("    Tab   : Skip the tutorial");
+    if config.sandbox {
+        log_info!("    R     : Refill the blocks above the player");
+    }
 
+    let mut last_tick = Instant::now();
+    let mut lag = LOGIC_STEP; // まだゲームを進めるのに使っていない実時間（最初のフレームですぐに1回進める）
+    let mut is_keydown = false; // 前のステップのあとでキーが押されたか
     'running: loop {
         let started = SystemTime::now();
-        let mut is_keydown = false;
+        let was_in_intro = ui.intro_frames > 0;
 
-        let mut command = Command::None;
-        for event in event_pump.poll_iter() {
+        // ゲームパッドの入力はキーボードのイベントに読み替えてから、キーボードと同じように扱う
+        let events: Vec<Event> = event_pump
+            .poll_iter()
+            .flat_map(|event| gamepad.translate(event, settings.controls))
+            .collect();
+        for event in events {
             match event {
                 Event::Quit { .. } => break 'running,
+                Event::Window {
+                    win_event: WindowEvent::FocusLost,
+                    ..
+                } => {
+                    input.release_all();
+                    // 席を外している間にエアが尽きないように止める
+                    if settings.pause_on_focus_loss {
+                        ui.focus_paused = true;
+                    }
+                }
+                Event::Window {
+                    win_event: WindowEvent::FocusGained,
+                    ..
+                } => ui.focus_paused = false,
+                Event::KeyUp {
+                    keycode: Some(code),
+                    ..
+                } => input.key_up(code),
                 Event::KeyDown {
                     keycode: Some(code),
+                    keymod,
                     ..
                 } => {
+                    // デバッグ中のBackspaceは1フレーム巻き戻す（押したままならリピートで続けて戻す）
+                    // コマ送りのキーとして扱うとすぐに1フレーム進んでしまうので、ここで済ませる
+                    if code == Keycode::Backspace && game.is_debug && ui.scene == Scene::Playing {
+                        if game.undo() {
+                            log_debug!("undo to frame {}", game.frame);
+                        } else {
+                            log_debug!("nothing to undo");
+                        }
+                        ui.death_cam = None;
+                        // 巻き戻したあとの操作は記録と合わなくなるので、このステージのリプレイは残さない
+                        replay = None;
+                        continue;
+                    }
+                    // キーリピートはデバッグ中のコマ送りと開始演出の飛ばしにだけ使う
+                    is_keydown = true;
+                    if !input.key_down(code) {
+                        continue;
+                    }
                     match code {
-                        Keycode::Left => command = Command::Left,
-                        Keycode::Right => command = Command::Right,
-                        Keycode::Down => command = Command::Down,
-                        Keycode::Up => command = Command::Up,
+                        Keycode::Space if ui.death_cam.is_some() => ui.death_cam = None,
+                        code if ui.death_cam.is_some() && input.command_of(code).is_some() => {}
+                        code if game.is_over && input.command_of(code) == Some(Command::Down) => {
+                            ui.move_game_over_cursor(1)
+                        }
+                        code if game.is_over && input.command_of(code) == Some(Command::Up) => {
+                            ui.move_game_over_cursor(-1)
+                        }
                         Keycode::Escape => {
                             break 'running;
                         }
-                        Keycode::F1 => game.toggle_debug(),
+                        Keycode::F1 => {
+                            game.toggle_debug();
+                        }
+                        Keycode::F2 => ui.camera_spring = !ui.camera_spring,
+                        Keycode::F3 => ui.show_air_number = !ui.show_air_number,
+                        Keycode::F4 => ui.show_grounded = !ui.show_grounded,
+                        Keycode::F5 => ui.show_planner = !ui.show_planner,
+                        Keycode::F6 => ui.show_landing = !ui.show_landing,
+                        Keycode::F7 => ui.show_air_path = !ui.show_air_path,
+                        Keycode::F9 => ui.show_dig_queue = !ui.show_dig_queue,
+                        Keycode::F10 => ui.show_minimap = !ui.show_minimap,
+                        Keycode::F8 => {
+                            settings.controls = settings.controls.next();
+                            input.set_scheme(settings.controls);
+                            ui.controls = settings.controls;
+                            log_info!("controls: {}", settings.controls.name());
+                            if let Err(e) = settings.save(Path::new(SETTINGS_PATH)) {
+                                log_warn!("cannot save settings: {}", e);
+                            }
+                        }
+                        Keycode::F12 => screenshot = Some(screenshot_path()),
+                        // Shift+F11で保存したゲームを読み込み、続きから遊ぶ（タイトル画面からでもよい）
+                        Keycode::F11
+                            if keymod.intersects(Mod::LSHIFTMOD | Mod::RSHIFTMOD)
+                                && ui.tutorial.is_none()
+                                && script.is_none() =>
+                        {
+                            match Game::load_state(Path::new(SAVE_PATH)) {
+                                Ok(loaded) => {
+                                    game = loaded;
+                                    ui.start_stage(&game);
+                                    ui.intro_frames = 0;
+                                    // 途中から始めたステージは最初からの操作の記録にならないので、リプレイは残さない
+                                    replay = None;
+                                    log_info!("loaded {}", SAVE_PATH);
+                                }
+                                Err(e) => log_warn!("cannot load game: {}", e),
+                            }
+                        }
+                        // F11で遊んでいる途中のゲームを保存する
+                        Keycode::F11
+                            if ui.scene == Scene::Playing
+                                && !game.is_over
+                                && !game.is_clear
+                                && ui.tutorial.is_none()
+                                && script.is_none() =>
+                        {
+                            match game.save_state(Path::new(SAVE_PATH)) {
+                                Ok(()) => {
+                                    log_info!("saved {}", SAVE_PATH);
+                                }
+                                Err(e) => log_warn!("cannot save game: {}", e),
+                            }
+                        }
+                        Keycode::P
+                            if ui.scene == Scene::Playing && !game.is_over && !game.is_clear =>
+                        {
+                            game.toggle_pause()
+                        }
+                        Keycode::R if game.config.sandbox && ui.scene == Scene::Playing => {
+                            input.press(Command::Refill)
+                        }
+                        Keycode::Tab if ui.tutorial.is_some() => {
+                            game = Game::new_with_config(config.clone());
+                            ui.start_stage(&game);
+                            finish_tutorial(&mut ui, &mut settings);
+                        }
                         Keycode::Space => {
-                            if game.is_over {
-                                game = Game::new();
+                            if ui.scene == Scene::Title {
+                                game = Game::new_with_config(config.clone());
+                                ui.start_stage(&game);
+                            } else if game.is_over {
+                                match GameOverOption::ALL[ui.game_over_cursor] {
+                                    GameOverOption::Retry => {
+                                        game = if ui.tutorial.is_some() {
+                                            tutorial_game(&settings)
+                                        } else {
+                                            Game::from_seed_at_stage(
+                                                game.seed,
+                                                game.config.clone(),
+                                                game.stage,
+                                            )
+                                        };
+                                        ui.start_stage(&game);
+                                    }
+                                    GameOverOption::NewGame => {
+                                        game = Game::new_with_config(config.clone());
+                                        ui.start_stage(&game);
+                                        finish_tutorial(&mut ui, &mut settings);
+                                    }
+                                    GameOverOption::Title => {
+                                        ui.scene = Scene::Title;
+                                        finish_tutorial(&mut ui, &mut settings);
+                                    }
+                                }
                             } else if game.is_clear {
                                 game = game.next_stage();
+                                ui.start_stage(&game);
+                            } else {
+                                input.press(Command::Collapse);
                             }
                         }
                         _ => {}
                     };
-                    is_keydown = true;
                 }
                 _ => {}
             }
         }
-        if !game.is_debug || is_keydown {
-            game.update(command);
+        // たまった実時間をLOGIC_STEPずつ使ってゲームを進める。描画が遅れても1秒あたりの更新回数は変わらない
+        // （1回も進めないフレームもあれば、遅れを取り戻すために何回か進めるフレームもある）
+        let now = Instant::now();
+        lag += now - last_tick;
+        last_tick = now;
+        let mut steps = 0;
+        while lag >= LOGIC_STEP && steps < MAX_STEPS_PER_FRAME {
+            lag -= LOGIC_STEP;
+            steps += 1;
+        }
+        // 追いつけないほど遅れたら（ウィンドウを動かしていた間など）、残りは捨てて今から数え直す
+        if steps == MAX_STEPS_PER_FRAME {
+            lag = Duration::ZERO;
+        }
+        for _ in 0..steps {
+            let command = input.take_command();
+            ui.snapshot_for_interpolation(&game);
+            if ui.scene == Scene::Title {
+                // タイトル画面ではゲームを進めない
+            } else if ui.focus_paused || game.is_paused {
+                // 一時停止中はゲームを進めない（開始演出も止める）
+                // Pキーの一時停止中もupdateを呼ばないので、リプレイに止まっていた間のフレームは入らない
+            } else if ui.intro_frames > 0 {
+                // 開始演出中は入力を無視し、何かキーが押されたら演出を飛ばす
+                // （このフレームで始まったばかりの演出は、始めたキーでは飛ばさない）
+                if is_keydown && was_in_intro {
+                    ui.intro_frames = 0;
+                } else {
+                    ui.intro_frames -= 1;
+                }
+            } else if ui.death_cam.is_some() {
+                // ふり返っている間はゲームを進めない（終わったらそのままゲームオーバーメニューに移る）
+                ui.advance_death_cam();
+            } else if !game.is_debug || is_keydown {
+                // チュートリアル以外のステージは、最初のupdateから記録する
+                if game.frame < 0 && ui.tutorial.is_none() {
+                    replay = Some(Replay::start(&game));
+                }
+                // スクリプトが動いている間は、プレイヤーの入力の代わりに予定のコマンドを使う
+                let command = match script.as_mut() {
+                    Some(s) => s.command_at(game.frame + 1),
+                    None => {
+                        let command = ui.hold_dig_command(command, &input, &game);
+                        input.with_soft_drop(ui.auto_repeat_command(command, &input, &game))
+                    }
+                };
+                let was_running = !game.is_over && !game.is_clear;
+                game.update(command);
+                ui.update_landing(&game);
+                if was_running {
+                    ui.record_history(&game);
+                }
+                // ゲームオーバーになったら、メニューを出す前に直前の数秒をゆっくり見せる
+                if was_running && game.is_over {
+                    ui.death_cam = Some(0);
+                }
+                // デモやバグの再現、練習モードやデバッグ中に実績を解除しないように、そのときは調べない
+                if counts_for_records(&game, script.is_some()) {
+                    let unlocked = achievements.check(&game);
+                    for achievement in &unlocked {
+                        ui.toasts.push_back(achievement.title);
+                    }
+                    if !unlocked.is_empty() {
+                        if let Err(e) = achievements.save(Path::new(ACHIEVEMENTS_PATH)) {
+                            log_warn!("cannot save achievements: {}", e);
+                        }
+                    }
+                }
+                if was_running && (game.is_over || game.is_clear) {
+                    add_dug_counts(&mut dug_totals, &game);
+                }
+                // チュートリアル、スクリプト、練習モードやデバッグ中の評価は残さない
+                if was_running
+                    && game.is_clear
+                    && ui.tutorial.is_none()
+                    && counts_for_records(&game, script.is_some())
+                {
+                    ui.new_best = ratings.record(game.seed, game.stars());
+                    if ui.new_best {
+                        if let Err(e) = ratings.save(Path::new(RATINGS_PATH)) {
+                            log_warn!("cannot save ratings: {}", e);
+                        }
+                    }
+                }
+                if was_running && game.is_over && ui.tutorial.is_none() && script.is_none() {
+                    if let Err(e) = game.record_score() {
+                        log_warn!("cannot save scores: {}", e);
+                    }
+                    ui.high_scores = load_high_scores();
+                }
+                if let Some(r) = replay.as_mut() {
+                    r.record(command, &game);
+                }
+                // 予定が尽きたらスクリプトを止める
+                if let Some(on_end) = script
+                    .as_ref()
+                    .filter(|s| s.is_finished())
+                    .map(|s| s.on_end)
+                {
+                    script = None;
+                    if on_end == ScriptEnd::Quit {
+                        break 'running;
+                    }
+                }
+                if game.is_over || game.is_clear {
+                    if let Some(mut r) = replay.take() {
+                        r.finish(&game);
+                        if let Err(e) = r.save(Path::new(REPLAY_PATH)) {
+                            log_warn!("failed to save replay: {}", e);
+                        }
+                    }
+                }
+            }
+            ui.handle_events(&mut game);
+            if ui.tutorial == Some(TutorialStep::Done) {
+                finish_tutorial(&mut ui, &mut settings);
+            }
+            let shown = ui.death_cam_game().cloned();
+            ui.update_camera(shown.as_ref().unwrap_or(&game));
+            ui.update_game_over(&game);
+            ui.update_toasts();
+            // 押したキーは、それを使うステップが来るまで覚えておく
+            is_keydown = false;
+        }
+        // ふり返っている間は覚えていた状態を描く（進めているゲームはゲームオーバーのまま）
+        let shown = ui.death_cam_game().cloned();
+        let shown = shown.as_ref().unwrap_or(&game);
+        // デバッグ中はマウスカーソルを出してセルを調べられるようにする
+        sdl_context.mouse().show_cursor(game.is_debug);
+        let mouse_state = event_pump.mouse_state();
+        ui.mouse_x = mouse_state.x();
+        ui.mouse_y = mouse_state.y();
+        // 最後のステップからの経過時間の分だけ、直前の状態から現在の状態へ補間して描く
+        // ふり返り中は覚えていた状態を描くので補間しない
+        let alpha = if ui.death_cam.is_some() {
+            1.0
+        } else {
+            lag.as_secs_f32() / LOGIC_STEP.as_secs_f32()
+        };
+        fit_window_width(&mut canvas, shown)?;
+        render(&mut canvas, shown, &ui, &mut resources, alpha)?;
+        // スクリーンショットはpresentすると読めなくなるので、その前に撮る
+        if let Some(path) = screenshot.take() {
+            match save_screenshot(&canvas, &path) {
+                Ok(()) => log_info!("saved screenshot: {}", path.display()),
+                Err(e) if screenshot_and_quit => return Err(e),
+                Err(e) => log_warn!("failed to save screenshot: {}", e),
+            }
+            if screenshot_and_quit {
+                break 'running;
+            }
         }
-        render(&mut canvas, &game, &mut resources)?;
+        canvas.present();
 
         play_sounds(&mut game, &resources);
 
         let finished = SystemTime::now();
         let elapsed = finished.duration_since(started).unwrap();
-        let frame_duration = Duration::new(0, 1_000_000_000u32 / model::FPS as u32);
-        if elapsed < frame_duration {
-            ::std::thread::sleep(frame_duration - elapsed)
+        ui.record_frame_time(elapsed);
+        // 描画はゲームの更新より細かい間隔で回し、その間の動きは補間で埋める
+        if elapsed < RENDER_STEP {
+            ::std::thread::sleep(RENDER_STEP - elapsed)
+        }
+    }
+
+    // キーで切り替えた表示を保存して、次に起動したときも同じにする（切り替えていなければ何も書かない）
+    // （--screenshotは画面を撮るためだけに起動するので、設定は書き換えない）
+    if !screenshot_and_quit {
+        settings.camera_spring = ui.camera_spring;
+        settings.show_air_number = ui.show_air_number;
+        settings.show_planner = ui.show_planner;
+        settings.show_landing = ui.show_landing;
+        settings.show_air_path = ui.show_air_path;
+        settings.show_dig_queue = ui.show_dig_queue;
+        settings.show_grounded = ui.show_grounded;
+        settings.show_minimap = ui.show_minimap;
+        if let Err(e) = settings.save(Path::new(SETTINGS_PATH)) {
+            log_warn!("cannot save settings: {}", e);
+        }
+    }
+
+    if let Some(path) = dig_stats_path {
+        // 遊んでいる途中でやめたステージの分も数える
+        if game.frame >= 0 && !game.is_over && !game.is_clear {
+            add_dug_counts(&mut dug_totals, &game);
+        }
+        match save_dig_stats(&path, &dug_totals) {
+            Ok(()) => log_info!("saved dig stats: {}", path.display()),
+            Err(e) => log_warn!("failed to save dig stats: {}", e),
         }
     }
 
     Ok(())
 }
 
-fn init_mixer() {
-    let chunk_size = 1_024;
-    mixer::open_audio(
-        mixer::DEFAULT_FREQUENCY,
-        mixer::DEFAULT_FORMAT,
-        mixer::DEFAULT_CHANNELS,
-        chunk_size,
-    )
-    .expect("cannot open audio");
-    let _mixer_context = mixer::init(mixer::InitFlag::MP3).expect("cannot init mixer");
+fn add_dug_counts(totals: &mut [i32; BLOCK_COLORS], game: &Game) {
+    for (color, count) in game.dug_by_color() {
+        totals[color as usize] += count;
+    }
 }
 
-fn load_resources<'a>(
-    texture_creator: &'a TextureCreator<WindowContext>,
+// 掘ったブロック数を「color,dug」のCSVで書き出す
+fn save_dig_stats(path: &Path, totals: &[i32; BLOCK_COLORS]) -> std::io::Result<()> {
+    let mut text = String::from("color,dug\n");
+    for color in BlockColor::ALL {
+        text += &format!("{:?},{}\n", color, totals[color as usize]);
+    }
+    fs::write(path, text)
+}
+
+// リプレイを再生して、記録された結果が再現されるか確かめる
+fn verify_replay(path: &Path) -> Result<(), String> {
+    let replay = Replay::load(path)?;
+    match replay.verify() {
+        Ok(result) => {
+            println!(
+                "PASS: {} updates, depth {}, score {}, hash {:016x}",
+                result.updates, result.depth, result.score, result.hash
+            );
+            Ok(())
+        }
+        Err(e) => {
+            println!("FAIL: {}", e);
+            Err(format!("replay verification failed: {}", path.display()))
+        }
+    }
+}
+
+// ウィンドウを開かずに、決まったシードの盤面をframesフレーム進めて深さを表示する
+// コマンドは--scriptで渡す（なければ何も操作しない）。シードは--seed、なければスクリプトのseed、それもなければ0
+fn simulate(frames: i32) -> Result<(), String> {
+    let mut script = match arg_value("--script")? {
+        Some(path) => Some(Script::load(Path::new(&path))?),
+        None => None,
+    };
+    let seed = match arg_value("--seed")? {
+        Some(seed) => seed
+            .parse()
+            .map_err(|_| format!("--seed must be a number: {}", seed))?,
+        None => script.as_ref().and_then(|s| s.seed).unwrap_or(0),
+    };
+    let commands: Vec<Command> = (0..frames)
+        .map(|frame| {
+            script
+                .as_mut()
+                .map_or(Command::None, |s| s.command_at(frame))
+        })
+        .collect();
+    let mut game = Game::new_seeded(seed);
+    game.run_commands(&commands);
+    println!(
+        "seed {}, {} frames: depth {}, score {}, hash {:016x}",
+        seed,
+        frames,
+        game.get_depth(),
+        game.score,
+        game.state_hash()
+    );
+    Ok(())
+}
+
+// 描画した画面（present前）をPNGで保存する
+// 読み出すときの形式をそのままサーフェスの形式にするので、画面と同じ色で保存される
+fn save_screenshot(canvas: &Canvas<Window>, path: &Path) -> Result<(), String> {
+    let (width, height) = canvas.output_size()?;
+    let format = PixelFormatEnum::RGB24;
+    let mut pixels = canvas.read_pixels(None, format)?;
+    let pitch = width * format.byte_size_per_pixel() as u32;
+    let surface = Surface::from_data(&mut pixels, width, height, pitch, format)?;
+    surface.save(path)
+}
+
+// F12で撮るスクリーンショットのファイル名
+fn screenshot_path() -> PathBuf {
+    let timestamp = SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    PathBuf::from(format!("screenshot-{}.png", timestamp))
+}
+
+// オーディオを初期化する。失敗したら音なしで続行できるようにエラーを返す
+fn init_mixer() -> Result<(), String> {
+    let chunk_size = 1_024;
+    mixer::open_audio(
+        mixer::DEFAULT_FREQUENCY,
+        mixer::DEFAULT_FORMAT,
+        mixer::DEFAULT_CHANNELS,
+        chunk_size,
+    )?;
+    // 効果音はwavだけなので、MP3のデコーダがなくても続行する
+    if let Err(e) = mixer::init(mixer::InitFlag::MP3) {
+        log_warn!("cannot init mixer for MP3: {}", e);
+    }
+    Ok(())
+}
+
+fn tutorial_game(settings: &Settings) -> Game {
+    let config = GameConfig {
+        shake_warning: settings.shake_warning,
+        ..GameConfig::default()
+    };
+    Game::from_layout(TUTORIAL_LAYOUT, config).expect("invalid tutorial layout")
+}
+
+// 評価や実績を残してよいプレイか。スクリプトで動かしているとき、練習モード（--sandbox）、
+// デバッグモード（F1、コマ送りや巻き戻しができる）では残さない
+fn counts_for_records(game: &Game, scripted: bool) -> bool {
+    !scripted && !game.config.sandbox && !game.is_debug
+}
+
+// チュートリアルを終えたか飛ばしたことを保存し、次からは表示しない
+fn finish_tutorial(ui: &mut UiState, settings: &mut Settings) {
+    ui.tutorial = None;
+    if settings.tutorial_done {
+        return;
+    }
+    settings.tutorial_done = true;
+    if let Err(e) = settings.save(Path::new(SETTINGS_PATH)) {
+        log_warn!("cannot save settings: {}", e);
+    }
+}
+
+// 「--name value」または「--name=value」の形で渡されたコマンドライン引数の値を返す
+fn arg_value(name: &str) -> Result<Option<String>, String> {
+    let mut args = env::args().skip(1);
+    let prefix = format!("{}=", name);
+    while let Some(arg) = args.next() {
+        if arg == name {
+            return args
+                .next()
+                .map(Some)
+                .ok_or_else(|| format!("{} requires a value", name));
+        }
+        if let Some(value) = arg.strip_prefix(&prefix) {
+            return Ok(Some(value.to_string()));
+        }
+    }
+    Ok(None)
+}
+
+// 「--name」の形のコマンドライン引数が渡されたか
+fn has_flag(name: &str) -> bool {
+    env::args().skip(1).any(|arg| arg == name)
+}
+
+// リソースを置いたディレクトリ
+// --resources <dir>、環境変数RUST_DRILLER_RESOURCES、カレントディレクトリのresourcesの順に探す
+fn resources_dir() -> Result<PathBuf, String> {
+    if let Some(dir) = arg_value("--resources")? {
+        return Ok(PathBuf::from(dir));
+    }
+    if let Ok(dir) = env::var("RUST_DRILLER_RESOURCES") {
+        return Ok(PathBuf::from(dir));
+    }
+    Ok(PathBuf::from("resources"))
+}
+
+fn load_resources<'a>(
+    texture_creator: &'a TextureCreator<WindowContext>,
     #[allow(unused_variables)] canvas: &mut Canvas<Window>,
     ttf_context: &'a Sdl2TtfContext,
+    resources_dir: &Path,
+    audio_enabled: bool,
 ) -> Resources<'a> {
     let mut resources = Resources {
         images: HashMap::new(),
         chunks: HashMap::new(),
         fonts: HashMap::new(),
+        audio_enabled,
     };
 
-    let entries = fs::read_dir("resources/image").unwrap();
+    let entries = read_resource_dir(resources_dir, "image");
     for entry in entries {
         let path = entry.unwrap().path();
         let path_str = path.to_str().unwrap();
@@ -162,7 +1530,7 @@ fn load_resources<'a>(
             let temp_surface = sdl2::surface::Surface::load_bmp(&path).unwrap();
             let texture = texture_creator
                 .create_texture_from_surface(&temp_surface)
-                .expect(&format!("cannot load image: {}", path_str));
+                .unwrap_or_else(|_| panic!("cannot load image: {}", path_str));
 
             let basename = path.file_name().unwrap().to_str().unwrap();
             let image = Image::new(texture);
@@ -170,102 +1538,1469 @@ fn load_resources<'a>(
         }
     }
 
-    let entries = fs::read_dir("./resources/sound").unwrap();
+    let entries = read_resource_dir(resources_dir, "sound");
     for entry in entries {
         let path = entry.unwrap().path();
         let path_str = path.to_str().unwrap();
-        if path_str.ends_with(".wav") {
+        if audio_enabled && path_str.ends_with(".wav") {
             let chunk = mixer::Chunk::from_file(path_str)
-                .expect(&format!("cannot load sound: {}", path_str));
+                .unwrap_or_else(|_| panic!("cannot load sound: {}", path_str));
             let basename = path.file_name().unwrap().to_str().unwrap();
             resources.chunks.insert(basename.to_string(), chunk);
         }
     }
 
-    let entries = fs::read_dir("./resources/font").unwrap();
-    for entry in entries {
-        let path = entry.unwrap().path();
-        let path_str = path.to_str().unwrap();
-        if path_str.ends_with(".ttf") {
-            let font = ttf_context
-                .load_font(path_str, 32) // FIXME: サイズ固定になっちゃってる
-                .expect(&format!("cannot load font: {}", path_str));
-            let basename = path.file_name().unwrap().to_str().unwrap();
-            resources.fonts.insert(basename.to_string(), font);
+    let entries = read_resource_dir(resources_dir, "font");
+    for entry in entries {
+        let path = entry.unwrap().path();
+        let path_str = path.to_str().unwrap();
+        if path_str.ends_with(".ttf") {
+            // 拡大して描くとぼやけるので、使う大きさごとに読み込む
+            let basename = path.file_name().unwrap().to_str().unwrap();
+            for size in FONT_SIZES {
+                let font = ttf_context
+                    .load_font(path_str, size)
+                    .unwrap_or_else(|_| panic!("cannot load font: {}", path_str));
+                resources.fonts.insert(font_key(basename, size), font);
+            }
+        }
+    }
+
+    resources
+}
+
+fn read_resource_dir(resources_dir: &Path, name: &str) -> fs::ReadDir {
+    let dir = resources_dir.join(name);
+    fs::read_dir(&dir)
+        .unwrap_or_else(|e| panic!("cannot read resource directory {}: {}", dir.display(), e))
+}
+
+// alphaは直前のシミュレーション状態から現在の状態までの補間の割合（1.0なら現在の状態そのまま）
+fn render(
+    canvas: &mut Canvas<Window>,
+    game: &Game,
+    ui: &UiState,
+    resources: &mut Resources,
+    alpha: f32,
+) -> Result<(), String> {
+    canvas.set_draw_color(Color::RGB(0, 0, 0));
+    canvas.clear();
+
+    if ui.scene == Scene::Title {
+        return render_title_screen(canvas, game, ui, resources);
+    }
+
+    // render cells
+    let camera_px = ui.camera_px(game);
+    let first_row = camera_px.div_euclid(CELL_SIZE);
+    for x in CELLS_X_MIN..=game.config.cells_x_max() {
+        for cell_y in first_row..=first_row + SCREEN_HEIGHT / CELL_SIZE {
+            let screen_y = CELL_SIZE * cell_y - camera_px;
+
+            // 世界の外の行は何もないセルとして返ってくるので何も描かれない
+            let cell = game.cell_at(x, cell_y);
+            let shaking = cell.shaking_frames;
+            let offset_x = if !cell.grounded && shaking >= 0 {
+                shake_offset_x(shaking, game.shake_frames_of(cell), cell.leader)
+            } else {
+                0
+            };
+            let offset_y =
+                ui.interpolated_fall_offset(game, x, cell_y, fall_offset_y(game, cell), alpha);
+
+            // 落下中のセルは着地する1つ下のセルに影を落とす（落ちるほど濃くする）
+            // 背景が黒なので、影は明るい半透明で描いて落下先を目立たせる
+            if cell.cell_type != CellType::None && !cell.grounded && cell.falling_frames >= 0 {
+                let shadow_alpha = 24 + 48 * offset_y / CELL_SIZE;
+                canvas.set_draw_color(Color::RGBA(255, 255, 255, shadow_alpha as u8));
+                canvas.fill_rect(Rect::new(
+                    CELL_SIZE * x,
+                    screen_y + CELL_SIZE,
+                    CELL_SIZE as u32,
+                    CELL_SIZE as u32,
+                ))?;
+            }
+
+            match cell.cell_type {
+                CellType::None => {}
+                CellType::Air => {
+                    let (rx, ry) = air_capsule_radii(cell.capacity, CELL_SIZE);
+                    canvas.filled_ellipse(
+                        ((CELL_SIZE * x) + (CELL_SIZE / 2) + offset_x) as i16,
+                        (screen_y + (CELL_SIZE / 2) + offset_y) as i16,
+                        rx,
+                        ry,
+                        ui.palette.air,
+                    )?;
+                }
+                CellType::Phantom => {
+                    canvas.set_draw_color(phantom_color(game, &ui.palette));
+                    canvas.fill_rect(Rect::new(
+                        CELL_SIZE * x,
+                        screen_y,
+                        CELL_SIZE as u32,
+                        CELL_SIZE as u32,
+                    ))?;
+                }
+                CellType::Vent => {
+                    render_vent(canvas, ui.palette.vent, CELL_SIZE * x, screen_y)?;
+                }
+                CellType::Item(kind) => {
+                    render_item(canvas, kind, CELL_SIZE * x + offset_x, screen_y + offset_y)?;
+                }
+                CellType::Block => {
+                    canvas.set_draw_color(ui.palette.block(cell.color));
+                    let dug_in_px = ((BLOCK_LIFE_MAX - cell.block_life) as f32 / 100.0
+                        * CELL_SIZE as f32) as i32;
+                    canvas.fill_rect(Rect::new(
+                        CELL_SIZE * x + offset_x,
+                        screen_y + dug_in_px + offset_y,
+                        CELL_SIZE as u32,
+                        (CELL_SIZE - dug_in_px) as u32,
+                    ))?;
+                    if cell.gift {
+                        // ギフトブロックにはリボンをかける
+                        canvas.set_draw_color(Color::RGBA(255, 255, 255, 200));
+                        canvas.fill_rect(Rect::new(
+                            CELL_SIZE * x + offset_x + CELL_SIZE / 2 - 3,
+                            screen_y + dug_in_px + offset_y,
+                            6,
+                            (CELL_SIZE - dug_in_px) as u32,
+                        ))?;
+                        if dug_in_px < CELL_SIZE / 2 - 3 {
+                            canvas.fill_rect(Rect::new(
+                                CELL_SIZE * x + offset_x,
+                                screen_y + offset_y + CELL_SIZE / 2 - 3,
+                                CELL_SIZE as u32,
+                                6,
+                            ))?;
+                        }
+                    }
+                }
+            }
+
+            let landing = ui.landing_frames_at(game, x, cell_y);
+            if ui.show_landing && landing > 0 && cell.cell_type == CellType::Block {
+                render_landing(canvas, CELL_SIZE * x, screen_y, landing)?;
+            }
+
+            if ui.show_grounded && cell.cell_type != CellType::None && !cell.grounded {
+                canvas.set_draw_color(Color::RGBA(255, 0, 0, 128));
+                canvas.fill_rect(Rect::new(
+                    CELL_SIZE * x,
+                    screen_y,
+                    CELL_SIZE as u32,
+                    CELL_SIZE as u32,
+                ))?;
+            }
+        }
+    }
+    for &(p, color, frames) in &ui.vanishing {
+        render_vanish(
+            canvas,
+            p.x * CELL_SIZE,
+            p.y * CELL_SIZE - camera_px,
+            ui.palette.block(color),
+            frames,
+        )?;
+    }
+    if game.vent_frames > 0 {
+        render_vent_steam(canvas, game, camera_px)?;
+    }
+    for &(p, points, frames) in &ui.score_popups {
+        render_score_popup(
+            canvas,
+            resources,
+            p.x * CELL_SIZE + CELL_SIZE / 2,
+            p.y * CELL_SIZE - camera_px + CELL_SIZE / 2,
+            points,
+            frames,
+        )?;
+    }
+    if ui.show_planner {
+        render_erase_plan(canvas, game, ui)?;
+    }
+    if ui.show_air_path && !game.is_over && !game.is_clear {
+        render_air_path(canvas, game, ui)?;
+    }
+    render_depth_ruler(canvas, game, resources, camera_px)?;
+
+    for enemy in &game.enemies {
+        render_enemy(
+            canvas,
+            enemy,
+            enemy.p.x * CELL_SIZE,
+            enemy.p.y * CELL_SIZE - camera_px,
+        )?;
+    }
+
+    // render player
+    let (world_x, world_y) = ui.interpolated_player_pos(game, alpha);
+    let (recoil_x, recoil_y) = ui.recoil_offset();
+    let player_x = world_x + recoil_x;
+    let player_y = world_y - camera_px + recoil_y;
+    canvas.set_draw_color(Color::RGB(0xfa, 0x17, 0x46));
+    canvas.fill_rect(Rect::new(player_x, player_y, CELL_SIZE as u32, 28))?;
+    canvas.set_draw_color(Color::RGB(0xff, 0xc3, 0x5b));
+    canvas.fill_rect(Rect::new(
+        player_x + (CELL_SIZE - 28) / 2,
+        player_y + 5,
+        28,
+        18,
+    ))?;
+    canvas.set_draw_color(Color::RGB(0x4b, 0xe4, 0xe9));
+    canvas.fill_rect(Rect::new(
+        player_x + 10,
+        player_y + CELL_SIZE / 2 + 2,
+        20,
+        18,
+    ))?;
+
+    canvas.set_draw_color(Color::RGB(0xd2, 0xcb, 0xbd));
+    canvas.fill_rect(Rect::new(
+        info_x(&game.config),
+        0,
+        INFO_WIDTH as u32,
+        SCREEN_HEIGHT as u32,
+    ))?;
+
+    let line_height = resources.font(FONT_SIZE).height();
+    for (element, rect) in layout_hud(game, ui, line_height) {
+        render_hud_element(canvas, game, ui, resources, element, rect)?;
+    }
+
+    if let Some(step) = ui.tutorial {
+        if !game.is_over && !game.is_clear {
+            render_tutorial_prompt(canvas, game, step, resources)?;
+        }
+    }
+
+    if game.freeze_frames > 0 && !game.is_over {
+        render_frost(canvas, game)?;
+    }
+
+    if game.last_breath_frames >= 0 && !game.is_over {
+        render_last_breath_warning(canvas, game, resources)?;
+    }
+
+    if ui.death_cam.is_some() {
+        render_death_cam_label(canvas, game, resources)?;
+    } else if game.is_over {
+        canvas.set_draw_color(ui.game_over_overlay());
+        canvas.fill_rect(Rect::new(
+            0,
+            0,
+            info_x(&game.config) as u32,
+            SCREEN_HEIGHT as u32,
+        ))?;
+        render_game_over_menu(canvas, game, ui, resources)?;
+    }
+
+    if game.is_clear {
+        render_clear_screen(canvas, game, ui, resources)?;
+    }
+
+    if let Some(title) = ui.toasts.front() {
+        render_toast(canvas, game, title, resources)?;
+    }
+
+    if ui.focus_paused || game.is_paused {
+        render_pause_screen(canvas, game, resources)?;
+    }
+
+    if game.is_debug {
+        canvas.set_draw_color(Color::RGBA(255, 255, 255, 255));
+        canvas.fill_rect(Rect::new(0, 0, 50, 16))?;
+        render_number(
+            canvas,
+            resources,
+            48,
+            0,
+            NumberStyle {
+                align: NumberAlign::Right,
+                ..NumberStyle::default()
+            },
+            game.frame.to_string(),
+        );
+
+        render_font(
+            canvas,
+            resources,
+            FONT_SIZE,
+            format!("{:.1}/{}FPS", ui.measured_fps(), FPS),
+            0,
+            16,
+            Color::RGBA(255, 255, 255, 255),
+        );
+        render_font(
+            canvas,
+            resources,
+            FONT_SIZE,
+            format!("{:.1}MS", ui.average_frame_ms()),
+            0,
+            48,
+            Color::RGBA(255, 255, 255, 255),
+        );
+        // 下にある一番浅いエアカプセルまでの行数と、一番近いエアカプセルの位置
+        if let (Some(rows), Some(nearest)) = (game.depth_to_next_air(), game.nearest_air_capsule())
+        {
+            render_font(
+                canvas,
+                resources,
+                FONT_SIZE,
+                format!("AIR +{} {},{}", rows, nearest.x, nearest.y),
+                0,
+                80,
+                Color::RGBA(255, 255, 255, 255),
+            );
+        }
+        // 盤面の集計：ブロック数、接地していないブロック数、かたまりの数、エアの数
+        let stats = game.scan_stats();
+        render_font(
+            canvas,
+            resources,
+            FONT_SIZE,
+            format!(
+                "BLK {} LOOSE {} GRP {} AIR {}",
+                stats.blocks_by_color.iter().sum::<i32>(),
+                stats.ungrounded,
+                stats.components,
+                stats.air_capsules
+            ),
+            0,
+            112,
+            Color::RGBA(255, 255, 255, 255),
+        );
+    }
+
+    if game.is_debug {
+        render_cell_inspector(canvas, game, ui, resources)?;
+    }
+
+    Ok(())
+}
+
+// ゲームオーバーの直前をふり返っている間の表示。プレイフィールドの下端に書く
+fn render_death_cam_label(
+    canvas: &mut Canvas<Window>,
+    game: &Game,
+    resources: &mut Resources,
+) -> Result<(), String> {
+    let font = resources.font(FONT_SIZE);
+    let text = "REPLAY  SPACE: SKIP";
+    let (w, h) = font.size_of(text).map_err(|e| e.to_string())?;
+    canvas.set_draw_color(Color::RGBA(0, 0, 0, 160));
+    canvas.fill_rect(Rect::new(
+        0,
+        SCREEN_HEIGHT - h as i32 - 8,
+        info_x(&game.config) as u32,
+        h + 8,
+    ))?;
+    render_font(
+        canvas,
+        resources,
+        FONT_SIZE,
+        text.to_string(),
+        (info_x(&game.config) - w as i32) / 2,
+        SCREEN_HEIGHT - h as i32 - 4,
+        Color::RGBA(255, 255, 255, 255),
+    );
+    Ok(())
+}
+
+// 一時停止中の表示。プレイフィールドを暗くして真ん中に書く
+fn render_pause_screen(
+    canvas: &mut Canvas<Window>,
+    game: &Game,
+    resources: &mut Resources,
+) -> Result<(), String> {
+    canvas.set_draw_color(Color::RGBA(0, 0, 0, 160));
+    canvas.fill_rect(Rect::new(
+        0,
+        0,
+        info_x(&game.config) as u32,
+        SCREEN_HEIGHT as u32,
+    ))?;
+    let font = resources.font(FONT_SIZE);
+    let text = "PAUSED";
+    let (w, h) = font.size_of(text).map_err(|e| e.to_string())?;
+    render_font(
+        canvas,
+        resources,
+        FONT_SIZE,
+        text.to_string(),
+        (info_x(&game.config) - w as i32) / 2,
+        (SCREEN_HEIGHT - h as i32) / 2,
+        Color::RGBA(255, 255, 255, 255),
+    );
+    Ok(())
+}
+
+// 実績を解除したお知らせ。プレイフィールドの上端に帯を出して書く
+fn render_toast(
+    canvas: &mut Canvas<Window>,
+    game: &Game,
+    title: &str,
+    resources: &mut Resources,
+) -> Result<(), String> {
+    let font = resources.font(FONT_SIZE);
+    let line_height = font.height();
+    canvas.set_draw_color(Color::RGBA(0, 0, 0, 192));
+    canvas.fill_rect(Rect::new(
+        0,
+        8,
+        info_x(&game.config) as u32,
+        (line_height * 2 + 8) as u32,
+    ))?;
+    for (i, (text, color)) in [
+        ("ACHIEVEMENT", Color::RGBA(255, 255, 0, 255)),
+        (title, Color::RGBA(255, 255, 255, 255)),
+    ]
+    .into_iter()
+    .enumerate()
+    {
+        let (w, _) = font.size_of(text).map_err(|e| e.to_string())?;
+        render_font(
+            canvas,
+            resources,
+            FONT_SIZE,
+            text.to_string(),
+            (info_x(&game.config) - w as i32) / 2,
+            12 + line_height * i as i32,
+            color,
+        );
+    }
+    Ok(())
+}
+
+// クリア画面。情報パネルに重ならないようにプレイフィールドの中だけに描く
+fn render_clear_screen(
+    canvas: &mut Canvas<Window>,
+    game: &Game,
+    ui: &UiState,
+    resources: &mut Resources,
+) -> Result<(), String> {
+    let font = resources.font(FONT_SIZE);
+    let line_height = font.height();
+    let best = if ui.new_best { "NEW BEST!" } else { "" };
+    // 2行目は星を描く場所として空けておく
+    let lines = [
+        ("CLEAR!!".to_string(), Color::RGBA(255, 255, 0, 255)),
+        (String::new(), Color::RGBA(255, 255, 255, 255)),
+        (best.to_string(), Color::RGBA(255, 255, 0, 255)),
+        (
+            format!("DEPTH {}", game.get_depth()),
+            Color::RGBA(255, 255, 255, 255),
+        ),
+        (
+            format!("TIME {:.1}", game.elapsed_seconds()),
+            Color::RGBA(255, 255, 255, 255),
+        ),
+        (
+            format!("PAR {:.1}", game.par_seconds),
+            Color::RGBA(255, 255, 255, 255),
+        ),
+        (
+            format!("DUG {}", game.blocks_dug),
+            Color::RGBA(255, 255, 255, 255),
+        ),
+        (
+            "SPACE: NEXT".to_string(),
+            Color::RGBA(0x63, 0xc1, 0xa5, 255),
+        ),
+    ];
+
+    // 1行目のCLEAR!!だけ大きいフォントで書く
+    let title_height = resources.font(TITLE_FONT_SIZE).height();
+    let height = title_height + line_height * (lines.len() as i32 - 1);
+    let top = (SCREEN_HEIGHT - height) / 2;
+    canvas.set_draw_color(Color::RGBA(0, 0, 0, 160));
+    canvas.fill_rect(Rect::new(
+        0,
+        top - line_height / 2,
+        info_x(&game.config) as u32,
+        (height + line_height) as u32,
+    ))?;
+    let mut y = top;
+    for (i, (text, color)) in lines.iter().enumerate() {
+        let size = if i == 0 { TITLE_FONT_SIZE } else { FONT_SIZE };
+        let font = resources.font(size);
+        if !text.is_empty() {
+            let (w, _) = font.size_of(text).map_err(|e| e.to_string())?;
+            render_font(
+                canvas,
+                resources,
+                size,
+                text.clone(),
+                (info_x(&game.config) - w as i32) / 2,
+                y,
+                *color,
+            );
+        }
+        y += font.height();
+    }
+    render_stars(
+        canvas,
+        info_x(&game.config) / 2,
+        top + title_height + line_height / 2,
+        game.stars(),
+    )
+}
+
+// 評価の星をSTARS_MAX個、(cx, cy)を中心に横に並べて描く。取った分だけ塗る
+fn render_stars(canvas: &mut Canvas<Window>, cx: i32, cy: i32, stars: u32) -> Result<(), String> {
+    let radius = 12.0;
+    let step = 32;
+    let left = cx - step * (STARS_MAX as i32 - 1) / 2;
+    for i in 0..STARS_MAX {
+        let x = left + step * i as i32;
+        // 外側と内側の頂点を交互に、上から時計回りに10個
+        let (vx, vy): (Vec<i16>, Vec<i16>) = (0..10)
+            .map(|k| {
+                let r = if k % 2 == 0 { radius } else { radius * 0.45 };
+                let a = std::f32::consts::PI * k as f32 / 5.0;
+                (
+                    (x as f32 + r * a.sin()) as i16,
+                    (cy as f32 - r * a.cos()) as i16,
+                )
+            })
+            .unzip();
+        if i < stars {
+            canvas.filled_polygon(&vx, &vy, Color::RGB(255, 220, 0))?;
+        } else {
+            canvas.polygon(&vx, &vy, Color::RGB(128, 128, 128))?;
+        }
+    }
+    Ok(())
+}
+
+// チュートリアルの案内をプレイフィールドの上部に出す
+fn render_tutorial_prompt(
+    canvas: &mut Canvas<Window>,
+    game: &Game,
+    step: TutorialStep,
+    resources: &mut Resources,
+) -> Result<(), String> {
+    let font = resources.font(FONT_SIZE);
+    let line_height = font.height();
+    let mut lines: Vec<(&str, Color)> = step
+        .prompt()
+        .iter()
+        .map(|&text| (text, Color::RGBA(255, 255, 255, 255)))
+        .collect();
+    lines.push(("TAB: SKIP", Color::RGBA(0x63, 0xc1, 0xa5, 255)));
+
+    let top = 8;
+    canvas.set_draw_color(Color::RGBA(0, 0, 0, 160));
+    canvas.fill_rect(Rect::new(
+        0,
+        top - 4,
+        info_x(&game.config) as u32,
+        (line_height * lines.len() as i32 + 8) as u32,
+    ))?;
+    for (i, (text, color)) in lines.iter().enumerate() {
+        let (w, _) = font.size_of(text).map_err(|e| e.to_string())?;
+        render_font(
+            canvas,
+            resources,
+            FONT_SIZE,
+            text.to_string(),
+            (info_x(&game.config) - w as i32) / 2,
+            top + line_height * i as i32,
+            *color,
+        );
+    }
+    Ok(())
+}
+
+// 着地すると消えるかたまりを、着地後の位置に枠で示す
+fn render_erase_plan(canvas: &mut Canvas<Window>, game: &Game, ui: &UiState) -> Result<(), String> {
+    let plan = game.predict_erase_groups();
+    for (p, color) in plan {
+        let (screen_x, screen_y) = ui.cell_to_screen(game, p);
+        if screen_y + CELL_SIZE < 0 || screen_y > SCREEN_HEIGHT {
+            continue;
+        }
+        let color = ui.palette.block(color);
+        canvas.set_draw_color(Color::RGBA(color.r, color.g, color.b, 96));
+        canvas.fill_rect(Rect::new(
+            screen_x,
+            screen_y,
+            CELL_SIZE as u32,
+            CELL_SIZE as u32,
+        ))?;
+        canvas.set_draw_color(Color::RGB(255, 255, 255));
+        canvas.draw_rect(Rect::new(
+            screen_x + 2,
+            screen_y + 2,
+            (CELL_SIZE - 4) as u32,
+            (CELL_SIZE - 4) as u32,
+        ))?;
+    }
+    Ok(())
+}
+
+// エアカプセルまでの道のりを、セルの中心を結ぶ点線で描く
+fn render_air_path(canvas: &mut Canvas<Window>, game: &Game, ui: &UiState) -> Result<(), String> {
+    let Some(path) = game.suggest_path_to_next_air() else {
+        return Ok(());
+    };
+    let center = |p: Point| {
+        let (x, y) = ui.cell_to_screen(game, p);
+        (x + CELL_SIZE / 2, y + CELL_SIZE / 2)
+    };
+    let Color { r, g, b, .. } = ui.palette.air;
+    let color = Color::RGBA(r, g, b, 220);
+    let mut from = center(game.player.p);
+    for p in path {
+        let to = center(p);
+        // 1マスにつき4つの点を打つ
+        for i in 1..=4 {
+            let x = from.0 + (to.0 - from.0) * i / 4;
+            let y = from.1 + (to.1 - from.1) * i / 4;
+            canvas.filled_circle(x as i16, y as i16, 3, color)?;
+        }
+        from = to;
+    }
+    Ok(())
+}
+
+// 一辺sizeのセルに描くエアカプセルの楕円の半径（横、縦）。大きいカプセルほど太くする
+fn air_capsule_radii(capacity: AirCapacity, size: i32) -> (i16, i16) {
+    let (rx, ry) = match capacity {
+        AirCapacity::Small => (size * 3 / 8, size * 3 / 16),
+        AirCapacity::Medium => (size / 2, size / 4),
+        AirCapacity::Large => (size / 2, size * 3 / 8),
+    };
+    (rx as i16, ry as i16)
+}
+
+// 着地したばかりのブロックを白く光らせ、下の両端から土煙を広げる
+// framesはLANDING_FRAMESから0へ減っていき、光も土煙もそれにつれて薄くなる
+fn render_landing(canvas: &mut Canvas<Window>, x: i32, y: i32, frames: i32) -> Result<(), String> {
+    let t = frames as f32 / LANDING_FRAMES as f32;
+    canvas.set_draw_color(Color::RGBA(255, 255, 255, (120.0 * t) as u8));
+    canvas.fill_rect(Rect::new(x, y, CELL_SIZE as u32, CELL_SIZE as u32))?;
+    let spread = ((1.0 - t) * CELL_SIZE as f32 / 4.0) as i32;
+    let radius = (3.0 + (1.0 - t) * 6.0) as i16;
+    let dust = Color::RGBA(0xc8, 0xb4, 0x96, (200.0 * t) as u8);
+    let bottom = (y + CELL_SIZE - 2) as i16;
+    canvas.filled_circle((x - spread) as i16, bottom, radius, dust)?;
+    canvas.filled_circle((x + CELL_SIZE + spread) as i16, bottom, radius, dust)?;
+    Ok(())
+}
+
+// 掘ったブロックが消える演出。セルの真ん中に向かって縮みながら、四隅へ破片を飛ばす
+fn render_vanish(
+    canvas: &mut Canvas<Window>,
+    x: i32,
+    y: i32,
+    c: Color,
+    frames: i32,
+) -> Result<(), String> {
+    let t = frames as f32 / VANISH_FRAMES as f32;
+    let size = (CELL_SIZE as f32 * t) as i32;
+    if size > 0 {
+        canvas.set_draw_color(c);
+        canvas.fill_rect(Rect::new(
+            x + (CELL_SIZE - size) / 2,
+            y + (CELL_SIZE - size) / 2,
+            size as u32,
+            size as u32,
+        ))?;
+    }
+    let spread = ((1.0 - t) * CELL_SIZE as f32 * 0.6) as i32;
+    let piece = Color::RGBA(c.r, c.g, c.b, (255.0 * t) as u8);
+    let center = CELL_SIZE / 2;
+    for (dx, dy) in [(-1, -1), (1, -1), (-1, 1), (1, 1)] {
+        canvas.filled_circle(
+            (x + center + dx * spread) as i16,
+            (y + center + dy * spread) as i16,
+            3,
+            piece,
+        )?;
+    }
+    Ok(())
+}
+
+// かたまりを消してもらった点。セルの真ん中から上へ浮かびながら薄くなる
+fn render_score_popup(
+    canvas: &mut Canvas<Window>,
+    resources: &mut Resources,
+    cx: i32,
+    cy: i32,
+    points: u32,
+    frames: i32,
+) -> Result<(), String> {
+    let t = 1.0 - frames as f32 / SCORE_POPUP_FRAMES as f32;
+    let font = resources.font(FONT_SIZE);
+    let text = format!("+{}", points);
+    let (w, h) = font.size_of(&text).map_err(|e| e.to_string())?;
+    let rise = (t * CELL_SIZE as f32) as i32;
+    render_font(
+        canvas,
+        resources,
+        FONT_SIZE,
+        text,
+        cx - w as i32 / 2,
+        cy - h as i32 / 2 - rise,
+        Color::RGBA(255, 255, 160, (255.0 * (1.0 - t * t)) as u8),
+    );
+    Ok(())
+}
+
+// 通気口は暗い鉄板に横向きのスリットを切った格子で描く
+fn render_vent(canvas: &mut Canvas<Window>, color: Color, x: i32, y: i32) -> Result<(), String> {
+    canvas.set_draw_color(color);
+    canvas.fill_rect(Rect::new(x, y, CELL_SIZE as u32, CELL_SIZE as u32))?;
+    canvas.set_draw_color(Color::RGB(0x18, 0x1c, 0x20));
+    for i in 1..=3 {
+        canvas.fill_rect(Rect::new(
+            x + 4,
+            y + CELL_SIZE * i / 4 - 2,
+            (CELL_SIZE - 8) as u32,
+            4,
+        ))?;
+    }
+    Ok(())
+}
+
+// 敵。紫の体に、進んでいる向きの側に目を1つ描く
+fn render_enemy(canvas: &mut Canvas<Window>, enemy: &Enemy, x: i32, y: i32) -> Result<(), String> {
+    canvas.set_draw_color(Color::RGB(0x8a, 0x2b, 0xe2));
+    canvas.fill_rect(Rect::new(
+        x + 4,
+        y + 8,
+        (CELL_SIZE - 8) as u32,
+        (CELL_SIZE - 8) as u32,
+    ))?;
+    let eye_x = if enemy.direction == Direction::Left {
+        x + 8
+    } else {
+        x + CELL_SIZE - 8 - 8
+    };
+    canvas.set_draw_color(Color::RGB(0xff, 0xff, 0xff));
+    canvas.fill_rect(Rect::new(eye_x, y + 14, 8, 8))?;
+    Ok(())
+}
+
+// 通気口のそばにいる間、プレイヤーの上下左右にある通気口からプレイヤーへ向かって蒸気を噴き出す
+fn render_vent_steam(
+    canvas: &mut Canvas<Window>,
+    game: &Game,
+    camera_px: i32,
+) -> Result<(), String> {
+    let center = CELL_SIZE / 2;
+    for direction in Direction::all() {
+        let Some(p) = game.neighbor(game.player.p, direction) else {
+            continue;
+        };
+        if game.cell(p).cell_type != CellType::Vent {
+            continue;
+        }
+        let (dx, dy) = (p.x - game.player.p.x, p.y - game.player.p.y);
+        for k in 0..3 {
+            // 通気口からプレイヤーへ向かって少しずつ流れ、薄くなりながら広がる
+            let t = ((game.vent_frames + k * 5) % 15) as f32 / 15.0;
+            let dist = (t * CELL_SIZE as f32) as i32;
+            let px = p.x * CELL_SIZE + center - dx * dist;
+            let py = p.y * CELL_SIZE - camera_px + center - dy * dist;
+            canvas.filled_circle(
+                px as i16,
+                py as i16,
+                (4.0 + 8.0 * t) as i16,
+                Color::RGBA(0xe0, 0xe8, 0xf0, (160.0 * (1.0 - t)) as u8),
+            )?;
+        }
+    }
+    Ok(())
+}
+
+// 時間停止中は盤面を白っぽい青で覆い、縁に霜を描く。切れる直前は点滅させる
+fn render_frost(canvas: &mut Canvas<Window>, game: &Game) -> Result<(), String> {
+    if game.freeze_frames < FPS && (game.frame / 4) % 2 == 0 {
+        return Ok(());
+    }
+    canvas.set_draw_color(Color::RGBA(0xb8, 0xf0, 0xff, 40));
+    canvas.fill_rect(Rect::new(
+        0,
+        0,
+        info_x(&game.config) as u32,
+        SCREEN_HEIGHT as u32,
+    ))?;
+    canvas.set_draw_color(Color::RGBA(0xe8, 0xfa, 0xff, 120));
+    for i in 0..4 {
+        canvas.draw_rect(Rect::new(
+            i * 2,
+            i * 2,
+            (info_x(&game.config) - i * 4) as u32,
+            (SCREEN_HEIGHT - i * 4) as u32,
+        ))?;
+    }
+    Ok(())
+}
+
+// エアが切れて息を止めているあいだ、画面を赤く点滅させて残り時間を出す
+fn render_last_breath_warning(
+    canvas: &mut Canvas<Window>,
+    game: &Game,
+    resources: &mut Resources,
+) -> Result<(), String> {
+    let flash_on = (game.frame / 4) % 2 == 0;
+    if !flash_on {
+        return Ok(());
+    }
+    canvas.set_draw_color(Color::RGBA(255, 0, 0, 80));
+    canvas.fill_rect(Rect::new(
+        0,
+        0,
+        info_x(&game.config) as u32,
+        SCREEN_HEIGHT as u32,
+    ))?;
+
+    let font = resources.font(FONT_SIZE);
+    let text = format!(
+        "LAST BREATH {:.1}",
+        game.last_breath_frames as f32 / FPS as f32
+    );
+    let (w, _) = font.size_of(&text).map_err(|e| e.to_string())?;
+    render_font(
+        canvas,
+        resources,
+        FONT_SIZE,
+        text,
+        (info_x(&game.config) - w as i32) / 2,
+        SCREEN_HEIGHT / 4,
+        Color::RGBA(255, 255, 255, 255),
+    );
+    Ok(())
+}
+
+// ゲームオーバー時のメニュー。選んでいる項目に「>」をつける
+fn render_game_over_menu(
+    canvas: &mut Canvas<Window>,
+    game: &Game,
+    ui: &UiState,
+    resources: &mut Resources,
+) -> Result<(), String> {
+    let font = resources.font(FONT_SIZE);
+    let line_height = font.height();
+    let height = line_height * (GameOverOption::ALL.len() as i32 + 1);
+    let top = (SCREEN_HEIGHT - height) / 2;
+    canvas.set_draw_color(Color::RGBA(0, 0, 0, 160));
+    canvas.fill_rect(Rect::new(
+        0,
+        top - line_height / 2,
+        info_x(&game.config) as u32,
+        (height + line_height) as u32,
+    ))?;
+
+    let title = "GAME OVER";
+    let (w, _) = font.size_of(title).map_err(|e| e.to_string())?;
+    render_font(
+        canvas,
+        resources,
+        FONT_SIZE,
+        title.to_string(),
+        (info_x(&game.config) - w as i32) / 2,
+        top,
+        Color::RGBA(255, 80, 80, 255),
+    );
+    for (i, option) in GameOverOption::ALL.iter().enumerate() {
+        let (text, color) = if i == ui.game_over_cursor {
+            (
+                format!("> {}", option.label()),
+                Color::RGBA(255, 255, 0, 255),
+            )
+        } else {
+            (
+                format!("  {}", option.label()),
+                Color::RGBA(255, 255, 255, 255),
+            )
+        };
+        render_font(
+            canvas,
+            resources,
+            FONT_SIZE,
+            text,
+            info_x(&game.config) / 4,
+            top + line_height * (i as i32 + 1),
+            color,
+        );
+    }
+    Ok(())
+}
+
+fn render_title_screen(
+    canvas: &mut Canvas<Window>,
+    game: &Game,
+    ui: &UiState,
+    resources: &mut Resources,
+) -> Result<(), String> {
+    let font = resources.font(FONT_SIZE);
+    let line_height = font.height();
+    let controls = format!("F8: {} KEYS", ui.controls.name().to_uppercase());
+    let lines = [
+        ("RUST-DRILLER", Color::RGBA(0xfe, 0x54, 0x00, 255)),
+        ("SPACE: START", Color::RGBA(0x63, 0xc1, 0xa5, 255)),
+        (controls.as_str(), Color::RGBA(255, 255, 255, 255)),
+    ];
+    let top = SCREEN_HEIGHT / 2 - line_height;
+    for (i, (text, color)) in lines.iter().enumerate() {
+        let (w, _) = font.size_of(text).map_err(|e| e.to_string())?;
+        render_font(
+            canvas,
+            resources,
+            FONT_SIZE,
+            text.to_string(),
+            (screen_width(&game.config) - w as i32) / 2,
+            top + line_height * i as i32,
+            *color,
+        );
+    }
+    Ok(())
+}
+
+// デバッグ用：マウスカーソルの下にあるセルの状態をツールチップで表示する
+fn render_cell_inspector(
+    canvas: &mut Canvas<Window>,
+    game: &Game,
+    ui: &UiState,
+    resources: &mut Resources,
+) -> Result<(), String> {
+    let Some(p) = ui.screen_to_cell(game, ui.mouse_x, ui.mouse_y) else {
+        return Ok(());
+    };
+    let text = format!("{},{} {:#?}", p.x, p.y, game.cell(p));
+    let lines: Vec<&str> = text.lines().collect();
+
+    let font = resources.font(FONT_SIZE);
+    let line_height = font.height();
+    let mut width = 0;
+    for line in &lines {
+        let (w, _) = font.size_of(line).map_err(|e| e.to_string())?;
+        width = width.max(w as i32);
+    }
+    let height = line_height * lines.len() as i32;
+
+    // カーソルの右下に出し、画面からはみ出すなら左上にずらす
+    let mut x = ui.mouse_x + 12;
+    let mut y = ui.mouse_y + 12;
+    if x + width > screen_width(&game.config) {
+        x = (ui.mouse_x - 12 - width).max(0);
+    }
+    if y + height > SCREEN_HEIGHT {
+        y = (ui.mouse_y - 12 - height).max(0);
+    }
+
+    canvas.set_draw_color(Color::RGBA(0, 0, 0, 192));
+    canvas.fill_rect(Rect::new(
+        x - 4,
+        y - 4,
+        (width + 8) as u32,
+        (height + 8) as u32,
+    ))?;
+    for (i, line) in lines.iter().enumerate() {
+        render_font(
+            canvas,
+            resources,
+            FONT_SIZE,
+            line.to_string(),
+            x,
+            y + line_height * i as i32,
+            Color::RGBA(255, 255, 255, 255),
+        );
+    }
+    Ok(())
+}
+
+// プレイフィールドの左端に深さの目盛りを描く（地表が0）
+fn render_depth_ruler(
+    canvas: &mut Canvas<Window>,
+    game: &Game,
+    resources: &Resources,
+    camera_px: i32,
+) -> Result<(), String> {
+    let first_row = camera_px.div_euclid(CELL_SIZE);
+    canvas.set_draw_color(Color::RGBA(255, 255, 255, 160));
+    for cell_y in first_row..=first_row + SCREEN_HEIGHT / CELL_SIZE {
+        let depth = cell_y - game.config.up_space_height;
+        if depth < 0 || cell_y > game.config.cells_y_len() || depth % DEPTH_RULER_INTERVAL != 0 {
+            continue;
+        }
+        let screen_y = CELL_SIZE * cell_y - camera_px;
+        canvas.fill_rect(Rect::new(0, screen_y - 1, 8, 2))?;
+        render_number(
+            canvas,
+            resources,
+            10,
+            screen_y + 2,
+            NumberStyle::default(),
+            depth.to_string(),
+        );
+    }
+    Ok(())
+}
+
+// 1マス分の大きさのアイテムを描く
+fn render_item(canvas: &mut Canvas<Window>, kind: ItemKind, x: i32, y: i32) -> Result<(), String> {
+    let cx = (x + CELL_SIZE / 2) as i16;
+    let cy = (y + CELL_SIZE / 2) as i16;
+    match kind {
+        ItemKind::Key => render_key(canvas, x, y, true)?,
+        ItemKind::AirTank => {
+            // エアより大きく、白い縁取りのついたカプセル
+            canvas.filled_ellipse(
+                cx,
+                cy,
+                (CELL_SIZE / 2) as i16,
+                (CELL_SIZE / 3) as i16,
+                Color::RGB(0x01, 0x2f, 0xd0),
+            )?;
+            canvas.ellipse(
+                cx,
+                cy,
+                (CELL_SIZE / 2) as i16,
+                (CELL_SIZE / 3) as i16,
+                Color::RGB(255, 255, 255),
+            )?;
+        }
+        ItemKind::Drill => {
+            // 下向きのドリルの刃
+            canvas.filled_trigon(
+                (x + 8) as i16,
+                (y + 8) as i16,
+                (x + CELL_SIZE - 8) as i16,
+                (y + 8) as i16,
+                cx,
+                (y + CELL_SIZE - 4) as i16,
+                Color::RGB(0xfe, 0x8a, 0x00),
+            )?;
+        }
+        ItemKind::Life => render_heart(canvas, x + 4, y + 4, CELL_SIZE - 8)?,
+        ItemKind::Freeze => {
+            // 雪の結晶（中心で交わる3本の線）
+            let r = (CELL_SIZE / 2 - 5) as f64;
+            for i in 0..3 {
+                let angle = std::f64::consts::PI * i as f64 / 3.0;
+                let dx = (r * angle.sin()) as i16;
+                let dy = (r * angle.cos()) as i16;
+                canvas.thick_line(
+                    cx - dx,
+                    cy - dy,
+                    cx + dx,
+                    cy + dy,
+                    3,
+                    Color::RGB(0xb8, 0xf0, 0xff),
+                )?;
+            }
+        }
+    }
+    Ok(())
+}
+
+// 左上が(x, y)、幅sizeのハート
+fn render_heart(canvas: &mut Canvas<Window>, x: i32, y: i32, size: i32) -> Result<(), String> {
+    let color = Color::RGB(0xfa, 0x17, 0x46);
+    let r = size / 4;
+    canvas.filled_circle((x + r) as i16, (y + r) as i16, r as i16, color)?;
+    canvas.filled_circle((x + size - r) as i16, (y + r) as i16, r as i16, color)?;
+    canvas.filled_trigon(
+        x as i16,
+        (y + r + 1) as i16,
+        (x + size) as i16,
+        (y + r + 1) as i16,
+        (x + size / 2) as i16,
+        (y + size) as i16,
+        color,
+    )?;
+    Ok(())
+}
+
+// 1マス分の大きさの鍵を描く。filledがfalseなら輪郭だけ（まだ拾っていない）
+fn render_key(canvas: &mut Canvas<Window>, x: i32, y: i32, filled: bool) -> Result<(), String> {
+    let color = Color::RGB(0xf5, 0xc5, 0x18);
+    let ring_x = (x + CELL_SIZE / 3) as i16;
+    let ring_y = (y + CELL_SIZE / 2) as i16;
+    let ring_radius = (CELL_SIZE / 5) as i16;
+    let shaft = Rect::new(
+        x + CELL_SIZE / 2,
+        y + CELL_SIZE / 2 - 2,
+        (CELL_SIZE / 3) as u32,
+        4,
+    );
+    let tooth = Rect::new(x + CELL_SIZE * 3 / 4, y + CELL_SIZE / 2, 4, 8);
+    canvas.set_draw_color(color);
+    if filled {
+        canvas.filled_circle(ring_x, ring_y, ring_radius, color)?;
+        canvas.fill_rect(shaft)?;
+        canvas.fill_rect(tooth)?;
+    } else {
+        canvas.circle(ring_x, ring_y, ring_radius, color)?;
+        canvas.draw_rect(shaft)?;
+        canvas.draw_rect(tooth)?;
+    }
+    Ok(())
+}
+
+// 情報パネルの要素の高さ（px）。表示しない設定ならNoneで、場所も空けない
+// ゲームの状態で出たり消えたりするもの（コンボなど）は場所を空けておき、ほかの要素が動かないようにする
+fn hud_element_height(
+    element: HudElement,
+    game: &Game,
+    ui: &UiState,
+    line_height: i32,
+) -> Option<i32> {
+    match element {
+        HudElement::DigQueue => ui
+            .show_dig_queue
+            .then_some(DIG_QUEUE_LEN * (DIG_QUEUE_CELL + 2) - 2),
+        HudElement::Combo => Some(line_height + 6),
+        HudElement::Stage => Some(line_height),
+        HudElement::Depth => Some(line_height),
+        HudElement::Minimap => ui.show_minimap.then_some(MINIMAP_ROWS + 2),
+        HudElement::Score => Some(line_height),
+        HudElement::HighScores => {
+            let rows = ui.high_scores.len().min(HUD_HIGH_SCORES) as i32;
+            (rows > 0).then_some(line_height * rows)
         }
+        HudElement::CollapseCharge => Some(6),
+        HudElement::Air => Some(AIR_GAUGE_RADIUS * 2),
+        HudElement::AirNumber => ui.show_air_number.then_some(line_height),
+        HudElement::Colors => Some(16),
+        HudElement::Key => {
+            (game.config.clear_requires_key || game.config.surface_return).then_some(CELL_SIZE)
+        }
+        HudElement::Lives => Some(28),
+        HudElement::PowerUps => Some(20),
     }
+}
 
-    resources
+// 情報パネルの中で各要素を置く場所。ui.hudの順に、パネルの上から詰めて積む
+fn layout_hud(game: &Game, ui: &UiState, line_height: i32) -> Vec<(HudElement, Rect)> {
+    let mut placed = Vec::new();
+    let mut y = HUD_PADDING;
+    for &element in &ui.hud {
+        let Some(height) = hud_element_height(element, game, ui, line_height) else {
+            continue;
+        };
+        let width = INFO_WIDTH - HUD_PADDING * 2;
+        placed.push((
+            element,
+            Rect::new(
+                info_x(&game.config) + HUD_PADDING,
+                y,
+                width as u32,
+                height as u32,
+            ),
+        ));
+        y += height + HUD_GAP;
+    }
+    placed
 }
 
-fn render(
+// 情報パネルの要素を1つ、layout_hudで決めた場所に描く
+fn render_hud_element(
     canvas: &mut Canvas<Window>,
     game: &Game,
+    ui: &UiState,
     resources: &mut Resources,
+    element: HudElement,
+    rect: Rect,
 ) -> Result<(), String> {
-    canvas.set_draw_color(Color::RGB(0, 0, 0));
-    canvas.clear();
-
-    // render cells
-    for x in CELLS_X_MIN..=CELLS_X_MAX {
-        for y in 0..12 {
-            let cell_y = game.camera_y + y;
-
-            let cell = game.cell(Point::new(x, cell_y));
-            let shaking = cell.shaking_frames;
-            let falling = cell.falling_frames;
-            let offset_xs = [0, 1, 2, 1, 0, -1, -2, -1];
-            let offset_x = if !cell.grounded && shaking >= 0 {
-                offset_xs[(shaking as usize) % offset_xs.len()]
+    let font = resources.font(FONT_SIZE);
+    let (x, y, w) = (rect.x(), rect.y(), rect.width() as i32);
+    match element {
+        HudElement::DigQueue => render_dig_queue(canvas, game, &ui.palette, rect)?,
+        HudElement::Minimap => render_minimap(canvas, game, rect)?,
+        HudElement::Combo => {
+            // コンボ数と、コンボが途切れるまでの残り時間
+            if game.combo > 0 {
+                render_font(
+                    canvas,
+                    resources,
+                    FONT_SIZE,
+                    format!("x{}", game.combo),
+                    x,
+                    y,
+                    Color::RGBA(0xfe, 0x54, 0x00, 255),
+                );
+                let width = w * game.combo_frames_left() / COMBO_WINDOW_FRAMES;
+                canvas.set_draw_color(Color::RGB(0xfe, 0x54, 0x00));
+                canvas.fill_rect(Rect::new(x, y + font.height() + 2, width as u32, 4))?;
+            }
+        }
+        HudElement::Stage => {
+            // 桁が増えても情報パネルからはみ出さないように、幅に収まるまで縮める
+            render_font_fit(
+                canvas,
+                resources,
+                FONT_SIZE,
+                format!("STAGE {}", game.stage),
+                rect,
+                Color::RGBA(255, 255, 255, 255),
+            );
+        }
+        HudElement::Depth => {
+            let depth = format!("{0: >4}", game.get_depth());
+            render_font(
+                canvas,
+                resources,
+                FONT_SIZE,
+                depth,
+                x,
+                y,
+                Color::RGBA(0xfe, 0x54, 0x00, 255),
+            );
+        }
+        HudElement::Score => {
+            // 桁数が増えても右端がそろうように右寄せにする
+            let score = game.score.to_string();
+            let (width, _) = font.size_of(&score).map_err(|e| e.to_string())?;
+            render_font(
+                canvas,
+                resources,
+                FONT_SIZE,
+                score,
+                x + w - width as i32,
+                y,
+                Color::RGBA(255, 255, 160, 255),
+            );
+        }
+        HudElement::HighScores => {
+            // 深さの記録の上位。深さの表示と桁をそろえて、暗めの色で書く
+            let line_height = font.height();
+            for (i, entry) in ui.high_scores.iter().take(HUD_HIGH_SCORES).enumerate() {
+                render_font(
+                    canvas,
+                    resources,
+                    FONT_SIZE,
+                    format!("{}{: >3}", i + 1, entry.depth),
+                    x,
+                    y + line_height * i as i32,
+                    Color::RGBA(0xa0, 0x80, 0x60, 255),
+                );
+            }
+        }
+        HudElement::CollapseCharge => {
+            // 崩落技のたまり具合。たまりきったら赤くする
+            let width = w * game.collapse_charge / COLLAPSE_CHARGE_FRAMES;
+            canvas.set_draw_color(if game.collapse_charge >= COLLAPSE_CHARGE_FRAMES {
+                Color::RGB(0xd0, 0x20, 0x20)
             } else {
-                0
-            };
-            let offset_y = if !cell.grounded && falling >= 0 {
-                clamp(
-                    0,
-                    ((falling as f32 / FALL_FRAMES as f32) * (CELL_SIZE as f32)) as i32,
-                    CELL_SIZE as i32,
-                )
+                Color::RGB(0x80, 0x78, 0x70)
+            });
+            canvas.fill_rect(Rect::new(x, y, width as u32, 6))?;
+        }
+        HudElement::Air => {
+            let circle_x = (x + w / 2) as i16;
+            let circle_y = (y + AIR_GAUGE_RADIUS) as i16;
+            if game.player.air > 0 {
+                // 外側
+                canvas.filled_pie(
+                    circle_x,
+                    circle_y,
+                    AIR_GAUGE_RADIUS as i16,
+                    -90,
+                    -90 + (360.0 * game.player.air_percent() / 100.0f32) as i16,
+                    Color::RGBA(0x01, 0x2f, 0xd0, 254), // なぜかalpha=255だと他の部分まで半透明が効かなくなってしまう
+                )?;
+            }
+            // 内側の円
+            let inner_circle_color = if game.player.air_percent() >= 20.0f32 {
+                Color::RGBA(0xd3, 0xe3, 0xe9, 254)
             } else {
-                0
+                Color::RGBA(0xdf, 0x7a, 0x98, 254)
             };
-
-            match game.cell(Point::new(x, cell_y)).cell_type {
-                CellType::None => {}
-                CellType::Air => {
-                    canvas.filled_ellipse(
-                        ((CELL_SIZE * x) + (CELL_SIZE / 2) + offset_x) as i16,
-                        ((CELL_SIZE * y) + (CELL_SIZE / 2) + offset_y) as i16,
-                        (CELL_SIZE / 2) as i16,
-                        (CELL_SIZE / 4) as i16,
-                        Color::RGB(0x63, 0xc1, 0xa5),
+            canvas.filled_circle(
+                circle_x,
+                circle_y,
+                (AIR_GAUGE_RADIUS / 2 - 1) as i16,
+                inner_circle_color,
+            )?;
+        }
+        HudElement::AirNumber => {
+            // エアの残量（%）
+            let air = format!("{0: >3}%", game.player.air_percent().ceil() as i32);
+            render_font(
+                canvas,
+                resources,
+                FONT_SIZE,
+                air,
+                x,
+                y,
+                Color::RGBA(0x01, 0x2f, 0xd0, 255),
+            );
+        }
+        HudElement::Colors => {
+            // 全色そろえボーナスの進み具合（色が多いときは詰めて並べる）
+            let colors = game.config.active_colors();
+            let step = 22.min(w / colors.len().max(1) as i32);
+            let size = 16.min(step - 4);
+            for (i, &color) in colors.iter().enumerate() {
+                let index = color.playable_index().unwrap();
+                let x = x + 5 + i as i32 * step;
+                canvas.set_draw_color(ui.palette.block(color));
+                if game.colors_erased[index] {
+                    canvas.fill_rect(Rect::new(x, y, size as u32, size as u32))?;
+                    let check_color = Color::RGB(0x30, 0x30, 0x30);
+                    canvas.thick_line(
+                        (x + 3) as i16,
+                        (y + size / 2) as i16,
+                        (x + size / 2 - 1) as i16,
+                        (y + size - 4) as i16,
+                        2,
+                        check_color,
                     )?;
+                    canvas.thick_line(
+                        (x + size / 2 - 1) as i16,
+                        (y + size - 4) as i16,
+                        (x + size - 3) as i16,
+                        (y + 4) as i16,
+                        2,
+                        check_color,
+                    )?;
+                } else {
+                    canvas.draw_rect(Rect::new(x, y, size as u32, size as u32))?;
                 }
-                CellType::Block => {
-                    let color = match game.cell(Point::new(x, cell_y)).color {
-                        BlockColor::Red => Color::RGB(255, 128, 128),
-                        BlockColor::Yellow => Color::RGB(255, 255, 128),
-                        BlockColor::Green => Color::RGB(128, 255, 128),
-                        BlockColor::Blue => Color::RGB(128, 128, 255),
-                        BlockColor::Clear => Color::RGB(0x63, 0xc1, 0xa5),
-                        BlockColor::Brown => Color::RGB(92, 48, 28),
-                    };
-                    canvas.set_draw_color(color);
-                    let dug_in_px = ((BLOCK_LIFE_MAX - game.cell(Point::new(x, cell_y)).block_life)
-                        as f32
-                        / 100.0
-                        * CELL_SIZE as f32) as i32;
-                    canvas.fill_rect(Rect::new(
-                        CELL_SIZE as i32 * x + offset_x,
-                        CELL_SIZE as i32 * y + dug_in_px + offset_y,
-                        CELL_SIZE as u32,
-                        (CELL_SIZE - dug_in_px) as u32,
-                    ))?;
-                }
+            }
+        }
+        HudElement::Key => render_key(canvas, x + (w - CELL_SIZE) / 2, y, game.has_key)?,
+        HudElement::Lives => {
+            for i in 0..game.lives {
+                render_heart(canvas, x + i * 30, y, 28)?;
+            }
+        }
+        HudElement::PowerUps => {
+            // ドリル強化と時間停止の残り時間
+            if game.drill_boost_frames > 0 {
+                let width = w * game.drill_boost_frames / DRILL_BOOST_FRAMES;
+                canvas.set_draw_color(Color::RGB(0xfe, 0x8a, 0x00));
+                canvas.fill_rect(Rect::new(x, y, width as u32, 8))?;
+            }
+            if game.freeze_frames > 0 {
+                let width = w * game.freeze_frames / FREEZE_FRAMES;
+                canvas.set_draw_color(Color::RGB(0xb8, 0xf0, 0xff));
+                canvas.fill_rect(Rect::new(x, y + 12, width as u32, 8))?;
             }
         }
     }
-    // render player
+    Ok(())
+}
+
+// プレイヤーの真下にあるセルを近い順に上から並べる（世界の底より下は描かない）
+fn render_dig_queue(
+    canvas: &mut Canvas<Window>,
+    game: &Game,
+    palette: &Palette,
+    rect: Rect,
+) -> Result<(), String> {
+    let size = DIG_QUEUE_CELL;
+    let x = rect.x() + (rect.width() as i32 - size) / 2;
+    for k in 1..=DIG_QUEUE_LEN {
+        let cell_y = game.player.p.y + k;
+        if cell_y > game.config.cells_y_max() {
+            break;
+        }
+        let y = rect.y() + (k - 1) * (size + 2);
+        let cell = game.cell_at(game.player.p.x, cell_y);
+        let rect = Rect::new(x, y, size as u32, size as u32);
+        match cell.cell_type {
+            CellType::Block => {
+                canvas.set_draw_color(palette.block(cell.color));
+                canvas.fill_rect(rect)?;
+            }
+            CellType::Phantom => {
+                canvas.set_draw_color(phantom_color(game, palette));
+                canvas.fill_rect(rect)?;
+            }
+            CellType::Vent => {
+                canvas.set_draw_color(palette.vent);
+                canvas.fill_rect(rect)?;
+            }
+            CellType::Air => {
+                let (rx, ry) = air_capsule_radii(cell.capacity, size);
+                canvas.filled_ellipse(
+                    (x + size / 2) as i16,
+                    (y + size / 2) as i16,
+                    rx,
+                    ry,
+                    palette.air,
+                )?;
+            }
+            CellType::Item(_) => {
+                canvas.filled_circle(
+                    (x + size / 2) as i16,
+                    (y + size / 2) as i16,
+                    (size / 4) as i16,
+                    palette.item,
+                )?;
+            }
+            CellType::None => {}
+        }
+        canvas.set_draw_color(Color::RGB(0x80, 0x78, 0x70));
+        canvas.draw_rect(rect)?;
+    }
+    Ok(())
+}
+
+// プレイヤーより下のMINIMAP_ROWS行の縮図。1行を1pxの高さの帯にして、ブロックが多い行ほど暗く塗る
+// エアカプセルのある行には、その列の位置に水色の印をつける。世界の底より下の行は描かない
+fn render_minimap(canvas: &mut Canvas<Window>, game: &Game, rect: Rect) -> Result<(), String> {
+    let (left, top) = (rect.x() + 1, rect.y() + 1);
+    let width = rect.width() as i32 - 2;
+    let cols = game.config.cells_x_len();
+    for k in 1..=MINIMAP_ROWS {
+        let cell_y = game.player.p.y + k;
+        if cell_y > game.config.cells_y_max() {
+            break;
+        }
+        let y = top + k - 1;
+        let solid = (CELLS_X_MIN..=game.config.cells_x_max())
+            .filter(|&x| {
+                matches!(
+                    game.cell_at(x, cell_y).cell_type,
+                    CellType::Block | CellType::Phantom | CellType::Vent
+                )
+            })
+            .count() as i32;
+        // 空の行は情報パネルの地の色、埋まった行ほど濃い茶色に近づける
+        let shade = |light: i32, dark: i32| (light + (dark - light) * solid / cols) as u8;
+        canvas.set_draw_color(Color::RGB(
+            shade(0xd2, 0x40),
+            shade(0xcb, 0x38),
+            shade(0xbd, 0x30),
+        ));
+        canvas.fill_rect(Rect::new(left, y, width as u32, 1))?;
+        canvas.set_draw_color(Color::RGB(0x00, 0xe0, 0xff));
+        for x in CELLS_X_MIN..=game.config.cells_x_max() {
+            if game.cell_at(x, cell_y).cell_type == CellType::Air {
+                let from = left + x * width / cols;
+                let to = left + (x + 1) * width / cols;
+                canvas.fill_rect(Rect::new(from, y, (to - from).max(1) as u32, 1))?;
+            }
+        }
+    }
+    canvas.set_draw_color(Color::RGB(0x80, 0x78, 0x70));
+    canvas.draw_rect(rect)?;
+    Ok(())
+}
+
+// ファントムブロックの色。実体化しているときは濃く、透明なときは薄く、
+// 実体化する直前の1秒間はだんだん濃くして予告する
+fn phantom_color(game: &Game, palette: &Palette) -> Color {
+    let alpha = if game.is_phantom_solid(0) {
+        230
+    } else {
+        let warn = (0..FPS).filter(|&n| game.is_phantom_solid(n)).count() as i32;
+        40 + 150 * warn / FPS
+    };
+    let Color { r, g, b, .. } = palette.phantom;
+    Color::RGBA(r, g, b, alpha as u8)
+}
+
+// プレイヤーのワールド座標（px）。歩行中は歩いた分だけずらす
+fn player_world_pos(game: &Game) -> (i32, i32) {
     let offset_x = match game.player.state {
         PlayerState::Walking => {
             ((game.player.walking_frames as f32 / WALK_FRAMES as f32) * CELL_SIZE as f32) as i32
@@ -277,107 +3012,103 @@ fn render(
         }
         _ => 0,
     };
-    canvas.set_draw_color(Color::RGB(0xfa, 0x17, 0x46));
-    canvas.fill_rect(Rect::new(
-        game.player.p.x * CELL_SIZE + offset_x,
-        (game.player.p.y - game.camera_y) * CELL_SIZE,
-        CELL_SIZE as u32,
-        28,
-    ))?;
-    canvas.set_draw_color(Color::RGB(0xff, 0xc3, 0x5b));
-    canvas.fill_rect(Rect::new(
-        game.player.p.x * CELL_SIZE + offset_x + (CELL_SIZE - 28) / 2,
-        (game.player.p.y - game.camera_y) * CELL_SIZE + 5,
-        28,
-        18,
-    ))?;
-    canvas.set_draw_color(Color::RGB(0x4b, 0xe4, 0xe9));
-    canvas.fill_rect(Rect::new(
-        game.player.p.x * CELL_SIZE + offset_x + 10,
-        (game.player.p.y - game.camera_y) * CELL_SIZE + CELL_SIZE / 2 + 2,
-        20,
-        18,
-    ))?;
-
-    canvas.set_draw_color(Color::RGB(0xd2, 0xcb, 0xbd));
-    canvas.fill_rect(Rect::new(
-        INFO_X,
-        0,
-        INFO_WIDTH as u32,
-        SCREEN_HEIGHT as u32,
-    ))?;
-
-    // render air
-    let radius = 30;
-    let circle_x = (INFO_X + INFO_WIDTH / 2) as i16;
-    let circle_y = 270;
-    if game.player.air > 0 {
-        // 外側
-        canvas.filled_pie(
-            circle_x,
-            circle_y,
-            radius as i16,
-            -90,
-            -90 + (360.0 * game.player.air_percent() / 100.0f32) as i16,
-            Color::RGBA(0x01, 0x2f, 0xd0, 254), // なぜかalpha=255だと他の部分まで半透明が効かなくなってしまう
-        )?;
-    }
-    // 内側の円
-    let inner_circle_color = if game.player.air_percent() >= 20.0f32 {
-        Color::RGBA(0xd3, 0xe3, 0xe9, 254)
+    // 落ちているブロックに乗っているときは、ブロックと一緒に下にずらす
+    // 登っているときは登った分だけ上にずらす
+    let below = game.cell_at(game.player.p.x, game.player.p.y + 1);
+    let offset_y = if game.player.state == PlayerState::Climbing {
+        -((game.player.climbing_frames as f32 / CLIMB_FRAMES as f32) * CELL_SIZE as f32) as i32
+    } else if game.player.state == PlayerState::Standing && below.cell_type == CellType::Block {
+        fall_offset_y(game, below)
     } else {
-        Color::RGBA(0xdf, 0x7a, 0x98, 254)
+        0
     };
-    canvas.filled_circle(
-        circle_x,
-        circle_y,
-        (radius / 2 - 1) as i16,
-        inner_circle_color,
-    )?;
-
-    let font = resources.fonts.get_mut("boxfont2.ttf").unwrap();
-    let depth = format!("{0: >4}", game.get_depth());
-    render_font(
-        canvas,
-        font,
-        depth,
-        INFO_X + 5,
-        180,
-        Color::RGBA(0xfe, 0x54, 0x00, 255),
-    );
+    (
+        game.player.p.x * CELL_SIZE + offset_x,
+        game.player.p.y * CELL_SIZE + offset_y,
+    )
+}
 
-    if game.is_over {
-        canvas.set_draw_color(Color::RGBA(255, 0, 0, 128));
-        canvas.fill_rect(Rect::new(
+// 落下中のセルの縦方向のずれ（セルごとの落下のフレーム数に合わせる）
+fn fall_offset_y(game: &Game, cell: &Cell) -> i32 {
+    if !cell.grounded && cell.falling_frames >= 0 {
+        let fall_frames = game.fall_frames_of(cell).max(1);
+        clamp(
             0,
-            0,
-            (SCREEN_WIDTH - INFO_WIDTH) as u32,
-            SCREEN_HEIGHT as u32,
-        ))?;
+            ((cell.falling_frames as f32 / fall_frames as f32) * (CELL_SIZE as f32)) as i32,
+            CELL_SIZE,
+        )
+    } else {
+        0
     }
+}
 
-    if game.is_clear {
-        let font = resources.fonts.get_mut("boxfont2.ttf").unwrap();
-        render_font(
-            canvas,
-            font,
-            "CLEAR!!".to_string(),
-            140,
-            240,
-            Color::RGBA(255, 255, 0, 255),
-        );
+// 情報パネルの左端（盤面の幅）と画面の幅（px）。盤面の幅は設定で変わる
+fn info_x(config: &GameConfig) -> i32 {
+    CELL_SIZE * config.cells_x_len()
+}
+
+fn screen_width(config: &GameConfig) -> i32 {
+    info_x(config) + INFO_WIDTH
+}
+
+// 幅の違う盤面のゲームを読み込んだら、情報パネルが盤面に重ならないようにウィンドウの幅を合わせる
+fn fit_window_width(canvas: &mut Canvas<Window>, game: &Game) -> Result<(), String> {
+    let width = screen_width(&game.config) as u32;
+    if canvas.window().size().0 != width {
+        canvas
+            .window_mut()
+            .set_size(width, SCREEN_HEIGHT as u32)
+            .map_err(|e| e.to_string())?;
     }
+    Ok(())
+}
 
-    if game.is_debug {
-        let frame_str = format!("{0: >6}", game.frame);
-        canvas.set_draw_color(Color::RGBA(255, 255, 255, 255));
-        canvas.fill_rect(Rect::new(0, 0, 50, 16))?;
-        render_number(canvas, resources, 0, 0, 1.0, frame_str);
+// 盤面のセルの数と、セルごとのバッファでの(x, y)の位置（上の行から順）
+fn cell_count(game: &Game) -> usize {
+    (game.config.cells_x_len() * game.config.cells_y_len()) as usize
+}
+
+fn cell_index(game: &Game, x: i32, y: i32) -> usize {
+    (y * game.config.cells_x_len() + x) as usize
+}
+
+fn lerp(from: i32, to: i32, alpha: f32) -> i32 {
+    from + ((to - from) as f32 * alpha).round() as i32
+}
+
+// カメラのずらしの目標（行）。落ちている（または次のフレームに落ち始める）間は下を、
+// 立ち止まっている間は上を広く映す。歩いたり登ったりしている間はずらさない
+fn camera_look_target(game: &Game) -> f32 {
+    if game.is_player_falling() {
+        CAMERA_LOOK_FALLING
+    } else if game.player.state == PlayerState::Standing {
+        CAMERA_LOOK_STANDING
+    } else {
+        0.0
     }
+}
 
-    canvas.present();
+// 不感帯つきのカメラの目標位置（行）。centeredはプレイヤーが画面の中心に来る位置
+// プレイヤーが中心からdeadzone行以内にいれば今の位置のまま、外に出たら出た分だけ動かす
+fn camera_target(current: i32, centered: i32, deadzone: i32) -> i32 {
+    clamp(centered - deadzone, current, centered + deadzone)
+}
 
-    Ok(())
+// 揺れているブロックの横方向のずれ
+// 揺らし終わり（shaking_frames == shake_frames + 1）でちょうど0になるよう位相を合わせてあるので、
+// 揺れるフレーム数がいくつでも落下開始時に位置が飛ばない
+// 揺れの周期はかたまりのリーダーの位置から決めるので、隣り合うかたまりどうしはずれて揺れる
+// 振れ幅は揺れ始めが小さく、落ちる直前ほど大きい
+fn shake_offset_x(shaking_frames: i32, shake_frames: i32, leader: Option<Point>) -> i32 {
+    let period = match leader {
+        Some(p) => SHAKE_PERIOD_MIN + (p.x * 31 + p.y * 17).rem_euclid(SHAKE_PERIOD_VARIANTS),
+        None => SHAKE_PERIOD_MIN + SHAKE_PERIOD_VARIANTS / 2,
+    };
+    let frames_left = shake_frames + 1 - shaking_frames;
+    let progress = (shaking_frames + 1) as f32 / (shake_frames + 1) as f32;
+    let amplitude = SHAKE_PX * progress.min(1.0);
+    let angle = std::f32::consts::TAU * frames_left as f32 / period as f32;
+    (amplitude * angle.sin()).round() as i32
 }
 
 fn render_number(
@@ -385,39 +3116,43 @@ fn render_number(
     resources: &Resources,
     x: i32,
     y: i32,
-    scale: f32,
+    style: NumberStyle,
     numstr: String,
 ) {
-    let mut x = x;
     let image = resources.images.get("numbers.bmp").unwrap();
-    let digit_width_in_px = 8;
+    let advance = ((style.digit_width + style.spacing) as f32 * style.scale) as i32;
+    let mut x = match style.align {
+        NumberAlign::Left => x,
+        NumberAlign::Right => x - advance * numstr.chars().count() as i32,
+    };
     for c in numstr.chars() {
-        if 0x30 <= c as i32 && c as i32 <= 0x39 {
+        if c.is_ascii_digit() {
             canvas
                 .copy(
                     &image.texture,
                     Rect::new(
-                        digit_width_in_px * (c as i32 - 0x30),
+                        style.digit_width * (c as i32 - 0x30),
                         0,
-                        digit_width_in_px as u32,
+                        style.digit_width as u32,
                         image.h,
                     ),
                     Rect::new(
                         x,
                         y,
-                        (digit_width_in_px as f32 * scale) as u32,
-                        (image.h as f32 * scale) as u32,
+                        (style.digit_width as f32 * style.scale) as u32,
+                        (image.h as f32 * style.scale) as u32,
                     ),
                 )
                 .unwrap();
         }
-        x += (digit_width_in_px as f32 * scale) as i32;
+        x += advance;
     }
 }
 
 fn render_font(
     canvas: &mut Canvas<Window>,
-    font: &sdl2::ttf::Font,
+    resources: &Resources,
+    size: u16,
     text: String,
     x: i32,
     y: i32,
@@ -425,7 +3160,7 @@ fn render_font(
 ) {
     let texture_creator = canvas.texture_creator();
 
-    let surface = font.render(&text).blended(color).unwrap();
+    let surface = resources.font(size).render(&text).blended(color).unwrap();
     let texture = texture_creator
         .create_texture_from_surface(&surface)
         .unwrap();
@@ -438,15 +3173,377 @@ fn render_font(
         .unwrap();
 }
 
+// render_fontと同じだが、areaの左上から描き、幅がareaより広ければ縦横比を保って縮める
+fn render_font_fit(
+    canvas: &mut Canvas<Window>,
+    resources: &Resources,
+    size: u16,
+    text: String,
+    area: Rect,
+    color: Color,
+) {
+    let (x, y, max_width) = (area.x(), area.y(), area.width() as i32);
+    let texture_creator = canvas.texture_creator();
+
+    let surface = resources.font(size).render(&text).blended(color).unwrap();
+    let texture = texture_creator
+        .create_texture_from_surface(&surface)
+        .unwrap();
+    let (width, height) = (texture.query().width, texture.query().height);
+    let (width, height) = if width as i32 > max_width {
+        (
+            max_width as u32,
+            (height as i64 * max_width as i64 / width as i64) as u32,
+        )
+    } else {
+        (width, height)
+    };
+    canvas
+        .copy(&texture, None, Rect::new(x, y, width, height))
+        .unwrap();
+}
+
 fn play_sounds(game: &mut Game, resources: &Resources) {
-    for sound_key in &game.requested_sounds {
+    if !resources.audio_enabled {
+        game.requested_sounds = Vec::new();
+        return;
+    }
+    for request in &game.requested_sounds {
         let chunk = resources
             .chunks
-            .get(&sound_key.to_string())
+            .get(request.name)
             .expect("cannot get sound");
-        sdl2::mixer::Channel::all()
-            .play(&chunk, 0)
+        let channel = sdl2::mixer::Channel::all()
+            .play(chunk, 0)
             .expect("cannot play sound");
+        // チャンネルの音量は次に使うときまで残るので、毎回設定する
+        channel.set_volume((request.volume.clamp(0.0, 1.0) * mixer::MAX_VOLUME as f32) as i32);
     }
     game.requested_sounds = Vec::new();
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_plain_play_counts_for_records() {
+        let mut game = Game::new_seeded(1);
+        assert!(counts_for_records(&game, false));
+        assert!(!counts_for_records(&game, true));
+        game.is_debug = true;
+        assert!(!counts_for_records(&game, false));
+        let game = Game::from_seed(
+            1,
+            GameConfig {
+                sandbox: true,
+                ..GameConfig::default()
+            },
+        );
+        assert!(!counts_for_records(&game, false));
+    }
+
+    #[test]
+    fn camera_target_without_deadzone_follows_the_player() {
+        for current in [0, 10, 20] {
+            assert_eq!(camera_target(current, 15, 0), 15);
+        }
+    }
+
+    #[test]
+    fn camera_target_stays_inside_the_deadzone() {
+        // 中心から3行以内なら動かない
+        for centered in 7..=13 {
+            assert_eq!(camera_target(10, centered, 3), 10);
+        }
+        // 外に出たら、出た分だけ動いて帯の端にプレイヤーが来る
+        assert_eq!(camera_target(10, 14, 3), 11);
+        assert_eq!(camera_target(10, 20, 3), 17);
+        assert_eq!(camera_target(10, 6, 3), 9);
+        assert_eq!(camera_target(10, 0, 3), 3);
+    }
+
+    #[test]
+    fn camera_looks_ahead_of_the_player_smoothly() {
+        let mut game = Game::from_layout(
+            "
+            .........
+            .P.......
+            .CCCCCCCC
+            .CCCCCCCC
+            .CCCCCCCC
+            .CCCCCCCC
+            .CCCCCCCC
+            .CCCCCCCC
+            .CCCCCCCC
+            .CCCCCCCC
+            .CCCCCCCC
+            .CCCCCCCC
+            ",
+            GameConfig::default(),
+        )
+        .unwrap();
+        let mut ui = UiState::new();
+        ui.intro_frames = 0;
+        game.update(Command::None);
+        assert_eq!(camera_look_target(&game), CAMERA_LOOK_STANDING);
+
+        // 左の穴へ歩いていく間はずらさず、落ちている間は下を広く映す
+        game.update(Command::Left);
+        ui.update_camera(&game);
+        assert_eq!(game.player.state, PlayerState::Walking);
+        assert_eq!(camera_look_target(&game), 0.0);
+        let mut saw_falling = false;
+        let mut px = ui.camera_px(&game);
+        while game.player.state != PlayerState::Standing || game.player.p.y < 11 {
+            game.update(Command::None);
+            ui.update_camera(&game);
+            if game.player.state == PlayerState::Falling {
+                assert_eq!(camera_look_target(&game), CAMERA_LOOK_FALLING);
+                saw_falling = true;
+            }
+            // 盤面より上は映さない
+            assert!(ui.camera_px(&game) >= 0);
+            px = ui.camera_px(&game);
+        }
+        assert!(saw_falling);
+
+        // 止まったあとは、1フレームに1行より小さい幅でなめらかに目標へ近づく
+        assert_eq!(camera_look_target(&game), CAMERA_LOOK_STANDING);
+        for _ in 0..FPS * 3 {
+            game.update(Command::None);
+            ui.update_camera(&game);
+            let next = ui.camera_px(&game);
+            assert!((next - px).abs() < CELL_SIZE / 2, "{} -> {}", px, next);
+            px = next;
+        }
+        assert_eq!(
+            px,
+            (game.camera_y as f32 + CAMERA_LOOK_STANDING) as i32 * CELL_SIZE
+        );
+    }
+
+    #[test]
+    fn intro_pans_only_when_the_camera_has_somewhere_to_go() {
+        // 既定の盤面ではプレイヤーが最初から画面に収まっているので、演出しない
+        let mut ui = UiState::new();
+        ui.start_stage(&Game::new_seeded(1));
+        assert_eq!(ui.intro_frames, 0);
+
+        // 上の空間が深ければ、地表からプレイヤーまでカメラを動かす
+        let game = Game::from_seed(
+            1,
+            GameConfig {
+                up_space_height: 20,
+                ..GameConfig::default()
+            },
+        );
+        ui.start_stage(&game);
+        assert_eq!(ui.intro_frames, INTRO_FRAMES);
+        assert_eq!(ui.camera_px(&game), 0);
+        // 演出の終わりには、ほぼ移っていく先に着いている
+        ui.intro_frames = 1;
+        let target = ui.intro_target_px(&game);
+        assert!(target > 0);
+        assert!((target - CELL_SIZE / 2..=target).contains(&ui.camera_px(&game)));
+    }
+
+    // 立っているプレイヤーの左右が空いている盤面と、くり返しを有効にしたUI
+    fn auto_repeat_setup() -> (Game, UiState, InputState) {
+        let mut game = Game::from_layout(
+            "
+            .........
+            ....P....
+            YBYBGBYBY
+            ",
+            GameConfig::default(),
+        )
+        .unwrap();
+        game.update(Command::None);
+        let ui = UiState {
+            auto_repeat: true,
+            ..UiState::new()
+        };
+        (game, ui, InputState::new(ControlScheme::Arrows))
+    }
+
+    // 1フレーム分の入力を取り出して、くり返しを反映したコマンドを返す
+    fn next_command(ui: &mut UiState, input: &mut InputState, game: &Game) -> Command {
+        let command = input.take_command();
+        ui.auto_repeat_command(command, input, game)
+    }
+
+    // 押したままでくり返しが始まるまで進め、最初にくり返した方向を返す
+    fn wait_for_repeat(ui: &mut UiState, input: &mut InputState, game: &Game) -> Command {
+        for _ in 0..AUTO_REPEAT_DELAY - 1 {
+            assert_eq!(next_command(ui, input, game), Command::None);
+        }
+        next_command(ui, input, game)
+    }
+
+    #[test]
+    fn auto_repeat_stops_when_every_key_is_released() {
+        let (game, mut ui, mut input) = auto_repeat_setup();
+        input.key_down(Keycode::Left);
+        assert_eq!(next_command(&mut ui, &mut input, &game), Command::Left);
+        assert_eq!(wait_for_repeat(&mut ui, &mut input, &game), Command::Left);
+
+        input.release_all();
+        for _ in 0..AUTO_REPEAT_DELAY * 2 {
+            assert_eq!(next_command(&mut ui, &mut input, &game), Command::None);
+        }
+    }
+
+    #[test]
+    fn auto_repeat_follows_the_latest_held_key() {
+        let (game, mut ui, mut input) = auto_repeat_setup();
+        input.key_down(Keycode::Left);
+        assert_eq!(next_command(&mut ui, &mut input, &game), Command::Left);
+        next_command(&mut ui, &mut input, &game);
+
+        // 左を押したまま右を押すと、右に変わって数え直す
+        input.key_down(Keycode::Right);
+        assert_eq!(next_command(&mut ui, &mut input, &game), Command::Right);
+        assert_eq!(wait_for_repeat(&mut ui, &mut input, &game), Command::Right);
+
+        // 右を離すと、押したままの左に戻って数え直す
+        input.key_up(Keycode::Right);
+        assert_eq!(wait_for_repeat(&mut ui, &mut input, &game), Command::None);
+        assert_eq!(next_command(&mut ui, &mut input, &game), Command::Left);
+    }
+
+    #[test]
+    fn auto_repeat_resumes_after_pressing_again_once_blocked() {
+        let (mut game, mut ui, mut input) = auto_repeat_setup();
+        input.key_down(Keycode::Left);
+        next_command(&mut ui, &mut input, &game);
+        assert_eq!(wait_for_repeat(&mut ui, &mut input, &game), Command::Left);
+
+        // 進めも掘れもしなかったら、押したままでもくり返さない
+        game.events.push(GameEvent::Blocked(Direction::Left));
+        ui.handle_events(&mut game);
+        for _ in 0..AUTO_REPEAT_DELAY * 2 {
+            assert_eq!(next_command(&mut ui, &mut input, &game), Command::None);
+        }
+
+        // 押し直すとその1回が出て、またくり返し始める
+        input.key_up(Keycode::Left);
+        input.key_down(Keycode::Left);
+        assert_eq!(next_command(&mut ui, &mut input, &game), Command::Left);
+        assert_eq!(wait_for_repeat(&mut ui, &mut input, &game), Command::Left);
+    }
+
+    #[test]
+    fn screen_and_cell_coordinates_round_trip() {
+        let game = Game::new_seeded(1);
+        let mut ui = UiState::new();
+        ui.intro_frames = 0;
+        ui.camera_look = 0.0;
+        for camera_row in [0, 7, game.config.cells_y_max()] {
+            ui.camera_row = camera_row;
+            for y in CELLS_Y_MIN..=game.config.cells_y_max() {
+                for x in CELLS_X_MIN..=game.config.cells_x_max() {
+                    let p = Point::new(x, y);
+                    let (sx, sy) = ui.cell_to_screen(&game, p);
+                    if !(0..SCREEN_HEIGHT).contains(&sy) {
+                        continue;
+                    }
+                    // セルの中ならどこを指してもそのセルになる
+                    for (dx, dy) in [(0, 0), (CELL_SIZE - 1, CELL_SIZE - 1)] {
+                        if sy + dy < SCREEN_HEIGHT {
+                            assert_eq!(ui.screen_to_cell(&game, sx + dx, sy + dy), Some(p));
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn screen_points_off_the_board_have_no_cell() {
+        let game = Game::new_seeded(1);
+        let mut ui = UiState::new();
+        ui.intro_frames = 0;
+        ui.camera_look = 0.0;
+        // 負の座標、情報パネル、画面の下
+        assert_eq!(ui.screen_to_cell(&game, -1, 10), None);
+        assert_eq!(ui.screen_to_cell(&game, 10, -1), None);
+        assert_eq!(ui.screen_to_cell(&game, -CELL_SIZE, -CELL_SIZE), None);
+        assert_eq!(ui.screen_to_cell(&game, info_x(&game.config), 10), None);
+        assert_eq!(ui.screen_to_cell(&game, 10, SCREEN_HEIGHT), None);
+        // 盤面の一番下より下を映しているところ
+        ui.camera_row = game.config.cells_y_max();
+        let (_, bottom) = ui.cell_to_screen(&game, Point::new(0, game.config.cells_y_max()));
+        assert_eq!(ui.screen_to_cell(&game, 10, bottom + CELL_SIZE), None);
+        // カメラが一番上なら、画面の一番上が盤面の一番上の行
+        ui.camera_row = 0;
+        assert_eq!(
+            ui.screen_to_cell(&game, 0, 0),
+            Some(Point::new(CELLS_X_MIN, CELLS_Y_MIN))
+        );
+    }
+
+    // 左の茶色のブロックを、押し方と掘り方を決めて壊れるまで掘り、掘った回数と削った量を返す
+    fn dig_brown_block(dig_mode: DigMode, tap: bool) -> (i32, i32) {
+        let mut game = Game::from_layout(
+            "
+            .........
+            ...OP....
+            YBYBGBYBY
+            ",
+            GameConfig::default(),
+        )
+        .unwrap();
+        game.update(Command::None);
+        let mut ui = UiState {
+            auto_repeat: true,
+            dig_mode,
+            ..UiState::new()
+        };
+        let mut input = InputState::new(ControlScheme::Arrows);
+        let brown = Point::new(3, 1);
+        let mut digs = 0;
+        let mut damage = 0;
+        for frame in 0..FPS * 10 {
+            match frame % 10 {
+                0 => {
+                    input.key_down(Keycode::Left);
+                }
+                5 if tap => input.key_up(Keycode::Left),
+                _ => {}
+            }
+            let command = input.take_command();
+            let command = ui.hold_dig_command(command, &input, &game);
+            let command = input.with_soft_drop(ui.auto_repeat_command(command, &input, &game));
+            let life = game.cell(brown).block_life;
+            game.update(command);
+            let broken = game.cell(brown).cell_type != CellType::Block;
+            let left = if broken {
+                0
+            } else {
+                game.cell(brown).block_life
+            };
+            for event in game.events.drain(..) {
+                if event == GameEvent::Dug(Direction::Left) {
+                    digs += 1;
+                    damage += life - left;
+                }
+            }
+            if broken {
+                return (digs, damage);
+            }
+        }
+        panic!("the brown block never broke ({:?})", dig_mode);
+    }
+
+    #[test]
+    fn hold_and_instant_digging_deal_the_same_damage() {
+        let instant = dig_brown_block(DigMode::Instant, true);
+        let hold = dig_brown_block(DigMode::Hold, false);
+        let hits = GameConfig::default().dig_hits_of(BlockColor::Brown);
+        assert!(hits > 1);
+        assert_eq!(instant.0, hits);
+        assert_eq!(hold, instant);
+        assert!(instant.1 >= BLOCK_LIFE_MAX);
+    }
+}
@@ -1,25 +1,79 @@
 use rand::prelude::*;
+use rand_chacha::ChaCha12Rng;
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, HashSet, VecDeque};
 use std::time;
 
+// 盤面の大きさの既定値（実際の大きさはGameConfigで決める）
 pub const UP_SPACE_HEIGHT: i32 = 6; // 初期状態の上の空間の高さ
 pub const NORMAL_BLOCKS_HEIGHT: i32 = 100; // 通常ブロックがある空間の高さ
 pub const CLEAR_BLOCKS_HEIGHT: i32 = 7; // 底にあるクリアブロックの高さ
 pub const CELLS_X_LEN: i32 = 9;
 pub const CELLS_X_MIN: i32 = 0;
-pub const CELLS_X_MAX: i32 = CELLS_X_LEN - 1;
-pub const CELLS_Y_LEN: i32 = UP_SPACE_HEIGHT + NORMAL_BLOCKS_HEIGHT + CLEAR_BLOCKS_HEIGHT;
 pub const CELLS_Y_MIN: i32 = 0;
-pub const CELLS_Y_MAX: i32 = CELLS_Y_LEN - 1;
 
 pub const AIR_MAX: i32 = 3000;
-pub const AIR_SPAWN_INTERVAL: i32 = 20;
+pub const BROWN_AIR_COST: i32 = AIR_MAX * 23 / 100; // 茶色ブロックを壊すと減るエア
+pub const AIR_SPAWN_INTERVAL: i32 = 20; // 1ステージ目でエアカプセルを置く間隔（行）
+pub const AIR_SPAWN_INTERVAL_MIN: i32 = 12; // ステージが進んでもこれより縮めない
+pub const STAGE_BROWN_SCALE: f64 = 0.25; // 1ステージ進むごとに茶色ブロックの出現比率に足す倍率
+pub const STAGE_BROWN_SCALE_MAX: f64 = 4.0; // 茶色ブロックの出現比率の倍率の上限
+pub const STAGE_FALL_PACE: f64 = 0.05; // 1ステージ進むごとにブロックの揺れ・落下の時間を縮める割合
+pub const STAGE_FALL_PACE_MIN: f64 = 0.5; // ブロックの揺れ・落下の時間の倍率の下限
+pub const AIR_BROWN_CLEARANCE: i32 = 2; // エアカプセルの真上に茶色ブロックを置かない行数の既定値
+pub const AIR_BAND_ROWS: i32 = 30; // エアカプセルの数を保証する深さの区切りの既定値（行）
+pub const AIR_BAND_MIN: i32 = 1; // その区切りごとに保証するエアカプセルの数の既定値
 pub const BLOCK_LIFE_MAX: i32 = 100;
+pub const BROWN_DIG_HITS: i32 = 4; // 茶色ブロックを壊すまでに掘る回数の既定値
+pub const PLAYABLE_COLORS: usize = 6; // 通常ブロックの色数の上限
+pub const DEFAULT_COLOR_COUNT: usize = 4; // 通常ブロックの色数の既定値
+pub const BLOCK_COLORS: usize = 8; // 茶色・クリアブロックを含めたブロックの色数
+pub const ALL_COLORS_BONUS: u32 = 1000; // ステージ中に全色のかたまりを消したときのボーナス
+pub const ERASE_POINTS_SCALE: u32 = 10; // かたまりを消したときの点（ブロック数の2乗に掛ける）
 
 pub const FPS: i32 = 30;
 pub const WALK_FRAMES: i32 = 3; // プレイヤーが1マス歩くのにかかるフレーム数
 pub const FALL_FRAMES: i32 = 3; // プレイヤーが1マス落ちるのにかかるフレーム数
-                                // pub const SHAKE_FRAMES: i32 = 48; // 落下予定のブロックがぐらついているフレーム数（揺れるアニメーションが片側4フレームなので、4の倍数）
-pub const SHAKE_FRAMES: i32 = 43; // 落下予定のブロックがぐらついているフレーム数（揺れるアニメーションが片側4フレームなので、4の倍数 - 1）
+pub const SOFT_DROP_FALL_FRAMES: i32 = (FALL_FRAMES + 1) / 2; // 下を押したまま落ちるときの、1マス落ちるのにかかるフレーム数（3に対して2で、1.5倍速い）
+pub const CLIMB_FRAMES: i32 = 6; // プレイヤーが1マス登るのにかかるフレーム数（surface_returnが有効なとき）
+pub const SHAKE_FRAMES: i32 = 43; // 落下予定のブロックがぐらついているフレーム数
+pub const PHANTOM_PERIOD: i32 = FPS * 4; // ファントムブロックが実体化・透明化を1周するフレーム数
+pub const PHANTOM_SOLID_FRAMES: i32 = FPS * 2; // 1周のうち実体化しているフレーム数（周期の最初）
+pub const PHANTOM_RATE: f64 = 0.01; // 出現する深さでセルがファントムブロックになる確率
+pub const VENT_RATE: f64 = 0.005; // 出現する深さで通常ブロックが通気口になる確率
+pub const VENT_AIR_DRAIN: i32 = AIR_MAX / 100; // 通気口のそばにいる間、毎フレーム余分に減るエア
+pub const GIFT_RATE: f64 = 0.01; // 通常ブロックがギフトブロックになる確率
+pub const DRILL_BOOST_FRAMES: i32 = FPS * 10; // ドリル強化が続くフレーム数
+pub const FREEZE_FRAMES: i32 = FPS * 5; // 時間停止が続くフレーム数
+pub const UNDO_HISTORY_LEN: usize = FPS as usize * 4; // デバッグ中に巻き戻せるフレーム数
+pub const ERASE_SOUND_STEPS: u32 = 5; // かたまりを消したときの音の高さの段数（コンボがこれより続いても一番高い音）
+pub const BONK_INTERVAL_FRAMES: i32 = FPS / 4; // 入力を止められた音を続けて鳴らすときに空ける最短のフレーム数
+pub const COMBO_WINDOW_FRAMES: i32 = FPS * 3; // かたまりを消してから、次に消せばコンボになるフレーム数
+pub const PLANNER_FRAMES: i32 = FPS * 4; // 消えるかたまりを予測するとき、最大何フレーム先まで落下を進めるか
+pub const LIVES_MAX: i32 = 3; // 持っていられるライフの最大数
+pub const LAST_BREATH_FRAMES: i32 = FPS * 3; // エアが0になってから息が続くフレーム数（last_breathが有効なとき）
+pub const PAR_SECONDS_PER_ROW: f32 = 1.2; // パータイムを決めるとき、クリアブロックまで1マス降りるのに見込む秒数
+pub const STARS_MAX: u32 = 3; // クリアしたときの評価の最大
+pub const COLLAPSE_CHARGE_FRAMES: i32 = FPS * 10; // 崩落技がたまるまでのフレーム数
+pub const COLLAPSE_AIR_COST: i32 = AIR_MAX / 10; // 崩落技を使うと減るエア
+pub const ENEMY_STEP_FRAMES: i32 = 8; // 敵が1マス進むのにかかるフレーム数（enemiesが有効なとき）
+pub const ENEMY_SPAWN_FRAMES: i32 = FPS * 6; // 敵を出そうとする間隔（フレーム）
+pub const ENEMY_MAX: usize = 3; // 同時にいる敵の数の上限
+pub const ENEMY_SPAWN_DISTANCE: i32 = 5; // プレイヤーからこのマス数（縦横の合計）より近いところには敵を出さない
+pub const ENEMY_SPAWN_ROWS: i32 = 12; // プレイヤーから上下この行数までのところに敵を出す
+
+// チュートリアル用のステージ（Game::from_layoutの書式）
+pub const TUTORIAL_LAYOUT: &str = "
+.........
+.........
+.........
+.........
+.........
+....P....
+GAGGRGGGG
+BBBBBBBBB
+CCCCCCCCC
+";
 
 #[derive(Debug, Clone, Copy, Eq, PartialEq)]
 pub enum Command {
@@ -28,6 +82,9 @@ pub enum Command {
     Right,
     Down,
     Up,
+    Collapse, // 足元の列を崩す（崩落技）
+    Refill,   // プレイヤーより上をブロックで埋めなおす（サンドボックスのときだけ）
+    SoftDrop, // 落下中に下を押したままにしている（速く落ちるだけで、掘らない）
 }
 
 #[derive(Debug, Clone, Copy, Eq, PartialEq)]
@@ -57,6 +114,55 @@ impl Direction {
             _ => panic!(),
         }
     }
+
+    pub fn opposite(self) -> Direction {
+        match self {
+            Direction::Left => Direction::Right,
+            Direction::Right => Direction::Left,
+            Direction::Up => Direction::Down,
+            Direction::Down => Direction::Up,
+        }
+    }
+}
+
+// 描画側に鳴らしてほしい音
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SoundRequest {
+    pub name: &'static str, // resources/soundのファイル名
+    pub volume: f32,        // 音量（1.0がファイルのままの大きさ）
+}
+
+impl SoundRequest {
+    pub fn new(name: &'static str) -> Self {
+        SoundRequest { name, volume: 1.0 }
+    }
+
+    // かたまりを消した音。コンボが続くほど高い音になり、少しずつ大きくなる
+    pub fn erase(combo: u32) -> Self {
+        const NAMES: [&str; ERASE_SOUND_STEPS as usize] = [
+            "erase1.wav",
+            "erase2.wav",
+            "erase3.wav",
+            "erase4.wav",
+            "erase5.wav",
+        ];
+        let step = combo.clamp(1, ERASE_SOUND_STEPS);
+        SoundRequest {
+            name: NAMES[step as usize - 1],
+            volume: 0.6 + 0.1 * (step - 1) as f32,
+        }
+    }
+}
+
+// 描画側に伝えるゲーム内の出来事。requested_soundsと同じく毎フレーム描画側で取り出して空にする
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum GameEvent {
+    Blocked(Direction),          // 入力された方向に歩くことも掘ることもできなかった
+    Dug(Direction), // 入力された方向のブロックを掘った（何回も掘るブロックで、まだ壊れていなくても）
+    AirCollected,   // エアを取った
+    Erased(i32),    // 落ちてきたブロックがつながって消えた（消えたブロックの数）
+    Vanished(Point, BlockColor), // 掘ったブロックのセルが消えた（セルごとに1つ。消える演出用）
+    Scored(Point, u32), // かたまりが消えて点をもらった（かたまりの重心に一番近いセル、もらった点）
 }
 
 #[derive(Debug, Clone, Copy, Eq, PartialEq)]
@@ -64,6 +170,66 @@ pub enum CellType {
     None,
     Air,
     Block,
+    Item(ItemKind), // プレイヤーが重なると拾うアイテム
+    Phantom,        // 一定周期で実体化と透明化をくり返すブロック。掘れず、落ちない
+    Vent,           // 噴き出すガスでそばにいるプレイヤーのエアを急速に奪う通気口。掘れず、落ちない
+}
+
+impl CellType {
+    // プレイヤーが歩いて入ったり落ちて通り抜けたりできるか
+    pub fn is_passable(self) -> bool {
+        matches!(self, CellType::None | CellType::Air | CellType::Item(_))
+    }
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum ItemKind {
+    Key,     // クリアブロックを掘るのに必要な鍵（clear_requires_keyが有効なときだけ出る）
+    AirTank, // エアを多めに回復する
+    Drill,   // しばらくの間、茶色ブロックを1回で掘れる
+    Life,    // エア切れやつぶされたときに1回だけ復活できる
+    Freeze,  // しばらくの間、ブロックのぐらつきと落下が止まる
+}
+
+impl ItemKind {
+    // ギフトブロックから出るアイテム
+    pub const POWER_UPS: [ItemKind; 4] = [
+        ItemKind::AirTank,
+        ItemKind::Drill,
+        ItemKind::Life,
+        ItemKind::Freeze,
+    ];
+}
+
+// エアカプセルの大きさ。大きいほど取ったときに多く回復する
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum AirCapacity {
+    Small,
+    Medium,
+    Large,
+}
+
+impl AirCapacity {
+    // 盤面に置くときの抽選の重み（小さいものほど多い）
+    pub const WEIGHTS: [(AirCapacity, f64); 3] = [
+        (AirCapacity::Small, 0.6),
+        (AirCapacity::Medium, 0.3),
+        (AirCapacity::Large, 0.1),
+    ];
+
+    // 取ったときに回復するエア（AIR_MAXに対する割合）
+    pub fn restore_ratio(self) -> f32 {
+        match self {
+            AirCapacity::Small => 0.1,
+            AirCapacity::Medium => 0.2,
+            AirCapacity::Large => 0.4,
+        }
+    }
+
+    // 取ったときに回復するエア
+    pub fn restore(self) -> i32 {
+        (AIR_MAX as f32 * self.restore_ratio()) as i32
+    }
 }
 
 #[derive(Debug, Clone, Copy, Eq, PartialEq)]
@@ -74,21 +240,285 @@ pub enum BlockColor {
     Blue,
     Clear,
     Brown,
+    Purple, // 5色目以降は色数を増やしたときだけ出る
+    Cyan,
 }
 
 impl BlockColor {
-    fn from_u32(n: u32) -> Self {
-        match n % 4 {
-            0 => BlockColor::Red,
-            1 => BlockColor::Yellow,
-            2 => BlockColor::Green,
-            3 => BlockColor::Blue,
-            _ => panic!(),
+    // 通常ブロックの色。色数を減らしたときは先頭から使う
+    pub const PLAYABLE: [BlockColor; PLAYABLE_COLORS] = [
+        BlockColor::Red,
+        BlockColor::Yellow,
+        BlockColor::Green,
+        BlockColor::Blue,
+        BlockColor::Purple,
+        BlockColor::Cyan,
+    ];
+
+    // 通常ブロックの色の通し番号（茶色・クリアブロックはNone）
+    pub fn playable_index(self) -> Option<usize> {
+        BlockColor::PLAYABLE.iter().position(|&c| c == self)
+    }
+
+    // すべての色（列挙の並び順。as usizeで添字にできる）
+    pub const ALL: [BlockColor; BLOCK_COLORS] = [
+        BlockColor::Red,
+        BlockColor::Yellow,
+        BlockColor::Green,
+        BlockColor::Blue,
+        BlockColor::Clear,
+        BlockColor::Brown,
+        BlockColor::Purple,
+        BlockColor::Cyan,
+    ];
+}
+
+// ステージ生成などのゲーム設定
+#[derive(Debug, Clone)]
+pub struct GameConfig {
+    // 通常ブロックの色ごとの出現比率。合計が1でなくてもよい（合計で割って使う）
+    // 色の並び順も抽選結果に影響するので、同じシードで同じ盤面を作るためにVecで持つ
+    pub block_weights: Vec<(BlockColor, f64)>,
+    // 掘りアシスト：横に掘ろうとしたとき、安全に掘れるなら代わりに真下を掘って降下を続ける
+    pub dig_assist: bool,
+    // trueなら接地していないかたまりは1つの単位として揺れて落ちる（セルごとのタイマーをそろえる）
+    // falseならセルごとに自分のタイマーで落ちる
+    pub rigid_fall: bool,
+    // trueならステージのどこかにある鍵を拾うまでクリアブロックを掘れない
+    // falseならクリアブロックを掘った時点でクリア
+    pub clear_requires_key: bool,
+    // trueなら地上帰還モード：深いところにある鍵を拾って、掘った穴を登って地上（最初の空間）に
+    // 戻ればクリア。クリアブロックは掘れない。壁（左右のブロック）があれば上に登れ、登ったあとは
+    // 壁につかまって落ちない
+    pub surface_return: bool,
+    // trueならエアが0になってもLAST_BREATH_FRAMESの間は息が続き、その間にエアを取れば助かる
+    pub last_breath: bool,
+    // 掘るたびに余分に減るエア（毎フレームの減少とは別）
+    pub dig_air_cost: i32,
+    // 1マス歩くたびに余分に減るエア（毎フレームの減少とは別）
+    pub walk_air_cost: i32,
+    // trueならサンドボックス：エアが減らず、つぶされてもゲームオーバーにならない
+    // Command::Refillでプレイヤーより上をブロックで埋めなおして、落下や消去を何度でも試せる
+    pub sandbox: bool,
+    // 次のステージに持ち越すエアの割合（前のステージの残りエアに掛ける）。Noneなら満タンから始める
+    pub air_carry: Option<f64>,
+    // trueならエアも上のブロックを支える（エアの上に積まれたブロックは落ちない）
+    // falseなら支えるのはブロックなどだけで、エアの上のブロックはエアをつぶして落ちる
+    pub grounding_through_air: bool,
+    // 色ごとに、ブロックが揺れて落ちるまでのフレーム数に掛ける倍率（1より小さいと重くて速く落ちる）
+    // ここにない色は1.0
+    pub fall_pace: Vec<(BlockColor, f64)>,
+    // trueならブロックが揺れ始めたときに地鳴りを鳴らす。音だけの設定なので、リプレイには書かない
+    pub shake_warning: bool,
+    // 色ごとに、ブロックを壊すまでに掘る回数（ドリル強化中はどれも1回）。ここにない色は1回
+    // 同じ色が2回出てきたら後のものを使う（set_dig_hits参照）
+    pub dig_hits: Vec<(BlockColor, i32)>,
+    // ステージのパータイム（秒）。Noneならクリアブロックまでの深さから決める
+    pub par_seconds: Option<f32>,
+    // エアカプセルの真上のこの行数までは茶色ブロックを置かない（掘りにくいブロックでエアがふさがれないように）
+    // 0なら置いてもよい
+    pub air_brown_clearance: i32,
+    // 通常ブロックのある深さをこの行数ごとに区切り、それぞれにエアカプセルがair_band_min個以上あるようにする
+    // 足りない区切りには通常ブロックのどこかをエアカプセルにして足す。0なら保証しない
+    pub air_band_rows: i32,
+    pub air_band_min: i32,
+    // trueなら落ちてきたブロックがプレイヤーのセルに入ったとき、左右が空いていればそちらへ押し出す
+    // （向いている方を先に調べる）。どちらもふさがっているときだけつぶされる
+    pub block_push: bool,
+    // trueなら掘った横穴にときどき敵が出て、左右に行き来する。触れるとライフを失う
+    pub enemies: bool,
+    // trueならプレイヤーは左右の端から反対側の端へ回り込んで歩いたり掘ったりできる
+    // 回り込むのはプレイヤーの移動と掘る向きだけで、ブロックの落下やつながりの判定は端で切れたまま
+    pub wrap_horizontal: bool,
+    // 盤面の大きさ。幅と、上から順に最初の空間・通常ブロック・クリアブロックの高さ
+    pub width: i32,
+    pub up_space_height: i32,
+    pub normal_blocks_height: i32,
+    pub clear_blocks_height: i32,
+}
+
+impl Default for GameConfig {
+    fn default() -> Self {
+        GameConfig::with_color_count(DEFAULT_COLOR_COUNT)
+    }
+}
+
+impl GameConfig {
+    // 通常ブロックをcount色にした設定。色が少ないほどつながりやすく簡単になる
+    // 茶色ブロックの比率は色数によらず0.05で、残りを通常ブロックの色で等分する
+    pub fn with_color_count(count: usize) -> Self {
+        let count = clamp(1, count, PLAYABLE_COLORS);
+        let mut block_weights: Vec<(BlockColor, f64)> = BlockColor::PLAYABLE[..count]
+            .iter()
+            .map(|&color| (color, 0.95 / count as f64))
+            .collect();
+        block_weights.push((BlockColor::Brown, 0.05));
+        GameConfig {
+            block_weights,
+            dig_assist: false,
+            rigid_fall: false,
+            clear_requires_key: false,
+            surface_return: false,
+            sandbox: false,
+            last_breath: false,
+            dig_air_cost: 0,
+            walk_air_cost: 0,
+            air_carry: None,
+            grounding_through_air: true,
+            fall_pace: Vec::new(),
+            shake_warning: true,
+            dig_hits: vec![(BlockColor::Brown, BROWN_DIG_HITS)],
+            par_seconds: None,
+            air_brown_clearance: AIR_BROWN_CLEARANCE,
+            air_band_rows: AIR_BAND_ROWS,
+            air_band_min: AIR_BAND_MIN,
+            block_push: false,
+            enemies: false,
+            wrap_horizontal: false,
+            width: CELLS_X_LEN,
+            up_space_height: UP_SPACE_HEIGHT,
+            normal_blocks_height: NORMAL_BLOCKS_HEIGHT,
+            clear_blocks_height: CLEAR_BLOCKS_HEIGHT,
+        }
+    }
+
+    pub fn cells_x_len(&self) -> i32 {
+        self.width
+    }
+
+    pub fn cells_y_len(&self) -> i32 {
+        self.up_space_height + self.normal_blocks_height + self.clear_blocks_height
+    }
+
+    pub fn cells_x_max(&self) -> i32 {
+        self.cells_x_len() - 1
+    }
+
+    pub fn cells_y_max(&self) -> i32 {
+        self.cells_y_len() - 1
+    }
+
+    // ファントムブロックが出始める深さ（通常ブロックの高さの半分）
+    pub fn phantom_min_depth(&self) -> i32 {
+        self.normal_blocks_height / 2
+    }
+
+    // 通気口が出始める深さ（通常ブロックの高さの3/5）
+    pub fn vent_min_depth(&self) -> i32 {
+        self.normal_blocks_height * 3 / 5
+    }
+
+    pub fn fall_pace_of(&self, color: BlockColor) -> f64 {
+        self.fall_pace
+            .iter()
+            .find(|&&(c, _)| c == color)
+            .map_or(1.0, |&(_, pace)| pace)
+    }
+
+    pub fn dig_hits_of(&self, color: BlockColor) -> i32 {
+        self.dig_hits
+            .iter()
+            .rev()
+            .find(|&&(c, _)| c == color)
+            .map_or(1, |&(_, hits)| hits.max(1))
+    }
+
+    // 色の掘る回数を決める（すでにあれば置き換える）
+    pub fn set_dig_hits(&mut self, color: BlockColor, hits: i32) {
+        self.dig_hits.retain(|&(c, _)| c != color);
+        self.dig_hits.push((color, hits));
+    }
+
+    // 1回掘るとその色のブロックのblock_lifeが減る量。dig_hits_of回掘るとBLOCK_LIFE_MAXがちょうど0以下になる
+    pub fn dig_damage(&self, color: BlockColor) -> i32 {
+        let hits = self.dig_hits_of(color);
+        (BLOCK_LIFE_MAX + hits - 1) / hits
+    }
+
+    // ブロックの色を抽選できるか（正の出現比率が1つ以上あるか）
+    pub fn has_block_weights(&self) -> bool {
+        self.block_weights.iter().any(|&(_, weight)| weight > 0.0)
+    }
+
+    // 盤面に出る通常ブロックの色
+    pub fn active_colors(&self) -> Vec<BlockColor> {
+        self.block_weights
+            .iter()
+            .filter(|&&(color, weight)| weight > 0.0 && color.playable_index().is_some())
+            .map(|&(color, _)| color)
+            .collect()
+    }
+}
+
+// ステージ番号から決まる難しさ。1ステージ目は倍率がすべて1で、進むほど
+// 茶色ブロックが増え、ブロックが速く崩れ落ちる。掘るのに使うエアが増える分、エアカプセルの間隔は縮める
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Difficulty {
+    pub brown_scale: f64,        // 茶色ブロックの出現比率に掛ける倍率
+    pub air_spawn_interval: i32, // エアカプセルを置く間隔（行）
+    pub fall_pace: f64, // ブロックの揺れ・落下の時間に掛ける倍率（色ごとのfall_paceとは別）
+}
+
+impl Difficulty {
+    pub fn for_stage(stage: u32) -> Self {
+        let n = stage.max(1) - 1;
+        Difficulty {
+            brown_scale: (1.0 + STAGE_BROWN_SCALE * n as f64).min(STAGE_BROWN_SCALE_MAX),
+            air_spawn_interval: (AIR_SPAWN_INTERVAL - n as i32).max(AIR_SPAWN_INTERVAL_MIN),
+            fall_pace: (1.0 - STAGE_FALL_PACE * n as f64).max(STAGE_FALL_PACE_MIN),
         }
     }
 }
 
-#[derive(Clone, Copy)]
+// 盤面とプレイヤー以外の、ゲームプレイに関わる状態（StateDiffで送る）
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct GameStatus {
+    pub frame: i32,
+    pub camera_y: i32,
+    pub depth: i32,
+    pub is_over: bool,
+    pub is_clear: bool,
+    pub clear_frame: i32,
+    pub blocks_dug: i32,
+    pub blocks_dug_by_color: [i32; BLOCK_COLORS],
+    pub score: u32,
+    pub colors_erased: [bool; PLAYABLE_COLORS],
+    pub has_key: bool,
+    pub lives: i32,
+    pub drill_boost_frames: i32,
+    pub freeze_frames: i32,
+    pub collapse_charge: i32,
+    pub vent_frames: i32,
+    pub combo: u32,
+    pub last_erase_frame: i32,
+    pub last_breath_frames: i32,
+    pub rng_word_pos: u128, // rngの今の位置（シードは同じはずなので位置だけ合わせる）
+}
+
+// 2つの状態の差分（Game::diffで作り、Game::apply_diffで当てる）。変わったセルと、
+// 変わっていればプレイヤーとそれ以外の状態だけを持つ。通信で毎フレームの状態を小さく送るため
+// rngは位置だけを送る（同じシードから作ったゲームどうしでないと、続きの乱数はそろわない）
+#[derive(Clone, Default)]
+pub struct StateDiff {
+    pub cells: Vec<(Point, Cell)>,
+    pub player: Option<Player>,
+    pub enemies: Option<Vec<Enemy>>, // 変わっていれば、すべての敵
+    pub status: Option<GameStatus>,
+}
+
+// 着地すると消えるかたまりのセル（着地後の位置と色）。Game::predict_erase_groupsで取得する
+pub type ErasePlan = Vec<(Point, BlockColor)>;
+
+// 盤面全体を1回なめて数えた集計。Game::scan_statsで取得する
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct BoardStats {
+    pub blocks_by_color: [i32; BLOCK_COLORS], // 色ごとのブロック数（BlockColorの並び順）
+    pub ungrounded: i32,                      // 接地していないブロック数
+    pub air_capsules: i32,                    // 盤面に残っているエアの数
+    pub components: i32,                      // ブロックのかたまりの数
+}
+
+#[derive(Clone, Copy, Eq, PartialEq)]
 pub struct Cell {
     pub cell_type: CellType,
     pub color: BlockColor,
@@ -97,11 +527,17 @@ pub struct Cell {
     pub grounded: bool,
     pub shaking_frames: i32,
     pub falling_frames: i32,
-    pub fell: bool, // このフレームに落下したか
+    pub fell: bool,            // このフレームに落下したか
+    pub gift: bool,            // ギフトブロック：かたまりごと消えたときにアイテムを落とす
+    pub loose: bool, // 崩落技で横のつながりを切られている。真下に支えがなければ落ちる（着地すると戻る）
+    pub capacity: AirCapacity, // エアカプセルの大きさ（エアのセルのときだけ意味がある）
 }
 
+// 世界の外のセル。描画などで範囲外を参照したときに何もないセルとして返す
+static OFF_WORLD_CELL: Cell = Cell::new();
+
 impl Cell {
-    fn new() -> Self {
+    const fn new() -> Self {
         Cell {
             cell_type: CellType::None,
             color: BlockColor::Red,
@@ -111,6 +547,9 @@ impl Cell {
             shaking_frames: -1,
             falling_frames: -1,
             fell: false,
+            gift: false,
+            loose: false,
+            capacity: AirCapacity::Medium,
         }
     }
 }
@@ -124,7 +563,42 @@ impl std::fmt::Debug for Cell {
             BlockColor::Blue => "B",
             BlockColor::Clear => "C",
             BlockColor::Brown => "O",
+            BlockColor::Purple => "V",
+            BlockColor::Cyan => "T",
         };
+        if f.alternate() {
+            // {:#?}ではすべての状態を1行ずつ表示する
+            let cell_type = match self.cell_type {
+                CellType::None => "None",
+                CellType::Air => "Air",
+                CellType::Block => "Block",
+                CellType::Item(ItemKind::Key) => "Key",
+                CellType::Item(ItemKind::AirTank) => "AirTank",
+                CellType::Item(ItemKind::Drill) => "Drill",
+                CellType::Item(ItemKind::Life) => "Life",
+                CellType::Item(ItemKind::Freeze) => "Freeze",
+                CellType::Phantom => "Phantom",
+                CellType::Vent => "Vent",
+            };
+            return write!(
+                f,
+                "{}{}{}{}\ncolor: {:?}\nleader: {:?}\nlife: {}\ngrounded: {}\nshaking: {}\nfalling: {}",
+                cell_type,
+                if self.gift { " (gift)" } else { "" },
+                if self.loose { " (loose)" } else { "" },
+                if self.cell_type == CellType::Air {
+                    format!(" ({:?})", self.capacity)
+                } else {
+                    String::new()
+                },
+                self.color,
+                self.leader.map(|p| (p.x, p.y)),
+                self.block_life,
+                self.grounded,
+                self.shaking_frames,
+                self.falling_frames
+            );
+        }
         let grounded = if self.grounded { "o" } else { "x" };
         let leader = if let Some(p) = self.leader {
             format!("{},{}", p.x, p.y)
@@ -134,6 +608,13 @@ impl std::fmt::Debug for Cell {
         match self.cell_type {
             CellType::None => write!(f, "None").unwrap(),
             CellType::Air => write!(f, "Air ").unwrap(),
+            CellType::Item(ItemKind::Key) => write!(f, "Key ").unwrap(),
+            CellType::Item(ItemKind::AirTank) => write!(f, "Tank").unwrap(),
+            CellType::Item(ItemKind::Drill) => write!(f, "Drl ").unwrap(),
+            CellType::Item(ItemKind::Life) => write!(f, "Life").unwrap(),
+            CellType::Item(ItemKind::Freeze) => write!(f, "Frz ").unwrap(),
+            CellType::Phantom => write!(f, "Phtm").unwrap(),
+            CellType::Vent => write!(f, "Vent").unwrap(),
             CellType::Block => write!(
                 f,
                 "{}({}){:?} {}",
@@ -145,19 +626,18 @@ impl std::fmt::Debug for Cell {
     }
 }
 
-#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
 pub struct Point {
     pub x: i32,
     pub y: i32,
 }
 
 impl Point {
+    // 上限は盤面の大きさ（GameConfig）によるので、ここでは下限だけ調べる
     pub fn new(x: i32, y: i32) -> Self {
         assert!(x >= CELLS_X_MIN);
-        assert!(x <= CELLS_X_MAX);
         assert!(y >= CELLS_Y_MIN);
-        assert!(y <= CELLS_Y_MAX);
-        Point { x: x, y: y }
+        Point { x, y }
     }
 }
 
@@ -166,8 +646,58 @@ pub enum PlayerState {
     Standing,
     Walking,
     Falling,
+    Climbing,
+}
+
+// updateが1フレームに行う処理の段階。UpdateStep::ORDERの順に行う
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum UpdateStep {
+    PlayerMove,     // プレイヤーの歩行・落下を進める
+    FallBlocks,     // 接地していないブロックを揺らし、落とす
+    SetLeaders,     // ブロックのつながりを判定し直す
+    EraseBlocks,    // 落ちてきて4個以上つながったかたまりを消す（コンボの時間切れもここ）
+    Command,        // 入力に応じて掘る・歩き出す
+    UpdateGrounded, // 接地判定をし直す
+    CollectItems,   // プレイヤーのいるセルのエアやアイテムを取る
+    TickPowerUps,   // パワーアップの残り時間を減らす
+    ConsumeAir,     // エアを減らし、尽きたらライフを失う
+    CheckCrush,     // ブロックにつぶされていたらライフを失う
+    MoveEnemies,    // 敵を出し、進める。プレイヤーに触れたらライフを失う
+}
+
+impl UpdateStep {
+    // 接地判定は掘った結果を反映させるため入力の後に、エアやアイテムは移動し終えた位置で取る
+    // 同じフレームに落下・消去と掘ることが重なったときは次のように決まる
+    // ・消去は入力より先に行う。消えたセルを掘ろうとしても、上下なら何も起きず、左右なら歩き出す
+    // ・このフレームに落ちてきて同じ色のブロックに触れたブロックは、SetLeadersでそのかたまりに加わるが、
+    //   接地するのはUpdateGroundedから。その間に掘っても一緒には消えず、落ち続ける（dig参照）
+    // ・掘って支えがなくなったブロックは、同じフレームのUpdateGroundedで接地していないことになり、
+    //   次のフレームからぐらつく（このフレームに落ちてきたブロックはぐらつかずにそのまま落ち続ける）
+    // 歩いている途中のプレイヤーのセルと落ちてくるブロックは次のように決まる
+    // ・player.pは歩き終えたフレームのPlayerMoveで移動先に変わる。それまでは元のセルのまま
+    // ・ただし元のセルは歩き出した次のフレームから空いたものとして扱う。それ以降にそこへブロックが
+    //   落ちてきてもつぶされない（check_crush参照）。歩き出したフレームのFallBlocksで落ちてきたブロックには
+    //   つぶされる（入力より先に落ちてきているので、歩き出しても逃げられない）
+    // ・移動先に歩いている間にブロックが落ちてきていたら、歩き終えたフレームのCheckCrushでつぶされる
+    // 落下と接地の解決はセルを調べる順番に左右されない（full_sweepで盤面全体を調べても同じ結果になる）
+    // ・UpdateGroundedは下の行から上へ調べ、接地はかたまりごとまとめて決める
+    // ・FallBlocksは列ごとに下から上へ進め、落ち終わったセルの真下に落ちている途中のセルがあれば下のセルを優先して待つ
+    pub const ORDER: [UpdateStep; 11] = [
+        UpdateStep::PlayerMove,
+        UpdateStep::FallBlocks,
+        UpdateStep::SetLeaders,
+        UpdateStep::EraseBlocks,
+        UpdateStep::Command,
+        UpdateStep::UpdateGrounded,
+        UpdateStep::CollectItems,
+        UpdateStep::TickPowerUps,
+        UpdateStep::ConsumeAir,
+        UpdateStep::CheckCrush,
+        UpdateStep::MoveEnemies,
+    ];
 }
 
+#[derive(Clone, Eq, PartialEq)]
 pub struct Player {
     pub p: Point,
     pub air: i32,
@@ -175,20 +705,24 @@ pub struct Player {
     pub direction: Direction,
     pub walking_frames: i32,
     pub falling_frames: i32,
+    pub climbing_frames: i32,
+    pub clinging: bool, // 登ったあと壁につかまっていて、足元が空いていても落ちない
 }
 
 impl Player {
-    pub fn new() -> Self {
-        let player = Player {
-            p: Point::new(CELLS_X_LEN / 2, 5),
+    // 盤面の真ん中の、最初の空間の一番下の行に置く
+    pub fn new(config: &GameConfig) -> Self {
+        Player {
+            p: Point::new(config.cells_x_len() / 2, config.up_space_height - 1),
             // p: Point::new(5, 13),
             air: AIR_MAX,
             direction: Direction::Left,
             walking_frames: 0,
             falling_frames: 0,
+            climbing_frames: 0,
+            clinging: false,
             state: PlayerState::Standing,
-        };
-        player
+        }
     }
 
     pub fn air_percent(&self) -> f32 {
@@ -196,176 +730,917 @@ impl Player {
     }
 }
 
+// 掘った横穴を左右に行き来する敵。空いたセル（CellType::None）だけを進み、進めなければ向きを変える
+// プレイヤーと同じセルに入るとプレイヤーはライフを失う
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct Enemy {
+    pub p: Point,
+    pub direction: Direction, // LeftかRight
+}
+
+// ブロックのかたまりの索引。set_leadersで1フレームに1回作り直す
+// 素集合森でつながりをまとめ、リーダーごとにかたまりのセルを並べておくので、
+// get_componentは盤面全体を調べずにかたまりの大きさだけの手間で答えられる
+#[derive(Clone, Default)]
+struct ComponentIndex {
+    width: i32,               // 盤面の幅（セルの番号はy * width + x）
+    parent: Vec<usize>,       // 素集合森の親（根はかたまりのうち一番上の行の一番左のセル）
+    members: Vec<Vec<Point>>, // リーダーのセルごとの、そのリーダーを持つセル（上の行から順に）
+}
+
+impl ComponentIndex {
+    fn index(&self, p: Point) -> usize {
+        (p.y * self.width + p.x) as usize
+    }
+
+    fn point(&self, i: usize) -> Point {
+        Point::new(i as i32 % self.width, i as i32 / self.width)
+    }
+
+    // 盤面の大きさを合わせて、すべてのセルを1つずつ別のかたまりにする
+    fn reset(&mut self, config: &GameConfig) {
+        self.width = config.cells_x_len();
+        let len = (config.cells_x_len() * config.cells_y_len()) as usize;
+        self.parent.clear();
+        self.parent.extend(0..len);
+    }
+
+    fn find(&mut self, i: usize) -> usize {
+        let mut root = i;
+        while self.parent[root] != root {
+            root = self.parent[root];
+        }
+        // 経路圧縮
+        let mut i = i;
+        while self.parent[i] != root {
+            let next = self.parent[i];
+            self.parent[i] = root;
+            i = next;
+        }
+        root
+    }
+
+    // 小さい番号の根に寄せるので、根はいつもかたまりで最初に走査されるセルになる
+    // （再帰でたどっていたころのリーダーの決まり方と同じ）
+    fn union(&mut self, a: usize, b: usize) {
+        let (a, b) = (self.find(a), self.find(b));
+        if a != b {
+            self.parent[a.max(b)] = a.min(b);
+        }
+    }
+
+    // セルに書いてあるリーダーからかたまりのセルの一覧を作り直す
+    fn collect(&mut self, cells: &[Vec<Cell>]) {
+        self.width = cells.first().map_or(0, |row| row.len() as i32);
+        let len = cells.len() * self.width as usize;
+        self.members.resize(len, Vec::new());
+        for members in &mut self.members {
+            members.clear();
+        }
+        for (y, row) in cells.iter().enumerate() {
+            for (x, cell) in row.iter().enumerate() {
+                if let Some(leader) = cell.leader {
+                    let i = self.index(leader);
+                    self.members[i].push(Point::new(x as i32, y as i32));
+                }
+            }
+        }
+    }
+
+    fn get(&self, leader: Point) -> Option<&[Point]> {
+        self.members.get(self.index(leader)).map(|m| m.as_slice())
+    }
+}
+
+#[derive(Clone)]
 pub struct Game {
-    pub rng: StdRng,
+    pub config: GameConfig,
+    pub seed: u64,        // 盤面生成に使った乱数のシード。同じシードでやり直すのに使う
+    pub rng: ChaCha12Rng, // StdRngと同じ乱数列になる。今の位置を取り出して状態ハッシュやセーブデータに含められるのでこちらを使う
     pub is_debug: bool,
     pub is_over: bool,
     pub is_clear: bool,
+    pub is_paused: bool, // Pキーで一時停止しているか（状態のハッシュやリプレイには含めない）
+    pub stage: u32,      // 何ステージ目か（1から）。next_stageで1つ進む
+    pub difficulty: Difficulty, // stageから決まる難しさ
     pub frame: i32,
     pub player: Player,
-    pub requested_sounds: Vec<&'static str>,
-    pub cells: [[Cell; CELLS_X_LEN as usize]; CELLS_Y_LEN as usize],
+    pub enemies: Vec<Enemy>, // config.enemiesが有効なときだけ出る
+    pub requested_sounds: Vec<SoundRequest>,
+    pub events: Vec<GameEvent>,
+    blocked: Option<Direction>, // このフレームの入力が止められた方向（入力の処理の最後に知らせる）
+    blocked_input: Option<(Point, Command)>, // 直前に止められた入力と、そのときのプレイヤーの位置
+    last_bonk_frame: Option<i32>, // 最後に入力を止められた音を鳴らしたフレーム
+    cells: Vec<Vec<Cell>>, // cells[y][x]。大きさはconfigの幅と高さ。書き換えはcell_mutなどを通す（差分処理のため）
     pub camera_y: i32,
     pub depth: i32,
+    pub blocks_dug: i32, // このステージでプレイヤーが掘って壊したブロック数
+    pub blocks_dug_by_color: [i32; BLOCK_COLORS], // blocks_dugの色ごとの内訳（BlockColorの並び順）
+    pub score: u32,
+    pub colors_erased: [bool; PLAYABLE_COLORS], // このステージで各色のかたまりを落下で消したか
+    pub has_key: bool,                          // このステージで鍵を拾ったか
+    pub lives: i32, // 残りライフ（0ならエア切れやつぶされたときにゲームオーバー）
+    pub drill_boost_frames: i32, // ドリル強化の残りフレーム数
+    pub freeze_frames: i32, // 時間停止の残りフレーム数
+    pub collapse_charge: i32, // 崩落技のたまり具合（COLLAPSE_CHARGE_FRAMESでたまりきる）
+    pub vent_frames: i32, // 通気口のそばに続けているフレーム数（そばにいなければ0）
+    pub combo: u32, // COMBO_WINDOW_FRAMES以内の間隔で続けて消したかたまりの数（途切れたら0）
+    pub last_erase_frame: i32, // 最後にかたまりを消したフレーム（まだ消していなければ-1）
+    pub last_breath_frames: i32, // 息が続く残りフレーム数（エアが残っていれば-1）
+    pub clear_frame: i32, // クリアしたフレーム（未クリアなら-1）
+    pub par_seconds: f32, // このステージのパータイム（秒）。盤面を作ったときに決まり、ステージの間は変わらない
+    pub full_sweep: bool, // trueなら物理処理を毎フレーム盤面全体に対して行う（差分処理の検証用）
+    dirty_columns: Vec<bool>, // 前回の接地判定以降にセルが書き換わった列
+    fall_columns: Vec<bool>, // 落下処理が必要な列（接地していないセルがある、またはこのフレームに落下したセルがある）
+    life_lost_frame: i32,    // 最後にライフを失ったフレーム（まだなければ-1）
+    undo_history: VecDeque<StateDiff>, // デバッグ中、各updateの直前の状態に戻すための差分（古い順、UNDO_HISTORY_LENまで）
+    stats_cache: std::cell::Cell<Option<(i32, BoardStats)>>, // scan_statsの結果と、それを数えたフレーム
+    erase_plan_cache: std::cell::RefCell<Option<(u64, ErasePlan)>>, // predict_erase_groupsの結果と、そのときのboard_hash
+    components: ComponentIndex, // set_leadersで作ったかたまりの索引
 }
 
 impl Game {
+    // 今の時刻をシードにして既定の設定で始める
+    #[allow(dead_code)]
     pub fn new() -> Self {
-        let now = time::SystemTime::now();
-        let timestamp = now
-            .duration_since(time::UNIX_EPOCH)
-            .expect("SystemTime before UNIX EPOCH!")
-            .as_secs();
-        let rng = StdRng::seed_from_u64(timestamp);
-        println!("random seed = {}", timestamp);
-        // let rng = StdRng::seed_from_u64(0);
-
-        let mut game = Game {
-            rng: rng,
-            is_debug: false,
-            is_over: false,
-            is_clear: false,
-            frame: -1,
-            player: Player::new(),
-            requested_sounds: Vec::new(),
-            cells: [[Cell::new(); CELLS_X_LEN as usize]; CELLS_Y_LEN as usize],
-            camera_y: 0,
-            depth: 0,
-        };
+        let seed = timestamp_seed();
+        log_info!("random seed = {}", seed);
+        Game::new_seeded(seed)
+    }
+
+    // 今の時刻をシードにして始める。シードはあとで同じ盤面を作り直せるようにログに出す
+    pub fn new_with_config(config: GameConfig) -> Self {
+        let seed = timestamp_seed();
+        log_info!("random seed = {}", seed);
+        Game::from_seed(seed, config)
+    }
+
+    // 指定したシードと既定の設定で始める。同じシードなら同じ盤面になる（シミュレーションやテスト用）
+    pub fn new_seeded(seed: u64) -> Self {
+        Game::from_seed(seed, GameConfig::default())
+    }
+
+    // 指定したシードで1ステージ目の盤面を生成する。同じシードと設定なら同じ盤面になる
+    pub fn from_seed(seed: u64, config: GameConfig) -> Self {
+        Game::from_seed_at_stage(seed, config, 1)
+    }
+
+    // 指定したシードでstageステージ目の盤面を生成する。難しさはステージ番号から決まる
+    pub fn from_seed_at_stage(seed: u64, config: GameConfig, stage: u32) -> Self {
+        let mut game = Game::empty(seed, config);
+        game.stage = stage.max(1);
+        game.difficulty = Difficulty::for_stage(game.stage);
 
         // ランダムに通常ブロックを敷き詰める
-        for y in UP_SPACE_HEIGHT..=CELLS_Y_MAX {
-            for x in CELLS_X_MIN..=CELLS_X_MAX {
+        for y in game.config.up_space_height..=game.config.cells_y_max() {
+            for x in CELLS_X_MIN..=game.config.cells_x_max() {
                 let p = Point::new(x, y);
                 game.cell_mut(p).cell_type = CellType::Block;
-                if game.rng.gen_bool(0.05) {
-                    game.cell_mut(p).color = BlockColor::Brown;
-                } else {
-                    game.cell_mut(p).color = BlockColor::from_u32(game.rng.gen::<u32>());
+                game.cell_mut(p).color = game.random_block_color();
+            }
+        }
+
+        // 通常ブロックのうちまれにギフトブロックにする
+        for y in game.config.up_space_height
+            ..game.config.up_space_height + game.config.normal_blocks_height
+        {
+            for x in CELLS_X_MIN..=game.config.cells_x_max() {
+                let p = Point::new(x, y);
+                if game.cell(p).color.playable_index().is_some()
+                    && game.rng.gen::<f64>() < GIFT_RATE
+                {
+                    game.cell_mut(p).gift = true;
+                }
+            }
+        }
+
+        // 深いところにまれにファントムブロックを配置
+        for y in game.config.up_space_height + game.config.phantom_min_depth()
+            ..game.config.up_space_height + game.config.normal_blocks_height
+        {
+            for x in CELLS_X_MIN..=game.config.cells_x_max() {
+                if game.rng.gen::<f64>() < PHANTOM_RATE {
+                    game.cell_mut(Point::new(x, y)).cell_type = CellType::Phantom;
                 }
             }
         }
 
         // airを配置
-        let mut depth = UP_SPACE_HEIGHT;
-        while depth < CELLS_Y_LEN {
-            let x = game.rng.gen::<u32>() % (CELLS_X_LEN as u32);
-            let y = depth as u32 + game.rng.gen::<u32>() % (AIR_SPAWN_INTERVAL as u32);
-            if y < CELLS_Y_LEN as u32 {
+        let mut depth = game.config.up_space_height;
+        while depth < game.config.cells_y_len() {
+            let x = game.rng.gen::<u32>() % (game.config.cells_x_len() as u32);
+            let interval = game.difficulty.air_spawn_interval;
+            let y = depth as u32 + game.rng.gen::<u32>() % (interval as u32);
+            if y < game.config.cells_y_len() as u32 {
                 let p = Point::new(x as i32, y as i32);
                 game.cell_mut(p).cell_type = CellType::Air;
             }
-            depth += AIR_SPAWN_INTERVAL;
+            depth += interval;
+        }
+
+        // 鍵を配置（通常ブロックの下半分のどこか）
+        if game.config.clear_requires_key || game.config.surface_return {
+            let x = game.rng.gen::<u32>() % (game.config.cells_x_len() as u32);
+            let y = game.config.up_space_height as u32
+                + (game.config.normal_blocks_height / 2) as u32
+                + game.rng.gen::<u32>() % ((game.config.normal_blocks_height / 2) as u32);
+            let p = Point::new(x as i32, y as i32);
+            game.cell_mut(p).cell_type = CellType::Item(ItemKind::Key);
+        }
+
+        // 深さの区切りごとにエアカプセルが足りなければ足す（鍵で上書きされた分も数え直す）
+        if game.config.air_band_rows > 0 {
+            let bottom = game.config.up_space_height + game.config.normal_blocks_height;
+            let mut top = game.config.up_space_height;
+            while top < bottom {
+                let band = top..(top + game.config.air_band_rows).min(bottom);
+                game.fill_air_band(band);
+                top += game.config.air_band_rows;
+            }
+        }
+
+        // 深いところの通常ブロックをまれに通気口にする
+        // （ほかの配置が変わらないように、乱数を使う配置の最後に行う）
+        for y in game.config.up_space_height + game.config.vent_min_depth()
+            ..game.config.up_space_height + game.config.normal_blocks_height
+        {
+            for x in CELLS_X_MIN..=game.config.cells_x_max() {
+                let p = Point::new(x, y);
+                let cell = game.cell(p);
+                let is_plain_block = cell.cell_type == CellType::Block
+                    && cell.color.playable_index().is_some()
+                    && !cell.gift;
+                if is_plain_block && game.rng.gen::<f64>() < VENT_RATE {
+                    game.cell_mut(p).cell_type = CellType::Vent;
+                }
+            }
+        }
+
+        // エアカプセルの真上にある茶色ブロックを通常ブロックにする
+        // （ほかの配置が変わらないように、直す色の抽選は乱数を使う配置のあとで行う）
+        for y in game.config.up_space_height..game.config.cells_y_len() {
+            for x in CELLS_X_MIN..=game.config.cells_x_max() {
+                if game.cell(Point::new(x, y)).cell_type != CellType::Air {
+                    continue;
+                }
+                for above in (y - game.config.air_brown_clearance).max(CELLS_Y_MIN)..y {
+                    let p = Point::new(x, above);
+                    let cell = game.cell(p);
+                    if cell.cell_type == CellType::Block && cell.color == BlockColor::Brown {
+                        game.cell_mut(p).color = game.random_playable_color();
+                    }
+                }
+            }
+        }
+
+        // エアカプセルの大きさを抽選する（小さいものほど多い）
+        // （ほかの配置が変わらないように、乱数を使う配置の最後に行う）
+        for y in CELLS_Y_MIN..=game.config.cells_y_max() {
+            for x in CELLS_X_MIN..=game.config.cells_x_max() {
+                let p = Point::new(x, y);
+                if game.cell(p).cell_type == CellType::Air {
+                    game.cell_mut(p).capacity = game.random_air_capacity();
+                }
+            }
         }
 
         // クリアブロックを配置
-        for y in 0..CLEAR_BLOCKS_HEIGHT {
-            for x in CELLS_X_MIN..=CELLS_X_MAX {
-                let p = Point::new(x, CELLS_Y_MAX - y);
+        for y in 0..game.config.clear_blocks_height {
+            for x in CELLS_X_MIN..=game.config.cells_x_max() {
+                let p = Point::new(x, game.config.cells_y_max() - y);
                 game.cell_mut(p).cell_type = CellType::Block;
                 game.cell_mut(p).color = BlockColor::Clear;
             }
         }
+        game.par_seconds = game.initial_par_seconds();
+        game.camera_y = game.player.p.y - 5;
 
         game
     }
 
+    // 文字で書いた配置からステージを作る（チュートリアルなど決まった盤面用）
+    // 1行が盤面の1行で、上から順に置く。レイアウトより下の行はクリアブロックで埋める
+    //   . 何もない  A エア  K 鍵  P プレイヤーの開始位置（何もない）
+    //   R Y G B V T 通常ブロック  O 茶色ブロック  C クリアブロック  X 通気口
+    // 色を見るのはブロックだけなので、ブロック以外のセルの色はCell::newと同じ赤にしておく
+    // （通気口もブロックとはつながらず、赤いブロックの隣に置いてもかたまりには入らない）
+    pub fn from_layout(layout: &str, config: GameConfig) -> Result<Self, String> {
+        let mut game = Game::empty(0, config);
+        let rows: Vec<&str> = layout
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .collect();
+        if rows.len() > game.config.cells_y_len() as usize {
+            return Err(format!("layout has too many rows: {}", rows.len()));
+        }
+        for (y, row) in rows.iter().enumerate() {
+            let row = row.trim();
+            if row.chars().count() != game.config.cells_x_len() as usize {
+                return Err(format!(
+                    "layout row {} must be {} cells wide",
+                    y,
+                    game.config.cells_x_len()
+                ));
+            }
+            for (x, c) in row.chars().enumerate() {
+                let p = Point::new(x as i32, y as i32);
+                let (cell_type, color) = match c {
+                    '.' => (CellType::None, BlockColor::Red),
+                    'P' => {
+                        game.player.p = p;
+                        (CellType::None, BlockColor::Red)
+                    }
+                    'A' => (CellType::Air, BlockColor::Red),
+                    'K' => (CellType::Item(ItemKind::Key), BlockColor::Red),
+                    'R' => (CellType::Block, BlockColor::Red),
+                    'Y' => (CellType::Block, BlockColor::Yellow),
+                    'G' => (CellType::Block, BlockColor::Green),
+                    'B' => (CellType::Block, BlockColor::Blue),
+                    'V' => (CellType::Block, BlockColor::Purple),
+                    'T' => (CellType::Block, BlockColor::Cyan),
+                    'O' => (CellType::Block, BlockColor::Brown),
+                    'C' => (CellType::Block, BlockColor::Clear),
+                    'X' => (CellType::Vent, BlockColor::Red),
+                    _ => return Err(format!("unknown layout cell '{}' at {},{}", c, x, y)),
+                };
+                game.cell_mut(p).cell_type = cell_type;
+                game.cell_mut(p).color = color;
+            }
+        }
+        for y in rows.len() as i32..game.config.cells_y_len() {
+            for x in CELLS_X_MIN..=game.config.cells_x_max() {
+                let p = Point::new(x, y);
+                game.cell_mut(p).cell_type = CellType::Block;
+                game.cell_mut(p).color = BlockColor::Clear;
+            }
+        }
+        game.par_seconds = game.initial_par_seconds();
+        game.camera_y = game.player.p.y - 5;
+        Ok(game)
+    }
+
+    // 何もない盤面のゲーム
+    fn empty(seed: u64, config: GameConfig) -> Self {
+        let width = config.cells_x_len();
+        let height = config.cells_y_len();
+        let player = Player::new(&config);
+        Game {
+            config,
+            seed,
+            rng: ChaCha12Rng::seed_from_u64(seed),
+            is_debug: false,
+            is_over: false,
+            is_clear: false,
+            is_paused: false,
+            stage: 1,
+            difficulty: Difficulty::for_stage(1),
+            frame: -1,
+            player,
+            enemies: Vec::new(),
+            requested_sounds: Vec::new(),
+            events: Vec::new(),
+            blocked: None,
+            blocked_input: None,
+            last_bonk_frame: None,
+            cells: vec![vec![Cell::new(); width as usize]; height as usize],
+            camera_y: 0,
+            depth: 0,
+            blocks_dug: 0,
+            blocks_dug_by_color: [0; BLOCK_COLORS],
+            score: 0,
+            colors_erased: [false; PLAYABLE_COLORS],
+            has_key: false,
+            lives: 0,
+            drill_boost_frames: 0,
+            freeze_frames: 0,
+            collapse_charge: 0,
+            vent_frames: 0,
+            combo: 0,
+            last_erase_frame: -1,
+            last_breath_frames: -1,
+            clear_frame: -1,
+            par_seconds: 0.0,
+            full_sweep: false,
+            dirty_columns: vec![true; width as usize],
+            fall_columns: vec![true; width as usize],
+            life_lost_frame: -1,
+            undo_history: VecDeque::new(),
+            stats_cache: std::cell::Cell::new(None),
+            erase_plan_cache: std::cell::RefCell::new(None),
+            components: ComponentIndex::default(),
+        }
+    }
+
+    // AirCapacity::WEIGHTSに従ってエアカプセルの大きさを抽選する
+    fn random_air_capacity(&mut self) -> AirCapacity {
+        let total: f64 = AirCapacity::WEIGHTS.iter().map(|&(_, w)| w).sum();
+        let mut r = self.rng.gen::<f64>() * total;
+        for (capacity, weight) in AirCapacity::WEIGHTS {
+            if r < weight {
+                return capacity;
+            }
+            r -= weight;
+        }
+        AirCapacity::Small
+    }
+
+    // 設定の出現比率に従って通常ブロックの色を抽選する
+    // 茶色ブロックの比率にはステージの難しさの倍率を掛ける
+    fn random_block_color(&mut self) -> BlockColor {
+        let brown_scale = self.difficulty.brown_scale;
+        let weight_of = |color: BlockColor, weight: f64| {
+            if color == BlockColor::Brown {
+                weight.max(0.0) * brown_scale
+            } else {
+                weight.max(0.0)
+            }
+        };
+        let weights = &self.config.block_weights;
+        let total: f64 = weights
+            .iter()
+            .map(|&(c, weight)| weight_of(c, weight))
+            .sum();
+        // 比率がすべて0なら抽選できないので最初の色にする（設定を読むときに弾いているので、ふつうは起きない）
+        if total <= 0.0 {
+            return BlockColor::PLAYABLE[0];
+        }
+
+        let mut r = self.rng.gen::<f64>() * total;
+        for &(color, weight) in weights {
+            let weight = weight_of(color, weight);
+            if r < weight {
+                return color;
+            }
+            r -= weight;
+        }
+        // 浮動小数点の誤差で抜けてきた場合は、比率が0でない最後の色にする
+        weights
+            .iter()
+            .rev()
+            .find(|&&(color, weight)| weight_of(color, weight) > 0.0)
+            .map_or(BlockColor::PLAYABLE[0], |&(color, _)| color)
+    }
+
+    // rowsの行にあるエアカプセルがair_band_min個に足りなければ、その行の通常ブロックをエアカプセルにして足す
+    // 掘ればたどり着けるように、選ぶのはギフトでない通常ブロックだけ
+    fn fill_air_band(&mut self, rows: std::ops::Range<i32>) {
+        let mut count = 0;
+        let mut candidates = Vec::new();
+        for y in rows {
+            for x in CELLS_X_MIN..=self.config.cells_x_max() {
+                let p = Point::new(x, y);
+                let cell = self.cell(p);
+                if cell.cell_type == CellType::Air {
+                    count += 1;
+                } else if cell.cell_type == CellType::Block
+                    && cell.color.playable_index().is_some()
+                    && !cell.gift
+                {
+                    candidates.push(p);
+                }
+            }
+        }
+        while count < self.config.air_band_min && !candidates.is_empty() {
+            let i = self.rng.gen::<usize>() % candidates.len();
+            let p = candidates.swap_remove(i);
+            self.cell_mut(p).cell_type = CellType::Air;
+            count += 1;
+        }
+    }
+
+    // 通常ブロックの色だけから出現比率に従って抽選する（通常ブロックの比率がなければ最初の色）
+    fn random_playable_color(&mut self) -> BlockColor {
+        let weights: Vec<(BlockColor, f64)> = self
+            .config
+            .block_weights
+            .iter()
+            .filter(|&&(color, weight)| color.playable_index().is_some() && weight > 0.0)
+            .copied()
+            .collect();
+        let total: f64 = weights.iter().map(|&(_, weight)| weight).sum();
+        let mut r = self.rng.gen::<f64>() * total;
+        for &(color, weight) in &weights {
+            if r < weight {
+                return color;
+            }
+            r -= weight;
+        }
+        weights
+            .last()
+            .map_or(BlockColor::PLAYABLE[0], |&(color, _)| color)
+    }
+
     pub fn toggle_debug(&mut self) {
         self.is_debug = !self.is_debug;
-        println!("is_debug: {}", self.is_debug);
+        // 巻き戻しはデバッグ中だけなので、やめたら覚えていた状態を捨てる
+        if !self.is_debug {
+            self.undo_history.clear();
+        }
+        log_info!("is_debug: {}", self.is_debug);
+    }
+
+    // デバッグ用：直前のupdateをする前の状態に戻す。戻せる状態がなければfalse
+    pub fn undo(&mut self) -> bool {
+        let Some(diff) = self.undo_history.pop_back() else {
+            return false;
+        };
+        self.apply_diff(&diff);
+        // 戻した状態で鳴らす音や演出はもう済んでいる
+        self.requested_sounds.clear();
+        self.events.clear();
+        true
     }
 
     // デバッグ用：ブロックの状態を表示
     #[allow(dead_code)]
     pub fn print_blocks(&self) {
-        println!("{:?}", self.player.p);
-        for y in CELLS_Y_MIN..=CELLS_Y_MAX {
-            print!("{: >3}: ", y);
-            for x in CELLS_X_MIN..=CELLS_X_MAX {
+        log_debug!("{:?}", self.player.p);
+        for y in CELLS_Y_MIN..=self.config.cells_y_max() {
+            let mut line = format!("{: >3}: ", y);
+            for x in CELLS_X_MIN..=self.config.cells_x_max() {
                 let p = Point::new(x, y);
                 if self.player.p == p {
-                    print!("\x1b[0;31m{:?} \x1b[0m", self.cell(p));
+                    line += &format!("\x1b[0;31m{:?} \x1b[0m", self.cell(p));
                 } else {
-                    print!("{:?} ", self.cell(p));
+                    line += &format!("{:?} ", self.cell(p));
                 }
             }
-            print!("\n");
+            log_debug!("{}", line);
         }
     }
 
+    // 次のステージ。シードは今のステージのシードの次の値にするので、最初のシードから全ステージを再現できる
     pub fn next_stage(&self) -> Self {
-        let mut game = Game::new();
+        let seed = self.seed.wrapping_add(1);
+        log_info!("stage {} seed = {}", self.stage + 1, seed);
+        let mut game = Game::from_seed_at_stage(seed, self.config.clone(), self.stage + 1);
         game.depth = self.depth;
+        game.score = self.score;
+        game.lives = self.lives;
+        if let Some(carry) = self.config.air_carry {
+            game.player.air = clamp(0, (self.player.air as f64 * carry) as i32, AIR_MAX);
+        }
         game
     }
 
+    // 一時停止を切り替える。一時停止中のupdateは何もしない
+    pub fn toggle_pause(&mut self) {
+        self.is_paused = !self.is_paused;
+    }
+
+    // 1フレーム進める（updateと同じ。ウィンドウなしで動かすときの入口）
+    pub fn step(&mut self, command: Command) {
+        self.update(command);
+    }
+
+    // commandsを1フレームに1つずつ渡して進める
+    pub fn run_commands(&mut self, commands: &[Command]) {
+        for &command in commands {
+            self.step(command);
+        }
+    }
+
     pub fn update(&mut self, command: Command) {
+        // 一時停止中はframeも進めないので、歩いている・落ちている途中でも再開したときに同じところから続く
+        if self.is_paused {
+            return;
+        }
+        if !self.is_debug {
+            self.advance_frame(command);
+            return;
+        }
+
+        // デバッグ中は、進める前の状態に戻すための差分を覚えておく（古いものから捨てる）
+        let before = self.snapshot();
+        self.advance_frame(command);
+        if self.undo_history.len() == UNDO_HISTORY_LEN {
+            self.undo_history.pop_front();
+        }
+        let undo = self.changes_since(before);
+        self.undo_history.push_back(undo);
+    }
+
+    // 1フレーム進める（updateの本体）
+    fn advance_frame(&mut self, command: Command) {
         self.frame += 1; // updateの最初でframeをインクリメント（early returnした場合も増加するように）
 
         if self.is_over || self.is_clear {
             return;
         }
 
-        self.player_move();
-
-        self.fall_ungrounded_blocks();
+        for step in UpdateStep::ORDER {
+            self.run_step(step, command);
+            // クリアしたらそのフレームの残りは行わない
+            if self.is_clear {
+                return;
+            }
+        }
 
-        self.set_leaders();
+        self.camera_y = self.player.p.y - 5;
+    }
 
-        self.erase_connected_blocks();
+    // プレイヤーが落ちているか、次のフレームに落ち始める（足元に入れるセルがある）か
+    // 着地した各セルでも、下にまだ入れるなら落ち続けるものとして扱う
+    pub fn is_player_falling(&self) -> bool {
+        self.player.state == PlayerState::Falling
+            || (self.player.state == PlayerState::Standing
+                && !self.player.clinging
+                && self
+                    .neighbor(self.player.p, Direction::Down)
+                    .is_some_and(|down| self.can_enter(down, FALL_FRAMES)))
+    }
 
-        match command {
-            Command::Left | Command::Right | Command::Up | Command::Down => {
-                self.dig_or_walk(Direction::from_command(command.clone()));
-                if self.is_clear {
-                    return;
+    // updateの1段階だけを行う（テストなどで段階ごとに結果を確かめる用）
+    // commandはUpdateStep::Commandと、速く落ちるかを決めるUpdateStep::PlayerMoveで使う
+    pub fn run_step(&mut self, step: UpdateStep, command: Command) {
+        match step {
+            UpdateStep::PlayerMove => self.player_move(command),
+            UpdateStep::FallBlocks => self.fall_ungrounded_blocks(),
+            UpdateStep::SetLeaders => self.set_leaders(),
+            UpdateStep::EraseBlocks => {
+                self.erase_connected_blocks();
+                if self.combo > 0 && self.combo_frames_left() == 0 {
+                    self.combo = 0;
                 }
             }
-            Command::None => {}
-        }
-
-        self.update_grounded();
+            UpdateStep::Command => {
+                match command {
+                    Command::Left | Command::Right | Command::Up | Command::Down => {
+                        self.dig_or_walk(Direction::from_command(command));
+                    }
+                    Command::Collapse => self.collapse_column(),
+                    Command::Refill => self.refill_above_player(),
+                    Command::SoftDrop | Command::None => {}
+                }
+                self.report_blocked(command);
+            }
+            UpdateStep::UpdateGrounded => self.update_grounded(),
+            UpdateStep::CollectItems => self.collect_at_player(),
+            UpdateStep::TickPowerUps => self.tick_power_ups(),
+            UpdateStep::ConsumeAir => self.consume_air(),
+            UpdateStep::CheckCrush => self.check_crush(),
+            UpdateStep::MoveEnemies => self.move_enemies(),
+        }
+    }
 
-        // エアを取得
+    // プレイヤーのいるセルのエアやアイテムを取る
+    fn collect_at_player(&mut self) {
+        // エアを取得（大きさによって回復する量が違う）
         if self.cell(self.player.p).cell_type == CellType::Air {
-            self.cell_mut(self.player.p.clone()).cell_type = CellType::None;
-            self.player.air = clamp(0, self.player.air + (AIR_MAX as f32 * 0.2) as i32, AIR_MAX);
-            self.requested_sounds.push("shrink.wav");
+            let restore = self.cell(self.player.p).capacity.restore();
+            self.cell_mut(self.player.p).cell_type = CellType::None;
+            self.player.air = clamp(0, self.player.air + restore, AIR_MAX);
+            self.requested_sounds.push(SoundRequest::new("shrink.wav"));
+            self.events.push(GameEvent::AirCollected);
+        }
+
+        // アイテムを取得
+        if let CellType::Item(kind) = self.cell(self.player.p).cell_type {
+            self.cell_mut(self.player.p).cell_type = CellType::None;
+            self.collect_item(kind);
         }
+    }
+
+    // パワーアップの残り時間を減らす
+    fn tick_power_ups(&mut self) {
+        if self.drill_boost_frames > 0 {
+            self.drill_boost_frames -= 1;
+        }
+        if self.freeze_frames > 0 {
+            self.freeze_frames -= 1;
+        }
+        if self.collapse_charge < COLLAPSE_CHARGE_FRAMES {
+            self.collapse_charge += 1;
+        }
+    }
 
-        // エア消費
+    // 毎フレームのエア消費。通気口のそばにいる間は余分に減る
+    fn consume_air(&mut self) {
+        if self.is_near_vent() {
+            if self.vent_frames == 0 {
+                self.requested_sounds.push(SoundRequest::new("hiss.wav"));
+            }
+            self.vent_frames += 1;
+        } else {
+            self.vent_frames = 0;
+        }
+        // サンドボックスでは掘ったり歩いたりして減った分もすぐに戻す
+        if self.config.sandbox {
+            self.player.air = AIR_MAX;
+            return;
+        }
         self.player.air -= 1;
-        if self.player.air <= 0 {
-            self.is_over = true;
-            self.requested_sounds.push("crash.wav");
+        if self.vent_frames > 0 {
+            self.player.air = (self.player.air - VENT_AIR_DRAIN).max(0);
+        }
+        if self.player.air > 0 {
+            self.last_breath_frames = -1;
+        } else if self.config.last_breath {
+            self.player.air = 0;
+            self.hold_breath();
+        } else {
+            self.lose_life();
         }
+    }
+
+    // プレイヤーのいるセルの上下左右に通気口があるか（その上に立っているときも含む）
+    pub fn is_near_vent(&self) -> bool {
+        Direction::all().iter().any(|&direction| {
+            self.neighbor(self.player.p, direction)
+                .is_some_and(|p| self.cell(p).cell_type == CellType::Vent)
+        })
+    }
 
-        // ブロックにつぶされたらゲームオーバー
+    // ブロックにつぶされたら（いる場所のファントムブロックが実体化したときも）ゲームオーバー
+    fn check_crush(&mut self) {
+        // 歩いている途中は元のセルから出ていくところなので、そこに何が来てもつぶされない
+        // （歩き出したフレームはまだ出ていない）
+        if self.player.state == PlayerState::Walking && self.player.walking_frames > 0 {
+            return;
+        }
         if self.cell(self.player.p).cell_type == CellType::Block {
-            self.is_over = true;
-            self.requested_sounds.push("crash.wav");
+            if !(self.config.block_push && self.push_player()) {
+                self.lose_life();
+            }
+        } else if self.cell(self.player.p).cell_type == CellType::Phantom
+            && self.is_phantom_solid(0)
+        {
+            self.lose_life();
         }
+    }
 
-        self.camera_y = self.player.p.y - 5;
+    // 敵をときどき出し、ENEMY_STEP_FRAMESごとに1マス進める。時間停止中は止まる
+    // 落ちてきたブロックがいるセルに入ったら、その敵はつぶれていなくなる
+    fn move_enemies(&mut self) {
+        if !self.config.enemies {
+            return;
+        }
+        self.enemies.retain(|enemy| {
+            self.cells[enemy.p.y as usize][enemy.p.x as usize].cell_type == CellType::None
+        });
+        if self.freeze_frames > 0 {
+            self.check_enemy_contact();
+            return;
+        }
+        if self.frame % ENEMY_SPAWN_FRAMES == 0 && self.enemies.len() < ENEMY_MAX {
+            self.spawn_enemy();
+        }
+        if self.frame % ENEMY_STEP_FRAMES == 0 {
+            for i in 0..self.enemies.len() {
+                let enemy = self.enemies[i];
+                match self
+                    .neighbor(enemy.p, enemy.direction)
+                    .filter(|&p| self.cell(p).cell_type == CellType::None)
+                {
+                    Some(p) => self.enemies[i].p = p,
+                    None => self.enemies[i].direction = enemy.direction.opposite(),
+                }
+            }
+        }
+        self.check_enemy_contact();
+    }
+
+    // プレイヤーから離れた、足元がふさがっている空いたセルのどれかに敵を1体出す（なければ出さない）
+    fn spawn_enemy(&mut self) {
+        let mut candidates = Vec::new();
+        let top = (self.player.p.y - ENEMY_SPAWN_ROWS).max(self.config.up_space_height);
+        let bottom = (self.player.p.y + ENEMY_SPAWN_ROWS).min(self.config.cells_y_max() - 1);
+        for y in top..=bottom {
+            for x in CELLS_X_MIN..=self.config.cells_x_max() {
+                let p = Point::new(x, y);
+                let distance = (p.x - self.player.p.x).abs() + (p.y - self.player.p.y).abs();
+                if distance >= ENEMY_SPAWN_DISTANCE
+                    && self.cell(p).cell_type == CellType::None
+                    && !self.cell(Point::new(x, y + 1)).cell_type.is_passable()
+                    && !self.enemies.iter().any(|enemy| enemy.p == p)
+                {
+                    candidates.push(p);
+                }
+            }
+        }
+        if candidates.is_empty() {
+            return;
+        }
+        let p = candidates[self.rng.gen_range(0..candidates.len())];
+        let direction = if self.rng.gen_bool(0.5) {
+            Direction::Left
+        } else {
+            Direction::Right
+        };
+        self.enemies.push(Enemy { p, direction });
+    }
+
+    // 敵がプレイヤーのセルにいたらライフを失う。触れた敵はいなくなる（復活した直後にまた触れないように）
+    fn check_enemy_contact(&mut self) {
+        let p = self.player.p;
+        if self.enemies.iter().any(|enemy| enemy.p == p) {
+            self.enemies.retain(|enemy| enemy.p != p);
+            self.lose_life();
+        }
+    }
+
+    // 落ちてきたブロックにプレイヤーを左右の空いたセルへ押し出させる。向いている方を先に調べる
+    // 歩いている途中や登っている途中でも、その場で押し出されて立ち止まる。押し出せなければfalse
+    fn push_player(&mut self) -> bool {
+        let sides = match self.player.direction {
+            Direction::Right => [Direction::Right, Direction::Left],
+            _ => [Direction::Left, Direction::Right],
+        };
+        let Some(side) = sides.iter().find_map(|&direction| {
+            self.neighbor(self.player.p, direction)
+                .filter(|&side| self.can_enter(side, 0))
+        }) else {
+            return false;
+        };
+        self.player.p = side;
+        self.player.state = PlayerState::Standing;
+        self.player.walking_frames = 0;
+        self.player.climbing_frames = 0;
+        self.player.clinging = false;
+        self.requested_sounds.push(SoundRequest::new("bonk.wav"));
+        true
+    }
+
+    fn collect_item(&mut self, kind: ItemKind) {
+        match kind {
+            ItemKind::Key => self.has_key = true,
+            ItemKind::AirTank => {
+                self.player.air =
+                    clamp(0, self.player.air + (AIR_MAX as f32 * 0.5) as i32, AIR_MAX);
+            }
+            ItemKind::Drill => self.drill_boost_frames = DRILL_BOOST_FRAMES,
+            ItemKind::Life => self.lives = (self.lives + 1).min(LIVES_MAX),
+            ItemKind::Freeze => self.freeze_frames = FREEZE_FRAMES,
+        }
+        self.requested_sounds.push(SoundRequest::new("shrink.wav"));
+    }
+
+    // エアが0のあいだ息を止める。LAST_BREATH_FRAMES以内にエアを取れなければ力尽きる
+    fn hold_breath(&mut self) {
+        if self.last_breath_frames < 0 {
+            self.last_breath_frames = LAST_BREATH_FRAMES;
+            self.requested_sounds.push(SoundRequest::new("bonk.wav"));
+        }
+        self.last_breath_frames -= 1;
+        if self.last_breath_frames <= 0 {
+            self.last_breath_frames = -1;
+            self.lose_life();
+        }
+    }
+
+    // ライフが残っていれば1つ使ってその場で復活し、なければゲームオーバー
+    // サンドボックスではライフを使わずに復活する
+    // 同じフレームにエア切れとつぶされたのが重なっても、失うライフは1つだけ
+    fn lose_life(&mut self) {
+        if self.is_over {
+            return;
+        }
+        if self.life_lost_frame != self.frame {
+            self.life_lost_frame = self.frame;
+            self.requested_sounds.push(SoundRequest::new("crash.wav"));
+            if !self.config.sandbox {
+                if self.lives <= 0 {
+                    self.is_over = true;
+                    return;
+                }
+                self.lives -= 1;
+            }
+        }
+        self.player.air = AIR_MAX;
+        // つぶされたときは重なっているブロックを取り除く
+        if !self.cell(self.player.p).cell_type.is_passable() {
+            self.cell_mut(self.player.p).cell_type = CellType::None;
+        }
     }
 
     // 落下や歩行中のアニメーション処理
-    fn player_move(&mut self) {
+    fn player_move(&mut self, command: Command) {
+        // つかまっていた壁がなくなったら手が離れる
+        if self.player.clinging && !self.has_grip(self.player.p) {
+            self.player.clinging = false;
+        }
+
         // 下に足場が無ければ落下中にする
         if let Some(down) = self.neighbor(self.player.p, Direction::Down) {
-            if (self.cell(down).cell_type == CellType::None
-                || self.cell(down).cell_type == CellType::Air)
+            if self.can_enter(down, FALL_FRAMES)
                 && self.player.state != PlayerState::Falling
+                && self.player.state != PlayerState::Climbing
+                && !self.player.clinging
             {
                 self.player.state = PlayerState::Falling;
                 self.player.falling_frames = 0;
             }
         }
 
-        // 落下中
+        // 落下中（下を押していればSOFT_DROP_FALL_FRAMESで1マス落ちる）
         if self.player.state == PlayerState::Falling {
+            let fall_frames = if matches!(command, Command::Down | Command::SoftDrop) {
+                SOFT_DROP_FALL_FRAMES
+            } else {
+                FALL_FRAMES
+            };
             self.player.falling_frames += 1;
-            if self.player.falling_frames >= FALL_FRAMES {
+            if self.player.falling_frames >= fall_frames {
                 // 1マス分落下完了
                 self.player.falling_frames = 0;
                 self.player.p.y += 1;
@@ -378,282 +1653,3483 @@ impl Game {
         if self.player.state == PlayerState::Walking {
             self.player.walking_frames += 1;
             if self.player.walking_frames >= WALK_FRAMES {
-                // 1マス分歩行完了
-                if self.player.direction == Direction::Left {
-                    self.player.p.x -= 1;
-                } else {
-                    self.player.p.x += 1;
+                // 1マス分歩行完了（歩き出せたのだから隣のセルは必ずある）
+                if let Some(p) = self.player_neighbor(self.player.p, self.player.direction) {
+                    self.player.p = p;
                 }
                 self.player.state = PlayerState::Standing;
+                self.spend_air(self.config.walk_air_cost);
+            }
+        }
+
+        // 登っている途中
+        if self.player.state == PlayerState::Climbing {
+            self.player.climbing_frames += 1;
+            if self.player.climbing_frames >= CLIMB_FRAMES {
+                // 1マス分登り終えた
+                self.player.climbing_frames = 0;
+                self.player.p.y -= 1;
+                self.player.state = PlayerState::Standing;
+                self.player.clinging = self.has_grip(self.player.p);
+                self.spend_air(self.config.walk_air_cost);
+                self.check_surface_return();
             }
         }
     }
 
+    // 地上帰還モードで、鍵を持って地上に戻っていたらクリア
+    fn check_surface_return(&mut self) {
+        if self.config.surface_return
+            && self.has_key
+            && self.player.p.y < self.config.up_space_height
+        {
+            self.is_clear = true;
+            self.clear_frame = self.frame;
+            self.requested_sounds.push(SoundRequest::new("clear.wav"));
+        }
+    }
+
+    // 指定したセルの左右に、つかまって登れる壁があるか
+    fn has_grip(&self, p: Point) -> bool {
+        [Direction::Left, Direction::Right]
+            .iter()
+            .any(|&direction| {
+                self.neighbor(p, direction)
+                    .is_some_and(|side| !self.cell(side).cell_type.is_passable())
+            })
+    }
+
     // 指定方向に掘る、または歩行開始する
     fn dig_or_walk(&mut self, direction: Direction) {
+        // 移動先が世界の端なら何もできない
+        let Some(p) = self.player_neighbor(self.player.p, direction) else {
+            self.blocked(direction);
+            return;
+        };
         match direction {
             Direction::Left | Direction::Right => {
-                if self.player.state == PlayerState::Standing {
-                    if let Some(p) = self.neighbor(self.player.p, direction) {
-                        match self.cell(p).cell_type {
-                            CellType::None | CellType::Air => {
-                                self.player.state = PlayerState::Walking;
-                                self.player.direction = direction;
-                                self.player.walking_frames = 0;
-                            }
-                            CellType::Block => {
-                                self.dig(p);
-                            }
-                        }
-                    }
+                // 歩行中や落下中は次の入力を受け付けない
+                if self.player.state != PlayerState::Standing {
+                    self.blocked(direction);
+                    return;
+                }
+                if self.can_enter(p, WALK_FRAMES) {
+                    self.player.state = PlayerState::Walking;
+                    self.player.direction = direction;
+                    self.player.walking_frames = 0;
+                    self.player.clinging = false;
+                } else if matches!(self.cell(p).cell_type, CellType::Phantom | CellType::Vent) {
+                    // ファントムブロックと通気口は掘れない
+                    self.blocked(direction);
+                } else {
+                    let target = self.assisted_dig_target(p);
+                    self.dig(target, direction);
                 }
             }
             Direction::Up | Direction::Down => {
-                if let Some(p) = self.neighbor(self.player.p, direction) {
-                    match self.cell(p).cell_type {
-                        CellType::Block => self.dig(p),
-                        _ => {}
-                    }
+                if self.cell(p).cell_type == CellType::Block && self.cell(p).falling_frames >= 0 {
+                    // 落ちている途中のブロックは掘れない（揺れているだけならまだ掘れる）
+                    self.blocked(direction);
+                } else if self.cell(p).cell_type == CellType::Block {
+                    self.dig(p, direction);
+                } else if direction == Direction::Up {
+                    self.climb(p);
+                } else {
+                    // 下を押すと壁から手を離して落ちる
+                    self.player.clinging = false;
                 }
             }
         }
     }
 
-    // 落下したブロックが指定個数以上つながったら消す
-    fn erase_connected_blocks(&mut self) {
-        for y in CELLS_Y_MIN..=CELLS_Y_MAX {
-            for x in CELLS_X_MIN..=CELLS_X_MAX {
-                let p = Point::new(x, y);
-                if self.cell(p).cell_type == CellType::Block && self.cell(p).fell {
-                    let component = self.get_component(p);
-                    if component.len() >= 4 {
-                        for point in component {
-                            self.cell_mut(point).cell_type = CellType::None;
-                        }
-                    }
-                }
+    // 地上帰還モードで、上の空いたセルpに登り始める。今いるセルかpの横に壁がなければ登れない
+    fn climb(&mut self, p: Point) {
+        if !self.config.surface_return {
+            return;
+        }
+        if self.player.state != PlayerState::Standing
+            || !self.can_enter(p, CLIMB_FRAMES)
+            || !(self.has_grip(self.player.p) || self.has_grip(p))
+        {
+            self.blocked(Direction::Up);
+            return;
+        }
+        self.player.state = PlayerState::Climbing;
+        self.player.climbing_frames = 0;
+    }
+
+    // プレイヤーの隣のdirectionの向きに、何回も掘らないと壊れないブロック（既定では茶色ブロック）があるか
+    pub fn is_tough_block_toward(&self, direction: Direction) -> bool {
+        self.player_neighbor(self.player.p, direction)
+            .is_some_and(|p| {
+                let cell = self.cell(p);
+                cell.cell_type == CellType::Block && self.config.dig_hits_of(cell.color) > 1
+            })
+    }
+
+    // ファントムブロックがnフレーム後に実体化しているか
+    pub fn is_phantom_solid(&self, n: i32) -> bool {
+        (self.frame + n).rem_euclid(PHANTOM_PERIOD) < PHANTOM_SOLID_FRAMES
+    }
+
+    // プレイヤーが指定したセルに移動し始めてよいか
+    // ファントムブロックは、移動し終わるまでのframesフレームの間ずっと透明なときだけ入れる
+    // （入った直後に実体化してつぶされないように）
+    fn can_enter(&self, p: Point, frames: i32) -> bool {
+        match self.cell(p).cell_type {
+            CellType::Phantom => (0..=frames).all(|n| !self.is_phantom_solid(n)),
+            cell_type => cell_type.is_passable(),
+        }
+    }
+
+    // 動いた分のエアを減らす。0を下回った分は切り捨てる（エア切れの処理はupdateで行う）
+    fn spend_air(&mut self, cost: i32) {
+        self.player.air = clamp(0, self.player.air - cost, AIR_MAX);
+    }
+
+    // 入力に応えられなかった。知らせるのは入力の処理の最後（report_blocked）
+    fn blocked(&mut self, direction: Direction) {
+        self.blocked = Some(direction);
+    }
+
+    // 入力に応えられなかったことを知らせる
+    // 同じ位置から同じ入力をくり返してまた止められただけなら、最初の1回で知らせてあるので黙っている
+    // 左右を交互に押すなど違う入力でも、音はBONK_INTERVAL_FRAMESに1回までにする
+    fn report_blocked(&mut self, command: Command) {
+        if command == Command::None {
+            return;
+        }
+        let input = (self.player.p, command);
+        let Some(direction) = self.blocked.take() else {
+            self.blocked_input = None;
+            return;
+        };
+        if self.blocked_input != Some(input) {
+            if self
+                .last_bonk_frame
+                .is_none_or(|frame| self.frame - frame >= BONK_INTERVAL_FRAMES)
+            {
+                self.requested_sounds.push(SoundRequest::new("bonk.wav"));
+                self.last_bonk_frame = Some(self.frame);
             }
+            self.events.push(GameEvent::Blocked(direction));
         }
+        self.blocked_input = Some(input);
     }
 
-    // ブロックが接地しているか判定して記録する
-    fn update_grounded(&mut self) {
-        // いったん全部falseにする
-        for y in CELLS_Y_MIN..=CELLS_Y_MAX {
-            for x in CELLS_X_MIN..=CELLS_X_MAX {
-                let p = Point::new(x, y);
-                self.cell_mut(p).grounded = false;
+    // 崩落技：プレイヤーの足元から世界の底までの列のブロックの横のつながりを切り、
+    // 真下に支えのないものをぐらつかせずにすぐ落とす。クリアブロックとファントムブロックはそのまま
+    // たまりきっていなければ使えない。使うとエアが減り、またためなおしになる
+    fn collapse_column(&mut self) {
+        if self.collapse_charge < COLLAPSE_CHARGE_FRAMES {
+            self.blocked(Direction::Down);
+            return;
+        }
+        self.collapse_charge = 0;
+        self.spend_air(COLLAPSE_AIR_COST);
+        let x = self.player.p.x;
+        for y in self.player.p.y + 1..=self.config.cells_y_max() {
+            let p = Point::new(x, y);
+            let cell = self.cell(p);
+            if cell.cell_type != CellType::Block || cell.color == BlockColor::Clear {
+                continue;
             }
+            // 支えがあればこのフレームのUpdateGroundedで接地し直し、ぐらつきも元に戻る
+            let shake_frames = self.shake_frames_of(cell);
+            let cell = self.cell_state_mut(p);
+            cell.loose = true;
+            cell.grounded = false;
+            cell.shaking_frames = shake_frames + 1;
         }
-        // 下からループして
-        for y in (CELLS_Y_MIN..=CELLS_Y_MAX).rev() {
-            for x in CELLS_X_MIN..=CELLS_X_MAX {
+        self.set_leaders();
+        self.dirty_columns[x as usize] = true;
+        self.requested_sounds.push(SoundRequest::new("crash.wav"));
+    }
+
+    // サンドボックスで、プレイヤーより上の行（最初の空間より下）をランダムな色のブロックで埋めなおす
+    // 下に支えのないブロックはそのあと揺れて落ち、つながれば消える
+    fn refill_above_player(&mut self) {
+        if !self.config.sandbox {
+            return;
+        }
+        for y in self.config.up_space_height..self.player.p.y {
+            for x in CELLS_X_MIN..=self.config.cells_x_max() {
                 let p = Point::new(x, y);
-                if self.cell(p).grounded == false {
-                    // 一番底のクリアブロック、または1個下に接地したブロックまたはエアがあるならそこも接地している
-                    let down = self.neighbor(p, Direction::Down);
-                    let grounded = down == None
-                        || (self.cell(down.unwrap()).cell_type != CellType::None
-                            && self.cell(down.unwrap()).grounded);
-                    if grounded {
-                        match self.cell(p).cell_type {
-                            CellType::None => {}
-                            CellType::Air => self.cell_mut(p).grounded = true,
-                            CellType::Block => {
-                                // つながったブロックを全部接地にする
-                                let component = self.get_component(p);
-                                for point in component {
-                                    self.cell_mut(point).grounded = true;
-                                    self.cell_mut(point).shaking_frames = -1;
-                                    self.cell_mut(point).falling_frames = -1;
-                                }
-                            }
-                        };
-                    }
-                }
+                let color = self.random_block_color();
+                let cell = self.cell_mut(p);
+                *cell = Cell::new();
+                cell.cell_type = CellType::Block;
+                cell.color = color;
+                self.fall_columns[x as usize] = true;
             }
         }
+        self.set_leaders();
+        self.requested_sounds.push(SoundRequest::new("crash.wav"));
     }
 
-    // 接地していないブロックを落とす
-    fn fall_ungrounded_blocks(&mut self) {
-        // 下からループして
-        for y in (CELLS_Y_MIN..=CELLS_Y_MAX).rev() {
-            for x in CELLS_X_MIN..=CELLS_X_MAX {
-                let p = Point::new(x, y);
+    // 横に掘ろうとしたときに実際に掘るセルを決める
+    // 掘りアシストが有効で、横のセルpを掘ると上のブロックが落ちてきてつぶされるおそれがあるときだけ、
+    // 真下のブロック、左右のブロックのうち残りの掘る回数が一番少ないもの、の順に掘ってもつぶされないセルを選ぶ
+    // （安全な横掘りはそのまま掘る。選べるセルがなければpを掘る）
+    fn assisted_dig_target(&self, p: Point) -> Point {
+        if !self.config.dig_assist || !self.is_dig_risk(p) {
+            return p;
+        }
+        let diggable = |q: &Point| {
+            let cell = self.cell(*q);
+            cell.cell_type == CellType::Block
+                && cell.color != BlockColor::Clear
+                && !self.is_dig_risk(*q)
+        };
+        if let Some(down) = self
+            .neighbor(self.player.p, Direction::Down)
+            .filter(diggable)
+        {
+            return down;
+        }
+        [Direction::Left, Direction::Right]
+            .into_iter()
+            .filter_map(|direction| self.neighbor(self.player.p, direction))
+            .filter(diggable)
+            .min_by_key(|q| self.remaining_digs(*q))
+            .unwrap_or(p)
+    }
 
-                self.cell_mut(p).fell = false;
-                if self.cell(p).cell_type != CellType::None {
-                    if !self.cell(p).grounded {
-                        if self.cell(p).shaking_frames < 0 {
-                            // 揺らし開始
-                            self.cell_mut(p).shaking_frames = 0;
-                        } else if self.cell(p).shaking_frames <= SHAKE_FRAMES {
-                            // 揺らし中
-                            self.cell_mut(p).shaking_frames += 1;
-                        } else {
-                            // 揺らし終わった
-                            if self.cell(p).falling_frames < 0 {
-                                // 揺らし終わったら落下開始
-                                self.cell_mut(p).falling_frames = 0;
-                            } else if self.cell(p).falling_frames <= FALL_FRAMES {
-                                self.cell_mut(p).falling_frames += 1;
+    // ブロックpを壊すまでにあと何回掘る必要があるか
+    fn remaining_digs(&self, p: Point) -> i32 {
+        let cell = self.cell(p);
+        let damage = self.config.dig_damage(cell.color);
+        (cell.block_life + damage - 1) / damage
+    }
+
+    // セルpのかたまりを掘って壊したとき、pに入るとつぶされるおそれがあるか（自分自身は変えない）
+    // 盤面のセルだけを写してかたまりを消し、接地を判定し直して、pの真上にある一番近いブロックが接地しているかを見る
+    fn is_dig_risk(&self, p: Point) -> bool {
+        let mut cells = self.cells.clone();
+        for q in self.get_component(p) {
+            cells[q.y as usize][q.x as usize].cell_type = CellType::None;
+        }
+        let grounded = self.grounded_cells(&cells);
+        let mut q = p;
+        while let Some(up) = self.neighbor(q, Direction::Up) {
+            if cells[up.y as usize][up.x as usize].cell_type == CellType::Block {
+                return !grounded[up.y as usize][up.x as usize];
+            }
+            q = up;
+        }
+        false
+    }
+
+    // 接地しているセルdownが、その上のセルupを支えるか
+    fn supports(&self, down: &Cell, up: &Cell) -> bool {
+        self.config.grounding_through_air
+            || down.cell_type != CellType::Air
+            || up.cell_type != CellType::Block
+    }
+
+    // 落下したブロックが指定個数以上つながったら消す
+    fn erase_connected_blocks(&mut self) {
+        for y in CELLS_Y_MIN..=self.config.cells_y_max() {
+            for x in CELLS_X_MIN..=self.config.cells_x_max() {
+                let p = Point::new(x, y);
+                if self.cell(p).cell_type == CellType::Block && self.cell(p).fell {
+                    let component = self.get_component(p);
+                    if component.len() >= 4 {
+                        let color = self.cell(p).color;
+                        // コンボが続いていれば、続いた数を点に掛ける
+                        self.count_combo();
+                        let points = erase_points(component.len(), self.combo);
+                        self.score = self.score.saturating_add(points);
+                        self.requested_sounds.push(SoundRequest::erase(self.combo));
+                        self.events.push(GameEvent::Erased(component.len() as i32));
+                        self.events
+                            .push(GameEvent::Scored(centroid_cell(&component), points));
+                        for point in component {
+                            if self.cell(point).gift {
+                                // ギフトブロックは消えた場所にアイテムを落とす
+                                let index = self.rng.gen_range(0..ItemKind::POWER_UPS.len());
+                                self.cell_mut(point).cell_type =
+                                    CellType::Item(ItemKind::POWER_UPS[index]);
+                                self.cell_mut(point).gift = false;
                             } else {
-                                // 落下し終わったらセル移動
-                                let down = self.neighbor(p, Direction::Down).unwrap();
-                                *self.cell_mut(down) = *self.cell(p);
-                                self.cell_mut(p).cell_type = CellType::None;
-                                self.cell_mut(down).fell = true;
-
-                                // 下にエアがあったら潰す
-                                if let Some(down2) = self.neighbor(down, Direction::Down) {
-                                    if self.cell(down2).cell_type == CellType::Air {
-                                        self.cell_mut(down2).cell_type = CellType::None;
-                                    }
-                                }
+                                self.cell_mut(point).cell_type = CellType::None;
                             }
                         }
+                        self.mark_color_erased(color);
                     }
                 }
             }
         }
     }
 
-    // 指定したブロックとつながっているブロックの座標のリストを返す
-    fn get_component(&self, p: Point) -> Vec<Point> {
-        let mut result = Vec::new();
-        for yi in CELLS_Y_MIN..=CELLS_Y_MAX {
-            for xi in CELLS_X_MIN..=CELLS_X_MAX {
-                let xiyi = Point::new(xi, yi);
-                if self.cell(xiyi).leader == self.cell(p).leader {
-                    result.push(xiyi);
-                }
-            }
+    // 重力だけで盤面をframesフレーム先まで進めたゲームを返す（自分自身は変えない）
+    // プレイヤーの操作、エアの消費、ブロックの消去は行わない
+    #[allow(dead_code)]
+    pub fn simulate_gravity_only(&self, frames: i32) -> Game {
+        let mut game = self.clone();
+        for _ in 0..frames {
+            game.gravity_step();
         }
-        result
+        game
     }
 
-    // 指定された箇所を掘る
-    fn dig(&mut self, p: Point) {
-        if self.cell(p).color == BlockColor::Clear {
-            self.is_clear = true;
-            self.requested_sounds.push("clear.wav");
-        }
-
-        if self.cell(p).color == BlockColor::Brown {
-            self.cell_mut(p).block_life -= 25;
-        } else {
-            self.cell_mut(p).block_life = 0;
-        }
-        if self.cell(p).block_life > 0 {
-            return;
-        }
-        if self.cell(p).color == BlockColor::Brown {
-            self.player.air = clamp(0, self.player.air - (AIR_MAX as f32 * 0.23) as i32, AIR_MAX);
-            self.requested_sounds.push("break_brown.wav");
+    // 今ぐらついている・落ちているブロックがすべて着地したとき、消えるかたまりのセル（着地後の位置と色）
+    // 描画で毎フレーム呼ぶので、落下に関わる盤面の状態が前回と同じなら前回の結果を返す
+    pub fn predict_erase_groups(&self) -> ErasePlan {
+        let key = self.board_hash();
+        if let Some((cached, plan)) = &*self.erase_plan_cache.borrow() {
+            if *cached == key {
+                return plan.clone();
+            }
         }
+        let plan = self.simulate_erase_groups();
+        *self.erase_plan_cache.borrow_mut() = Some((key, plan.clone()));
+        plan
+    }
 
-        // つながっているブロックを消去
-        let leader = self.cell(p).leader;
-        for y in CELLS_Y_MIN..=CELLS_Y_MAX {
-            for x in CELLS_X_MIN..=CELLS_X_MAX {
-                let xy = Point::new(x, y);
-                if self.cell(xy).leader == leader {
-                    self.cell_mut(xy).cell_type = CellType::None;
+    // 重力だけで盤面を落ち着くまで（最大PLANNER_FRAMESフレーム）進め、落下したブロックを含む
+    // 4個以上のかたまりを探す。連鎖して消えるものは含まない
+    fn simulate_erase_groups(&self) -> ErasePlan {
+        let mut game = self.clone();
+        let mut landed = Vec::new();
+        let mut seen = HashSet::new();
+        for _ in 0..PLANNER_FRAMES {
+            if !game.fall_columns.contains(&true) && game.freeze_frames <= 0 {
+                break;
+            }
+            game.gravity_step();
+            for y in CELLS_Y_MIN..=self.config.cells_y_max() {
+                for x in CELLS_X_MIN..=self.config.cells_x_max() {
+                    let p = Point::new(x, y);
+                    if game.cell(p).fell && seen.insert(p) {
+                        landed.push(p);
+                    }
                 }
             }
         }
-    }
 
-    // 全ブロックのつながり方を判定
-    fn set_leaders(&mut self) {
-        for y in CELLS_Y_MIN..=CELLS_Y_MAX {
-            for x in CELLS_X_MIN..=CELLS_X_MAX {
-                let p = Point::new(x, y);
-                self.cell_mut(p).leader = None;
+        let mut result = ErasePlan::new();
+        let mut in_result = HashSet::new();
+        for p in landed {
+            if game.cell(p).cell_type != CellType::Block || in_result.contains(&p) {
+                continue;
+            }
+            let component = game.get_component(p);
+            if component.len() >= 4 {
+                in_result.extend(component.iter().copied());
+                result.extend(component.into_iter().map(|q| (q, game.cell(q).color)));
             }
         }
+        result
+    }
 
-        for y in CELLS_Y_MIN..=CELLS_Y_MAX {
-            for x in CELLS_X_MIN..=CELLS_X_MAX {
+    // 盤面にあるすべてのエアカプセルの位置（上の行から順、同じ行は左から）
+    // 今は盤面全体を持っているのでステージのすべてだが、盤面の一部だけを持つようになったら
+    // 持っている範囲にあるものだけになる（その外のエアカプセルはまだ、またはもう存在しない扱い）
+    pub fn air_capsules(&self) -> Vec<Point> {
+        let mut capsules = Vec::new();
+        for y in CELLS_Y_MIN..=self.config.cells_y_max() {
+            for x in CELLS_X_MIN..=self.config.cells_x_max() {
                 let p = Point::new(x, y);
-                if self.cell(p).leader == None {
-                    self.set_leader(p, p);
+                if self.cell(p).cell_type == CellType::Air {
+                    capsules.push(p);
                 }
             }
         }
+        capsules
     }
 
-    fn set_leader(&mut self, p: Point, leader: Point) {
-        if self.cell(p).cell_type != CellType::Block {
-            return;
-        }
+    // プレイヤーから一番近い（マンハッタン距離で）エアカプセル。同じ距離なら上の行、左のもの。なければNone
+    pub fn nearest_air_capsule(&self) -> Option<Point> {
+        let p = self.player.p;
+        self.air_capsules()
+            .into_iter()
+            .min_by_key(|q| (q.x - p.x).abs() + (q.y - p.y).abs())
+    }
 
-        self.cell_mut(p).leader = Some(leader);
-        let directions = Direction::all();
-        for direction in directions {
-            if let Some(neighbor) = self.neighbor(p, direction) {
-                if self.cell(neighbor).color == self.cell(p).color
-                    && self.cell(neighbor).leader == None
-                {
-                    self.set_leader(neighbor, leader);
-                }
-            }
-        }
+    // プレイヤーより下にある一番浅いエアカプセルまでの行数。なければNone
+    pub fn depth_to_next_air(&self) -> Option<i32> {
+        self.air_capsules()
+            .into_iter()
+            .map(|q| q.y - self.player.p.y)
+            .find(|&rows| rows > 0)
     }
 
-    pub fn neighbor(&self, p: Point, direction: Direction) -> Option<Point> {
-        match direction {
-            Direction::Left => {
-                if p.x - 1 >= CELLS_X_MIN {
-                    Some(Point::new(p.x - 1, p.y))
-                } else {
-                    None
-                }
-            }
-            Direction::Right => {
-                if p.x + 1 <= CELLS_X_MAX {
-                    Some(Point::new(p.x + 1, p.y))
-                } else {
-                    None
-                }
+    // プレイヤーから一番少ないエアでたどり着けるエアカプセルまでの道のりを探す
+    // 返すのはプレイヤーの次のセルからエアカプセルのセルまで。たどり着けなければNone
+    // 盤面は今のまま止まっているものとし、ブロックの落下やつながったブロックがまとめて消えることは考えない
+    pub fn suggest_path_to_next_air(&self) -> Option<Vec<Point>> {
+        // エアカプセルが1つもなければ探すまでもない
+        if self.air_capsules().is_empty() {
+            return None;
+        }
+        let index = |p: Point| (p.y * self.config.cells_x_len() + p.x) as usize;
+        let mut cost =
+            vec![i32::MAX; (self.config.cells_x_len() * self.config.cells_y_len()) as usize];
+        let mut prev: Vec<Option<Point>> = vec![None; cost.len()];
+        let mut queue = BinaryHeap::new();
+        let start = self.player.p;
+        cost[index(start)] = 0;
+        queue.push(Reverse((0, start.y, start.x)));
+        while let Some(Reverse((c, y, x))) = queue.pop() {
+            let p = Point::new(x, y);
+            if c > cost[index(p)] {
+                continue;
             }
-            Direction::Up => {
-                if p.y - 1 >= CELLS_Y_MIN {
-                    Some(Point::new(p.x, p.y - 1))
-                } else {
-                    None
+            if p != start && self.cell(p).cell_type == CellType::Air {
+                let mut path = vec![p];
+                while let Some(q) = prev[index(path[path.len() - 1])] {
+                    if q == start {
+                        break;
+                    }
+                    path.push(q);
                 }
+                path.reverse();
+                return Some(path);
             }
-            Direction::Down => {
-                if p.y + 1 <= CELLS_Y_MAX {
-                    Some(Point::new(p.x, p.y + 1))
-                } else {
-                    None
+            // 足元が空いていれば落ちるしかない
+            let falling = self
+                .neighbor(p, Direction::Down)
+                .is_some_and(|down| self.cell(down).cell_type.is_passable());
+            let directions: &[Direction] = if falling {
+                &[Direction::Down]
+            } else {
+                &[Direction::Left, Direction::Right, Direction::Down]
+            };
+            for &direction in directions {
+                let Some(next) = self.neighbor(p, direction) else {
+                    continue;
+                };
+                let Some(step) = self.path_step_cost(next, direction) else {
+                    continue;
+                };
+                let c = c + step;
+                if c < cost[index(next)] {
+                    cost[index(next)] = c;
+                    prev[index(next)] = Some(p);
+                    queue.push(Reverse((c, next.y, next.x)));
                 }
             }
         }
+        None
     }
 
-    pub fn cell<'a>(&'a self, p: Point) -> &'a Cell {
-        &self.cells[p.y as usize][p.x as usize]
+    // suggest_path_to_next_airで、隣のセルpへdirectionの向きに進むのに使うエア。進めなければNone
+    // 掘る回数（茶色ブロックは何回も掘る）と掘ったあとに入る分を足す
+    fn path_step_cost(&self, p: Point, direction: Direction) -> Option<i32> {
+        let enter = match direction {
+            Direction::Down => FALL_FRAMES,
+            _ => WALK_FRAMES + self.config.walk_air_cost,
+        };
+        let cell = self.cell(p);
+        if cell.cell_type.is_passable() {
+            return Some(enter);
+        }
+        // 掘れないブロックと、掘るとステージが終わってしまうクリアブロックは通らない
+        if cell.cell_type != CellType::Block || cell.color == BlockColor::Clear {
+            return None;
+        }
+        let mut dig = 1 + self.config.dig_air_cost;
+        if self.drill_boost_frames <= 0 {
+            dig *= self.remaining_digs(p);
+            if cell.color == BlockColor::Brown {
+                dig += BROWN_AIR_COST;
+            }
+        }
+        Some(dig + enter)
     }
 
-    fn cell_mut<'a>(&'a mut self, p: Point) -> &'a mut Cell {
-        &mut self.cells[p.y as usize][p.x as usize]
+    // 1フレーム分、ブロックの落下とそれに伴うつながり・接地の判定だけを進める
+    fn gravity_step(&mut self) {
+        self.frame += 1;
+        if self.freeze_frames > 0 {
+            self.freeze_frames -= 1;
+        }
+        self.fall_ungrounded_blocks();
+        self.set_leaders();
+        self.update_grounded();
+    }
+
+    // かたまりを消したときに呼ぶ。前に消してからCOMBO_WINDOW_FRAMES以内ならコンボが続く
+    fn count_combo(&mut self) {
+        if self.combo_frames_left() > 0 {
+            self.combo += 1;
+        } else {
+            self.combo = 1;
+        }
+        self.last_erase_frame = self.frame;
+    }
+
+    // コンボが途切れるまでの残りフレーム数（コンボ中でなければ0）
+    pub fn combo_frames_left(&self) -> i32 {
+        if self.combo == 0 {
+            return 0;
+        }
+        (self.last_erase_frame + COMBO_WINDOW_FRAMES - self.frame).max(0)
+    }
+
+    // 全色そろえボーナス：ステージ中に盤面に出る色すべてのかたまりを消したらボーナス
+    fn mark_color_erased(&mut self, color: BlockColor) {
+        let Some(index) = color.playable_index() else {
+            return;
+        };
+        if self.colors_erased[index] {
+            return;
+        }
+        self.colors_erased[index] = true;
+        let all_erased = self
+            .config
+            .active_colors()
+            .iter()
+            .all(|c| self.colors_erased[c.playable_index().unwrap()]);
+        if all_erased {
+            self.score = self.score.saturating_add(ALL_COLORS_BONUS);
+            self.requested_sounds.push(SoundRequest::new("fanfare.wav"));
+        }
+    }
+
+    // ブロックが接地しているか判定して記録する
+    // 前回の判定以降にどの列も書き換わっていなければ結果は変わらないので省略する。
+    // ブロックのつながりは列をまたぐので、どこか1列でも書き換わったら全体を判定し直す
+    // 下の行から上へ、同じ行は左から順に調べる。接地は真下のセルからしか伝わらず、
+    // ブロックはかたまりごとまとめて接地にするので、同じ行の中の順番は結果に影響しない
+    fn update_grounded(&mut self) {
+        if !self.full_sweep && !self.dirty_columns.contains(&true) {
+            return;
+        }
+
+        let grounded = self.grounded_cells(&self.cells);
+        for y in CELLS_Y_MIN..=self.config.cells_y_max() {
+            for x in CELLS_X_MIN..=self.config.cells_x_max() {
+                let p = Point::new(x, y);
+                let is_grounded = grounded[y as usize][x as usize];
+                self.cell_state_mut(p).grounded = is_grounded;
+                if is_grounded && self.cell(p).cell_type == CellType::Block {
+                    self.cell_state_mut(p).shaking_frames = -1;
+                    self.cell_state_mut(p).falling_frames = -1;
+                    self.cell_state_mut(p).loose = false;
+                }
+            }
+        }
+
+        // 落下処理が必要な列を記録し直す
+        for x in CELLS_X_MIN..=self.config.cells_x_max() {
+            self.fall_columns[x as usize] = (CELLS_Y_MIN..=self.config.cells_y_max()).any(|y| {
+                let cell = self.cell(Point::new(x, y));
+                (cell.cell_type != CellType::None && !cell.grounded) || cell.fell
+            });
+        }
+        self.dirty_columns.fill(false);
+    }
+
+    // セルの配置cellsで、それぞれのセルが接地しているか（grounded[y][x]）
+    // 掘ったあとの盤面を試すときにも使うので、自分のセルではなく渡されたセルを見る
+    // ブロックのつながりは自分のリーダーで調べるので、cellsは自分のセルからセルの種類だけを変えたものにする
+    fn grounded_cells(&self, cells: &[Vec<Cell>]) -> Vec<Vec<bool>> {
+        let cell = |p: Point| &cells[p.y as usize][p.x as usize];
+        let mut grounded = vec![
+            vec![false; self.config.cells_x_len() as usize];
+            self.config.cells_y_len() as usize
+        ];
+        // 下からループして
+        for y in (CELLS_Y_MIN..=self.config.cells_y_max()).rev() {
+            for x in CELLS_X_MIN..=self.config.cells_x_max() {
+                let p = Point::new(x, y);
+                if !grounded[y as usize][x as usize] {
+                    // 一番底のクリアブロック、または1個下に接地したブロックまたはエアがあるならそこも接地している
+                    // （grounding_through_airがfalseなら、エアはブロックを支えない）
+                    // ファントムブロックと通気口は宙に固定されていて、（透明なときも）上のブロックを支える
+                    let down = self.neighbor(p, Direction::Down);
+                    let is_grounded =
+                        matches!(cell(p).cell_type, CellType::Phantom | CellType::Vent)
+                            || match down {
+                                None => true,
+                                Some(down) => {
+                                    cell(down).cell_type != CellType::None
+                                        && grounded[down.y as usize][down.x as usize]
+                                        && self.supports(cell(down), cell(p))
+                                }
+                            };
+                    if is_grounded {
+                        match cell(p).cell_type {
+                            CellType::None => {}
+                            CellType::Air
+                            | CellType::Item(_)
+                            | CellType::Phantom
+                            | CellType::Vent => grounded[y as usize][x as usize] = true,
+                            CellType::Block => {
+                                // つながったブロックを全部接地にする
+                                for point in self.get_component(p) {
+                                    grounded[point.y as usize][point.x as usize] = true;
+                                }
+                            }
+                        };
+                    }
+                }
+            }
+        }
+        grounded
+    }
+
+    // 接地していないブロックを落とす
+    // セルは真下にしか動かないので列ごとに独立して処理でき、落下処理が必要な列だけを見ればよい
+    // 列の中は下から上へ進めるので、同じフレームに真下のセルが動けば、上のセルは空いたところへ続けて動ける
+    // 落ち終わったセルの真下に接地していないセルがあれば（遅いブロックがまだ揺れている、落ちている途中など）
+    // 下のセルが優先で、上のセルはタイマーを進め終わったままその場で待ち、空いたフレームに動く
+    // 真下が接地したエア（grounding_through_airがfalseで、ブロックを支えない）なら、これまでどおりつぶして入る
+    fn fall_ungrounded_blocks(&mut self) {
+        if self.config.rigid_fall {
+            self.sync_component_timers();
+        }
+
+        let mut started_shaking = false;
+        for x in CELLS_X_MIN..=self.config.cells_x_max() {
+            if !self.full_sweep && !self.fall_columns[x as usize] {
+                continue;
+            }
+            // 下からループして
+            for y in (CELLS_Y_MIN..=self.config.cells_y_max()).rev() {
+                let p = Point::new(x, y);
+
+                self.cell_state_mut(p).fell = false;
+                // 時間停止中はぐらつきも落下も進めない（落下済みの印だけは消しておく）
+                if self.freeze_frames > 0 {
+                    continue;
+                }
+                if self.cell(p).cell_type != CellType::None && !self.cell(p).grounded {
+                    if self.cell(p).shaking_frames < 0 {
+                        // 揺らし開始
+                        self.cell_state_mut(p).shaking_frames = 0;
+                        if self.cell(p).cell_type == CellType::Block {
+                            started_shaking = true;
+                        }
+                    } else if self.cell(p).shaking_frames <= self.shake_frames_of(self.cell(p)) {
+                        // 揺らし中
+                        self.cell_state_mut(p).shaking_frames += 1;
+                    } else {
+                        // 揺らし終わった
+                        if self.cell(p).falling_frames < 0 {
+                            // 揺らし終わったら落下開始
+                            self.cell_state_mut(p).falling_frames = 0;
+                        } else if self.cell(p).falling_frames <= self.fall_frames_of(self.cell(p)) {
+                            self.cell_state_mut(p).falling_frames += 1;
+                        } else {
+                            // 落下し終わったらセル移動
+                            // 真下にまだ落ちている途中のセルがあれば、空くまで待つ（上書きしない）
+                            let down = self.neighbor(p, Direction::Down).unwrap();
+                            if self.cell(down).cell_type != CellType::None
+                                && !self.cell(down).grounded
+                            {
+                                continue;
+                            }
+                            *self.cell_mut(down) = *self.cell(p);
+                            self.cell_mut(p).cell_type = CellType::None;
+                            // ギフトの印はブロックといっしょに移る（空いたセルに残さない）
+                            self.cell_mut(p).gift = false;
+                            self.cell_state_mut(down).fell = true;
+                            // 崩落技で切り離されたブロックは、何かの上に降りたところで横のつながりが戻る
+                            // （このフレームのうちにつながって、消えるかどうかの判定を受けられるように）
+                            if self
+                                .neighbor(down, Direction::Down)
+                                .is_some_and(|d| self.cell(d).cell_type != CellType::None)
+                            {
+                                self.cell_state_mut(down).loose = false;
+                            }
+
+                            // 上にプレイヤーが立っていたら、ブロックに乗ったまま一緒に1マス落とす
+                            // （足場が消えてから落ち始めると、速く落ちるブロックに置いていかれる）
+                            if self.player.state == PlayerState::Standing
+                                && self.neighbor(p, Direction::Up) == Some(self.player.p)
+                            {
+                                self.player.p = p;
+                                self.depth += 1;
+                            }
+
+                            // 下にエアがあったら潰す
+                            if let Some(down2) = self.neighbor(down, Direction::Down) {
+                                if self.cell(down2).cell_type == CellType::Air {
+                                    self.cell_mut(down2).cell_type = CellType::None;
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        // 画面の外で崩れ始めたことも分かるように鳴らす。一度にたくさん揺れ始めても1フレームに1回だけ
+        if started_shaking && self.config.shake_warning {
+            self.requested_sounds.push(SoundRequest::new("rumble.wav"));
+        }
+    }
+
+    // セルが揺れ終わるまでのフレーム数。ブロックは色ごとのfall_paceで伸び縮みする
+    pub fn shake_frames_of(&self, cell: &Cell) -> i32 {
+        (SHAKE_FRAMES as f64 * self.fall_pace_of(cell)).round() as i32
+    }
+
+    // 揺れ終わったセルが1マス目を落ちるのにかかるフレーム数
+    pub fn fall_frames_of(&self, cell: &Cell) -> i32 {
+        (FALL_FRAMES as f64 * self.fall_pace_of(cell)).round() as i32
+    }
+
+    fn fall_pace_of(&self, cell: &Cell) -> f64 {
+        match cell.cell_type {
+            CellType::Block => self.config.fall_pace_of(cell.color) * self.difficulty.fall_pace,
+            _ => self.difficulty.fall_pace,
+        }
+    }
+
+    // 接地していないかたまりの揺れ・落下タイマーをそろえる
+    // 揺れ始めた時期が違うかたまりどうしがつながった場合は、いちばん進んでいないセルに合わせて
+    // かたまり全体が同じフレームに1マス落ちるようにする（一部だけ先に落ちてちぎれないように）
+    // 接地していないセルは落下処理が必要な列にしかないので、その列だけを見る
+    fn sync_component_timers(&mut self) {
+        let columns: Vec<i32> = (CELLS_X_MIN..=self.config.cells_x_max())
+            .filter(|&x| self.full_sweep || self.fall_columns[x as usize])
+            .collect();
+        let mut timers: HashMap<Point, (i32, i32)> = HashMap::new();
+        for y in CELLS_Y_MIN..=self.config.cells_y_max() {
+            for &x in &columns {
+                let cell = self.cell(Point::new(x, y));
+                if cell.cell_type != CellType::Block || cell.grounded {
+                    continue;
+                }
+                if let Some(leader) = cell.leader {
+                    let timer = (cell.shaking_frames, cell.falling_frames);
+                    timers
+                        .entry(leader)
+                        .and_modify(|t| *t = (*t).min(timer))
+                        .or_insert(timer);
+                }
+            }
+        }
+        if timers.is_empty() {
+            return;
+        }
+
+        for y in CELLS_Y_MIN..=self.config.cells_y_max() {
+            for &x in &columns {
+                let p = Point::new(x, y);
+                let cell = self.cell(p);
+                if cell.cell_type != CellType::Block || cell.grounded {
+                    continue;
+                }
+                if let Some(&(shaking, falling)) = cell.leader.and_then(|l| timers.get(&l)) {
+                    self.cell_state_mut(p).shaking_frames = shaking;
+                    self.cell_state_mut(p).falling_frames = falling;
+                }
+            }
+        }
+    }
+
+    // 指定したブロックとつながっているブロックの座標のリストを返す
+    // （同じリーダーを持つセルすべて。リーダーのないセルなら、リーダーのないセルすべて）
+    fn get_component(&self, p: Point) -> Vec<Point> {
+        // 索引を作ったあとに掘ったり消したりしたセルは、リーダーが変わっていれば外す
+        let leader = self.cell(p).leader;
+        if let Some(members) = leader.and_then(|l| self.components.get(l)) {
+            if !members.is_empty() {
+                return members
+                    .iter()
+                    .copied()
+                    .filter(|&q| self.cell(q).leader == leader)
+                    .collect();
+            }
+        }
+        let mut result = Vec::new();
+        for yi in CELLS_Y_MIN..=self.config.cells_y_max() {
+            for xi in CELLS_X_MIN..=self.config.cells_x_max() {
+                let xiyi = Point::new(xi, yi);
+                if self.cell(xiyi).leader == self.cell(p).leader {
+                    result.push(xiyi);
+                }
+            }
+        }
+        result
+    }
+
+    // 指定された箇所を掘る
+    fn dig(&mut self, p: Point, direction: Direction) {
+        if self.cell(p).color == BlockColor::Clear {
+            // 鍵が必要なルールでは、鍵を持っていなければ掘れない
+            // 地上帰還モードでは地上に戻るのがゴールなので掘れない
+            if self.config.surface_return || (self.config.clear_requires_key && !self.has_key) {
+                self.blocked(direction);
+                return;
+            }
+            self.is_clear = true;
+            self.clear_frame = self.frame;
+            self.requested_sounds.push(SoundRequest::new("clear.wav"));
+        }
+        self.spend_air(self.config.dig_air_cost);
+
+        if self.drill_boost_frames <= 0 {
+            let damage = self.config.dig_damage(self.cell(p).color);
+            self.cell_mut(p).block_life -= damage;
+        } else {
+            self.cell_mut(p).block_life = 0;
+        }
+        self.events.push(GameEvent::Dug(direction));
+        if self.cell(p).block_life > 0 {
+            return;
+        }
+        // ドリル強化中は茶色ブロックも1回で壊れ、エアも減らない
+        if self.cell(p).color == BlockColor::Brown && self.drill_boost_frames <= 0 {
+            self.player.air = clamp(0, self.player.air - BROWN_AIR_COST, AIR_MAX);
+            self.requested_sounds
+                .push(SoundRequest::new("break_brown.wav"));
+        }
+
+        // 数えるのは掘ったブロックだけ（いっしょに消えるつながったブロックは含めない）
+        self.blocks_dug += 1;
+        self.blocks_dug_by_color[self.cell(p).color as usize] += 1;
+
+        // つながっているブロックを消去
+        // ただし掘ったブロックと接地しているかどうかが違うものは残す。このフレームに落ちてきて
+        // つながったばかりの（まだ着地していない）ブロックが、掘ったかたまりと一緒に消えないように
+        let leader = self.cell(p).leader;
+        let grounded = self.cell(p).grounded;
+        for y in CELLS_Y_MIN..=self.config.cells_y_max() {
+            for x in CELLS_X_MIN..=self.config.cells_x_max() {
+                let xy = Point::new(x, y);
+                if self.cell(xy).leader == leader
+                    && self.cell(xy).cell_type == CellType::Block
+                    && self.cell(xy).grounded == grounded
+                {
+                    self.cell_mut(xy).cell_type = CellType::None;
+                    self.events
+                        .push(GameEvent::Vanished(xy, self.cell(xy).color));
+                }
+            }
+        }
+    }
+
+    // このステージで掘って壊したブロック数を色ごとに返す
+    pub fn dug_by_color(&self) -> Vec<(BlockColor, i32)> {
+        BlockColor::ALL
+            .iter()
+            .map(|&color| (color, self.blocks_dug_by_color[color as usize]))
+            .collect()
+    }
+
+    // 全ブロックのつながり方を判定
+    // つながり方が変わると接地判定の結果も変わりうるので、リーダーが変わった列は書き換わったものとして扱う
+    fn set_leaders(&mut self) {
+        let old_leaders: Vec<Vec<Option<Point>>> = self
+            .cells
+            .iter()
+            .map(|row| row.iter().map(|cell| cell.leader).collect())
+            .collect();
+        for y in CELLS_Y_MIN..=self.config.cells_y_max() {
+            for x in CELLS_X_MIN..=self.config.cells_x_max() {
+                let p = Point::new(x, y);
+                self.cell_state_mut(p).leader = None;
+            }
+        }
+
+        // 同じ色でつながったブロックを素集合森でまとめ、根をリーダーにする
+        // 崩落技で切り離されたブロックは、それだけで1つのかたまりになる
+        self.components.reset(&self.config);
+        for y in CELLS_Y_MIN..=self.config.cells_y_max() {
+            for x in CELLS_X_MIN..=self.config.cells_x_max() {
+                let p = Point::new(x, y);
+                let cell = *self.cell(p);
+                if cell.cell_type != CellType::Block || cell.loose {
+                    continue;
+                }
+                for direction in [Direction::Right, Direction::Down] {
+                    if let Some(neighbor) = self.neighbor(p, direction) {
+                        let other = *self.cell(neighbor);
+                        if other.cell_type == CellType::Block
+                            && other.color == cell.color
+                            && !other.loose
+                        {
+                            let (a, b) =
+                                (self.components.index(p), self.components.index(neighbor));
+                            self.components.union(a, b);
+                        }
+                    }
+                }
+            }
+        }
+        for y in CELLS_Y_MIN..=self.config.cells_y_max() {
+            for x in CELLS_X_MIN..=self.config.cells_x_max() {
+                let p = Point::new(x, y);
+                if self.cell(p).cell_type == CellType::Block {
+                    let i = self.components.index(p);
+                    let root = self.components.find(i);
+                    let leader = self.components.point(root);
+                    self.cell_state_mut(p).leader = Some(leader);
+                }
+            }
+        }
+        self.components.collect(&self.cells);
+
+        for y in CELLS_Y_MIN..=self.config.cells_y_max() {
+            for x in CELLS_X_MIN..=self.config.cells_x_max() {
+                let p = Point::new(x, y);
+                if self.cell(p).leader != old_leaders[y as usize][x as usize] {
+                    self.dirty_columns[x as usize] = true;
+                }
+            }
+        }
+    }
+
+    pub fn neighbor(&self, p: Point, direction: Direction) -> Option<Point> {
+        match direction {
+            Direction::Left => {
+                if p.x > CELLS_X_MIN {
+                    Some(Point::new(p.x - 1, p.y))
+                } else {
+                    None
+                }
+            }
+            Direction::Right => {
+                if p.x < self.config.cells_x_max() {
+                    Some(Point::new(p.x + 1, p.y))
+                } else {
+                    None
+                }
+            }
+            Direction::Up => {
+                if p.y > CELLS_Y_MIN {
+                    Some(Point::new(p.x, p.y - 1))
+                } else {
+                    None
+                }
+            }
+            Direction::Down => {
+                if p.y < self.config.cells_y_max() {
+                    Some(Point::new(p.x, p.y + 1))
+                } else {
+                    None
+                }
+            }
+        }
+    }
+
+    // プレイヤーが歩いたり掘ったりする先の隣のセル
+    // wrap_horizontalなら左右の端で反対側の端に回り込む。それ以外はneighborと同じ
+    pub fn player_neighbor(&self, p: Point, direction: Direction) -> Option<Point> {
+        if self.config.wrap_horizontal {
+            match direction {
+                Direction::Left if p.x == CELLS_X_MIN => {
+                    return Some(Point::new(self.config.cells_x_max(), p.y))
+                }
+                Direction::Right if p.x == self.config.cells_x_max() => {
+                    return Some(Point::new(CELLS_X_MIN, p.y))
+                }
+                _ => {}
+            }
+        }
+        self.neighbor(p, direction)
+    }
+
+    pub fn cell(&self, p: Point) -> &Cell {
+        &self.cells[p.y as usize][p.x as usize]
+    }
+
+    // 範囲外の座標でもパニックしないセル参照。世界の外は何もないセルとして扱う
+    pub fn cell_at(&self, x: i32, y: i32) -> &Cell {
+        if !(CELLS_X_MIN..=self.config.cells_x_max()).contains(&x)
+            || !(CELLS_Y_MIN..=self.config.cells_y_max()).contains(&y)
+        {
+            return &OFF_WORLD_CELL;
+        }
+        &self.cells[y as usize][x as usize]
+    }
+
+    // セルを書き換える。書き換えた列は接地判定をやり直す対象になる
+    fn cell_mut(&mut self, p: Point) -> &mut Cell {
+        self.dirty_columns[p.x as usize] = true;
+        self.stats_cache.set(None);
+        &mut self.cells[p.y as usize][p.x as usize]
+    }
+
+    // 接地・揺れ・落下・リーダーなど、物理処理が自分で管理する状態だけを書き換える
+    // （セルの種類や色は変えないので、接地判定をやり直す対象にしない）
+    fn cell_state_mut(&mut self, p: Point) -> &mut Cell {
+        self.stats_cache.set(None);
+        &mut self.cells[p.y as usize][p.x as usize]
+    }
+
+    // 盤面の集計を1回のループでまとめて数える
+    // 同じフレームのうちにセルが書き換わっていなければ前回の結果を返す
+    pub fn scan_stats(&self) -> BoardStats {
+        if let Some((frame, stats)) = self.stats_cache.get() {
+            if frame == self.frame {
+                return stats;
+            }
+        }
+
+        let mut stats = BoardStats::default();
+        for y in CELLS_Y_MIN..=self.config.cells_y_max() {
+            for x in CELLS_X_MIN..=self.config.cells_x_max() {
+                let p = Point::new(x, y);
+                let cell = self.cell(p);
+                match cell.cell_type {
+                    CellType::Block => {
+                        stats.blocks_by_color[cell.color as usize] += 1;
+                        if !cell.grounded {
+                            stats.ungrounded += 1;
+                        }
+                        // かたまりのリーダーは自分自身を指しているので、リーダーの数がかたまりの数
+                        if cell.leader == Some(p) {
+                            stats.components += 1;
+                        }
+                    }
+                    CellType::Air => stats.air_capsules += 1,
+                    _ => {}
+                }
+            }
+        }
+        self.stats_cache.set(Some((self.frame, stats)));
+        stats
+    }
+
+    // ゲームプレイに影響する状態をまとめたハッシュ値。リプレイの検証や非決定性の検出に使う
+    // rngは途中でも使う（ギフトブロックから出るアイテム）ので、今の位置も含める
+    // ハッシュが同じなら、同じ入力を与えた次のフレームも同じになる
+    pub fn state_hash(&self) -> u64 {
+        let mut h = StateHasher::new();
+        self.write_cells(&mut h);
+        h.write_point(Some(self.player.p));
+        h.write_i32(self.player.air);
+        h.write_i32(self.player.state as i32);
+        h.write_i32(self.player.direction as i32);
+        h.write_i32(self.player.walking_frames);
+        h.write_i32(self.player.climbing_frames);
+        h.write_bool(self.player.clinging);
+        h.write_i32(self.player.falling_frames);
+        h.write_i32(self.frame);
+        h.write_i32(self.depth);
+        h.write_bool(self.is_over);
+        h.write_bool(self.is_clear);
+        h.write_i32(self.blocks_dug);
+        for n in self.blocks_dug_by_color {
+            h.write_i32(n);
+        }
+        h.write_bool(self.has_key);
+        h.write_i32(self.lives);
+        h.write_i32(self.drill_boost_frames);
+        h.write_i32(self.freeze_frames);
+        h.write_i32(self.collapse_charge);
+        h.write_i32(self.vent_frames);
+        h.write_i32(self.combo as i32);
+        h.write_i32(self.last_erase_frame);
+        h.write_i32(self.last_breath_frames);
+        h.write_u128(self.rng.get_word_pos());
+        for enemy in &self.enemies {
+            h.write_point(Some(enemy.p));
+            h.write_i32(enemy.direction as i32);
+        }
+        h.0
+    }
+
+    // otherの状態にするための差分。self.apply_diff(&self.diff(other))のあとは状態ハッシュがotherと一致する
+    pub fn diff(&self, other: &Game) -> StateDiff {
+        let mut cells = Vec::new();
+        for y in CELLS_Y_MIN..=self.config.cells_y_max() {
+            for x in CELLS_X_MIN..=self.config.cells_x_max() {
+                let p = Point::new(x, y);
+                if self.cell(p) != other.cell(p) {
+                    cells.push((p, *other.cell(p)));
+                }
+            }
+        }
+        let status = other.status();
+        StateDiff {
+            cells,
+            player: (self.player != other.player).then(|| other.player.clone()),
+            enemies: (self.enemies != other.enemies).then(|| other.enemies.clone()),
+            status: (self.status() != status).then_some(status),
+        }
+    }
+
+    pub fn apply_diff(&mut self, diff: &StateDiff) {
+        for &(p, cell) in &diff.cells {
+            *self.cell_mut(p) = cell;
+            // 落下処理の対象かどうかは次の接地判定で決め直す
+            self.fall_columns[p.x as usize] = true;
+        }
+        // セルごとリーダーも書き換わるので、かたまりの索引を作り直す
+        if !diff.cells.is_empty() {
+            self.components.collect(&self.cells);
+        }
+        if let Some(player) = &diff.player {
+            self.player = player.clone();
+        }
+        if let Some(enemies) = &diff.enemies {
+            self.enemies = enemies.clone();
+        }
+        if let Some(status) = &diff.status {
+            self.set_status(status);
+        }
+    }
+
+    // 今の状態を丸ごと持った差分。当てるとこの状態に戻る
+    fn snapshot(&self) -> StateDiff {
+        let mut cells = Vec::new();
+        for y in CELLS_Y_MIN..=self.config.cells_y_max() {
+            for x in CELLS_X_MIN..=self.config.cells_x_max() {
+                let p = Point::new(x, y);
+                cells.push((p, *self.cell(p)));
+            }
+        }
+        StateDiff {
+            cells,
+            player: Some(self.player.clone()),
+            enemies: Some(self.enemies.clone()),
+            status: Some(self.status()),
+        }
+    }
+
+    // snapshotで覚えた状態に戻すための差分から、今と変わっていないところを除く
+    fn changes_since(&self, mut before: StateDiff) -> StateDiff {
+        before.cells.retain(|&(p, cell)| *self.cell(p) != cell);
+        before.player = before.player.filter(|player| *player != self.player);
+        before.enemies = before.enemies.filter(|enemies| *enemies != self.enemies);
+        before.status = before.status.filter(|status| *status != self.status());
+        before
+    }
+
+    fn status(&self) -> GameStatus {
+        GameStatus {
+            frame: self.frame,
+            camera_y: self.camera_y,
+            depth: self.depth,
+            is_over: self.is_over,
+            is_clear: self.is_clear,
+            clear_frame: self.clear_frame,
+            blocks_dug: self.blocks_dug,
+            blocks_dug_by_color: self.blocks_dug_by_color,
+            score: self.score,
+            colors_erased: self.colors_erased,
+            has_key: self.has_key,
+            lives: self.lives,
+            drill_boost_frames: self.drill_boost_frames,
+            freeze_frames: self.freeze_frames,
+            collapse_charge: self.collapse_charge,
+            vent_frames: self.vent_frames,
+            combo: self.combo,
+            last_erase_frame: self.last_erase_frame,
+            last_breath_frames: self.last_breath_frames,
+            rng_word_pos: self.rng.get_word_pos(),
+        }
+    }
+
+    fn set_status(&mut self, status: &GameStatus) {
+        self.frame = status.frame;
+        self.camera_y = status.camera_y;
+        self.depth = status.depth;
+        self.is_over = status.is_over;
+        self.is_clear = status.is_clear;
+        self.clear_frame = status.clear_frame;
+        self.blocks_dug = status.blocks_dug;
+        self.blocks_dug_by_color = status.blocks_dug_by_color;
+        self.score = status.score;
+        self.colors_erased = status.colors_erased;
+        self.has_key = status.has_key;
+        self.lives = status.lives;
+        self.drill_boost_frames = status.drill_boost_frames;
+        self.freeze_frames = status.freeze_frames;
+        self.collapse_charge = status.collapse_charge;
+        self.vent_frames = status.vent_frames;
+        self.combo = status.combo;
+        self.last_erase_frame = status.last_erase_frame;
+        self.last_breath_frames = status.last_breath_frames;
+        self.rng.set_word_pos(status.rng_word_pos);
+    }
+
+    // ブロックの落下に関わる状態（盤面のセルと時間停止）だけのハッシュ値。落下の先読みをやり直すかの判定に使う
+    fn board_hash(&self) -> u64 {
+        let mut h = StateHasher::new();
+        self.write_cells(&mut h);
+        h.write_i32(self.freeze_frames);
+        h.0
+    }
+
+    fn write_cells(&self, h: &mut StateHasher) {
+        for y in CELLS_Y_MIN..=self.config.cells_y_max() {
+            for x in CELLS_X_MIN..=self.config.cells_x_max() {
+                let cell = self.cell(Point::new(x, y));
+                h.write_cell_type(cell.cell_type);
+                h.write_i32(cell.color as i32);
+                h.write_point(cell.leader);
+                h.write_i32(cell.block_life);
+                h.write_bool(cell.grounded);
+                h.write_i32(cell.shaking_frames);
+                h.write_i32(cell.falling_frames);
+                h.write_bool(cell.fell);
+                h.write_bool(cell.gift);
+                h.write_bool(cell.loose);
+                h.write_i32(cell.capacity as i32);
+            }
+        }
     }
 
     pub fn get_depth(&self) -> i32 {
         self.depth
     }
-}
 
-pub fn clamp<T: PartialOrd>(min: T, value: T, max: T) -> T {
-    if value < min {
-        return min;
+    // このステージの経過時間（秒）。クリア後はクリアした時点で止まる
+    pub fn elapsed_seconds(&self) -> f32 {
+        let frame = if self.is_clear {
+            self.clear_frame
+        } else {
+            self.frame
+        };
+        frame.max(0) as f32 / FPS as f32
+    }
+
+    // このステージのパータイム（秒）。設定になければ、一番上のクリアブロックの深さから決める
+    // クリアブロックを掘ると変わってしまうので、盤面を作ったときに1回だけ呼んでpar_secondsに入れておく
+    fn initial_par_seconds(&self) -> f32 {
+        if let Some(par) = self.config.par_seconds {
+            return par;
+        }
+        let clear_row = (CELLS_Y_MIN..=self.config.cells_y_max())
+            .find(|&y| {
+                (CELLS_X_MIN..=self.config.cells_x_max()).any(|x| {
+                    let cell = self.cell(Point::new(x, y));
+                    cell.cell_type == CellType::Block && cell.color == BlockColor::Clear
+                })
+            })
+            .unwrap_or(self.config.cells_y_max());
+        clear_row as f32 * PAR_SECONDS_PER_ROW
+    }
+
+    // クリアしたときの評価（1〜STARS_MAX）。クリアしていなければ0
+    // クリアで1つ、パータイム以内なら2つ、さらにエアを半分以上残していれば3つ
+    pub fn stars(&self) -> u32 {
+        if !self.is_clear {
+            return 0;
+        }
+        if self.elapsed_seconds() > self.par_seconds {
+            1
+        } else if self.player.air < AIR_MAX / 2 {
+            2
+        } else {
+            STARS_MAX
+        }
+    }
+}
+
+// 状態ハッシュ用のFNV-1a。Rustのバージョンや実行ごとに結果が変わらないよう自前で実装する
+struct StateHasher(u64);
+
+impl StateHasher {
+    fn new() -> Self {
+        StateHasher(0xcbf2_9ce4_8422_2325)
+    }
+
+    fn write_i32(&mut self, n: i32) {
+        for b in n.to_le_bytes() {
+            self.0 ^= b as u64;
+            self.0 = self.0.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+
+    fn write_u128(&mut self, n: u128) {
+        for b in n.to_le_bytes() {
+            self.0 ^= b as u64;
+            self.0 = self.0.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+
+    fn write_bool(&mut self, b: bool) {
+        self.write_i32(b as i32);
+    }
+
+    fn write_cell_type(&mut self, cell_type: CellType) {
+        match cell_type {
+            CellType::None => self.write_i32(0),
+            CellType::Air => self.write_i32(1),
+            CellType::Block => self.write_i32(2),
+            CellType::Item(kind) => {
+                self.write_i32(3);
+                self.write_i32(kind as i32);
+            }
+            CellType::Phantom => self.write_i32(4),
+            CellType::Vent => self.write_i32(5),
+        }
+    }
+
+    fn write_point(&mut self, p: Option<Point>) {
+        match p {
+            Some(p) => {
+                self.write_i32(p.x);
+                self.write_i32(p.y);
+            }
+            None => {
+                self.write_i32(-1);
+                self.write_i32(-1);
+            }
+        }
+    }
+}
+
+// 今の時刻（UNIX時間の秒）をシードにする
+fn timestamp_seed() -> u64 {
+    time::SystemTime::now()
+        .duration_since(time::UNIX_EPOCH)
+        .expect("SystemTime before UNIX EPOCH!")
+        .as_secs()
+}
+
+pub fn clamp<T: PartialOrd>(min: T, value: T, max: T) -> T {
+    if value < min {
+        return min;
+    }
+    if value > max {
+        return max;
+    }
+    value
+}
+
+// size個のブロックのかたまりを、combo個目のコンボで消したときにもらえる点
+// 大きいかたまりほど1個あたりの点が高くなるように、ブロック数の2乗にする
+pub fn erase_points(size: usize, combo: u32) -> u32 {
+    // 大きいかたまりを長いコンボで消しても桁あふれしないように、上限で止める
+    let size = u32::try_from(size).unwrap_or(u32::MAX);
+    size.saturating_mul(size)
+        .saturating_mul(ERASE_POINTS_SCALE)
+        .saturating_mul(combo.max(1))
+}
+
+// セルの集まりのうち、重心に一番近いセル（空でないこと）。同じ距離なら先にあるもの
+// 凹んだ形のかたまりでも、かたまりの外のセルは選ばない
+fn centroid_cell(points: &[Point]) -> Point {
+    let n = points.len() as f32;
+    let x = points.iter().map(|p| p.x as f32).sum::<f32>() / n;
+    let y = points.iter().map(|p| p.y as f32).sum::<f32>() / n;
+    let distance = |p: &Point| (p.x as f32 - x).powi(2) + (p.y as f32 - y).powi(2);
+    *points
+        .iter()
+        .min_by(|a, b| distance(a).total_cmp(&distance(b)))
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // レイアウトから盤面を作り、入力なしで1フレーム進めてつながりと接地を判定させる
+    fn layout_game(layout: &str, config: GameConfig) -> Game {
+        let mut game = Game::from_layout(layout, config).unwrap();
+        game.update(Command::None);
+        game
+    }
+
+    fn is_block(game: &Game, x: i32, y: i32) -> bool {
+        game.cell(Point::new(x, y)).cell_type == CellType::Block
+    }
+
+    fn dig_assist_config() -> GameConfig {
+        GameConfig {
+            dig_assist: true,
+            ..GameConfig::default()
+        }
+    }
+
+    #[test]
+    fn dig_assist_keeps_safe_sideways_dig() {
+        let layout = "
+            .........
+            .........
+            ...RP....
+            YBYBGBYBY
+        ";
+        let mut game = layout_game(layout, dig_assist_config());
+        game.update(Command::Left);
+        assert!(!is_block(&game, 3, 2));
+        assert!(is_block(&game, 4, 3));
+    }
+
+    #[test]
+    fn dig_assist_digs_down_instead_of_under_a_loose_block() {
+        let layout = "
+            .........
+            ...Y.....
+            ...RP....
+            YBYBGBYBY
+        ";
+        let mut game = layout_game(layout, dig_assist_config());
+        game.update(Command::Left);
+        assert!(is_block(&game, 3, 2));
+        assert!(!is_block(&game, 4, 3));
+
+        let mut game = layout_game(layout, GameConfig::default());
+        game.update(Command::Left);
+        assert!(!is_block(&game, 3, 2));
+        assert!(is_block(&game, 4, 3));
+    }
+
+    #[test]
+    fn dig_assist_prefers_down_over_the_other_side() {
+        let layout = "
+            .........
+            ...Y.....
+            ...RPG...
+            YBYBGBYBY
+        ";
+        let mut game = layout_game(layout, dig_assist_config());
+        game.update(Command::Left);
+        assert!(is_block(&game, 3, 2));
+        assert!(!is_block(&game, 4, 3));
+        assert!(is_block(&game, 5, 2));
+    }
+
+    #[test]
+    fn dig_assist_digs_the_weakest_safe_side_when_down_is_not_diggable() {
+        let layout = "
+            .........
+            ...Y.....
+            ...RPG...
+            YBYBXBYBY
+        ";
+        let mut game = layout_game(layout, dig_assist_config());
+        let right = Point::new(5, 2);
+        game.cell_mut(right).block_life = 1;
+        game.update(Command::Left);
+        assert!(is_block(&game, 3, 2));
+        assert_eq!(game.cell(Point::new(3, 2)).block_life, BLOCK_LIFE_MAX);
+        assert!(!is_block(&game, 5, 2));
+
+        // 横がどちらも危ないときは押した方向をそのまま掘る
+        let layout = "
+            .........
+            ...Y.Y...
+            ...RPG...
+            YBYBXBYBY
+        ";
+        let mut game = layout_game(layout, dig_assist_config());
+        game.update(Command::Left);
+        assert!(is_block(&game, 5, 2));
+        assert!(!is_block(&game, 3, 2) || game.cell(Point::new(3, 2)).block_life < BLOCK_LIFE_MAX);
+    }
+
+    #[test]
+    fn erase_sounds_rise_with_the_combo() {
+        // 浮いている2つのかたまりが同じフレームに着地して、それぞれ4つつながって消える
+        let layout = "
+            .........
+            .R.....Y.
+            .R.....Y.
+            .........
+            .R.....Y.
+            .R..P..Y.
+            OOOOOOOOO
+        ";
+        let mut game = layout_game(layout, GameConfig::default());
+        let mut sounds = Vec::new();
+        for _ in 0..FPS * 5 {
+            game.update(Command::None);
+            sounds.extend(
+                game.requested_sounds
+                    .drain(..)
+                    .filter(|s| s.name.starts_with("erase")),
+            );
+        }
+        assert_eq!(
+            sounds.iter().map(|s| s.name).collect::<Vec<_>>(),
+            ["erase1.wav", "erase2.wav"]
+        );
+        assert!(sounds[0].volume < sounds[1].volume);
+
+        // コンボがERASE_SOUND_STEPSより続いても一番高い音のまま
+        assert_eq!(
+            SoundRequest::erase(9),
+            SoundRequest::erase(ERASE_SOUND_STEPS)
+        );
+        assert_eq!(SoundRequest::erase(9).name, "erase5.wav");
+        assert_eq!(SoundRequest::erase(9).volume, 1.0);
+        // ほかの音はファイルのままの大きさで鳴らす
+        assert_eq!(SoundRequest::new("crash.wav").volume, 1.0);
+    }
+
+    #[test]
+    fn combo_counts_groups_erased_inside_the_window_and_then_resets() {
+        let layout = "
+            .........
+            .R.....Y.
+            .R.....Y.
+            .........
+            .R.....Y.
+            .R..P..Y.
+            OOOOOOOOO
+        ";
+        let mut game = layout_game(layout, GameConfig::default());
+        assert_eq!(game.combo_frames_left(), 0);
+        while game.combo < 2 {
+            assert!(game.frame < FPS * 5, "the groups were never erased");
+            game.update(Command::None);
+        }
+        // 2つ目を消したフレームから窓が数え直しになる
+        assert_eq!(game.last_erase_frame, game.frame);
+        assert_eq!(game.combo_frames_left(), COMBO_WINDOW_FRAMES);
+        game.update(Command::None);
+        assert_eq!(game.combo_frames_left(), COMBO_WINDOW_FRAMES - 1);
+
+        // 窓が切れたらコンボは0に戻る
+        for _ in 1..COMBO_WINDOW_FRAMES {
+            assert_eq!(game.combo, 2);
+            game.update(Command::None);
+        }
+        assert_eq!(game.combo, 0);
+        assert_eq!(game.combo_frames_left(), 0);
+
+        // 窓の外で消したかたまりは1つ目から数え直す
+        game.count_combo();
+        assert_eq!(game.combo, 1);
+        game.frame += COMBO_WINDOW_FRAMES - 1;
+        game.count_combo();
+        assert_eq!(game.combo, 2);
+        game.frame += COMBO_WINDOW_FRAMES;
+        game.count_combo();
+        assert_eq!(game.combo, 1);
+    }
+
+    // 一時停止している間に押したキーは捨てて、止めたところからそのまま続くか
+    fn assert_pause_resumes(mut game: Game, state: PlayerState) {
+        let mut twin = game.clone();
+        game.toggle_pause();
+        let paused = game.state_hash();
+        for command in [Command::Left, Command::Down, Command::Right, Command::None] {
+            game.update(command);
+        }
+        assert_eq!(game.state_hash(), paused);
+        assert_eq!(game.player.state, state);
+        game.toggle_pause();
+        for _ in 0..FPS {
+            game.update(Command::None);
+            twin.update(Command::None);
+            assert_eq!(game.state_hash(), twin.state_hash());
+        }
+        assert!(game.player == twin.player);
+    }
+
+    #[test]
+    fn pausing_mid_walk_resumes_from_the_same_step() {
+        let layout = "
+            .........
+            ....P....
+            YBYBGBYBY
+        ";
+        let mut game = layout_game(layout, GameConfig::default());
+        game.update(Command::Right);
+        game.update(Command::None);
+        assert_eq!(game.player.state, PlayerState::Walking);
+        assert!(game.player.walking_frames > 0);
+        assert_pause_resumes(game, PlayerState::Walking);
+    }
+
+    #[test]
+    fn pausing_mid_fall_resumes_from_the_same_step() {
+        let layout = "
+            .........
+            ....P....
+            .........
+            .........
+            .........
+            YBYBGBYBY
+        ";
+        let mut game = layout_game(layout, GameConfig::default());
+        while game.player.falling_frames == 0 {
+            game.update(Command::None);
+        }
+        assert_eq!(game.player.state, PlayerState::Falling);
+        assert_pause_resumes(game, PlayerState::Falling);
+    }
+
+    #[test]
+    fn block_colors_follow_the_weights_and_skip_zero_weights() {
+        let config = GameConfig {
+            block_weights: vec![
+                (BlockColor::Red, 3.0),
+                (BlockColor::Blue, 1.0),
+                (BlockColor::Brown, 0.0),
+            ],
+            ..GameConfig::default()
+        };
+        let mut game = Game::from_seed(7, config);
+        let draws = 10000;
+        let mut red = 0;
+        for _ in 0..draws {
+            match game.random_block_color() {
+                BlockColor::Red => red += 1,
+                BlockColor::Blue => {}
+                color => panic!("drew {:?} with a zero weight", color),
+            }
+        }
+        let ratio = red as f64 / draws as f64;
+        assert!((0.73..0.77).contains(&ratio), "{}", ratio);
+
+        // 比率がすべて0でも止まらない
+        game.config.block_weights = vec![(BlockColor::Red, 0.0), (BlockColor::Brown, 0.0)];
+        assert_eq!(game.random_block_color(), BlockColor::PLAYABLE[0]);
+    }
+
+    // 遊んでいるような入力の列。同じ方向を何フレームか続けてから変える
+    fn play_command(frame: i32, seed: u64) -> Command {
+        let commands = [
+            Command::Down,
+            Command::Left,
+            Command::Down,
+            Command::Right,
+            Command::None,
+            Command::Down,
+            Command::Right,
+        ];
+        commands[((frame / 5) as usize + seed as usize) % commands.len()]
+    }
+
+    #[test]
+    fn dirty_update_matches_full_sweep() {
+        for seed in 0..6 {
+            let mut dirty = Game::new_with_config(GameConfig {
+                rigid_fall: seed % 2 == 1,
+                ..GameConfig::default()
+            });
+            let mut full = dirty.clone();
+            full.full_sweep = true;
+            for frame in 0..FPS * 60 {
+                let command = play_command(frame, seed);
+                dirty.update(command);
+                full.update(command);
+                assert!(
+                    dirty.cells == full.cells && dirty.player == full.player,
+                    "seed {} frame {}",
+                    seed,
+                    frame
+                );
+                if dirty.is_over || dirty.is_clear {
+                    break;
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn same_start_and_commands_give_the_same_hash_every_frame() {
+        let mut a = Game::new();
+        let mut b = a.clone();
+        let mut other = a.clone();
+        let mut diverged = false;
+        for frame in 0..FPS * 15 {
+            let command = play_command(frame, 6);
+            a.update(command);
+            b.update(command);
+            assert_eq!(a.state_hash(), b.state_hash(), "frame {}", frame);
+            // 違う入力で進めたゲームとはハッシュが変わる
+            other.update(play_command(frame, 1));
+            diverged |= other.state_hash() != a.state_hash();
+        }
+        assert!(diverged);
+    }
+
+    #[test]
+    fn state_hash_tells_apart_rng_positions() {
+        let game = Game::from_seed(1, GameConfig::default());
+        let mut other = game.clone();
+        other.rng.gen::<u32>();
+        assert_ne!(game.state_hash(), other.state_hash());
+    }
+
+    #[test]
+    fn pressing_into_the_same_wall_bonks_once() {
+        let mut game = layout_game(
+            "
+            .........
+            P........
+            ",
+            GameConfig::default(),
+        );
+        let bonks = |game: &mut Game, command: Command| {
+            game.events.clear();
+            game.update(command);
+            game.events
+                .iter()
+                .filter(|event| matches!(event, GameEvent::Blocked(Direction::Left)))
+                .count()
+        };
+        assert_eq!(bonks(&mut game, Command::Left), 1);
+        assert_eq!(bonks(&mut game, Command::None), 0);
+        assert_eq!(bonks(&mut game, Command::Left), 0);
+        assert_eq!(bonks(&mut game, Command::Left), 0);
+
+        // 右に歩いて戻ってきたら、新しく押したことになる
+        game.update(Command::Right);
+        while game.player.state != PlayerState::Standing {
+            game.update(Command::None);
+        }
+        game.update(Command::Left);
+        while game.player.state != PlayerState::Standing {
+            game.update(Command::None);
+        }
+        assert_eq!(game.player.p, Point::new(0, 1));
+        assert_eq!(bonks(&mut game, Command::Left), 1);
+    }
+
+    #[test]
+    fn alternating_blocked_inputs_bonk_at_most_once_per_interval() {
+        // 左右を壁にはさまれていて、どちらに押しても止められる
+        let mut game = layout_game(
+            "
+            .........
+            ...XPX...
+            OOOOOOOOO
+            ",
+            GameConfig::default(),
+        );
+        let mut bonks = 0;
+        let mut recoils = 0;
+        for frame in 0..BONK_INTERVAL_FRAMES * 2 {
+            let command = if frame % 2 == 0 {
+                Command::Left
+            } else {
+                Command::Right
+            };
+            game.update(command);
+            bonks += game
+                .requested_sounds
+                .drain(..)
+                .filter(|s| s.name == "bonk.wav")
+                .count();
+            recoils += game.events.drain(..).count();
+        }
+        // 押し直すたびに跳ね返りは見せるが、音は間を空けて鳴らす
+        assert_eq!(recoils, BONK_INTERVAL_FRAMES as usize * 2);
+        assert_eq!(bonks, 2);
+    }
+
+    // 宙に浮いた2個のかたまりの揺れ始めをずらしてから進め、最初にどちらかが動いたときに残っているかを返す
+    fn first_fall_of_pair(rigid_fall: bool) -> (bool, bool) {
+        let layout = "
+            .........
+            ...RR....
+            .........
+            .........
+            P........
+        ";
+        let config = GameConfig {
+            rigid_fall,
+            ..GameConfig::default()
+        };
+        let mut game = layout_game(layout, config);
+        game.cell_state_mut(Point::new(3, 1)).shaking_frames = 0;
+        game.cell_state_mut(Point::new(4, 1)).shaking_frames = SHAKE_FRAMES;
+        for _ in 0..FPS * 5 {
+            game.update(Command::None);
+            let left = is_block(&game, 3, 1);
+            let right = is_block(&game, 4, 1);
+            if !left || !right {
+                return (left, right);
+            }
+        }
+        panic!("the pair never fell");
+    }
+
+    #[test]
+    fn independent_fall_lets_each_cell_keep_its_own_timer() {
+        assert_eq!(first_fall_of_pair(false), (true, false));
+    }
+
+    #[test]
+    fn rigid_fall_drops_a_group_as_one_unit() {
+        assert_eq!(first_fall_of_pair(true), (false, false));
+    }
+
+    #[test]
+    fn viewport_at_the_bottom_reads_off_world_rows_as_empty() {
+        let mut game = Game::new();
+        let bottom = game.config.cells_y_max();
+        game.player.p = Point::new(4, bottom);
+        *game.cell_mut(game.player.p) = Cell::new();
+        game.update(Command::None);
+        // 描画と同じく、カメラの行から画面の行数と1行ぶん下まで（左右は1列ずつはみ出して）読む
+        let rows = game.camera_y - 1..=game.camera_y + 13;
+        assert!(*rows.end() > bottom);
+        for y in rows {
+            for x in CELLS_X_MIN - 1..=game.config.cells_x_max() + 1 {
+                let off_world = y > bottom || x < CELLS_X_MIN || x > game.config.cells_x_max();
+                if off_world {
+                    assert_eq!(game.cell_at(x, y).cell_type, CellType::None);
+                } else {
+                    assert!(game.cell_at(x, y) == game.cell(Point::new(x, y)));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn phantom_and_vent_depths_scale_with_the_block_height() {
+        let config = GameConfig {
+            normal_blocks_height: 40,
+            ..GameConfig::default()
+        };
+        assert_eq!(config.phantom_min_depth(), 20);
+        assert_eq!(config.vent_min_depth(), 24);
+
+        // 既定の高さの半分（50行）より浅い盤面でも、高さの半分から下に出る
+        let mut phantoms = 0;
+        for seed in 0..20 {
+            let game = Game::from_seed(seed, config.clone());
+            for y in CELLS_Y_MIN..=game.config.cells_y_max() {
+                for x in CELLS_X_MIN..=game.config.cells_x_max() {
+                    if game.cell(Point::new(x, y)).cell_type == CellType::Phantom {
+                        assert!(y >= config.up_space_height + config.phantom_min_depth());
+                        phantoms += 1;
+                    }
+                }
+            }
+        }
+        assert!(phantoms > 0);
+    }
+
+    // プレイヤーの右隣（phantom_at_playerならプレイヤーのいるセル）をファントムブロックにする
+    fn phantom_game(phantom_at_player: bool) -> Game {
+        let mut game = layout_game(
+            "
+            .........
+            ....P....
+            ",
+            GameConfig::default(),
+        );
+        let x = if phantom_at_player { 4 } else { 5 };
+        game.cell_mut(Point::new(x, 1)).cell_type = CellType::Phantom;
+        game
+    }
+
+    #[test]
+    fn phantom_block_crushes_on_the_frame_it_solidifies() {
+        let mut game = phantom_game(true);
+        // updateは最初にframeを進めるので、3回目のupdateで周期の頭（実体化）になる
+        game.frame = PHANTOM_PERIOD - 3;
+        game.update(Command::None);
+        assert!(!game.is_over);
+        game.update(Command::None);
+        assert!(!game.is_over);
+        game.update(Command::None);
+        assert!(game.is_phantom_solid(0));
+        assert!(game.is_over);
+    }
+
+    #[test]
+    fn walking_into_a_phantom_block_needs_it_faded_for_the_whole_walk() {
+        // 歩き終わる前に実体化するなら入れない
+        let mut game = phantom_game(false);
+        game.frame = PHANTOM_PERIOD - WALK_FRAMES - 1;
+        game.update(Command::Right);
+        assert_eq!(game.player.state, PlayerState::Standing);
+        assert!(game.events.contains(&GameEvent::Blocked(Direction::Right)));
+
+        // 歩き終わるまで透明なままなら入れる
+        let mut game = phantom_game(false);
+        game.frame = PHANTOM_SOLID_FRAMES;
+        game.update(Command::Right);
+        assert_eq!(game.player.state, PlayerState::Walking);
+    }
+
+    // エアが残り1のプレイヤー（右隣にエアカプセル）
+    fn out_of_breath(last_breath: bool) -> Game {
+        let config = GameConfig {
+            last_breath,
+            ..GameConfig::default()
+        };
+        let mut game = layout_game(
+            "
+            .........
+            P.A......
+            ",
+            config,
+        );
+        game.player.air = 1;
+        game
+    }
+
+    #[test]
+    fn last_breath_ends_the_game_after_its_grace_period() {
+        let mut game = out_of_breath(true);
+        for _ in 0..LAST_BREATH_FRAMES {
+            assert!(!game.is_over);
+            game.update(Command::None);
+        }
+        assert!(game.is_over);
+
+        // 無効ならエアが0になったフレームで終わる
+        let mut game = out_of_breath(false);
+        game.update(Command::None);
+        assert!(game.is_over);
+    }
+
+    fn walk(game: &mut Game, command: Command) {
+        game.update(command);
+        while game.player.state != PlayerState::Standing {
+            game.update(Command::None);
+        }
+    }
+
+    #[test]
+    fn reaching_air_during_the_last_breath_saves_the_player() {
+        let mut game = out_of_breath(true);
+        while game.last_breath_frames < 0 || game.last_breath_frames > WALK_FRAMES * 3 {
+            game.update(Command::None);
+        }
+        walk(&mut game, Command::Right);
+        walk(&mut game, Command::Right);
+        assert_eq!(game.player.p, Point::new(2, 1));
+        assert!(!game.is_over);
+        assert!(game.player.air > 0);
+        assert_eq!(game.last_breath_frames, -1);
+    }
+
+    // 真下のブロックを1回掘ったときに出た、掘った出来事の数
+    fn dug_events(color: char) -> usize {
+        let layout = format!("....P....\n....{}....", color);
+        let mut game = layout_game(&layout, GameConfig::default());
+        game.events.clear();
+        game.update(Command::Down);
+        game.events
+            .iter()
+            .filter(|&&e| e == GameEvent::Dug(Direction::Down))
+            .count()
+    }
+
+    #[test]
+    fn every_dig_reports_dug() {
+        assert_eq!(dug_events('R'), 1);
+        // 茶色ブロックは1回では壊れないが、掘ったことは知らせる
+        assert_eq!(dug_events('O'), 1);
+    }
+
+    fn air_cost_config() -> GameConfig {
+        GameConfig {
+            dig_air_cost: 100,
+            walk_air_cost: 40,
+            ..GameConfig::default()
+        }
+    }
+
+    #[test]
+    fn digging_costs_its_extra_air() {
+        let layout = "
+            .........
+            .P.......
+            RYGBRYGBR
+            ";
+        let mut game = layout_game(layout, air_cost_config());
+        let air = game.player.air;
+        game.update(Command::Down);
+        assert!(!is_block(&game, 1, 2));
+        assert_eq!(air - game.player.air, 1 + 100);
+
+        // 既定では毎フレームの1だけ
+        let mut game = layout_game(layout, GameConfig::default());
+        let air = game.player.air;
+        game.update(Command::Down);
+        assert_eq!(air - game.player.air, 1);
+    }
+
+    #[test]
+    fn walking_one_cell_costs_its_extra_air() {
+        let mut game = layout_game(
+            "
+            .........
+            .P.......
+            RYGBRYGBR
+            ",
+            air_cost_config(),
+        );
+        let air = game.player.air;
+        let frame = game.frame;
+        walk(&mut game, Command::Right);
+        assert_eq!(game.player.p, Point::new(2, 1));
+        assert_eq!(air - game.player.air, (game.frame - frame) + 40);
+    }
+
+    #[test]
+    fn player_rides_a_falling_floor_block_down() {
+        // プレイヤーが乗っているRの下は空いているので、Rは揺れてから落ちる
+        let mut game = layout_game(
+            "
+            .........
+            ....P....
+            ....R....
+            .........
+            .........
+            .........
+            YYYYGYYYY
+            ",
+            GameConfig::default(),
+        );
+        for _ in 0..FPS * 2 {
+            game.update(Command::None);
+            // どのフレームでもプレイヤーは落ちていくブロックのすぐ上にいる
+            let floor = (1..=5).find(|&y| is_block(&game, 4, y)).unwrap();
+            assert_eq!(
+                game.player.p,
+                Point::new(4, floor - 1),
+                "frame {}",
+                game.frame
+            );
+            assert!(!game.is_over);
+        }
+        assert!(is_block(&game, 4, 5));
+        assert_eq!(game.player.p, Point::new(4, 4));
+        assert_eq!(game.player.state, PlayerState::Standing);
+    }
+
+    #[test]
+    fn next_stage_carries_the_configured_share_of_air() {
+        let config = GameConfig {
+            air_carry: Some(0.5),
+            ..GameConfig::default()
+        };
+        let mut game = Game::from_seed(1, config);
+        game.player.air = 1000;
+        assert_eq!(game.next_stage().player.air, 500);
+
+        // 既定では満タンから始まる
+        let mut game = Game::from_seed(1, GameConfig::default());
+        game.player.air = 10;
+        assert_eq!(game.next_stage().player.air, AIR_MAX);
+    }
+
+    // エアカプセルの上にYとRを積んで、落ち着くまで進める
+    fn stack_on_air(grounding_through_air: bool) -> Game {
+        let config = GameConfig {
+            grounding_through_air,
+            ..GameConfig::default()
+        };
+        let mut game = layout_game(
+            "
+            P........
+            ....R....
+            ....Y....
+            ....A....
+            YYYYGYYYY
+            ",
+            config,
+        );
+        for _ in 0..FPS * 3 {
+            game.update(Command::None);
+        }
+        game
+    }
+
+    #[test]
+    fn air_holds_up_a_stack_only_when_grounding_through_air() {
+        let game = stack_on_air(true);
+        assert!(is_block(&game, 4, 1));
+        assert!(is_block(&game, 4, 2));
+        assert_eq!(game.cell(Point::new(4, 3)).cell_type, CellType::Air);
+
+        // エアが支えなければ、積んだブロックはエアをつぶして1段下がる
+        let game = stack_on_air(false);
+        assert_eq!(game.cell(Point::new(4, 1)).cell_type, CellType::None);
+        assert!(is_block(&game, 4, 2));
+        assert!(is_block(&game, 4, 3));
+    }
+
+    #[test]
+    fn running_the_steps_one_by_one_matches_update() {
+        let mut whole = Game::from_seed(9, GameConfig::default());
+        let mut stepped = whole.clone();
+        for frame in 0..FPS * 10 {
+            let command = play_command(frame, 9);
+            whole.update(command);
+            // updateと同じく、frameを進めてから順番どおりに1段階ずつ進める
+            stepped.frame += 1;
+            for step in UpdateStep::ORDER {
+                stepped.run_step(step, command);
+                if stepped.is_clear {
+                    break;
+                }
+            }
+            assert_eq!(stepped.state_hash(), whole.state_hash(), "frame {}", frame);
+            if whole.is_over || whole.is_clear {
+                break;
+            }
+        }
+    }
+
+    #[test]
+    fn fall_step_alone_moves_only_falling_blocks() {
+        let mut game = layout_game(
+            "
+            P........
+            ....R....
+            .........
+            YYYYGYYYY
+            ",
+            GameConfig::default(),
+        );
+        // 1マス落ちるまで落下の段階だけを進める。プレイヤーもほかのセルも動かない
+        let player = game.player.clone();
+        for _ in 0..FPS * 2 {
+            if !is_block(&game, 4, 1) {
+                break;
+            }
+            game.run_step(UpdateStep::FallBlocks, Command::None);
+            assert!(game.player == player);
+        }
+        assert!(!is_block(&game, 4, 1));
+        assert!(is_block(&game, 4, 2));
+        assert!((0..9).all(|x| is_block(&game, x, 3)));
+    }
+
+    #[test]
+    fn digging_under_a_falling_block_lets_it_land_in_the_hole() {
+        // 上のRは支えがなく落ちてきて、プレイヤーの右のRの上に乗る
+        let layout = "
+            ....R....
+            .........
+            .........
+            .........
+            .........
+            ...PR....
+            YYYYYYYYY
+            ";
+        // 落ちてくる途中や、乗った直後（かたまりに加わったがまだ接地していない）フレームに掘っても、
+        // 掘るのは下のRだけで、上のRはその穴に落ちて残る
+        for dig_frame in 30..54 {
+            let mut game = Game::from_layout(layout, GameConfig::default()).unwrap();
+            for frame in 0..FPS * 4 {
+                let command = if frame == dig_frame {
+                    Command::Right
+                } else {
+                    Command::None
+                };
+                game.update(command);
+            }
+            assert!(!game.is_over, "dig at {}", dig_frame);
+            assert!(is_block(&game, 4, 5), "dig at {}", dig_frame);
+            assert!(
+                (0..5).all(|y| !is_block(&game, 4, y)),
+                "dig at {}",
+                dig_frame
+            );
+            assert_eq!(game.blocks_dug, 1, "dig at {}", dig_frame);
+        }
+        // 接地したあとは2つで1つのかたまりなので、まとめて掘る
+        let mut game = Game::from_layout(layout, GameConfig::default()).unwrap();
+        for _ in 0..54 {
+            game.update(Command::None);
+        }
+        assert!(game.cell(Point::new(4, 4)).grounded);
+        game.update(Command::Right);
+        assert!((0..=5).all(|y| !is_block(&game, 4, y)));
+    }
+
+    // 宙に浮いた2つのブロックが揺れ始めるまで進め、その間に鳴らした地鳴りの数
+    fn rumbles(shake_warning: bool) -> usize {
+        let layout = "
+            .R...Y...
+            .........
+            P........
+        ";
+        let config = GameConfig {
+            shake_warning,
+            ..GameConfig::default()
+        };
+        let mut game = Game::from_layout(layout, config).unwrap();
+        let mut count = 0;
+        for _ in 0..3 {
+            game.update(Command::None);
+            count += game
+                .requested_sounds
+                .drain(..)
+                .filter(|s| s.name == "rumble.wav")
+                .count();
+        }
+        count
     }
-    if value > max {
-        return max;
+
+    #[test]
+    fn shake_warning_rumbles_once_and_can_be_turned_off() {
+        assert_eq!(rumbles(true), 1);
+        assert_eq!(rumbles(false), 0);
+    }
+
+    #[test]
+    fn column_collapse_drops_the_column_under_the_player() {
+        let mut game = layout_game(
+            "
+            ....P....
+            ...RRR...
+            ...Y.Y...
+            YYYY.YYYY
+            YYYYYYYYY
+            ",
+            GameConfig::default(),
+        );
+        // たまっていなければ何も起きない
+        game.collapse_charge = 0;
+        game.update(Command::Collapse);
+        assert!(game.events.contains(&GameEvent::Blocked(Direction::Down)));
+        assert!(!game.cell(Point::new(4, 1)).loose);
+
+        for _ in 0..COLLAPSE_CHARGE_FRAMES {
+            game.update(Command::None);
+        }
+        let air = game.player.air;
+        game.update(Command::Collapse);
+        assert!(air - game.player.air >= COLLAPSE_AIR_COST);
+        assert!(game.collapse_charge <= 1);
+        // 足元のRだけが横のつながりを切られて支えを失い、両隣のRはそのまま
+        assert!(game.cell(Point::new(4, 1)).loose);
+        assert!(!game.cell(Point::new(4, 1)).grounded);
+        assert!(game.cell(Point::new(3, 1)).grounded);
+        assert!(game.cell(Point::new(5, 1)).grounded);
+
+        // ぐらつかずにすぐ落ち、世界の底の手前の床で止まる
+        let mut landed = None;
+        for frame in 0..FPS {
+            game.update(Command::None);
+            let cell = game.cell(Point::new(4, 3));
+            if landed.is_none() && cell.cell_type == CellType::Block && cell.grounded {
+                landed = Some(frame);
+            }
+        }
+        assert!(landed.unwrap() < SHAKE_FRAMES);
+        assert_eq!(game.cell(Point::new(4, 1)).cell_type, CellType::None);
+        assert!(!game.cell(Point::new(4, 3)).loose);
+    }
+
+    const WALK_AWAY_LAYOUT: &str = "
+        .........
+        .........
+        .........
+        ....R....
+        .........
+        ....P....
+        YYYYYYYYY
+        ";
+
+    // 立ったままだとRがプレイヤーのセルに入ってくる（つぶされる）フレーム
+    fn frame_block_lands_on_player() -> i32 {
+        let mut game = Game::from_layout(WALK_AWAY_LAYOUT, GameConfig::default()).unwrap();
+        while !is_block(&game, 4, 5) {
+            game.update(Command::None);
+        }
+        assert!(game.is_over);
+        game.frame
+    }
+
+    #[test]
+    fn walking_out_before_the_block_arrives_escapes_it() {
+        let landing = frame_block_lands_on_player();
+        for start in landing - 4..=landing {
+            let mut game = Game::from_layout(WALK_AWAY_LAYOUT, GameConfig::default()).unwrap();
+            while game.frame + 1 < start {
+                game.update(Command::None);
+            }
+            game.update(Command::Right);
+            for _ in 0..FPS / 6 {
+                game.update(Command::None);
+            }
+            // 歩き出したフレームにはもう元のセルを出ていく途中なので、次のフレームから落ちてきても助かる
+            // 歩き出したのと同じフレームに落ちてきたブロックにはつぶされる
+            if start < landing {
+                assert!(!game.is_over, "walk at {}", start);
+                assert_eq!(game.player.p, Point::new(5, 5));
+            } else {
+                assert!(game.is_over, "walk at {}", start);
+            }
+        }
+    }
+
+    // エアカプセルの真上clearance行以内にある茶色ブロックの数
+    fn brown_over_air(game: &Game, clearance: i32) -> usize {
+        (CELLS_Y_MIN..=game.config.cells_y_max())
+            .flat_map(|y| (CELLS_X_MIN..=game.config.cells_x_max()).map(move |x| Point::new(x, y)))
+            .filter(|&p| game.cell(p).cell_type == CellType::Air)
+            .flat_map(|p| {
+                ((p.y - clearance).max(CELLS_Y_MIN)..p.y).map(move |y| Point::new(p.x, y))
+            })
+            .filter(|&q| {
+                let cell = game.cell(q);
+                cell.cell_type == CellType::Block && cell.color == BlockColor::Brown
+            })
+            .count()
+    }
+
+    #[test]
+    fn no_brown_block_sits_over_an_air_capsule() {
+        let mut without_rule = 0;
+        for seed in 0..100 {
+            let game = Game::from_seed(seed, GameConfig::default());
+            assert_eq!(
+                brown_over_air(&game, AIR_BROWN_CLEARANCE),
+                0,
+                "seed {}",
+                seed
+            );
+            let config = GameConfig {
+                air_brown_clearance: 0,
+                ..GameConfig::default()
+            };
+            without_rule += brown_over_air(&Game::from_seed(seed, config), AIR_BROWN_CLEARANCE);
+        }
+        // 決まりがなければ茶色ブロックがエアカプセルの上に来ることがある
+        assert!(without_rule > 0);
+    }
+
+    fn three_capsules_game() -> Game {
+        Game::from_layout(
+            "
+            A........
+            ....P....
+            RYRYRYRYR
+            YRYRYRYRY
+            RYRYAYRYR
+            YRYRYRYRY
+            ARYRYRYRY
+            ",
+            GameConfig::default(),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn air_capsule_helpers_find_the_capsules_around_the_player() {
+        let game = three_capsules_game();
+        assert_eq!(
+            game.air_capsules(),
+            vec![Point::new(0, 0), Point::new(4, 4), Point::new(0, 6)]
+        );
+        // (0, 0)は5マス、(4, 4)は3マス離れている
+        assert_eq!(game.nearest_air_capsule(), Some(Point::new(4, 4)));
+        // プレイヤーより上のエアカプセルは数えない
+        assert_eq!(game.depth_to_next_air(), Some(3));
+    }
+
+    #[test]
+    fn air_capsule_helpers_without_capsules_return_none() {
+        let game = Game::from_layout("....P....\nRYRYRYRYR\n", GameConfig::default()).unwrap();
+        assert!(game.air_capsules().is_empty());
+        assert_eq!(game.nearest_air_capsule(), None);
+        assert_eq!(game.depth_to_next_air(), None);
+        assert_eq!(game.suggest_path_to_next_air(), None);
+    }
+
+    #[test]
+    fn path_to_next_air_ends_at_the_cheapest_capsule() {
+        let game = three_capsules_game();
+        // 真下に2つ掘ればたどり着く
+        assert_eq!(
+            game.suggest_path_to_next_air(),
+            Some(vec![Point::new(4, 2), Point::new(4, 3), Point::new(4, 4)])
+        );
+    }
+
+    #[test]
+    fn path_to_next_air_digs_brown_only_when_the_detour_costs_more() {
+        // 真下の茶色ブロックを掘るか、左へ回りこんで落ちるか
+        let layout = "
+            .........
+            ....P....
+            CCC.OCCCC
+            CCC.ACCCC
+            CCCCCCCCC
+        ";
+        let through = vec![Point::new(4, 2), Point::new(4, 3)];
+        let around = vec![
+            Point::new(3, 1),
+            Point::new(3, 2),
+            Point::new(3, 3),
+            Point::new(4, 3),
+        ];
+        let path_with_walk_cost = |walk_air_cost: i32| {
+            let config = GameConfig {
+                walk_air_cost,
+                ..GameConfig::default()
+            };
+            let game = Game::from_layout(layout, config).unwrap();
+            game.suggest_path_to_next_air().unwrap()
+        };
+        // 茶色ブロックはエアがBROWN_AIR_COST減るので、2歩よけいに歩くほうが安い
+        assert_eq!(path_with_walk_cost(0), around);
+        // 1歩でBROWN_AIR_COSTの半分より多く減るなら、2歩歩くより掘るほうが安い
+        assert_eq!(path_with_walk_cost(BROWN_AIR_COST / 2 + 1), through);
+
+        // ドリル強化中は茶色ブロックでもエアが減らないので、掘り抜ける
+        let mut game = Game::from_layout(layout, GameConfig::default()).unwrap();
+        game.drill_boost_frames = DRILL_BOOST_FRAMES;
+        assert_eq!(game.suggest_path_to_next_air().unwrap(), through);
+    }
+
+    // 通常ブロックのある深さをrows行ずつに区切ったときの、区切りごとのエアカプセルの数
+    fn air_per_band(game: &Game, rows: i32) -> Vec<usize> {
+        let top = game.config.up_space_height;
+        let bottom = top + game.config.normal_blocks_height;
+        let capsules = game.air_capsules();
+        (top..bottom)
+            .step_by(rows as usize)
+            .map(|band| {
+                let band = band..(band + rows).min(bottom);
+                capsules.iter().filter(|p| band.contains(&p.y)).count()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn every_depth_band_has_its_air_capsules() {
+        for seed in 0..100 {
+            let game = Game::from_seed(seed, GameConfig::default());
+            let bands = air_per_band(&game, AIR_BAND_ROWS);
+            assert!(
+                bands.iter().all(|&n| n >= AIR_BAND_MIN as usize),
+                "seed {}",
+                seed
+            );
+
+            let config = GameConfig {
+                air_band_rows: 10,
+                air_band_min: 2,
+                ..GameConfig::default()
+            };
+            let game = Game::from_seed(seed, config);
+            assert!(
+                air_per_band(&game, 10).iter().all(|&n| n >= 2),
+                "seed {}",
+                seed
+            );
+        }
+    }
+
+    #[test]
+    fn applying_a_diff_reaches_the_other_frame() {
+        let mut game = Game::from_seed(3, GameConfig::default());
+        let mut frames = vec![game.clone()];
+        for frame in 0..FPS * 20 {
+            game.update(play_command(frame, 3));
+            frames.push(game.clone());
+        }
+        let mut rng = ChaCha12Rng::seed_from_u64(0);
+        for _ in 0..200 {
+            let from = &frames[rng.gen_range(0..frames.len())];
+            let to = &frames[rng.gen_range(0..frames.len())];
+            let mut synced = from.clone();
+            synced.apply_diff(&from.diff(to));
+            assert_eq!(synced.state_hash(), to.state_hash());
+            // 当てたあとは同じ入力で同じように進む
+            let mut expected = to.clone();
+            for frame in 0..FPS {
+                synced.update(play_command(frame, 0));
+                expected.update(play_command(frame, 0));
+            }
+            assert_eq!(synced.state_hash(), expected.state_hash());
+        }
+    }
+
+    #[test]
+    fn layout_characters_map_to_cells() {
+        let game = Game::from_layout(
+            "
+            .P.AK....
+            RYGBVTOCX
+            ",
+            GameConfig::default(),
+        )
+        .unwrap();
+        assert_eq!(game.player.p, Point::new(1, 0));
+        let cell = |x: i32, y: i32| {
+            (
+                game.cell(Point::new(x, y)).cell_type,
+                game.cell(Point::new(x, y)).color,
+            )
+        };
+        assert_eq!(cell(0, 0).0, CellType::None);
+        assert_eq!(cell(1, 0).0, CellType::None);
+        assert_eq!(cell(3, 0).0, CellType::Air);
+        assert_eq!(cell(4, 0).0, CellType::Item(ItemKind::Key));
+        let colors = [
+            BlockColor::Red,
+            BlockColor::Yellow,
+            BlockColor::Green,
+            BlockColor::Blue,
+            BlockColor::Purple,
+            BlockColor::Cyan,
+            BlockColor::Brown,
+            BlockColor::Clear,
+        ];
+        for (x, color) in colors.into_iter().enumerate() {
+            assert_eq!(cell(x as i32, 1), (CellType::Block, color));
+        }
+        assert_eq!(cell(8, 1).0, CellType::Vent);
+        // レイアウトより下はクリアブロックで埋まる
+        for y in 2..game.config.cells_y_len() {
+            for x in CELLS_X_MIN..=game.config.cells_x_max() {
+                assert_eq!(cell(x, y), (CellType::Block, BlockColor::Clear));
+            }
+        }
+    }
+
+    #[test]
+    fn layouts_with_bad_rows_are_rejected() {
+        let config = GameConfig::default();
+        assert!(Game::from_layout("........", config.clone()).is_err());
+        assert!(Game::from_layout("..........", config.clone()).is_err());
+        assert!(Game::from_layout("....Z....", config.clone()).is_err());
+        let too_many = ".........\n".repeat(config.cells_y_len() as usize + 1);
+        assert!(Game::from_layout(&too_many, config).is_err());
+    }
+
+    #[test]
+    fn layout_vent_does_not_join_red_blocks() {
+        // 通気口が赤いブロックとつながるなら4つのかたまりになって消えてしまう
+        let mut game = layout_game(
+            "
+            .........
+            ....P....
+            RRRX.....
+            ",
+            GameConfig::default(),
+        );
+        for _ in 0..FPS * 2 {
+            game.update(Command::None);
+        }
+        for x in 0..3 {
+            assert!(is_block(&game, x, 2));
+        }
+        assert_eq!(game.cell(Point::new(3, 2)).cell_type, CellType::Vent);
+        assert_eq!(game.cell(Point::new(3, 2)).leader, None);
+    }
+
+    #[test]
+    fn vent_drains_air_only_while_the_player_is_beside_it() {
+        let air_used = |layout: &str| {
+            let mut game = layout_game(layout, GameConfig::default());
+            let air = game.player.air;
+            for _ in 0..10 {
+                game.update(Command::None);
+            }
+            air - game.player.air
+        };
+        let far = air_used(
+            "
+            .........
+            X...P....
+            ",
+        );
+        let near = air_used(
+            "
+            .........
+            XP.......
+            ",
+        );
+        assert_eq!(far, 10);
+        assert_eq!(near, 10 * (1 + VENT_AIR_DRAIN));
+
+        // 離れれば余分に減らなくなる
+        let mut game = layout_game(
+            "
+            .........
+            XP.......
+            ",
+            GameConfig::default(),
+        );
+        while game.player.p.x < 3 {
+            game.update(Command::Right);
+        }
+        while game.player.state != PlayerState::Standing {
+            game.update(Command::None);
+        }
+        assert!(!game.is_near_vent());
+        let air = game.player.air;
+        for _ in 0..10 {
+            game.update(Command::None);
+        }
+        assert_eq!(air - game.player.air, 10);
+    }
+
+    fn count_blocks(game: &Game) -> usize {
+        (CELLS_Y_MIN..=game.config.cells_y_max())
+            .flat_map(|y| (CELLS_X_MIN..=game.config.cells_x_max()).map(move |x| (x, y)))
+            .filter(|&(x, y)| is_block(game, x, y))
+            .count()
+    }
+
+    #[test]
+    fn fast_block_does_not_overwrite_a_slow_block_below() {
+        let layout = "
+            P........
+            .........
+            ....R....
+            ....Y....
+            .........
+            .........
+            BBBBBBBBB
+            ";
+        for rigid_fall in [false, true] {
+            let config = GameConfig {
+                rigid_fall,
+                fall_pace: vec![(BlockColor::Red, 0.3), (BlockColor::Yellow, 2.0)],
+                ..GameConfig::default()
+            };
+            let mut game = Game::from_layout(layout, config).unwrap();
+            let before = count_blocks(&game);
+            for _ in 0..FPS * 5 {
+                game.update(Command::None);
+            }
+            assert_eq!(count_blocks(&game), before, "rigid_fall {}", rigid_fall);
+        }
+    }
+
+    #[test]
+    fn random_boards_fall_the_same_as_a_full_sweep() {
+        let commands = [
+            Command::None,
+            Command::None,
+            Command::Left,
+            Command::Right,
+            Command::Down,
+            Command::Down,
+        ];
+        for seed in 0..20 {
+            let mut rng = ChaCha12Rng::seed_from_u64(seed);
+            let config = GameConfig {
+                rigid_fall: seed % 2 == 0,
+                fall_pace: BlockColor::PLAYABLE
+                    .iter()
+                    .map(|&color| (color, rng.gen_range(0.3..2.0)))
+                    .collect(),
+                ..GameConfig::default()
+            };
+            let mut dirty = Game::from_seed(seed, config);
+            let mut full = dirty.clone();
+            full.full_sweep = true;
+            for _ in 0..FPS * 20 {
+                let command = commands[rng.gen_range(0..commands.len())];
+                dirty.update(command);
+                full.update(command);
+                assert_eq!(
+                    dirty.state_hash(),
+                    full.state_hash(),
+                    "seed {} frame {}",
+                    seed,
+                    dirty.frame
+                );
+                if dirty.is_over || dirty.is_clear {
+                    break;
+                }
+            }
+        }
+    }
+
+    // 上から落ちてくるブロックの下にプレイヤーを立たせて、落ち終わるまで進める
+    fn drop_on_player(layout: &str, block_push: bool) -> Game {
+        let config = GameConfig {
+            block_push,
+            ..GameConfig::default()
+        };
+        let mut game = Game::from_layout(layout, config).unwrap();
+        for _ in 0..FPS * 3 {
+            game.update(Command::None);
+            if game.is_over {
+                break;
+            }
+        }
+        game
+    }
+
+    #[test]
+    fn falling_block_pushes_the_player_into_a_free_side() {
+        let layout = "
+            ....R....
+            .........
+            ...YP....
+            GGGGGGGGG
+            ";
+        let game = drop_on_player(layout, true);
+        assert!(!game.is_over);
+        assert_eq!(game.player.p, Point::new(5, 2));
+        assert!(is_block(&game, 4, 2));
+        assert!(drop_on_player(layout, false).is_over);
+    }
+
+    #[test]
+    fn falling_block_crushes_a_boxed_in_player() {
+        let game = drop_on_player(
+            "
+            ....R....
+            .........
+            ...YPY...
+            GGGGGGGGG
+            ",
+            true,
+        );
+        assert!(game.is_over);
+    }
+
+    #[test]
+    fn digging_up_into_a_falling_block_is_refused() {
+        // プレイヤーの上のRは支えがないので、揺れてから落ちてくる
+        let mut game = Game::from_layout(
+            "
+            .........
+            ....R....
+            ....P....
+            GGGGGGGGG
+            ",
+            GameConfig::default(),
+        )
+        .unwrap();
+        let above = Point::new(4, 1);
+        let mut tries = 0;
+        while !game.is_over && game.frame < FPS * 5 {
+            let cell = *game.cell(above);
+            if cell.cell_type != CellType::Block || cell.falling_frames < 0 {
+                game.update(Command::None);
+                continue;
+            }
+            game.events.clear();
+            game.update(Command::Up);
+            // このフレームの落下でプレイヤーのセルまで来ていたら、掘る前につぶされている
+            if game.is_over {
+                break;
+            }
+            // 押し直しても知らせるのは最初の1回だけ
+            if tries == 0 {
+                assert!(game.events.contains(&GameEvent::Blocked(Direction::Up)));
+            }
+            assert_eq!(game.blocks_dug, 0);
+            tries += 1;
+        }
+        assert!(tries > 0);
+        assert!(game.is_over);
+    }
+
+    #[test]
+    fn walking_off_each_edge_wraps_around() {
+        let layout = "
+            .........
+            P........
+            ";
+        let wrap = GameConfig {
+            wrap_horizontal: true,
+            ..GameConfig::default()
+        };
+        let max = wrap.cells_x_max();
+        let mut game = layout_game(layout, wrap);
+        walk(&mut game, Command::Left);
+        assert_eq!(game.player.p, Point::new(max, 1));
+        walk(&mut game, Command::Right);
+        assert_eq!(game.player.p, Point::new(CELLS_X_MIN, 1));
+
+        // 回り込まない設定では端で止まる
+        let mut game = layout_game(layout, GameConfig::default());
+        walk(&mut game, Command::Left);
+        assert_eq!(game.player.p, Point::new(CELLS_X_MIN, 1));
+        game.player.p.x = max;
+        walk(&mut game, Command::Right);
+        assert_eq!(game.player.p, Point::new(max, 1));
+    }
+
+    #[test]
+    fn digging_off_the_left_edge_digs_the_right_column() {
+        let wrap = GameConfig {
+            wrap_horizontal: true,
+            ..GameConfig::default()
+        };
+        let mut game = layout_game(
+            "
+            .........
+            P.......R
+            ",
+            wrap,
+        );
+        game.update(Command::Left);
+        assert!(!is_block(&game, 8, 1));
+        assert_eq!(game.player.p, Point::new(0, 1));
+    }
+
+    #[test]
+    fn a_tall_single_color_field_shares_one_leader() {
+        let layout = format!("P........\n{}", "RRRRRRRRR\n".repeat(100));
+        let mut game = Game::from_layout(&layout, GameConfig::default()).unwrap();
+        game.set_leaders();
+        for y in 1..=100 {
+            for x in CELLS_X_MIN..=game.config.cells_x_max() {
+                assert_eq!(game.cell(Point::new(x, y)).leader, Some(Point::new(0, 1)));
+            }
+        }
+    }
+
+    // 素集合森を使わずに、pから同じ色でつながったブロックをたどる（比べるための素直な塗りつぶし）
+    fn flood_fill(game: &Game, p: Point) -> Vec<Point> {
+        let color = game.cell(p).color;
+        let mut seen = vec![p];
+        let mut stack = vec![p];
+        while let Some(q) = stack.pop() {
+            for direction in Direction::all() {
+                let Some(r) = game.neighbor(q, direction) else {
+                    continue;
+                };
+                let cell = game.cell(r);
+                if cell.cell_type == CellType::Block
+                    && cell.color == color
+                    && !cell.loose
+                    && !seen.contains(&r)
+                {
+                    seen.push(r);
+                    stack.push(r);
+                }
+            }
+        }
+        seen.sort_by_key(|q| (q.y, q.x));
+        seen
+    }
+
+    #[test]
+    fn component_index_matches_a_flood_fill() {
+        for seed in 0..4 {
+            let mut game = Game::from_seed(seed, GameConfig::default());
+            for frame in 0..FPS * 10 {
+                game.frame += 1;
+                for step in UpdateStep::ORDER {
+                    game.run_step(step, play_command(frame, seed));
+                    if step != UpdateStep::SetLeaders {
+                        continue;
+                    }
+                    for y in CELLS_Y_MIN..=game.config.cells_y_max() {
+                        for x in CELLS_X_MIN..=game.config.cells_x_max() {
+                            let p = Point::new(x, y);
+                            let cell = game.cell(p);
+                            if cell.cell_type != CellType::Block || cell.loose {
+                                continue;
+                            }
+                            let mut component = game.get_component(p);
+                            component.sort_by_key(|q| (q.y, q.x));
+                            assert_eq!(component, flood_fill(&game, p), "seed {} at {:?}", seed, p);
+                        }
+                    }
+                }
+                if game.is_over || game.is_clear {
+                    break;
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn player_spawns_on_the_last_row_of_the_up_space() {
+        let config = GameConfig {
+            width: 13,
+            up_space_height: 9,
+            ..GameConfig::default()
+        };
+        let game = Game::from_seed(1, config);
+        assert_eq!(game.player.p, Point::new(6, 8));
+        assert!(is_block(&game, 6, 9));
+    }
+
+    #[test]
+    fn run_commands_replays_the_same_run_from_a_seed() {
+        let commands: Vec<Command> = (0..FPS * 15).map(|frame| play_command(frame, 2)).collect();
+        let mut game = Game::new_seeded(2);
+        game.run_commands(&commands);
+        let mut stepped = Game::new_seeded(2);
+        for &command in &commands {
+            stepped.step(command);
+        }
+        assert_eq!(game.frame, commands.len() as i32 - 1);
+        assert!(game.depth > 0);
+        assert_eq!(game.depth, stepped.depth);
+        assert_eq!(game.state_hash(), stepped.state_hash());
+    }
+
+    #[test]
+    fn the_same_seed_builds_the_same_cells() {
+        let game = Game::new_seeded(42);
+        assert!(game.cells == Game::new_seeded(42).cells);
+        assert!(game.cells != Game::new_seeded(43).cells);
+        // 次のステージのシードも時計ではなく前のシードから決まる
+        let next = game.next_stage();
+        assert_eq!(next.seed, 43);
+        assert!(next.cells == Game::new_seeded(42).next_stage().cells);
+        assert!(next.cells == Game::from_seed_at_stage(43, GameConfig::default(), 2).cells);
+    }
+
+    #[test]
+    fn erase_points_grow_with_the_square_of_the_group() {
+        assert_eq!(erase_points(4, 0), 160);
+        assert_eq!(erase_points(4, 1), 160);
+        assert_eq!(erase_points(6, 1), 360);
+        assert_eq!(erase_points(6, 3), 1080);
+        assert_eq!(erase_points(100_000, 1000), u32::MAX);
+    }
+
+    // 宙に浮いた赤ブロックを落として、rowの赤ブロックとその下の赤ブロックにつなげて消したときの点
+    fn score_of_fallen_group(row: &str) -> u32 {
+        let layout = format!(".........\n..R......\n{}\nYGRGYGYGY", row);
+        let mut game = layout_game(&layout, GameConfig::default());
+        for _ in 0..FPS * 5 {
+            game.update(Command::None);
+        }
+        assert!(!is_block(&game, 2, 2));
+        assert!(!is_block(&game, 2, 3));
+        game.score
+    }
+
+    #[test]
+    fn erasing_fallen_groups_of_four_and_six_scores_160_and_360() {
+        assert_eq!(score_of_fallen_group(".R.R....P"), 160);
+        assert_eq!(score_of_fallen_group("RR.RR...P"), 360);
+    }
+
+    // プレイヤーのいるセルに大きさcapacityのエアカプセルを置き、エアがairのときに取らせる
+    fn collect_air(capacity: AirCapacity, air: i32) -> i32 {
+        let mut game = Game::from_layout("P........", GameConfig::default()).unwrap();
+        let p = game.player.p;
+        game.cell_mut(p).cell_type = CellType::Air;
+        game.cell_mut(p).capacity = capacity;
+        game.player.air = air;
+        game.run_step(UpdateStep::CollectItems, Command::None);
+        assert_eq!(game.cell(p).cell_type, CellType::None);
+        game.player.air
+    }
+
+    #[test]
+    fn large_air_capsule_restores_its_share_and_clamps_at_full() {
+        assert_eq!(AirCapacity::Large.restore(), AIR_MAX * 2 / 5);
+        assert_eq!(
+            collect_air(AirCapacity::Large, 1000),
+            1000 + AIR_MAX * 2 / 5
+        );
+        assert_eq!(collect_air(AirCapacity::Large, AIR_MAX - 10), AIR_MAX);
+        assert_eq!(collect_air(AirCapacity::Small, 1000), 1000 + AIR_MAX / 10);
+    }
+
+    #[test]
+    fn generated_air_capsules_come_in_every_size() {
+        let game = Game::from_seed(3, GameConfig::default());
+        let capsules = game.air_capsules();
+        for capacity in [AirCapacity::Small, AirCapacity::Medium, AirCapacity::Large] {
+            assert!(
+                capsules.iter().any(|&p| game.cell(p).capacity == capacity),
+                "{:?}",
+                capacity
+            );
+        }
+    }
+
+    fn brown_blocks(game: &Game) -> usize {
+        (CELLS_Y_MIN..=game.config.cells_y_max())
+            .flat_map(|y| (CELLS_X_MIN..=game.config.cells_x_max()).map(move |x| (x, y)))
+            .filter(|&(x, y)| {
+                let cell = game.cell(Point::new(x, y));
+                cell.cell_type == CellType::Block && cell.color == BlockColor::Brown
+            })
+            .count()
+    }
+
+    #[test]
+    fn later_stages_have_more_brown_blocks() {
+        for seed in 0..5 {
+            let first = Game::from_seed_at_stage(seed, GameConfig::default(), 1);
+            let fifth = Game::from_seed_at_stage(seed, GameConfig::default(), 5);
+            assert!(brown_blocks(&fifth) > brown_blocks(&first), "seed {}", seed);
+        }
+        assert!(Difficulty::for_stage(5).brown_scale > Difficulty::for_stage(1).brown_scale);
+    }
+
+    // プレイヤーが6マス落ちて着地するまでのフレーム数
+    fn frames_to_land(command: Command) -> i32 {
+        let layout = "
+            P........
+            .........
+            .........
+            .........
+            .........
+            .........
+            .........
+        ";
+        let mut game = Game::from_layout(layout, GameConfig::default()).unwrap();
+        for frame in 1..=FPS * 5 {
+            game.update(command);
+            if game.player.p.y == 6 && game.player.state == PlayerState::Standing {
+                return frame;
+            }
+        }
+        panic!("the player never landed");
+    }
+
+    #[test]
+    fn soft_drop_falls_one_and_a_half_times_as_fast() {
+        assert_eq!(frames_to_land(Command::None), 6 * FALL_FRAMES);
+        assert_eq!(frames_to_land(Command::SoftDrop), 6 * SOFT_DROP_FALL_FRAMES);
+        assert_eq!(2 * FALL_FRAMES, 3 * SOFT_DROP_FALL_FRAMES);
+    }
+
+    fn enemies_config() -> GameConfig {
+        GameConfig {
+            enemies: true,
+            ..GameConfig::default()
+        }
+    }
+
+    #[test]
+    fn enemy_reaching_the_player_ends_the_game() {
+        let layout = "
+            .........
+            .........
+            P.....BBB
+            YGYGYGYGY
+        ";
+        let mut game = layout_game(layout, enemies_config());
+        game.enemies.push(Enemy {
+            p: Point::new(3, 2),
+            direction: Direction::Left,
+        });
+        for _ in 0..ENEMY_STEP_FRAMES * 4 {
+            game.update(Command::None);
+        }
+        assert!(game.is_over);
+        assert!(game.enemies.is_empty());
+    }
+
+    #[test]
+    fn enemy_turns_back_at_a_block() {
+        let layout = "
+            .........
+            .........
+            P....B...
+            YGYGYGYGY
+        ";
+        let mut game = layout_game(layout, enemies_config());
+        game.enemies.push(Enemy {
+            p: Point::new(4, 2),
+            direction: Direction::Right,
+        });
+        for _ in 0..ENEMY_STEP_FRAMES {
+            game.update(Command::None);
+        }
+        assert_eq!(game.enemies[0].p, Point::new(4, 2));
+        assert_eq!(game.enemies[0].direction, Direction::Left);
+    }
+
+    #[test]
+    fn state_diff_carries_enemies() {
+        let game = Game::from_seed(1, enemies_config());
+        let mut other = game.clone();
+        other.enemies.push(Enemy {
+            p: Point::new(0, 0),
+            direction: Direction::Right,
+        });
+        let mut peer = game.clone();
+        peer.apply_diff(&game.diff(&other));
+        assert_eq!(peer.enemies, other.enemies);
+        assert_eq!(peer.state_hash(), other.state_hash());
+    }
+
+    #[test]
+    fn enemies_never_stand_in_a_block() {
+        let commands = [
+            Command::Down,
+            Command::Left,
+            Command::Left,
+            Command::Down,
+            Command::Right,
+            Command::Right,
+            Command::None,
+        ];
+        let mut seen = 0;
+        for seed in 0..4 {
+            let mut game = Game::from_seed(seed, enemies_config());
+            game.lives = LIVES_MAX;
+            for frame in 0..FPS * 120 {
+                game.update(commands[(frame as usize / 4) % commands.len()]);
+                if game.is_over || game.is_clear {
+                    break;
+                }
+                for enemy in &game.enemies {
+                    assert_eq!(game.cell(enemy.p).cell_type, CellType::None);
+                }
+                seen += game.enemies.len();
+            }
+        }
+        assert!(seen > 0, "no enemy ever spawned");
+    }
+
+    // プレイヤーの真下に置いたcolorの色のブロックが壊れるまでに、下を掘った回数
+    fn hits_to_break(config: &GameConfig, color: char, drill: bool) -> i32 {
+        let layout = format!(".........\n....P....\n....{}....\n", color);
+        let mut game = layout_game(&layout, config.clone());
+        if drill {
+            game.drill_boost_frames = DRILL_BOOST_FRAMES;
+        }
+        for hits in 1..=20 {
+            game.update(Command::Down);
+            if !is_block(&game, 4, 2) {
+                return hits;
+            }
+            game.update(Command::None);
+        }
+        panic!("{} did not break", color);
+    }
+
+    #[test]
+    fn each_color_breaks_after_its_dig_hits() {
+        let mut config = GameConfig::default();
+        assert_eq!(hits_to_break(&config, 'O', false), BROWN_DIG_HITS);
+        assert_eq!(hits_to_break(&config, 'R', false), 1);
+        assert_eq!(hits_to_break(&config, 'B', false), 1);
+        config.set_dig_hits(BlockColor::Blue, 2);
+        config.set_dig_hits(BlockColor::Green, 3);
+        config.set_dig_hits(BlockColor::Brown, 6);
+        assert_eq!(hits_to_break(&config, 'B', false), 2);
+        assert_eq!(hits_to_break(&config, 'G', false), 3);
+        assert_eq!(hits_to_break(&config, 'O', false), 6);
+        assert_eq!(hits_to_break(&config, 'R', false), 1);
+        // ドリル強化中はどの色も1回で壊れる
+        assert_eq!(hits_to_break(&config, 'O', true), 1);
+        for hits in 1..=10 {
+            config.set_dig_hits(BlockColor::Yellow, hits);
+            assert_eq!(hits_to_break(&config, 'Y', false), hits);
+        }
+    }
+
+    #[test]
+    fn drill_boost_breaks_brown_without_the_air_penalty() {
+        let layout = ".........\n....P....\n....O....\n";
+        let mut boosted = layout_game(layout, GameConfig::default());
+        boosted.drill_boost_frames = DRILL_BOOST_FRAMES;
+        let air = boosted.player.air;
+        boosted.update(Command::Down);
+        assert!(!is_block(&boosted, 4, 2));
+        assert!(boosted.player.air >= air - 1 - boosted.config.dig_air_cost);
+
+        // 強化していなければ、壊したときにエアが大きく減る
+        let mut plain = layout_game(layout, GameConfig::default());
+        for _ in 0..BROWN_DIG_HITS {
+            plain.update(Command::Down);
+            plain.update(Command::None);
+        }
+        assert!(!is_block(&plain, 4, 2));
+        assert!(plain.player.air <= air - BROWN_AIR_COST);
+    }
+
+    #[test]
+    fn a_gift_block_drops_a_power_up_where_its_group_is_erased() {
+        let layout = "
+            .........
+            .R.......
+            .R.......
+            .........
+            .R.......
+            .R..P....
+            OOOOOOOOO
+        ";
+        let mut game = layout_game(layout, GameConfig::default());
+        game.cell_mut(Point::new(1, 1)).gift = true;
+        for _ in 0..FPS * 3 {
+            game.update(Command::None);
+        }
+        // 落ちてきたギフトブロックはつながったかたまりといっしょに消え、代わりにアイテムが1つ出る
+        let items: Vec<ItemKind> = (1..=5)
+            .filter_map(|y| match game.cell(Point::new(1, y)).cell_type {
+                CellType::Item(kind) => Some(kind),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(items.len(), 1, "{:?}", items);
+        assert!(ItemKind::POWER_UPS.contains(&items[0]));
+        assert!((1..=5).all(|y| !is_block(&game, 1, y) && !game.cell(Point::new(1, y)).gift));
+    }
+
+    #[test]
+    fn running_out_of_air_while_crushed_costs_one_life() {
+        let layout = "
+            .........
+            ....P....
+            YBYBGBYBY
+        ";
+        let mut game = layout_game(layout, GameConfig::default());
+        game.lives = 2;
+        game.player.air = 1;
+        let p = game.player.p;
+        game.cell_mut(p).cell_type = CellType::Block;
+        game.update(Command::None);
+        assert_eq!(game.lives, 1);
+        assert!(!game.is_over);
+        assert_eq!(game.player.air, AIR_MAX);
+        assert!(!is_block(&game, p.x, p.y));
+
+        // 次のフレームにまた失えば、ちゃんともう1つ減る
+        game.player.air = 1;
+        game.update(Command::None);
+        assert_eq!(game.lives, 0);
+    }
+
+    // 浮いている2個のかたまりが、下の同じ色の2個の上に落ちる盤面
+    const FLOATING_PAIR_LAYOUT: &str = "
+        .........
+        .R.......
+        .R.......
+        .........
+        .R.......
+        .R..P....
+        OOOOOOOOO
+    ";
+
+    #[test]
+    fn gravity_only_simulation_moves_blocks_without_touching_the_game() {
+        let game = layout_game(FLOATING_PAIR_LAYOUT, GameConfig::default());
+        let before = game.state_hash();
+        let settled = game.simulate_gravity_only(PLANNER_FRAMES);
+        assert_eq!(game.state_hash(), before);
+        assert!(is_block(&game, 1, 1) && !is_block(&game, 1, 3));
+        // 重力だけなので、着地して4つつながっても消えず、エアも減らない
+        assert!((2..=5).all(|y| is_block(&settled, 1, y)));
+        assert!(!is_block(&settled, 1, 1));
+        assert_eq!(settled.player.air, game.player.air);
+        assert_eq!(settled.player.p, game.player.p);
+    }
+
+    #[test]
+    fn erase_plan_shows_the_landing_group_and_follows_board_changes() {
+        let mut game = layout_game(FLOATING_PAIR_LAYOUT, GameConfig::default());
+        let mut plan = game.predict_erase_groups();
+        plan.sort_by_key(|&(p, _)| p.y);
+        let expected: Vec<(Point, BlockColor)> = (2..=5)
+            .map(|y| (Point::new(1, y), BlockColor::Red))
+            .collect();
+        assert_eq!(plan, expected);
+        // 盤面が変わらなければ同じ結果を返し、変われば読み直す
+        assert_eq!(game.predict_erase_groups().len(), 4);
+        game.cell_mut(Point::new(1, 1)).cell_type = CellType::None;
+        game.set_leaders();
+        assert!(game.predict_erase_groups().is_empty());
+    }
+
+    #[test]
+    fn centroid_cell_is_always_a_member_of_the_group() {
+        // ∩の形。重心(1, 0.86)に一番近いのは、かたまりの外の(1, 1)ではなく(1, 0)
+        let points: Vec<Point> = [(0, 0), (1, 0), (2, 0), (0, 1), (2, 1), (0, 2), (2, 2)]
+            .iter()
+            .map(|&(x, y)| Point::new(x, y))
+            .collect();
+        assert_eq!(centroid_cell(&points), Point::new(1, 0));
+        let line: Vec<Point> = (3..7).map(|y| Point::new(5, y)).collect();
+        assert_eq!(centroid_cell(&line), Point::new(5, 4));
+    }
+
+    #[test]
+    fn erasing_a_group_reports_its_points_at_a_member_cell() {
+        let mut game = layout_game(FLOATING_PAIR_LAYOUT, GameConfig::default());
+        let mut scored = Vec::new();
+        for _ in 0..FPS * 3 {
+            game.update(Command::None);
+            scored.extend(game.events.drain(..).filter_map(|event| match event {
+                GameEvent::Scored(p, points) => Some((p, points)),
+                _ => None,
+            }));
+        }
+        let points = erase_points(4, 1);
+        assert_eq!(scored.len(), 1);
+        assert_eq!(scored[0].1, points);
+        assert!((2..=5).contains(&scored[0].0.y) && scored[0].0.x == 1);
+        assert_eq!(game.score, points);
+    }
+
+    #[test]
+    fn undo_restores_each_state_before_an_update() {
+        let mut game = Game::new_seeded(3);
+        game.toggle_debug();
+        let mut hashes = Vec::new();
+        for frame in 0..FPS * 3 {
+            hashes.push(game.state_hash());
+            game.update(play_command(frame, 3));
+        }
+        while let Some(hash) = hashes.pop() {
+            assert!(game.undo());
+            assert_eq!(game.state_hash(), hash, "{} updates back", hashes.len());
+        }
+        assert!(!game.undo());
+
+        // 戻したところから同じ入力で進めれば、同じ状態になる
+        let mut fresh = Game::new_seeded(3);
+        for frame in 0..FPS {
+            game.update(play_command(frame, 3));
+            fresh.update(play_command(frame, 3));
+        }
+        assert_eq!(game.state_hash(), fresh.state_hash());
+    }
+
+    #[test]
+    fn undo_history_is_bounded_and_only_kept_in_debug() {
+        let mut game = Game::new_seeded(1);
+        game.update(Command::None);
+        assert!(!game.undo());
+        game.toggle_debug();
+        for _ in 0..UNDO_HISTORY_LEN + 10 {
+            game.update(Command::None);
+        }
+        assert_eq!(game.undo_history.len(), UNDO_HISTORY_LEN);
+        game.toggle_debug();
+        assert!(!game.undo());
+    }
+
+    #[test]
+    fn rigid_fall_is_off_by_default() {
+        assert!(!GameConfig::default().rigid_fall);
+    }
+
+    #[test]
+    fn par_time_stays_the_same_after_the_clear_blocks_are_dug() {
+        let mut game = Game::new_seeded(1);
+        let par = game.par_seconds;
+        let top_clear_row = game.config.cells_y_max() + 1 - game.config.clear_blocks_height;
+        assert_eq!(par, top_clear_row as f32 * PAR_SECONDS_PER_ROW);
+
+        // 一番上のクリアブロックの行を消しても、パータイムは深くならない
+        for x in CELLS_X_MIN..=game.config.cells_x_max() {
+            game.cell_mut(Point::new(x, top_clear_row)).cell_type = CellType::None;
+        }
+        game.update(Command::None);
+        assert_eq!(game.initial_par_seconds(), par + PAR_SECONDS_PER_ROW);
+        assert_eq!(game.par_seconds, par);
+
+        // 評価もステージを作ったときのパータイムで決まる
+        game.is_clear = true;
+        game.clear_frame = (par * FPS as f32) as i32;
+        assert_eq!(game.stars(), STARS_MAX);
+        game.clear_frame = ((par + 1.0) * FPS as f32) as i32;
+        assert_eq!(game.stars(), 1);
+    }
+
+    // 左右を茶色ブロックで囲まれた縦穴の底に立っている
+    const SHAFT_LAYOUT: &str = "
+        .........
+        .........
+        ...O.O...
+        ...O.O...
+        ...OPO...
+        OOOOOOOOO
+    ";
+
+    fn surface_return_config() -> GameConfig {
+        GameConfig {
+            surface_return: true,
+            up_space_height: 2,
+            ..GameConfig::default()
+        }
+    }
+
+    fn climb_once(game: &mut Game) {
+        game.update(Command::Up);
+        for _ in 0..CLIMB_FRAMES {
+            game.update(Command::None);
+        }
+    }
+
+    #[test]
+    fn climbing_out_of_a_shaft_with_the_key_clears_the_stage() {
+        let mut game = layout_game(SHAFT_LAYOUT, surface_return_config());
+        game.has_key = true;
+        for y in [3, 2] {
+            climb_once(&mut game);
+            assert_eq!(game.player.p, Point::new(4, y));
+            assert!(!game.is_clear);
+        }
+        climb_once(&mut game);
+        assert_eq!(game.player.p, Point::new(4, 1));
+        assert!(game.is_clear);
+    }
+
+    #[test]
+    fn reaching_the_surface_without_the_key_does_not_clear() {
+        let mut game = layout_game(SHAFT_LAYOUT, surface_return_config());
+        for _ in 0..3 {
+            climb_once(&mut game);
+        }
+        assert!(!game.is_clear);
+    }
+
+    #[test]
+    fn climber_clings_to_the_wall_until_down_is_pressed() {
+        let mut game = layout_game(SHAFT_LAYOUT, surface_return_config());
+        climb_once(&mut game);
+        assert!(game.player.clinging);
+        for _ in 0..FPS {
+            game.update(Command::None);
+        }
+        assert_eq!(game.player.p, Point::new(4, 3));
+
+        game.update(Command::Down);
+        assert!(!game.player.clinging);
+        for _ in 0..FPS {
+            game.update(Command::None);
+        }
+        assert_eq!(game.player.p, Point::new(4, 4));
+    }
+
+    #[test]
+    fn climbing_needs_a_wall_beside_the_player() {
+        let layout = "
+            .........
+            .........
+            .........
+            ....P....
+            OOOOOOOOO
+        ";
+        let mut game = layout_game(layout, surface_return_config());
+        game.events.clear();
+        climb_once(&mut game);
+        assert_eq!(game.player.p, Point::new(4, 3));
+        assert!(game.events.contains(&GameEvent::Blocked(Direction::Up)));
+    }
+
+    #[test]
+    fn up_does_not_climb_without_surface_return() {
+        let mut game = layout_game(SHAFT_LAYOUT, GameConfig::default());
+        climb_once(&mut game);
+        assert_eq!(game.player.p, Point::new(4, 4));
+        assert!(game.player.state == PlayerState::Standing);
+    }
+
+    #[test]
+    fn clear_blocks_cannot_be_dug_in_surface_return() {
+        let layout = "
+            .........
+            ....P....
+            OOOOCOOOO
+        ";
+        let mut game = layout_game(layout, surface_return_config());
+        game.has_key = true;
+        game.update(Command::Down);
+        assert!(is_block(&game, 4, 2));
+        assert!(!game.is_clear);
+    }
+
+    #[test]
+    fn scan_stats_counts_the_board_and_recounts_after_a_change() {
+        let layout = "
+            .........
+            .RA......
+            .........
+            RR..P..YA
+            OOOOOOOOO
+        ";
+        let mut game = Game::from_layout(layout, GameConfig::default()).unwrap();
+        game.update(Command::None);
+        let stats = game.scan_stats();
+        assert_eq!(stats.blocks_by_color[BlockColor::Red as usize], 3);
+        assert_eq!(stats.blocks_by_color[BlockColor::Yellow as usize], 1);
+        assert_eq!(stats.blocks_by_color[BlockColor::Brown as usize], 9);
+        assert_eq!(stats.air_capsules, 2);
+        // 浮いている(1,1)の赤だけが接地していない
+        assert_eq!(stats.ungrounded, 1);
+        // 赤2つ、浮いた赤、黄、床の茶色、レイアウトの下を埋めるクリアブロック
+        assert_eq!(stats.components, 5);
+
+        // 同じフレームでもセルが書き換われば数え直す
+        game.cell_mut(Point::new(7, 3)).cell_type = CellType::None;
+        let stats = game.scan_stats();
+        assert_eq!(stats.blocks_by_color[BlockColor::Yellow as usize], 0);
+        assert_eq!(stats.components, 4);
     }
-    value
 }
@@ -0,0 +1,166 @@
+use sdl2::pixels::Color;
+use std::fs;
+use std::path::Path;
+
+use crate::model::*;
+use crate::settings::parse_rgb;
+
+pub const PALETTE_PATH: &str = "palette.toml"; // セルの色の置き換え（なければ既定の色で描く）
+
+// セルを描く色。palette.tomlで色ごとに置き換えられる（赤と緑を見分けにくい人が色を変えるなど）
+// palette.tomlには「red = "ff8080"」のように、名前と16進の色の行だけを書く。書かなかった色は既定のまま
+#[derive(Debug, Clone)]
+pub struct Palette {
+    pub blocks: [Color; BLOCK_COLORS], // ブロックの色（BlockColorの並び順）
+    pub air: Color,                    // エアカプセル
+    pub phantom: Color,                // ファントムブロック（不透明度は実体化の具合で変える）
+    pub vent: Color,                   // 通気口の鉄板
+    pub item: Color,                   // 情報パネルでアイテムを表す印
+}
+
+impl Default for Palette {
+    fn default() -> Self {
+        Palette {
+            blocks: [
+                Color::RGB(255, 128, 128),    // Red
+                Color::RGB(255, 255, 128),    // Yellow
+                Color::RGB(128, 255, 128),    // Green
+                Color::RGB(128, 128, 255),    // Blue
+                Color::RGB(0x63, 0xc1, 0xa5), // Clear
+                Color::RGB(92, 48, 28),       // Brown
+                Color::RGB(208, 128, 255),    // Purple
+                Color::RGB(128, 240, 240),    // Cyan
+            ],
+            air: Color::RGB(0x63, 0xc1, 0xa5),
+            phantom: Color::RGB(0xa0, 0x80, 0xe0),
+            vent: Color::RGB(0x50, 0x58, 0x60),
+            item: Color::RGB(0xfe, 0x8a, 0x00),
+        }
+    }
+}
+
+impl Palette {
+    // ファイルがなければ既定の色。知らない名前や読めない色の行は警告して飛ばす
+    pub fn load(path: &Path) -> Palette {
+        let mut palette = Palette::default();
+        let Ok(text) = fs::read_to_string(path) else {
+            return palette;
+        };
+        for line in text.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let Some((key, value)) = line.split_once('=') else {
+                continue;
+            };
+            // 色のあとに書いたコメントは読まない
+            let key = key.trim();
+            let value = value.split_whitespace().next().unwrap_or("");
+            let Some([r, g, b]) = parse_rgb(value) else {
+                log_warn!("palette: cannot read color for {}: {}", key, value);
+                continue;
+            };
+            match palette.color_mut(key) {
+                Some(color) => *color = Color::RGB(r, g, b),
+                None => log_warn!("palette: unknown color name: {}", key),
+            }
+        }
+        palette
+    }
+
+    pub fn block(&self, color: BlockColor) -> Color {
+        self.blocks[color as usize]
+    }
+
+    // palette.tomlでの名前に対応する色。ブロックの色は小文字の色名（red, brownなど）
+    fn color_mut(&mut self, name: &str) -> Option<&mut Color> {
+        match name {
+            "air" => Some(&mut self.air),
+            "phantom" => Some(&mut self.phantom),
+            "vent" => Some(&mut self.vent),
+            "item" => Some(&mut self.item),
+            _ => {
+                let color = BlockColor::ALL
+                    .into_iter()
+                    .find(|c| format!("{:?}", c).to_lowercase() == name)?;
+                Some(&mut self.blocks[color as usize])
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!(
+            "rust-driller-palette-{}-{}.toml",
+            name,
+            std::process::id()
+        ))
+    }
+
+    fn load_text(name: &str, text: &str) -> Palette {
+        let path = temp_path(name);
+        fs::write(&path, text).unwrap();
+        let palette = Palette::load(&path);
+        fs::remove_file(&path).unwrap();
+        palette
+    }
+
+    #[test]
+    fn missing_file_gives_the_default_colors() {
+        let palette = Palette::load(&temp_path("missing"));
+        let default = Palette::default();
+        assert_eq!(palette.blocks, default.blocks);
+        assert_eq!(palette.air, default.air);
+        assert_eq!(palette.item, default.item);
+    }
+
+    #[test]
+    fn listed_colors_replace_the_defaults() {
+        let text = "\
+# 赤と緑を入れ替える
+red = \"80ff80\"
+green = \"#ff8080\"  # 赤っぽく
+vent = 102030
+";
+        let palette = load_text("swap", text);
+        let default = Palette::default();
+        assert_eq!(palette.block(BlockColor::Red), Color::RGB(0x80, 0xff, 0x80));
+        assert_eq!(
+            palette.block(BlockColor::Green),
+            Color::RGB(0xff, 0x80, 0x80)
+        );
+        assert_eq!(palette.vent, Color::RGB(0x10, 0x20, 0x30));
+        // 書かなかった色は既定のまま
+        assert_eq!(
+            palette.block(BlockColor::Blue),
+            default.block(BlockColor::Blue)
+        );
+        assert_eq!(palette.air, default.air);
+    }
+
+    #[test]
+    fn unreadable_lines_are_skipped() {
+        let text = "\
+pink = \"ff00ff\"
+yellow = \"yellowish\"
+blue
+brown = \"010203\"
+";
+        let palette = load_text("bad", text);
+        let default = Palette::default();
+        assert_eq!(
+            palette.block(BlockColor::Yellow),
+            default.block(BlockColor::Yellow)
+        );
+        assert_eq!(
+            palette.block(BlockColor::Blue),
+            default.block(BlockColor::Blue)
+        );
+        assert_eq!(palette.block(BlockColor::Brown), Color::RGB(1, 2, 3));
+    }
+}
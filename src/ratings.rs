@@ -0,0 +1,55 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+
+pub const RATINGS_PATH: &str = "ratings.txt"; // ステージごとの最高評価の保存先
+
+// ステージ（シード）ごとの最高評価。ファイルには「シード 星の数」を1行に1つ書く
+#[derive(Debug, Clone, Default)]
+pub struct Ratings {
+    best: Vec<(u64, u32)>,
+}
+
+impl Ratings {
+    // ファイルがなければ空。読めない行は無視する
+    pub fn load(path: &Path) -> Ratings {
+        let Ok(text) = fs::read_to_string(path) else {
+            return Ratings::default();
+        };
+        let best = text
+            .lines()
+            .filter_map(|line| {
+                let (seed, stars) = line.trim().split_once(' ')?;
+                Some((seed.parse().ok()?, stars.trim().parse().ok()?))
+            })
+            .collect();
+        Ratings { best }
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let lines: Vec<String> = self
+            .best
+            .iter()
+            .map(|(seed, stars)| format!("{} {}", seed, stars))
+            .collect();
+        fs::write(path, lines.join("\n") + "\n")
+    }
+
+    // そのステージの最高評価。まだクリアしていなければ0
+    pub fn best(&self, seed: u64) -> u32 {
+        self.best
+            .iter()
+            .find(|&&(s, _)| s == seed)
+            .map_or(0, |&(_, stars)| stars)
+    }
+
+    // 評価を記録する。最高評価を更新したらtrue
+    pub fn record(&mut self, seed: u64, stars: u32) -> bool {
+        if stars <= self.best(seed) {
+            return false;
+        }
+        self.best.retain(|&(s, _)| s != seed);
+        self.best.push((seed, stars));
+        true
+    }
+}
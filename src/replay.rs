@@ -0,0 +1,545 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::model::*;
+
+pub const REPLAY_PATH: &str = "last.replay"; // 最後に遊んだステージのリプレイの保存先
+const REPLAY_HEADER: &str = "rust-driller-replay 2"; // 盤面の作り方が変わってリプレイが再現しなくなったら番号を上げる
+const CHECK_INTERVAL: u32 = FPS as u32; // この間隔（update回数）ごとに状態ハッシュを記録する
+const BOARD_LEN_MAX: i64 = 10_000; // 盤面の幅・高さとして読む値の上限（大きすぎる盤面を作らないように）
+
+// ステージ開始時に前のステージから引き継ぐ状態
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct ReplayStart {
+    pub stage: u32,
+    pub depth: i32,
+    pub score: u32,
+    pub lives: i32,
+    pub air: i32,
+}
+
+// ステージが終わったときの状態
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct ReplayResult {
+    pub updates: u32,
+    pub depth: i32,
+    pub score: u32,
+    pub hash: u64,
+}
+
+// 1ステージ分のリプレイ。シードと設定から盤面を作り直し、updateに渡したコマンドを順に再生する
+// ファイルは1行1項目のテキストで、コマンドは同じものが続く回数でまとめて書く
+#[derive(Debug, Clone)]
+pub struct Replay {
+    pub seed: u64,
+    pub config: GameConfig,
+    pub start: ReplayStart,
+    pub inputs: Vec<(Command, u32)>, // (コマンド, 続けて渡した回数)
+    pub checks: Vec<(u32, u64)>,     // (それまでのupdate回数, 状態ハッシュ)
+    pub result: Option<ReplayResult>,
+    updates: u32,
+}
+
+impl Replay {
+    // 始まる前（まだ一度もupdateしていない）のゲームから記録を始める
+    pub fn start(game: &Game) -> Replay {
+        Replay {
+            seed: game.seed,
+            config: game.config.clone(),
+            start: ReplayStart {
+                stage: game.stage,
+                depth: game.depth,
+                score: game.score,
+                lives: game.lives,
+                air: game.player.air,
+            },
+            inputs: Vec::new(),
+            checks: Vec::new(),
+            result: None,
+            updates: 0,
+        }
+    }
+
+    // updateを1回呼んだ後に、そのとき渡したコマンドを記録する
+    pub fn record(&mut self, command: Command, game: &Game) {
+        match self.inputs.last_mut() {
+            Some((last, count)) if *last == command => *count += 1,
+            _ => self.inputs.push((command, 1)),
+        }
+        self.updates += 1;
+        if self.updates.is_multiple_of(CHECK_INTERVAL) {
+            self.checks.push((self.updates, game.state_hash()));
+        }
+    }
+
+    pub fn finish(&mut self, game: &Game) {
+        self.result = Some(ReplayResult {
+            updates: self.updates,
+            depth: game.depth,
+            score: game.score,
+            hash: game.state_hash(),
+        });
+    }
+
+    // 記録を始めたときと同じ状態のゲームを作る
+    pub fn new_game(&self) -> Game {
+        let mut game = Game::from_seed_at_stage(self.seed, self.config.clone(), self.start.stage);
+        game.depth = self.start.depth;
+        game.score = self.start.score;
+        game.lives = self.start.lives;
+        game.player.air = self.start.air;
+        game
+    }
+
+    // リプレイを再生して、記録した状態ハッシュと結果が再現されるか確かめる
+    // 食い違ったら、食い違いに気づいた時点までのupdate回数を含むメッセージを返す
+    pub fn verify(&self) -> Result<ReplayResult, String> {
+        let Some(expected) = self.result else {
+            return Err("replay has no result".to_string());
+        };
+        let mut game = self.new_game();
+        let mut updates = 0;
+        let mut last_good = 0;
+        let mut checks = self.checks.iter().peekable();
+        for &(command, count) in &self.inputs {
+            for _ in 0..count {
+                game.update(command);
+                game.requested_sounds.clear();
+                game.events.clear();
+                updates += 1;
+                if let Some(&&(at, hash)) = checks.peek() {
+                    if at == updates {
+                        if game.state_hash() != hash {
+                            return Err(format!(
+                                "diverged between update {} and {}",
+                                last_good, updates
+                            ));
+                        }
+                        last_good = updates;
+                        checks.next();
+                    }
+                }
+            }
+        }
+        let actual = ReplayResult {
+            updates,
+            depth: game.depth,
+            score: game.score,
+            hash: game.state_hash(),
+        };
+        if actual != expected {
+            return Err(format!(
+                "result mismatch after update {}: expected {:?}, got {:?}",
+                last_good, expected, actual
+            ));
+        }
+        Ok(actual)
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        fs::write(path, self.to_text())
+    }
+
+    pub fn load(path: &Path) -> Result<Replay, String> {
+        let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        Replay::parse(&text)
+    }
+
+    pub fn to_text(&self) -> String {
+        let mut lines = vec![REPLAY_HEADER.to_string()];
+        lines.push(format!("seed {}", self.seed));
+        lines.extend(config_lines(&self.config));
+        lines.push(format!("stage {}", self.start.stage));
+        lines.push(format!("depth {}", self.start.depth));
+        lines.push(format!("score {}", self.start.score));
+        lines.push(format!("lives {}", self.start.lives));
+        lines.push(format!("air {}", self.start.air));
+        for &(command, count) in &self.inputs {
+            lines.push(format!("cmd {:?} {}", command, count));
+        }
+        for &(at, hash) in &self.checks {
+            lines.push(format!("check {} {:016x}", at, hash));
+        }
+        if let Some(r) = self.result {
+            lines.push(format!(
+                "result {} {} {} {:016x}",
+                r.updates, r.depth, r.score, r.hash
+            ));
+        }
+        lines.join("\n") + "\n"
+    }
+
+    pub fn parse(text: &str) -> Result<Replay, String> {
+        let mut lines = text.lines().enumerate();
+        match lines.next() {
+            Some((_, REPLAY_HEADER)) => {}
+            // 前の版のリプレイは同じ盤面にならないので読まない
+            Some((_, line)) if line.starts_with("rust-driller-replay ") => {
+                return Err(format!(
+                    "unsupported replay version: {} (expected {})",
+                    line, REPLAY_HEADER
+                ))
+            }
+            _ => return Err("not a rust-driller replay".to_string()),
+        }
+        let mut replay = Replay {
+            seed: 0,
+            config: GameConfig::default(),
+            start: ReplayStart::default(),
+            inputs: Vec::new(),
+            checks: Vec::new(),
+            result: None,
+            updates: 0,
+        };
+        // 書き出すときに必ず書く項目。どれかが欠けたリプレイは読まない（既定値で補うと違う盤面になりうる）
+        let required: Vec<String> = replay
+            .to_text()
+            .lines()
+            .skip(1)
+            .filter_map(|line| line.split_whitespace().next())
+            .map(str::to_string)
+            .collect();
+        let mut seen: Vec<String> = Vec::new();
+        replay.config.block_weights.clear();
+        for (i, line) in lines {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if let Some(&key) = fields.first() {
+                seen.push(key.to_string());
+            }
+            let err = |what: &str| format!("line {}: {}: {}", i + 1, what, line);
+            let num = |s: Option<&&str>| -> Result<i64, String> {
+                s.and_then(|s| s.parse().ok())
+                    .ok_or_else(|| err("bad number"))
+            };
+            let hash = |s: Option<&&str>| -> Result<u64, String> {
+                s.and_then(|s| u64::from_str_radix(s, 16).ok())
+                    .ok_or_else(|| err("bad hash"))
+            };
+            if parse_config_line(&mut replay.config, &fields).map_err(|what| err(&what))? {
+                continue;
+            }
+            match fields.first().copied() {
+                None => {}
+                Some("seed") => {
+                    replay.seed = fields
+                        .get(1)
+                        .and_then(|s| s.parse().ok())
+                        .ok_or_else(|| err("bad seed"))?
+                }
+                Some("stage") => replay.start.stage = num(fields.get(1))? as u32,
+                Some("depth") => replay.start.depth = num(fields.get(1))? as i32,
+                Some("score") => replay.start.score = num(fields.get(1))? as u32,
+                Some("lives") => replay.start.lives = num(fields.get(1))? as i32,
+                Some("air") => replay.start.air = num(fields.get(1))? as i32,
+                Some("cmd") => {
+                    let command = fields
+                        .get(1)
+                        .and_then(|s| parse_command(s))
+                        .ok_or_else(|| err("bad command"))?;
+                    let count = num(fields.get(2))? as u32;
+                    replay.inputs.push((command, count));
+                    replay.updates += count;
+                }
+                Some("check") => {
+                    let at = num(fields.get(1))? as u32;
+                    replay.checks.push((at, hash(fields.get(2))?));
+                }
+                Some("result") => {
+                    replay.result = Some(ReplayResult {
+                        updates: num(fields.get(1))? as u32,
+                        depth: num(fields.get(2))? as i32,
+                        score: num(fields.get(3))? as u32,
+                        hash: hash(fields.get(4))?,
+                    });
+                }
+                Some(_) => return Err(err("unknown entry")),
+            }
+        }
+        if let Some(key) = required.iter().find(|key| !seen.contains(key)) {
+            return Err(format!("replay has no {} line", key));
+        }
+        if !replay.config.has_block_weights() {
+            return Err("replay has no positive block weight".to_string());
+        }
+        Ok(replay)
+    }
+}
+
+// 盤面と遊び方の設定を1行1項目で書く（リプレイとセーブデータで共通）
+pub fn config_lines(config: &GameConfig) -> Vec<String> {
+    let mut lines = Vec::new();
+    for &(color, weight) in &config.block_weights {
+        lines.push(format!("weight {:?} {}", color, weight));
+    }
+    for &(color, pace) in &config.fall_pace {
+        lines.push(format!("fall_pace {:?} {}", color, pace));
+    }
+    // 書いていない色を既定の回数にしないよう、全部の色を書く
+    for color in BlockColor::ALL {
+        lines.push(format!(
+            "dig_hits {:?} {}",
+            color,
+            config.dig_hits_of(color)
+        ));
+    }
+    lines.push(format!("width {}", config.width));
+    lines.push(format!("up_space_height {}", config.up_space_height));
+    lines.push(format!(
+        "normal_blocks_height {}",
+        config.normal_blocks_height
+    ));
+    lines.push(format!(
+        "clear_blocks_height {}",
+        config.clear_blocks_height
+    ));
+    lines.push(format!("dig_assist {}", config.dig_assist));
+    lines.push(format!("rigid_fall {}", config.rigid_fall));
+    lines.push(format!("clear_requires_key {}", config.clear_requires_key));
+    lines.push(format!("surface_return {}", config.surface_return));
+    lines.push(format!("sandbox {}", config.sandbox));
+    lines.push(format!("block_push {}", config.block_push));
+    lines.push(format!("wrap_horizontal {}", config.wrap_horizontal));
+    lines.push(format!("enemies {}", config.enemies));
+    lines.push(format!("last_breath {}", config.last_breath));
+    lines.push(format!(
+        "grounding_through_air {}",
+        config.grounding_through_air
+    ));
+    lines.push(format!("dig_air_cost {}", config.dig_air_cost));
+    lines.push(format!("walk_air_cost {}", config.walk_air_cost));
+    lines.push(format!(
+        "air_brown_clearance {}",
+        config.air_brown_clearance
+    ));
+    lines.push(format!("air_band_rows {}", config.air_band_rows));
+    lines.push(format!("air_band_min {}", config.air_band_min));
+    if let Some(carry) = config.air_carry {
+        lines.push(format!("air_carry {}", carry));
+    }
+    if let Some(par) = config.par_seconds {
+        lines.push(format!("par_seconds {}", par));
+    }
+    lines
+}
+
+// 設定の行なら読んでconfigに入れ、trueを返す。設定の行でなければfalse
+// 読めない値はエラー（何が読めなかったか）を返す
+pub fn parse_config_line(config: &mut GameConfig, fields: &[&str]) -> Result<bool, String> {
+    let err = |what: &str| what.to_string();
+    let num = |s: Option<&&str>| -> Result<i64, String> {
+        s.and_then(|s| s.parse().ok())
+            .ok_or_else(|| err("bad number"))
+    };
+    let flag = |s: Option<&&str>| -> Result<bool, String> {
+        s.and_then(|s| s.parse().ok())
+            .ok_or_else(|| err("bad bool"))
+    };
+    // 盤面の大きさはmin以上でないと盤面を作れない（鍵やエアの配置で0で割ったり、範囲外を指したりする）
+    let board_len = |s: Option<&&str>, min: i64| -> Result<i32, String> {
+        let n = num(s)?;
+        if !(min..=BOARD_LEN_MAX).contains(&n) {
+            return Err(err(&format!("must be {} to {}", min, BOARD_LEN_MAX)));
+        }
+        Ok(n as i32)
+    };
+    match fields.first().copied() {
+        Some("weight") => {
+            let color = fields
+                .get(1)
+                .and_then(|s| parse_color(s))
+                .ok_or_else(|| err("bad color"))?;
+            let weight = fields
+                .get(2)
+                .and_then(|s| s.parse::<f64>().ok())
+                .filter(|weight| weight.is_finite() && *weight >= 0.0)
+                .ok_or_else(|| err("bad weight"))?;
+            config.block_weights.push((color, weight));
+        }
+        Some("fall_pace") => {
+            let color = fields
+                .get(1)
+                .and_then(|s| parse_color(s))
+                .ok_or_else(|| err("bad color"))?;
+            let pace = fields
+                .get(2)
+                .and_then(|s| s.parse().ok())
+                .ok_or_else(|| err("bad fall_pace"))?;
+            config.fall_pace.push((color, pace));
+        }
+        Some("dig_hits") => {
+            let color = fields
+                .get(1)
+                .and_then(|s| parse_color(s))
+                .ok_or_else(|| err("bad color"))?;
+            let hits = fields
+                .get(2)
+                .and_then(|s| s.parse().ok())
+                .ok_or_else(|| err("bad dig_hits"))?;
+            config.set_dig_hits(color, hits);
+        }
+        Some("width") => config.width = board_len(fields.get(1), 1)?,
+        Some("up_space_height") => config.up_space_height = board_len(fields.get(1), 1)?,
+        Some("normal_blocks_height") => config.normal_blocks_height = board_len(fields.get(1), 2)?,
+        Some("clear_blocks_height") => config.clear_blocks_height = board_len(fields.get(1), 1)?,
+        Some("dig_assist") => config.dig_assist = flag(fields.get(1))?,
+        Some("rigid_fall") => config.rigid_fall = flag(fields.get(1))?,
+        Some("clear_requires_key") => config.clear_requires_key = flag(fields.get(1))?,
+        Some("sandbox") => config.sandbox = flag(fields.get(1))?,
+        Some("block_push") => config.block_push = flag(fields.get(1))?,
+        Some("wrap_horizontal") => config.wrap_horizontal = flag(fields.get(1))?,
+        Some("enemies") => config.enemies = flag(fields.get(1))?,
+        Some("surface_return") => config.surface_return = flag(fields.get(1))?,
+        Some("last_breath") => config.last_breath = flag(fields.get(1))?,
+        Some("grounding_through_air") => config.grounding_through_air = flag(fields.get(1))?,
+        Some("dig_air_cost") => config.dig_air_cost = num(fields.get(1))? as i32,
+        Some("walk_air_cost") => config.walk_air_cost = num(fields.get(1))? as i32,
+        Some("air_brown_clearance") => config.air_brown_clearance = num(fields.get(1))? as i32,
+        Some("air_band_rows") => config.air_band_rows = num(fields.get(1))? as i32,
+        Some("air_band_min") => config.air_band_min = num(fields.get(1))? as i32,
+        Some("air_carry") => {
+            let carry = fields
+                .get(1)
+                .and_then(|s| s.parse().ok())
+                .ok_or_else(|| err("bad air_carry"))?;
+            config.air_carry = Some(carry);
+        }
+        Some("par_seconds") => {
+            let par = fields
+                .get(1)
+                .and_then(|s| s.parse().ok())
+                .ok_or_else(|| err("bad par_seconds"))?;
+            config.par_seconds = Some(par);
+        }
+        _ => return Ok(false),
+    }
+    Ok(true)
+}
+
+pub fn parse_color(s: &str) -> Option<BlockColor> {
+    BlockColor::ALL
+        .into_iter()
+        .find(|c| format!("{:?}", c) == s)
+}
+
+pub fn parse_command(s: &str) -> Option<Command> {
+    [
+        Command::None,
+        Command::Left,
+        Command::Right,
+        Command::Down,
+        Command::Up,
+        Command::Collapse,
+        Command::Refill,
+        Command::SoftDrop,
+    ]
+    .into_iter()
+    .find(|c| format!("{:?}", c) == s)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn recorded_replay() -> Replay {
+        let mut game = Game::from_seed(7, GameConfig::default());
+        let mut replay = Replay::start(&game);
+        for frame in 0..FPS * 3 {
+            let command = if frame % 20 == 0 {
+                Command::Down
+            } else {
+                Command::None
+            };
+            game.update(command);
+            replay.record(command, &game);
+        }
+        replay.finish(&game);
+        replay
+    }
+
+    #[test]
+    fn saved_replay_loads_back_and_verifies() {
+        let text = recorded_replay().to_text();
+        let replay = Replay::parse(&text).unwrap();
+        assert_eq!(replay.to_text(), text);
+        assert!(replay.verify().is_ok());
+    }
+
+    #[test]
+    fn replay_from_an_older_version_is_rejected() {
+        let text = recorded_replay()
+            .to_text()
+            .replacen(REPLAY_HEADER, "rust-driller-replay 1", 1);
+        let err = Replay::parse(&text).unwrap_err();
+        assert!(err.contains("unsupported replay version"), "{}", err);
+    }
+
+    #[test]
+    fn replay_missing_a_config_line_is_rejected() {
+        let text = recorded_replay().to_text();
+        for key in ["seed", "weight", "rigid_fall", "last_breath", "lives"] {
+            let missing: Vec<&str> = text
+                .lines()
+                .filter(|line| line.split_whitespace().next() != Some(key))
+                .collect();
+            assert!(Replay::parse(&missing.join("\n")).is_err(), "{}", key);
+        }
+    }
+
+    #[test]
+    fn replay_without_a_positive_weight_is_rejected() {
+        let text = recorded_replay().to_text();
+        let zeroed: Vec<String> = text
+            .lines()
+            .map(
+                |line| match line.split_whitespace().collect::<Vec<_>>()[..] {
+                    ["weight", color, _] => format!("weight {} 0", color),
+                    _ => line.to_string(),
+                },
+            )
+            .collect();
+        let err = Replay::parse(&zeroed.join("\n")).unwrap_err();
+        assert!(err.contains("positive block weight"), "{}", err);
+
+        let negative = text.replacen("weight Red ", "weight Red -", 1);
+        assert_ne!(negative, text);
+        assert!(Replay::parse(&negative).is_err());
+    }
+
+    #[test]
+    fn board_size_lines_reject_sizes_that_cannot_make_a_board() {
+        let text = recorded_replay().to_text();
+        // 盤面の大きさの行だけを書き換えたリプレイ
+        let with = |key: &str, value: &str| {
+            let lines: Vec<String> = text
+                .lines()
+                .map(|line| match line.split_whitespace().next() {
+                    Some(k) if k == key => format!("{} {}", key, value),
+                    _ => line.to_string(),
+                })
+                .collect();
+            Replay::parse(&lines.join("\n"))
+        };
+        for (key, value) in [
+            ("width", "0"),
+            ("width", "-3"),
+            ("up_space_height", "0"),
+            ("normal_blocks_height", "1"),
+            ("clear_blocks_height", "0"),
+            ("width", "100000"),
+        ] {
+            assert!(with(key, value).is_err(), "{} {}", key, value);
+        }
+        assert_eq!(with("width", "12").unwrap().config.width, 12);
+        // 通った大きさなら盤面を作れる
+        let config = GameConfig {
+            width: 1,
+            up_space_height: 1,
+            normal_blocks_height: 2,
+            clear_blocks_height: 1,
+            clear_requires_key: true,
+            ..GameConfig::default()
+        };
+        Game::from_seed(1, config);
+    }
+}
@@ -0,0 +1,407 @@
+use rand::SeedableRng;
+use rand_chacha::ChaCha12Rng;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::model::*;
+use crate::replay::{config_lines, parse_color, parse_config_line};
+
+pub const SAVE_PATH: &str = "game.save"; // 遊んでいる途中のゲームの保存先
+const SAVE_HEADER: &str = "rust-driller-save 1";
+
+// 遊んでいる途中のゲームをまるごと保存し、読み込んだら続きから同じように進むようにする
+// ファイルはリプレイと同じく1行1項目のテキスト。セルは1つ1行で、シードとステージから
+// 生成し直した盤面と違うものだけ書く（読み込むときは生成し直した盤面に差分として当てる）
+// 乱数は今の位置まで書くので、読み込んだあとに出るギフトのアイテムなども保存しなかった場合と同じになる
+// 鳴らす音・演出の出来事・一時停止・デバッグ表示・巻き戻し用の履歴は保存しない
+impl Game {
+    pub fn save_state(&self, path: &Path) -> io::Result<()> {
+        fs::write(path, self.state_text())
+    }
+
+    pub fn load_state(path: &Path) -> Result<Game, String> {
+        let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        Game::parse_state(&text)
+    }
+
+    pub fn state_text(&self) -> String {
+        let mut lines = vec![SAVE_HEADER.to_string()];
+        lines.push(format!("seed {}", self.seed));
+        lines.extend(config_lines(&self.config));
+        lines.push(format!("stage {}", self.stage));
+        let seed: String = self
+            .rng
+            .get_seed()
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+        lines.push(format!(
+            "rng {} {} {}",
+            seed,
+            self.rng.get_stream(),
+            self.rng.get_word_pos()
+        ));
+        lines.push(format!("frame {}", self.frame));
+        lines.push(format!("camera_y {}", self.camera_y));
+        lines.push(format!("depth {}", self.depth));
+        lines.push(format!("blocks_dug {}", self.blocks_dug));
+        lines.push(format!(
+            "blocks_dug_by_color {}",
+            join(&self.blocks_dug_by_color)
+        ));
+        lines.push(format!("score {}", self.score));
+        lines.push(format!("colors_erased {}", join(&self.colors_erased)));
+        lines.push(format!("has_key {}", self.has_key));
+        lines.push(format!("lives {}", self.lives));
+        lines.push(format!("drill_boost_frames {}", self.drill_boost_frames));
+        lines.push(format!("freeze_frames {}", self.freeze_frames));
+        lines.push(format!("collapse_charge {}", self.collapse_charge));
+        lines.push(format!("vent_frames {}", self.vent_frames));
+        lines.push(format!("combo {}", self.combo));
+        lines.push(format!("last_erase_frame {}", self.last_erase_frame));
+        lines.push(format!("last_breath_frames {}", self.last_breath_frames));
+        lines.push(format!("clear_frame {}", self.clear_frame));
+        lines.push(format!("is_over {}", self.is_over));
+        lines.push(format!("is_clear {}", self.is_clear));
+        lines.push(format!("full_sweep {}", self.full_sweep));
+        let player = &self.player;
+        lines.push(format!(
+            "player {} {} {} {:?} {:?} {} {} {} {}",
+            player.p.x,
+            player.p.y,
+            player.air,
+            player.state,
+            player.direction,
+            player.walking_frames,
+            player.falling_frames,
+            player.climbing_frames,
+            player.clinging
+        ));
+        for enemy in &self.enemies {
+            lines.push(format!(
+                "enemy {} {} {:?}",
+                enemy.p.x, enemy.p.y, enemy.direction
+            ));
+        }
+        let base = Game::from_seed_at_stage(self.seed, self.config.clone(), self.stage);
+        for (p, cell) in base.diff(self).cells {
+            let leader = match cell.leader {
+                Some(p) => format!("{},{}", p.x, p.y),
+                None => "-".to_string(),
+            };
+            lines.push(format!(
+                "cell {} {} {:?} {:?} {} {} {} {} {} {} {} {} {:?}",
+                p.x,
+                p.y,
+                cell.cell_type,
+                cell.color,
+                leader,
+                cell.block_life,
+                cell.grounded,
+                cell.shaking_frames,
+                cell.falling_frames,
+                cell.fell,
+                cell.gift,
+                cell.loose,
+                cell.capacity
+            ));
+        }
+        lines.join("\n") + "\n"
+    }
+
+    pub fn parse_state(text: &str) -> Result<Game, String> {
+        let mut lines = text.lines().enumerate();
+        match lines.next() {
+            Some((_, SAVE_HEADER)) => {}
+            _ => return Err("not a rust-driller save".to_string()),
+        }
+        // 設定とシードがそろうまで盤面を作れないので、いったん全部読んでから組み立てる
+        let mut seed = 0;
+        let mut config = GameConfig::default();
+        config.block_weights.clear();
+        let mut rng = None;
+        let mut values: Vec<(&str, &str)> = Vec::new();
+        let mut player = None;
+        let mut enemies = Vec::new();
+        let mut cells = Vec::new();
+        for (i, line) in lines {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let err = |what: &str| format!("line {}: {}: {}", i + 1, what, line);
+            if parse_config_line(&mut config, &fields).map_err(|what| err(&what))? {
+                continue;
+            }
+            match fields.first().copied() {
+                None => {}
+                Some("seed") => {
+                    seed = fields
+                        .get(1)
+                        .and_then(|s| s.parse().ok())
+                        .ok_or_else(|| err("bad seed"))?
+                }
+                Some("rng") => rng = Some(parse_rng(&fields).ok_or_else(|| err("bad rng"))?),
+                Some("player") => {
+                    player = Some(parse_player(&fields).ok_or_else(|| err("bad player"))?)
+                }
+                Some("enemy") => {
+                    enemies.push(parse_enemy(&fields).ok_or_else(|| err("bad enemy"))?)
+                }
+                Some("cell") => cells.push(parse_cell(&fields).ok_or_else(|| err("bad cell"))?),
+                Some(_) if fields.len() < 2 => return Err(err("missing value")),
+                Some(key) => values.push((key, line.trim_start()[key.len()..].trim())),
+            }
+        }
+        if !config.has_block_weights() {
+            return Err("save has no positive block weight".to_string());
+        }
+
+        let stage = values
+            .iter()
+            .find(|(key, _)| *key == "stage")
+            .and_then(|(_, v)| v.parse().ok())
+            .ok_or("save has no stage")?;
+        let mut game = Game::from_seed_at_stage(seed, config, stage);
+        game.rng = rng.ok_or("save has no rng")?;
+        for (key, value) in values {
+            let err = |what: &str| format!("{}: {}: {}", key, what, value);
+            let num = |s: &str| -> Result<i64, String> { s.parse().map_err(|_| err("bad number")) };
+            let flag = |s: &str| -> Result<bool, String> { s.parse().map_err(|_| err("bad bool")) };
+            match key {
+                "stage" => {}
+                "frame" => game.frame = num(value)? as i32,
+                "camera_y" => game.camera_y = num(value)? as i32,
+                "depth" => game.depth = num(value)? as i32,
+                "blocks_dug" => game.blocks_dug = num(value)? as i32,
+                "blocks_dug_by_color" => {
+                    let counts: Vec<i32> = value
+                        .split_whitespace()
+                        .map(|s| num(s).map(|n| n as i32))
+                        .collect::<Result<_, _>>()?;
+                    game.blocks_dug_by_color = counts.try_into().map_err(|_| err("bad count"))?;
+                }
+                "score" => game.score = num(value)? as u32,
+                "colors_erased" => {
+                    let erased: Vec<bool> = value
+                        .split_whitespace()
+                        .map(flag)
+                        .collect::<Result<_, _>>()?;
+                    game.colors_erased = erased.try_into().map_err(|_| err("bad count"))?;
+                }
+                "has_key" => game.has_key = flag(value)?,
+                "lives" => game.lives = num(value)? as i32,
+                "drill_boost_frames" => game.drill_boost_frames = num(value)? as i32,
+                "freeze_frames" => game.freeze_frames = num(value)? as i32,
+                "collapse_charge" => game.collapse_charge = num(value)? as i32,
+                "vent_frames" => game.vent_frames = num(value)? as i32,
+                "combo" => game.combo = num(value)? as u32,
+                "last_erase_frame" => game.last_erase_frame = num(value)? as i32,
+                "last_breath_frames" => game.last_breath_frames = num(value)? as i32,
+                "clear_frame" => game.clear_frame = num(value)? as i32,
+                "is_over" => game.is_over = flag(value)?,
+                "is_clear" => game.is_clear = flag(value)?,
+                "full_sweep" => game.full_sweep = flag(value)?,
+                _ => return Err(err("unknown entry")),
+            }
+        }
+
+        let in_board = |p: &Point| {
+            (CELLS_X_MIN..=game.config.cells_x_max()).contains(&p.x)
+                && (CELLS_Y_MIN..=game.config.cells_y_max()).contains(&p.y)
+        };
+        if let Some((p, _)) = cells.iter().find(|(p, _)| !in_board(p)) {
+            return Err(format!("cell out of range: {:?}", p));
+        }
+        let player = player.ok_or("save has no player")?;
+        if !in_board(&player.p) {
+            return Err(format!("player out of range: {:?}", player.p));
+        }
+        if let Some(enemy) = enemies.iter().find(|enemy| !in_board(&enemy.p)) {
+            return Err(format!("enemy out of range: {:?}", enemy.p));
+        }
+        // 書いてあるセルは生成し直した盤面との差分なので、apply_diffで当てる
+        // （接地判定・落下・かたまりの索引も全部やり直しになる）
+        game.apply_diff(&StateDiff {
+            cells,
+            player: Some(player),
+            enemies: Some(enemies),
+            status: None,
+        });
+        Ok(game)
+    }
+}
+
+fn join<T: ToString>(values: &[T]) -> String {
+    values
+        .iter()
+        .map(|v| v.to_string())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+// Debugで書いた名前から値に戻す
+fn by_name<T: std::fmt::Debug>(candidates: impl IntoIterator<Item = T>, name: &str) -> Option<T> {
+    candidates
+        .into_iter()
+        .find(|value| format!("{:?}", value) == name)
+}
+
+// 「rng <シード（16進64桁）> <ストリーム> <位置>」
+fn parse_rng(fields: &[&str]) -> Option<ChaCha12Rng> {
+    let hex = fields.get(1)?;
+    if hex.len() != 64 {
+        return None;
+    }
+    let mut seed = [0u8; 32];
+    for (i, byte) in seed.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(hex.get(i * 2..i * 2 + 2)?, 16).ok()?;
+    }
+    let mut rng = ChaCha12Rng::from_seed(seed);
+    rng.set_stream(fields.get(2)?.parse().ok()?);
+    rng.set_word_pos(fields.get(3)?.parse().ok()?);
+    Some(rng)
+}
+
+fn parse_player(fields: &[&str]) -> Option<Player> {
+    let num = |i: usize| fields.get(i)?.parse::<i32>().ok();
+    Some(Player {
+        p: point(num(1)?, num(2)?)?,
+        air: num(3)?,
+        state: by_name(
+            [
+                PlayerState::Standing,
+                PlayerState::Walking,
+                PlayerState::Falling,
+                PlayerState::Climbing,
+            ],
+            fields.get(4)?,
+        )?,
+        direction: by_name(Direction::all(), fields.get(5)?)?,
+        walking_frames: num(6)?,
+        falling_frames: num(7)?,
+        climbing_frames: num(8)?,
+        clinging: fields.get(9)?.parse().ok()?,
+    })
+}
+
+fn parse_enemy(fields: &[&str]) -> Option<Enemy> {
+    Some(Enemy {
+        p: point(fields.get(1)?.parse().ok()?, fields.get(2)?.parse().ok()?)?,
+        direction: by_name(Direction::all(), fields.get(3)?)?,
+    })
+}
+
+fn parse_cell(fields: &[&str]) -> Option<(Point, Cell)> {
+    let num = |i: usize| fields.get(i)?.parse::<i32>().ok();
+    let flag = |i: usize| fields.get(i)?.parse::<bool>().ok();
+    let mut cell_types = vec![
+        CellType::None,
+        CellType::Air,
+        CellType::Block,
+        CellType::Phantom,
+        CellType::Vent,
+        CellType::Item(ItemKind::Key),
+    ];
+    cell_types.extend(ItemKind::POWER_UPS.map(CellType::Item));
+    let leader = match *fields.get(5)? {
+        "-" => None,
+        s => {
+            let (x, y) = s.split_once(',')?;
+            Some(point(x.parse().ok()?, y.parse().ok()?)?)
+        }
+    };
+    let cell = Cell {
+        cell_type: by_name(cell_types, fields.get(3)?)?,
+        color: parse_color(fields.get(4)?)?,
+        leader,
+        block_life: num(6)?,
+        grounded: flag(7)?,
+        shaking_frames: num(8)?,
+        falling_frames: num(9)?,
+        fell: flag(10)?,
+        gift: flag(11)?,
+        loose: flag(12)?,
+        capacity: by_name(
+            AirCapacity::WEIGHTS.map(|(capacity, _)| capacity),
+            fields.get(13)?,
+        )?,
+    };
+    Some((point(num(1)?, num(2)?)?, cell))
+}
+
+// Point::newは下限より小さい座標でパニックするので、その前に調べる。上限は盤面を作ってから調べる
+fn point(x: i32, y: i32) -> Option<Point> {
+    (x >= CELLS_X_MIN && y >= CELLS_Y_MIN).then(|| Point::new(x, y))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn play(game: &mut Game, frames: i32) {
+        let commands = [Command::Down, Command::Left, Command::Down, Command::Right];
+        for _ in 0..frames {
+            let command = commands[(game.frame / 7).rem_euclid(4) as usize];
+            game.update(command);
+        }
+    }
+
+    #[test]
+    fn loading_a_save_restores_the_game_and_its_future() {
+        let mut game = Game::new_seeded(5);
+        play(&mut game, FPS * 10);
+        let path = std::env::temp_dir().join(format!("rust-driller-{}.save", std::process::id()));
+        game.save_state(&path).unwrap();
+        let mut expected = game.clone();
+
+        // 保存したあとに進めたり書き換えたりしても、読み込めば保存したときに戻る
+        play(&mut game, FPS * 3);
+        game.score += 1000;
+        let mut loaded = Game::load_state(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_ne!(game.state_hash(), expected.state_hash());
+        assert_eq!(loaded.state_hash(), expected.state_hash());
+
+        // 読み込んだあとも、保存しなかった場合と同じように進む
+        for _ in 0..FPS * 10 {
+            play(&mut loaded, 1);
+            play(&mut expected, 1);
+            assert_eq!(
+                loaded.state_hash(),
+                expected.state_hash(),
+                "frame {}",
+                loaded.frame
+            );
+        }
+    }
+
+    #[test]
+    fn saves_without_every_cell_are_completed_from_the_stage() {
+        let game = Game::new_seeded(8);
+        let text = game.state_text();
+        assert!(!text.lines().any(|line| line.starts_with("cell ")));
+        let loaded = Game::parse_state(&text).unwrap();
+        assert_eq!(loaded.state_hash(), game.state_hash());
+    }
+
+    #[test]
+    fn positions_off_the_board_are_errors() {
+        let text = Game::new_seeded(8).state_text();
+        for (from, to) in [
+            ("player 4 5 ", "player -1 5 "),
+            ("player 4 5 ", "player 4 100000 "),
+        ] {
+            assert!(text.contains(from));
+            assert!(Game::parse_state(&text.replace(from, to)).is_err());
+        }
+        for line in [
+            "cell -3 0 None Red - 0 true 0 0 false false false Small",
+            "cell 0 -3 None Red - 0 true 0 0 false false false Small",
+            "cell 9 0 None Red - 0 true 0 0 false false false Small",
+            "cell 0 0 Block Red -1,0 0 true 0 0 false false false Small",
+            "enemy -1 3 Left",
+            "enemy 3 100000 Left",
+        ] {
+            assert!(Game::parse_state(&format!("{}{}\n", text, line)).is_err());
+        }
+    }
+}
@@ -0,0 +1,203 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::model::*;
+
+pub const SCORES_PATH: &str = "scores.json"; // ゲームオーバーになったときの記録の保存先
+pub const HIGH_SCORES_LEN: usize = 10; // 保存しておく記録の数（深い順）
+
+// ゲームオーバーになったときの記録
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct ScoreEntry {
+    pub depth: i32,
+    pub score: u32,
+    pub seed: u64,
+}
+
+impl Game {
+    // ゲームオーバーになったときの深さを記録に加え、深い順のHIGH_SCORES_LEN個だけを残して保存する
+    pub fn record_score(&self) -> io::Result<()> {
+        let entry = ScoreEntry {
+            depth: self.get_depth(),
+            score: self.score,
+            seed: self.seed,
+        };
+        record_score_to(Path::new(SCORES_PATH), entry)
+    }
+}
+
+// 保存してある記録を深い順に（同じ深さなら点の高い順に）最大HIGH_SCORES_LEN個
+// ファイルがない、または読めないときは記録なしとする
+pub fn load_high_scores() -> Vec<ScoreEntry> {
+    load_high_scores_from(Path::new(SCORES_PATH))
+}
+
+fn load_high_scores_from(path: &Path) -> Vec<ScoreEntry> {
+    let Ok(text) = fs::read_to_string(path) else {
+        return Vec::new();
+    };
+    let mut entries = parse_scores(&text).unwrap_or_default();
+    sort_scores(&mut entries);
+    entries
+}
+
+fn record_score_to(path: &Path, entry: ScoreEntry) -> io::Result<()> {
+    let mut entries = load_high_scores_from(path);
+    entries.push(entry);
+    sort_scores(&mut entries);
+    // 同時に動いている別のゲームと書き込みが重なっても壊れたファイルが残らないように、
+    // 一時ファイルに書いてから置き換える
+    let tmp = path.with_extension(format!("json.{}.tmp", std::process::id()));
+    fs::write(&tmp, format_scores(&entries))?;
+    fs::rename(&tmp, path)
+}
+
+fn sort_scores(entries: &mut Vec<ScoreEntry>) {
+    entries.sort_by(|a, b| b.depth.cmp(&a.depth).then(b.score.cmp(&a.score)));
+    entries.truncate(HIGH_SCORES_LEN);
+}
+
+// [{"depth": 120, "score": 340, "seed": 1234}, ...] の形で書く
+fn format_scores(entries: &[ScoreEntry]) -> String {
+    let lines: Vec<String> = entries
+        .iter()
+        .map(|e| {
+            format!(
+                "  {{\"depth\": {}, \"score\": {}, \"seed\": {}}}",
+                e.depth, e.score, e.seed
+            )
+        })
+        .collect();
+    format!("[\n{}\n]\n", lines.join(",\n"))
+}
+
+// format_scoresで書いた形だけを読む。全体が配列でなければNone、読めない要素は飛ばす
+fn parse_scores(text: &str) -> Option<Vec<ScoreEntry>> {
+    let inner = text.trim().strip_prefix('[')?.strip_suffix(']')?;
+    let entries = json_objects(inner)
+        .into_iter()
+        .filter_map(|object| {
+            Some(ScoreEntry {
+                depth: json_number(object, "depth")?,
+                score: json_number(object, "score")?,
+                seed: json_number(object, "seed")?,
+            })
+        })
+        .collect();
+    Some(entries)
+}
+
+// 配列の中身から一番外側の {...} の中身を順に取り出す
+// 文字列の中の括弧やエスケープされた引用符では区切らない
+fn json_objects(inner: &str) -> Vec<&str> {
+    let mut objects = Vec::new();
+    let mut start = None;
+    let mut nest = 0;
+    let mut in_string = false;
+    let mut escaped = false;
+    for (i, c) in inner.char_indices() {
+        if in_string {
+            match c {
+                _ if escaped => escaped = false,
+                '\\' => escaped = true,
+                '"' => in_string = false,
+                _ => {}
+            }
+            continue;
+        }
+        match c {
+            '"' => in_string = true,
+            '{' => {
+                if nest == 0 {
+                    start = Some(i + 1);
+                }
+                nest += 1;
+            }
+            '}' if nest > 0 => {
+                nest -= 1;
+                if nest == 0 {
+                    objects.extend(start.take().map(|s| &inner[s..i]));
+                }
+            }
+            _ => {}
+        }
+    }
+    objects
+}
+
+// {"key": 123, ...} の中身からkeyの数値を取り出す
+fn json_number<T: std::str::FromStr>(object: &str, key: &str) -> Option<T> {
+    let rest = &object[object.find(&format!("\"{}\"", key))? + key.len() + 2..];
+    let rest = rest.trim_start().strip_prefix(':')?.trim_start();
+    let end = rest
+        .find(|c: char| !c.is_ascii_digit() && c != '-')
+        .unwrap_or(rest.len());
+    rest[..end].parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!(
+            "rust-driller-scores-{}-{}.json",
+            name,
+            std::process::id()
+        ))
+    }
+
+    fn entry(depth: i32) -> ScoreEntry {
+        ScoreEntry {
+            depth,
+            score: depth as u32 * 10,
+            seed: depth as u64 + 1000,
+        }
+    }
+
+    #[test]
+    fn a_missing_file_is_an_empty_table() {
+        let path = temp_path("missing");
+        let _ = fs::remove_file(&path);
+        assert!(load_high_scores_from(&path).is_empty());
+    }
+
+    #[test]
+    fn a_corrupt_file_is_an_empty_table() {
+        let path = temp_path("corrupt");
+        fs::write(&path, "{\"depth\": 12, \"score\"").unwrap();
+        assert!(load_high_scores_from(&path).is_empty());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn scores_round_trip_through_the_file() {
+        let entries = vec![entry(30), entry(20), entry(10)];
+        assert_eq!(parse_scores(&format_scores(&entries)), Some(entries));
+        assert_eq!(parse_scores(&format_scores(&[])), Some(Vec::new()));
+    }
+
+    #[test]
+    fn only_the_deepest_ten_are_kept() {
+        let path = temp_path("top_ten");
+        let _ = fs::remove_file(&path);
+        for depth in 1..=HIGH_SCORES_LEN as i32 + 2 {
+            record_score_to(&path, entry(depth)).unwrap();
+        }
+        let entries = load_high_scores_from(&path);
+        let depths: Vec<i32> = entries.iter().map(|e| e.depth).collect();
+        let expected: Vec<i32> = (3..=HIGH_SCORES_LEN as i32 + 2).rev().collect();
+        assert_eq!(depths, expected);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn string_fields_with_escapes_do_not_break_the_entries() {
+        let text = r#"[
+  {"name": "a \"}{\" b", "depth": 7, "score": 70, "seed": 1007},
+  {"depth": 5, "score": 50, "seed": 1005, "note": "}"}
+]"#;
+        assert_eq!(parse_scores(text), Some(vec![entry(7), entry(5)]));
+    }
+}
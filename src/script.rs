@@ -0,0 +1,159 @@
+use std::fs;
+use std::path::Path;
+
+use crate::model::*;
+use crate::replay::parse_command;
+
+// 予定が尽きたあとどうするか
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum ScriptEnd {
+    Player, // プレイヤーの操作に戻す
+    Quit,   // ゲームを終了する
+}
+
+// 決めたフレームに決めたコマンドをupdateへ渡すスクリプト（デモやバグの再現用）
+// 書式は1行に1項目で、#から行末まではコメント
+//   seed: 1234     この盤面で始める（なければ普通に始める）
+//   end: quit      予定が尽きたら終了する（playerならプレイヤーの操作に戻す。既定はplayer）
+//   120: Down      frameが120になるupdateにDownを渡す
+// 予定のないフレームはCommand::Noneで、スクリプトが動いている間はプレイヤーの入力を使わない
+#[derive(Debug, Clone)]
+pub struct Script {
+    pub seed: Option<u64>,
+    pub on_end: ScriptEnd,
+    schedule: Vec<(i32, Command)>, // (フレーム, コマンド)。フレームの昇順
+    next: usize,                   // 次に渡す予定の添字
+}
+
+impl Script {
+    pub fn load(path: &Path) -> Result<Script, String> {
+        let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        Script::parse(&text)
+    }
+
+    pub fn parse(text: &str) -> Result<Script, String> {
+        let mut script = Script {
+            seed: None,
+            on_end: ScriptEnd::Player,
+            schedule: Vec::new(),
+            next: 0,
+        };
+        for (i, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let err = |what: &str| format!("line {}: {}: {}", i + 1, what, line);
+            let Some((key, value)) = line.split_once(':') else {
+                return Err(err("expected '<frame>: <command>'"));
+            };
+            let value = value.trim();
+            match key.trim() {
+                "seed" => script.seed = Some(value.parse().map_err(|_| err("bad seed"))?),
+                "end" => {
+                    script.on_end = match value {
+                        "player" => ScriptEnd::Player,
+                        "quit" => ScriptEnd::Quit,
+                        _ => return Err(err("end must be player or quit")),
+                    }
+                }
+                frame => {
+                    let frame: i32 = frame.parse().map_err(|_| err("bad frame"))?;
+                    let command = parse_command(value).ok_or_else(|| err("bad command"))?;
+                    script.schedule.push((frame, command));
+                }
+            }
+        }
+        script.schedule.sort_by_key(|&(frame, _)| frame);
+        if let Some(w) = script.schedule.windows(2).find(|w| w[0].0 == w[1].0) {
+            return Err(format!("two commands at frame {}", w[0].0));
+        }
+        Ok(script)
+    }
+
+    // frameになるupdateに渡すコマンドを取り出す。予定がなければCommand::None
+    pub fn command_at(&mut self, frame: i32) -> Command {
+        // 渡しそびれた予定は飛ばす
+        while self.next < self.schedule.len() && self.schedule[self.next].0 < frame {
+            self.next += 1;
+        }
+        match self.schedule.get(self.next) {
+            Some(&(f, command)) if f == frame => {
+                self.next += 1;
+                command
+            }
+            _ => Command::None,
+        }
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.next >= self.schedule.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn script_hands_out_each_command_at_its_frame() {
+        let text = "\
+# デモ
+seed: 1234
+end: quit
+
+3: Right   # 右へ
+1: Down
+";
+        let mut script = Script::parse(text).unwrap();
+        assert_eq!(script.seed, Some(1234));
+        assert_eq!(script.on_end, ScriptEnd::Quit);
+        let commands: Vec<Command> = (0..5).map(|frame| script.command_at(frame)).collect();
+        assert_eq!(
+            commands,
+            [
+                Command::None,
+                Command::Down,
+                Command::None,
+                Command::Right,
+                Command::None
+            ]
+        );
+        assert!(script.is_finished());
+    }
+
+    #[test]
+    fn script_defaults_to_returning_control_to_the_player() {
+        let script = Script::parse("10: Left\n").unwrap();
+        assert_eq!(script.seed, None);
+        assert_eq!(script.on_end, ScriptEnd::Player);
+        assert!(!script.is_finished());
+    }
+
+    #[test]
+    fn unknown_command_is_rejected_with_its_line() {
+        let err = Script::parse("1: Down\n2: Jump\n").unwrap_err();
+        assert_eq!(err, "line 2: bad command: 2: Jump");
+    }
+
+    #[test]
+    fn malformed_lines_are_rejected() {
+        assert!(Script::parse("Down\n").is_err());
+        assert!(Script::parse("x: Down\n").is_err());
+        assert!(Script::parse("seed: -1\n").is_err());
+        assert!(Script::parse("end: later\n").is_err());
+        assert_eq!(
+            Script::parse("5: Down\n5: Left\n").unwrap_err(),
+            "two commands at frame 5"
+        );
+    }
+
+    #[test]
+    fn missed_frames_are_skipped() {
+        let mut script = Script::parse("2: Down\n4: Left\n").unwrap();
+        // 2を渡しそびれて3から問い合わせると、2の予定は捨てられる
+        assert_eq!(script.command_at(3), Command::None);
+        assert_eq!(script.command_at(4), Command::Left);
+        assert!(script.is_finished());
+    }
+}
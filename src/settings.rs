@@ -0,0 +1,813 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::model::{
+    BlockColor, AIR_BAND_MIN, AIR_BAND_ROWS, AIR_BROWN_CLEARANCE, BROWN_DIG_HITS, CELLS_X_LEN,
+    CLEAR_BLOCKS_HEIGHT, DEFAULT_COLOR_COUNT, NORMAL_BLOCKS_HEIGHT, PLAYABLE_COLORS,
+    UP_SPACE_HEIGHT,
+};
+
+pub const SETTINGS_PATH: &str = "settings.toml";
+pub const WIDTH_MAX: i32 = 40; // 盤面の幅の上限（ウィンドウが画面に収まるように）
+pub const HEIGHT_MAX: i32 = 1000; // 上の空間・通常ブロック・クリアブロックそれぞれの高さの上限
+
+// 方向キーの割り当て
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum ControlScheme {
+    Arrows,         // 矢印キー
+    Wasd,           // W A S D
+    Ijkl,           // I J K L
+    MirroredArrows, // 矢印キーの左右を入れ替えたもの
+}
+
+impl ControlScheme {
+    pub const ALL: [ControlScheme; 4] = [
+        ControlScheme::Arrows,
+        ControlScheme::Wasd,
+        ControlScheme::Ijkl,
+        ControlScheme::MirroredArrows,
+    ];
+
+    // settings.tomlに書く名前
+    pub fn name(self) -> &'static str {
+        match self {
+            ControlScheme::Arrows => "arrows",
+            ControlScheme::Wasd => "wasd",
+            ControlScheme::Ijkl => "ijkl",
+            ControlScheme::MirroredArrows => "mirrored",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<ControlScheme> {
+        ControlScheme::ALL.into_iter().find(|s| s.name() == name)
+    }
+
+    // 切り替えキーを押したときの次の割り当て（最後の次は最初に戻る）
+    pub fn next(self) -> ControlScheme {
+        let i = ControlScheme::ALL.iter().position(|&s| s == self).unwrap();
+        ControlScheme::ALL[(i + 1) % ControlScheme::ALL.len()]
+    }
+}
+
+// 何回も掘るブロック（既定では茶色）の掘り方
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum DigMode {
+    Instant, // 1回押すごとに1回削る
+    Hold,    // 押したままにすると一定の間隔で削り続ける（1回ごとに削る量は同じ）
+}
+
+impl DigMode {
+    pub const ALL: [DigMode; 2] = [DigMode::Instant, DigMode::Hold];
+
+    // settings.tomlに書く名前
+    pub fn name(self) -> &'static str {
+        match self {
+            DigMode::Instant => "instant",
+            DigMode::Hold => "hold",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<DigMode> {
+        DigMode::ALL.into_iter().find(|m| m.name() == name)
+    }
+}
+
+// 情報パネルに並べる要素。Settings::hudの順に上から詰めて積む
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum HudElement {
+    DigQueue,       // プレイヤーの真下のセル（F9で表示したときだけ）
+    Combo,          // コンボ数と、途切れるまでの残り時間
+    Stage,          // 何ステージ目か
+    Depth,          // 深さ
+    Minimap,        // プレイヤーより下の行のブロックの込み具合とエアの場所（F10で表示したときだけ）
+    Score,          // 点
+    HighScores,     // 深さの記録の上位（記録があるときだけ）
+    CollapseCharge, // 崩落技のたまり具合
+    Air,            // エアゲージ
+    AirNumber,      // エアの残量（%）（F3で表示したときだけ）
+    Colors,         // 全色そろえボーナスの進み具合
+    Key,            // 鍵を拾ったか（鍵を使うルールのときだけ）
+    Lives,          // 残りライフ
+    PowerUps,       // ドリル強化と時間停止の残り時間
+}
+
+impl HudElement {
+    // すべての要素（既定の並び順）
+    pub const ALL: [HudElement; 14] = [
+        HudElement::DigQueue,
+        HudElement::Combo,
+        HudElement::Stage,
+        HudElement::Depth,
+        HudElement::Minimap,
+        HudElement::Score,
+        HudElement::HighScores,
+        HudElement::CollapseCharge,
+        HudElement::Air,
+        HudElement::AirNumber,
+        HudElement::Colors,
+        HudElement::Key,
+        HudElement::Lives,
+        HudElement::PowerUps,
+    ];
+
+    // settings.tomlに書く名前
+    pub fn name(self) -> &'static str {
+        match self {
+            HudElement::DigQueue => "dig_queue",
+            HudElement::Combo => "combo",
+            HudElement::Stage => "stage",
+            HudElement::Depth => "depth",
+            HudElement::Minimap => "minimap",
+            HudElement::Score => "score",
+            HudElement::HighScores => "high_scores",
+            HudElement::CollapseCharge => "collapse",
+            HudElement::Air => "air",
+            HudElement::AirNumber => "air_number",
+            HudElement::Colors => "colors",
+            HudElement::Key => "key",
+            HudElement::Lives => "lives",
+            HudElement::PowerUps => "power_ups",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<HudElement> {
+        HudElement::ALL.into_iter().find(|e| e.name() == name)
+    }
+}
+
+// プレイをまたいで保存する設定
+// settings.tomlには「key = value」の行だけを書く（tomlのごく一部だけを自前で読み書きする）
+#[derive(Debug, Clone)]
+pub struct Settings {
+    pub tutorial_done: bool,      // チュートリアルを最後まで進めたか、飛ばしたか
+    pub color_count: usize,       // 通常ブロックの色数（少ないほど簡単）
+    pub dig_air_cost: i32,        // 掘るたびに余分に減るエア
+    pub walk_air_cost: i32,       // 1マス歩くたびに余分に減るエア
+    pub air_brown_clearance: i32, // エアカプセルの真上のこの行数までは茶色ブロックを置かない
+    pub air_band_rows: i32,       // この行数ごとにエアカプセルの数を保証する（0なら保証しない）
+    pub air_band_min: i32,        // その区切りごとに保証するエアカプセルの数
+    pub air_carry: Option<f64>, // 次のステージに持ち越す残りエアの割合（なければ満タンから始める）
+    pub game_over_color: [u8; 3], // ゲームオーバーでプレイフィールドにかける色（RGB）
+    pub game_over_alpha: u8,    // その色の最終的な不透明度
+    pub game_over_fade_frames: i32, // その不透明度になるまでのフレーム数（0ならすぐに）
+    pub controls: ControlScheme, // 方向キーの割り当て
+    pub shake_warning: bool,    // ブロックが揺れ始めたときに地鳴りを鳴らす
+    pub par_seconds: Option<f32>, // ステージのパータイム（秒。なければ深さから決める）
+    pub surface_return: bool,   // 地上帰還モード（鍵を拾って地上に戻ればクリア）
+    pub block_push: bool,       // 落ちてきたブロックがプレイヤーを左右の空いたセルへ押し出す
+    pub wrap_horizontal: bool,  // 左右の端から反対側の端へ回り込んで歩いたり掘ったりできる
+    pub enemies: bool,          // 掘った横穴に敵が出る
+    pub pause_on_focus_loss: bool, // ウィンドウがフォーカスを失ったら一時停止する
+    pub camera_deadzone: i32,   // プレイヤーが中心からこの行数までずれてもカメラを動かさない
+    pub hud: Vec<HudElement>,   // 情報パネルに上から並べる要素（ないものは出さない）
+    pub dig_mode: DigMode,      // 何回も掘るブロックを押すたびに削るか、押したままで削り続けるか
+    pub dig_hits: Vec<(BlockColor, i32)>, // 色ごとの、壊すまでに掘る回数（ないものは1回）
+    pub auto_repeat: bool,      // 方向キーを押したままにすると、同じ方向をくり返し出す
+    // ゲームのルール（GameConfigの同じ名前の項目にそのまま写す）
+    pub dig_assist: bool,                  // 掘りアシスト
+    pub rigid_fall: bool,                  // 接地していないかたまりを1つの単位として落とす
+    pub clear_requires_key: bool,          // 鍵を拾うまでクリアブロックを掘れない
+    pub last_breath: bool,                 // エアが0になってもしばらく息が続く
+    pub grounding_through_air: bool,       // エアも上のブロックを支える
+    pub fall_pace: Vec<(BlockColor, f64)>, // 色ごとの落ちる速さの倍率（ない色は1.0）
+    // 盤面の大きさ（幅と、上の空間・通常ブロック・クリアブロックの高さ）。読むときに範囲に収める
+    pub width: i32,
+    pub up_space_height: i32,
+    pub normal_blocks_height: i32,
+    pub clear_blocks_height: i32,
+    // 遊びながらキーで切り替える表示（終了時に保存し、次に起動したときも同じにする）
+    pub camera_spring: bool,   // カメラの慣性（F2）
+    pub show_air_number: bool, // エアの残量の数字（F3）
+    pub show_planner: bool,    // 消えるかたまりの予告（F5）
+    pub show_landing: bool,    // 着地演出（F6）
+    pub show_air_path: bool,   // 一番近いエアまでの道のり（F7）
+    pub show_dig_queue: bool,  // 真下のセルの並び（F9）
+    pub show_grounded: bool,   // 接地していないセルを赤く塗る（F4）
+    pub show_minimap: bool,    // 下の行の縮図（F10）
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            tutorial_done: false,
+            color_count: DEFAULT_COLOR_COUNT,
+            dig_air_cost: 0,
+            walk_air_cost: 0,
+            air_brown_clearance: AIR_BROWN_CLEARANCE,
+            air_band_rows: AIR_BAND_ROWS,
+            air_band_min: AIR_BAND_MIN,
+            air_carry: None,
+            game_over_color: [255, 0, 0],
+            game_over_alpha: 128,
+            game_over_fade_frames: 15,
+            controls: ControlScheme::Arrows,
+            shake_warning: true,
+            par_seconds: None,
+            surface_return: false,
+            block_push: false,
+            wrap_horizontal: false,
+            enemies: false,
+            pause_on_focus_loss: true,
+            camera_deadzone: 0,
+            hud: HudElement::ALL.to_vec(),
+            dig_mode: DigMode::Instant,
+            dig_hits: vec![(BlockColor::Brown, BROWN_DIG_HITS)],
+            auto_repeat: true,
+            dig_assist: false,
+            rigid_fall: false,
+            clear_requires_key: false,
+            last_breath: false,
+            grounding_through_air: false,
+            fall_pace: Vec::new(),
+            width: CELLS_X_LEN,
+            up_space_height: UP_SPACE_HEIGHT,
+            normal_blocks_height: NORMAL_BLOCKS_HEIGHT,
+            clear_blocks_height: CLEAR_BLOCKS_HEIGHT,
+            camera_spring: false,
+            show_air_number: false,
+            show_planner: false,
+            show_landing: true,
+            show_air_path: false,
+            show_dig_queue: false,
+            show_grounded: false,
+            show_minimap: false,
+        }
+    }
+}
+
+impl Settings {
+    // ファイルがなければ既定値。ない項目や読めない値も既定値のまま
+    // 知らないキーの行や「key = value」でない行は無視する（saveはそういう行を書き換えずに残す）
+    // 行の途中の#から後ろはコメントとして読まない
+    pub fn load(path: &Path) -> Settings {
+        match fs::read_to_string(path) {
+            Ok(text) => Settings::parse(&text),
+            Err(_) => Settings::default(),
+        }
+    }
+
+    fn parse(text: &str) -> Settings {
+        let mut settings = Settings::default();
+        for line in text.lines() {
+            let line = strip_comment(line).trim();
+            if line.is_empty() {
+                continue;
+            }
+            let Some((key, value)) = line.split_once('=') else {
+                continue;
+            };
+            match key.trim() {
+                "tutorial_done" => {
+                    if let Ok(b) = value.trim().parse() {
+                        settings.tutorial_done = b;
+                    }
+                }
+                "color_count" => {
+                    // 0色では抽選できる色がなくなるので、少なくとも1色にする
+                    if let Ok(n) = value.trim().parse::<usize>() {
+                        settings.color_count = n.clamp(1, PLAYABLE_COLORS);
+                    }
+                }
+                "dig_air_cost" => {
+                    if let Ok(n) = value.trim().parse() {
+                        settings.dig_air_cost = n;
+                    }
+                }
+                "walk_air_cost" => {
+                    if let Ok(n) = value.trim().parse() {
+                        settings.walk_air_cost = n;
+                    }
+                }
+                "air_brown_clearance" => {
+                    if let Ok(n) = value.trim().parse() {
+                        settings.air_brown_clearance = n;
+                    }
+                }
+                "air_band_rows" => {
+                    if let Ok(n) = value.trim().parse() {
+                        settings.air_band_rows = n;
+                    }
+                }
+                "air_band_min" => {
+                    if let Ok(n) = value.trim().parse() {
+                        settings.air_band_min = n;
+                    }
+                }
+                "air_carry" => {
+                    if let Ok(f) = value.trim().parse() {
+                        settings.air_carry = Some(f);
+                    }
+                }
+                "game_over_color" => {
+                    if let Some(rgb) = parse_rgb(value.trim()) {
+                        settings.game_over_color = rgb;
+                    }
+                }
+                "game_over_alpha" => {
+                    if let Ok(n) = value.trim().parse() {
+                        settings.game_over_alpha = n;
+                    }
+                }
+                "game_over_fade_frames" => {
+                    if let Ok(n) = value.trim().parse() {
+                        settings.game_over_fade_frames = n;
+                    }
+                }
+                "par_seconds" => {
+                    if let Ok(f) = value.trim().parse() {
+                        settings.par_seconds = Some(f);
+                    }
+                }
+                "block_push" => {
+                    if let Ok(b) = value.trim().parse() {
+                        settings.block_push = b;
+                    }
+                }
+                "wrap_horizontal" => {
+                    if let Ok(b) = value.trim().parse() {
+                        settings.wrap_horizontal = b;
+                    }
+                }
+                "enemies" => {
+                    if let Ok(b) = value.trim().parse() {
+                        settings.enemies = b;
+                    }
+                }
+                "surface_return" => {
+                    if let Ok(b) = value.trim().parse() {
+                        settings.surface_return = b;
+                    }
+                }
+                "hud" => {
+                    // 知らない名前は飛ばす。1つも読めなければ既定の並びのまま
+                    let hud: Vec<HudElement> = value
+                        .trim()
+                        .trim_matches('"')
+                        .split(',')
+                        .filter_map(|name| HudElement::from_name(name.trim()))
+                        .collect();
+                    if !hud.is_empty() {
+                        settings.hud = hud;
+                    }
+                }
+                "camera_deadzone" => {
+                    if let Ok(n) = value.trim().parse() {
+                        settings.camera_deadzone = n;
+                    }
+                }
+                "pause_on_focus_loss" => {
+                    if let Ok(b) = value.trim().parse() {
+                        settings.pause_on_focus_loss = b;
+                    }
+                }
+                "shake_warning" => {
+                    if let Ok(b) = value.trim().parse() {
+                        settings.shake_warning = b;
+                    }
+                }
+                "camera_spring" => {
+                    if let Ok(b) = value.trim().parse() {
+                        settings.camera_spring = b;
+                    }
+                }
+                "show_air_number" => {
+                    if let Ok(b) = value.trim().parse() {
+                        settings.show_air_number = b;
+                    }
+                }
+                "show_planner" => {
+                    if let Ok(b) = value.trim().parse() {
+                        settings.show_planner = b;
+                    }
+                }
+                "show_landing" => {
+                    if let Ok(b) = value.trim().parse() {
+                        settings.show_landing = b;
+                    }
+                }
+                "show_air_path" => {
+                    if let Ok(b) = value.trim().parse() {
+                        settings.show_air_path = b;
+                    }
+                }
+                "show_dig_queue" => {
+                    if let Ok(b) = value.trim().parse() {
+                        settings.show_dig_queue = b;
+                    }
+                }
+                "dig_assist" => {
+                    if let Ok(b) = value.trim().parse() {
+                        settings.dig_assist = b;
+                    }
+                }
+                "rigid_fall" => {
+                    if let Ok(b) = value.trim().parse() {
+                        settings.rigid_fall = b;
+                    }
+                }
+                "clear_requires_key" => {
+                    if let Ok(b) = value.trim().parse() {
+                        settings.clear_requires_key = b;
+                    }
+                }
+                "last_breath" => {
+                    if let Ok(b) = value.trim().parse() {
+                        settings.last_breath = b;
+                    }
+                }
+                "grounding_through_air" => {
+                    if let Ok(b) = value.trim().parse() {
+                        settings.grounding_through_air = b;
+                    }
+                }
+                "fall_pace" => {
+                    if let Some(pace) = parse_fall_pace(value.trim()) {
+                        settings.fall_pace = pace;
+                    }
+                }
+                "width" => {
+                    if let Ok(n) = value.trim().parse::<i32>() {
+                        settings.width = n.clamp(1, WIDTH_MAX);
+                    }
+                }
+                "up_space_height" => {
+                    if let Ok(n) = value.trim().parse::<i32>() {
+                        settings.up_space_height = n.clamp(1, HEIGHT_MAX);
+                    }
+                }
+                "normal_blocks_height" => {
+                    if let Ok(n) = value.trim().parse::<i32>() {
+                        settings.normal_blocks_height = n.clamp(2, HEIGHT_MAX);
+                    }
+                }
+                "clear_blocks_height" => {
+                    if let Ok(n) = value.trim().parse::<i32>() {
+                        settings.clear_blocks_height = n.clamp(1, HEIGHT_MAX);
+                    }
+                }
+                "show_grounded" => {
+                    if let Ok(b) = value.trim().parse() {
+                        settings.show_grounded = b;
+                    }
+                }
+                "show_minimap" => {
+                    if let Ok(b) = value.trim().parse() {
+                        settings.show_minimap = b;
+                    }
+                }
+                "dig_hits" => {
+                    // "brown:4,blue:2"のように色名と回数を並べる。読めない項目は飛ばす
+                    // 1つも読めなければ既定のまま（茶色まで1回で壊れるようにはしない）
+                    let dig_hits: Vec<(BlockColor, i32)> = value
+                        .trim()
+                        .trim_matches('"')
+                        .split(',')
+                        .filter_map(|entry| {
+                            let (name, hits) = entry.split_once(':')?;
+                            let color = BlockColor::ALL
+                                .into_iter()
+                                .find(|c| format!("{:?}", c).to_lowercase() == name.trim())?;
+                            Some((color, hits.trim().parse().ok()?))
+                        })
+                        .collect();
+                    if !dig_hits.is_empty() {
+                        settings.dig_hits = dig_hits;
+                    }
+                }
+                "auto_repeat" => {
+                    if let Ok(b) = value.trim().parse() {
+                        settings.auto_repeat = b;
+                    }
+                }
+                "dig_mode" => {
+                    if let Some(mode) = DigMode::from_name(value.trim().trim_matches('"')) {
+                        settings.dig_mode = mode;
+                    }
+                }
+                "controls" => {
+                    if let Some(scheme) = ControlScheme::from_name(value.trim().trim_matches('"')) {
+                        settings.controls = scheme;
+                    }
+                }
+                _ => {}
+            }
+        }
+        settings
+    }
+
+    // ファイルにある設定と違う項目の行だけを書き換え、ファイルにない項目は最後に足す
+    // ほかの行（コメント、知らないキー、読めなかった値）はそのまま残す。違う項目がなければ書かない
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let text = fs::read_to_string(path).unwrap_or_default();
+        let changed: Vec<(&str, Option<String>)> = self
+            .entries()
+            .into_iter()
+            .zip(Settings::parse(&text).entries())
+            .filter(|(new, old)| new != old)
+            .map(|(new, _)| new)
+            .collect();
+        if changed.is_empty() {
+            return Ok(());
+        }
+        let mut lines: Vec<String> = Vec::new();
+        let mut written: Vec<&str> = Vec::new();
+        for line in text.lines() {
+            let setting = strip_comment(line);
+            let key = setting.split_once('=').map(|(key, _)| key.trim());
+            match changed.iter().find(|(k, _)| Some(*k) == key) {
+                // 同じキーが何行もあれば最初の行だけを書き換え、残りは消す
+                Some((key, value)) => {
+                    if let (Some(value), false) = (value, written.contains(key)) {
+                        let comment = &line[setting.len()..];
+                        if comment.is_empty() {
+                            lines.push(format!("{} = {}", key, value));
+                        } else {
+                            lines.push(format!("{} = {}  {}", key, value, comment));
+                        }
+                    }
+                    written.push(key);
+                }
+                None => lines.push(line.to_string()),
+            }
+        }
+        for (key, value) in &changed {
+            if let (Some(value), false) = (value, written.contains(key)) {
+                lines.push(format!("{} = {}", key, value));
+            }
+        }
+        fs::write(path, lines.join("\n") + "\n")
+    }
+
+    // settings.tomlに書く項目と値。既定のときは書かない項目の値はNone
+    fn entries(&self) -> Vec<(&'static str, Option<String>)> {
+        let [r, g, b] = self.game_over_color;
+        let dig_hits: Vec<String> = self
+            .dig_hits
+            .iter()
+            .map(|&(color, hits)| format!("{}:{}", color_name(color), hits))
+            .collect();
+        let pace: Vec<String> = self
+            .fall_pace
+            .iter()
+            .map(|&(color, pace)| format!("{}:{}", color_name(color), pace))
+            .collect();
+        // 既定の並びのときは書かない。あとの版で増えた要素も、書いていなければ既定の並びで出る
+        let hud = (self.hud != Settings::default().hud).then(|| {
+            let names: Vec<&str> = self.hud.iter().map(|e| e.name()).collect();
+            format!("\"{}\"", names.join(","))
+        });
+        vec![
+            ("tutorial_done", Some(self.tutorial_done.to_string())),
+            ("color_count", Some(self.color_count.to_string())),
+            ("dig_air_cost", Some(self.dig_air_cost.to_string())),
+            ("walk_air_cost", Some(self.walk_air_cost.to_string())),
+            (
+                "game_over_color",
+                Some(format!("\"{:02x}{:02x}{:02x}\"", r, g, b)),
+            ),
+            ("game_over_alpha", Some(self.game_over_alpha.to_string())),
+            (
+                "game_over_fade_frames",
+                Some(self.game_over_fade_frames.to_string()),
+            ),
+            ("controls", Some(format!("\"{}\"", self.controls.name()))),
+            ("dig_mode", Some(format!("\"{}\"", self.dig_mode.name()))),
+            ("dig_hits", Some(format!("\"{}\"", dig_hits.join(",")))),
+            ("auto_repeat", Some(self.auto_repeat.to_string())),
+            ("shake_warning", Some(self.shake_warning.to_string())),
+            ("surface_return", Some(self.surface_return.to_string())),
+            ("block_push", Some(self.block_push.to_string())),
+            ("wrap_horizontal", Some(self.wrap_horizontal.to_string())),
+            ("enemies", Some(self.enemies.to_string())),
+            (
+                "pause_on_focus_loss",
+                Some(self.pause_on_focus_loss.to_string()),
+            ),
+            ("camera_deadzone", Some(self.camera_deadzone.to_string())),
+            (
+                "air_brown_clearance",
+                Some(self.air_brown_clearance.to_string()),
+            ),
+            ("air_band_rows", Some(self.air_band_rows.to_string())),
+            ("air_band_min", Some(self.air_band_min.to_string())),
+            ("dig_assist", Some(self.dig_assist.to_string())),
+            ("rigid_fall", Some(self.rigid_fall.to_string())),
+            (
+                "clear_requires_key",
+                Some(self.clear_requires_key.to_string()),
+            ),
+            ("last_breath", Some(self.last_breath.to_string())),
+            (
+                "grounding_through_air",
+                Some(self.grounding_through_air.to_string()),
+            ),
+            ("fall_pace", Some(format!("\"{}\"", pace.join(",")))),
+            ("width", Some(self.width.to_string())),
+            ("up_space_height", Some(self.up_space_height.to_string())),
+            (
+                "normal_blocks_height",
+                Some(self.normal_blocks_height.to_string()),
+            ),
+            (
+                "clear_blocks_height",
+                Some(self.clear_blocks_height.to_string()),
+            ),
+            ("hud", hud),
+            // 深さから決めるとき（既定）は書かない
+            ("par_seconds", self.par_seconds.map(|par| par.to_string())),
+            // 満タンから始めるとき（既定）は書かない
+            ("air_carry", self.air_carry.map(|carry| carry.to_string())),
+            ("camera_spring", Some(self.camera_spring.to_string())),
+            ("show_air_number", Some(self.show_air_number.to_string())),
+            ("show_planner", Some(self.show_planner.to_string())),
+            ("show_landing", Some(self.show_landing.to_string())),
+            ("show_air_path", Some(self.show_air_path.to_string())),
+            ("show_dig_queue", Some(self.show_dig_queue.to_string())),
+            ("show_grounded", Some(self.show_grounded.to_string())),
+            ("show_minimap", Some(self.show_minimap.to_string())),
+        ]
+    }
+}
+
+// 行の#から後ろ（コメント）を取り除く。""の中の#はコメントではない（"#ff0000"など）
+fn strip_comment(line: &str) -> &str {
+    let mut quoted = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            '#' if !quoted => return &line[..i],
+            _ => {}
+        }
+    }
+    line
+}
+
+// "ff0000"のような16進の色を読む。前後の"や先頭の#はあってもなくてもよい
+pub fn parse_rgb(s: &str) -> Option<[u8; 3]> {
+    let s = s.trim_matches('"').trim_start_matches('#');
+    if s.len() != 6 {
+        return None;
+    }
+    let channel = |i: usize| u8::from_str_radix(s.get(i..i + 2)?, 16).ok();
+    Some([channel(0)?, channel(2)?, channel(4)?])
+}
+
+// settings.tomlに書く色の名前（"red"など）
+fn color_name(color: BlockColor) -> String {
+    format!("{:?}", color).to_lowercase()
+}
+
+// "red:0.5,yellow:2"のような色ごとの倍率を読む。""（空）ならどの色も1.0
+// 読めない項目が1つでもあれば全体を読めなかったことにする
+fn parse_fall_pace(s: &str) -> Option<Vec<(BlockColor, f64)>> {
+    let s = s.trim_matches('"');
+    if s.trim().is_empty() {
+        return Some(Vec::new());
+    }
+    s.split(',')
+        .map(|item| {
+            let (name, pace) = item.split_once(':')?;
+            let color = BlockColor::ALL
+                .into_iter()
+                .find(|&c| color_name(c) == name.trim())?;
+            let pace: f64 = pace.trim().parse().ok()?;
+            (pace > 0.0).then_some((color, pace))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::GameConfig;
+
+    #[test]
+    fn trailing_comments_are_not_part_of_the_value() {
+        let settings = Settings::parse(
+            "controls = \"wasd\"         # direction keys\n\
+             dig_mode = \"hold\"  # hold to keep chipping\n\
+             auto_repeat = false # no repeat\n\
+             game_over_color = \"#00ff00\" # the # inside the quotes is part of the color\n\
+             # a whole line of comment\n",
+        );
+        assert_eq!(settings.controls, ControlScheme::Wasd);
+        assert_eq!(settings.dig_mode, DigMode::Hold);
+        assert!(!settings.auto_repeat);
+        assert_eq!(settings.game_over_color, [0, 255, 0]);
+    }
+
+    #[test]
+    fn dig_hits_reads_colors_and_counts_before_a_comment() {
+        let settings = Settings::parse("dig_hits = \"brown:4,blue:2\"  # hits per color\n");
+        assert_eq!(
+            settings.dig_hits,
+            vec![(BlockColor::Brown, 4), (BlockColor::Blue, 2)]
+        );
+    }
+
+    #[test]
+    fn malformed_dig_hits_keeps_the_default() {
+        let default = Settings::default().dig_hits;
+        for value in ["\"brown\"", "\"brown:x\"", "\"pink:3\"", "\"\"", "4"] {
+            let settings = Settings::parse(&format!("dig_hits = {}\n", value));
+            assert_eq!(settings.dig_hits, default, "{}", value);
+        }
+        // 読める項目だけを使い、読めない項目は飛ばす
+        let settings = Settings::parse("dig_hits = \"brown:x,red:3\"\n");
+        assert_eq!(settings.dig_hits, vec![(BlockColor::Red, 3)]);
+    }
+
+    #[test]
+    fn colors_missing_from_dig_hits_break_in_one_hit() {
+        let settings = Settings::parse("dig_hits = \"blue:3\"\n");
+        let config = GameConfig {
+            dig_hits: settings.dig_hits,
+            ..GameConfig::default()
+        };
+        assert_eq!(config.dig_hits_of(BlockColor::Blue), 3);
+        assert_eq!(config.dig_hits_of(BlockColor::Brown), 1);
+        assert_eq!(config.dig_hits_of(BlockColor::Red), 1);
+    }
+
+    // テストごとに別のファイルに書く
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("rust-driller-{}-{}.toml", name, std::process::id()))
+    }
+
+    #[test]
+    fn the_default_hud_is_not_saved() {
+        let path = temp_path("hud");
+        let settings = Settings {
+            tutorial_done: true,
+            ..Settings::default()
+        };
+        settings.save(&path).unwrap();
+        assert!(!fs::read_to_string(&path).unwrap().contains("hud"));
+
+        let settings = Settings {
+            hud: vec![HudElement::Depth, HudElement::Air],
+            ..Settings::default()
+        };
+        settings.save(&path).unwrap();
+        assert_eq!(Settings::load(&path).hud, settings.hud);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn save_rewrites_only_the_changed_lines() {
+        let path = temp_path("save");
+        let text = "# my settings\n\
+                    show_planner = false   # erase planner (F5)\n\
+                    show_landing = maybe\n\
+                    from_a_newer_version = 3\n";
+        fs::write(&path, text).unwrap();
+
+        // 何も変えていなければ書かない
+        let mut settings = Settings::load(&path);
+        settings.save(&path).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), text);
+
+        // 変えた項目の行だけを書き換え、ファイルになかった項目は最後に足す
+        settings.show_planner = true;
+        settings.show_air_path = true;
+        settings.save(&path).unwrap();
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            "# my settings\n\
+             show_planner = true  # erase planner (F5)\n\
+             show_landing = maybe\n\
+             from_a_newer_version = 3\n\
+             show_air_path = true\n"
+        );
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn unchanged_settings_do_not_create_a_file() {
+        let path = temp_path("missing");
+        Settings::default().save(&path).unwrap();
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn color_count_is_clamped_to_the_playable_colors() {
+        assert_eq!(Settings::parse("color_count = 0").color_count, 1);
+        assert_eq!(
+            Settings::parse("color_count = 99").color_count,
+            PLAYABLE_COLORS
+        );
+        assert_eq!(Settings::parse("color_count = 3").color_count, 3);
+    }
+
+    #[test]
+    fn board_sizes_are_clamped() {
+        let settings = Settings::parse(
+            "width = 100000\nup_space_height = 0\nnormal_blocks_height = 1\nclear_blocks_height = 5000\n",
+        );
+        assert_eq!(settings.width, WIDTH_MAX);
+        assert_eq!(settings.up_space_height, 1);
+        assert_eq!(settings.normal_blocks_height, 2);
+        assert_eq!(settings.clear_blocks_height, HEIGHT_MAX);
+    }
+}